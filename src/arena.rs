@@ -0,0 +1,400 @@
+//! Items kept in memory the embedder supplies, such as a memory-mapped file, so that they
+//! survive a restart, like upstream's `-e`.
+//!
+//! The memory starts with a header: the magic `INCRAREN`, the layout version (`u32`), whether
+//! it's in use (`u32`), how far records go (`u64`) and the memory's length (`u64`). Records
+//! follow, each its length (`u32`, a multiple of 8), whether it's live (`u8`), whether the item
+//! is stale (`u8`), key length (`u16`), flags (`u32`), expiry in clock seconds (`u32`), CAS value
+//! (`u64`), value length (`u32`), key and value. A record that isn't live is free space. All
+//! numbers are little-endian.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::{ControlFlow, Range};
+
+use crate::storage::Storage;
+use crate::{bump_cas, Entry, Map, ReassignError, SlabStats};
+
+const MAGIC: &[u8; 8] = b"INCRAREN";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 32;
+const RECORD_HEADER_LEN: usize = 28;
+/// Free space smaller than this is left in the record before it rather than split off.
+const MIN_RECORD_LEN: usize = 32;
+
+/// Storage keeping a copy of every item in `memory`, which it's opened on again after a
+/// restart to get them back. The items are served from `items`, which is filled from the
+/// memory on opening, so values take room on the heap too.
+///
+/// The memory is marked in use while the arena is open, and no longer once it's dropped, so
+/// an arena that wasn't dropped, say because the process was killed, opens empty. Flush a
+/// memory-mapped file only after dropping the handler.
+///
+/// Once the memory is full, items are evicted to make room for a new one. Those evictions
+/// happen beneath the handler, like [`Slabs`](crate::Slabs)'. An item larger than the memory
+/// is only kept in `items`, so it's served until the restart.
+///
+/// ```
+/// use incr_memcached::{Arena, Clock, CommandHandler, Map};
+///
+/// # struct Uptime;
+/// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+/// let mut memory = vec![0; 64 * 1024];
+/// let handler: CommandHandler<Arena, _> =
+///     CommandHandler::with_clock(Arena::open(&mut memory, Map::default()), Uptime);
+/// drop(handler);
+/// let reopened: Arena = Arena::open(&mut memory, Map::default());
+/// assert!(reopened.restored());
+/// ```
+pub struct Arena<'a, S = Map<Box<[u8]>, Entry>> {
+    items: S,
+    memory: &'a mut [u8],
+    /// Where each key's record is.
+    records: Map<Box<[u8]>, usize>,
+    /// Free records by where they start, with their length. Adjacent ones are merged.
+    free: BTreeMap<usize, usize>,
+    /// Where the records end.
+    end: usize,
+    restored: bool,
+}
+
+impl<'a, S: Storage> Arena<'a, S> {
+    /// Opens the arena in `memory`, adding the items it holds to `items`, which would be empty.
+    /// If it was in use by an arena that wasn't dropped, or doesn't hold one at all, it's
+    /// formatted empty instead.
+    ///
+    /// # Panics
+    ///
+    /// If `memory` is too small for the header.
+    pub fn open(memory: &'a mut [u8], items: S) -> Self {
+        assert!(memory.len() >= HEADER_LEN, "arena memory too small");
+        let mut arena = Self {
+            items,
+            memory,
+            records: Map::default(),
+            free: BTreeMap::default(),
+            end: HEADER_LEN,
+            restored: false,
+        };
+        arena.restored = arena.is_clean() && arena.rebuild();
+        if !arena.restored {
+            arena.format();
+        }
+        arena.memory[12..16].copy_from_slice(&1u32.to_le_bytes());
+        arena
+    }
+
+    /// Whether the items came back from the memory, or it was formatted.
+    pub fn restored(&self) -> bool {
+        self.restored
+    }
+
+    fn is_clean(&self) -> bool {
+        &self.memory[..8] == MAGIC
+            && read_u32(self.memory, 8) == VERSION
+            && read_u32(self.memory, 12) == 0
+            && read_u64(self.memory, 24) == self.memory.len() as u64
+    }
+
+    /// Walks the records into `items`. Returns whether they made sense, and if not, leaves the
+    /// mess to [`format`](Self::format).
+    fn rebuild(&mut self) -> bool {
+        let Ok(end) = usize::try_from(read_u64(self.memory, 16)) else {
+            return false;
+        };
+        if !(HEADER_LEN..=self.memory.len()).contains(&end) {
+            return false;
+        }
+        self.end = end;
+        let mut max_cas = 0;
+        let mut offset = HEADER_LEN;
+        while offset < end {
+            let len = read_u32(self.memory, offset) as usize;
+            if len < RECORD_HEADER_LEN || !len.is_multiple_of(8) || len > end - offset {
+                return false;
+            }
+            let record = &self.memory[offset..offset + len];
+            if record[4] == 0 {
+                self.release_range(offset, len);
+            } else {
+                let Some((key, entry)) = parse_record(record) else {
+                    return false;
+                };
+                max_cas = max_cas.max(entry.cas);
+                // Storage with less room than the memory drops what it can't hold.
+                if self.items.try_insert(key, entry).is_ok() {
+                    self.records.insert(key.into(), offset);
+                } else {
+                    self.release_range(offset, len);
+                }
+            }
+            offset += len;
+        }
+        bump_cas(max_cas);
+        true
+    }
+
+    fn format(&mut self) {
+        self.items.clear();
+        self.records.clear();
+        self.free.clear();
+        self.memory[..8].copy_from_slice(MAGIC);
+        self.memory[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        let len = self.memory.len() as u64;
+        self.memory[24..32].copy_from_slice(&len.to_le_bytes());
+        self.set_end(HEADER_LEN);
+    }
+
+    fn set_end(&mut self, end: usize) {
+        self.end = end;
+        self.memory[16..24].copy_from_slice(&(end as u64).to_le_bytes());
+    }
+
+    /// Room for a record of `len` bytes, from free space or past the end. Its length may be
+    /// more than asked for.
+    fn allocate(&mut self, len: usize) -> Option<Range<usize>> {
+        let found = self.free.iter().find(|(_, &free)| free >= len);
+        if let Some((&offset, &free)) = found {
+            self.free.remove(&offset);
+            if free - len >= MIN_RECORD_LEN {
+                self.mark_free(offset + len, free - len);
+                self.free.insert(offset + len, free - len);
+                return Some(offset..offset + len);
+            }
+            return Some(offset..offset + free);
+        }
+        if len > self.memory.len() - self.end {
+            return None;
+        }
+        let start = self.end;
+        self.set_end(start + len);
+        Some(start..start + len)
+    }
+
+    fn mark_free(&mut self, offset: usize, len: usize) {
+        let len32 = u32::try_from(len).expect("records are smaller than 4 GB");
+        self.memory[offset..offset + 4].copy_from_slice(&len32.to_le_bytes());
+        self.memory[offset + 4] = 0;
+    }
+
+    /// Frees the record at `offset`.
+    fn release(&mut self, offset: usize) {
+        let len = read_u32(self.memory, offset) as usize;
+        self.release_range(offset, len);
+    }
+
+    /// Adds `len` bytes at `offset` to the free space, merged with what's free either side of
+    /// them, or given back to what's past the end.
+    fn release_range(&mut self, mut offset: usize, mut len: usize) {
+        if let Some((&before, &before_len)) = self.free.range(..offset).next_back() {
+            if before + before_len == offset {
+                self.free.remove(&before);
+                offset = before;
+                len += before_len;
+            }
+        }
+        if let Some(after_len) = self.free.remove(&(offset + len)) {
+            len += after_len;
+        }
+        if offset + len == self.end {
+            self.set_end(offset);
+        } else {
+            self.mark_free(offset, len);
+            self.free.insert(offset, len);
+        }
+    }
+
+    /// Writes `key`'s item to a new record, in place of its old one, evicting other items if
+    /// need be.
+    fn persist(&mut self, key: &[u8]) {
+        if let Some(offset) = self.records.remove(key) {
+            self.release(offset);
+        }
+        let Some(value_len) = self.items.read(key, |entry| entry.value.len()) else {
+            return;
+        };
+        let len = (RECORD_HEADER_LEN + key.len() + value_len).next_multiple_of(8);
+        if len > self.memory.len() - HEADER_LEN {
+            return;
+        }
+        let range = loop {
+            if let Some(range) = self.allocate(len) {
+                break range;
+            }
+            let victim = self.records.keys().find(|&other| **other != *key).cloned();
+            let Some(victim) = victim else {
+                return;
+            };
+            self.items.remove(&victim);
+            let offset = self.records.remove(&victim).expect("listed key");
+            self.release(offset);
+        };
+        let Self { items, memory, .. } = self;
+        let record = &mut memory[range.clone()];
+        items.read(key, |entry| write_record(record, key, entry));
+        self.records.insert(key.into(), range.start);
+    }
+}
+
+impl<S> Drop for Arena<'_, S> {
+    fn drop(&mut self) {
+        // Everything's written through already, so it's only to say so.
+        self.memory[12..16].copy_from_slice(&0u32.to_le_bytes());
+    }
+}
+
+/// What a record holds of an entry, to tell whether it needs writing again.
+fn persisted(entry: &Entry) -> (u32, u32, u64, bool, *const u8, usize) {
+    (
+        entry.flags,
+        entry.expires_at,
+        entry.cas,
+        entry.stale,
+        entry.value.as_ptr(),
+        entry.value.len(),
+    )
+}
+
+impl<S: Storage> Storage for Arena<'_, S> {
+    type Cursor = S::Cursor;
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.items.read(key, f)
+    }
+
+    /// Writes the item again if `f` changed it, rather than only marking it fetched, say.
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        let before = self.items.read(key, persisted)?;
+        let r = self.items.update(key, f)?;
+        if self.items.read(key, persisted) != Some(before) {
+            self.persist(key);
+        }
+        Some(r)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        let old = self.items.insert(key, entry);
+        self.persist(key);
+        old
+    }
+
+    fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+        let old = self.items.try_insert(key, entry)?;
+        self.persist(key);
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        if let Some(offset) = self.records.remove(key) {
+            self.release(offset);
+        }
+        self.items.remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn clear(&mut self) {
+        self.format();
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        self.items.capacity()
+    }
+
+    fn slab_stats(&self) -> Option<SlabStats> {
+        self.items.slab_stats()
+    }
+
+    fn class_of(entry: &Entry) -> u32 {
+        S::class_of(entry)
+    }
+
+    fn reassign(
+        &mut self,
+        src: Option<u32>,
+        dst: u32,
+        mut evicted: impl FnMut(&[u8], &Entry),
+    ) -> Result<(), ReassignError> {
+        let mut gone = Vec::new();
+        let moved = self.items.reassign(src, dst, |key, entry| {
+            gone.push(Box::<[u8]>::from(key));
+            evicted(key, entry);
+        });
+        for key in gone {
+            if let Some(offset) = self.records.remove(&key) {
+                self.release(offset);
+            }
+        }
+        moved
+    }
+
+    /// Flushed items left in the memory would come back after a restart.
+    fn flush_lazily(&self) -> bool {
+        false
+    }
+
+    fn allocates_values(&self) -> bool {
+        self.items.allocates_values()
+    }
+
+    fn scan<B>(&self, f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.items.scan(f)
+    }
+
+    fn scan_from<B>(
+        &self,
+        cursor: &mut Self::Cursor,
+        f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        self.items.scan_from(cursor, f)
+    }
+}
+
+fn read_u32(memory: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(memory[offset..offset + 4].try_into().expect("4 bytes"))
+}
+
+fn read_u64(memory: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(memory[offset..offset + 8].try_into().expect("8 bytes"))
+}
+
+/// Fills `record`, which has room for it, with `key`'s item.
+fn write_record(record: &mut [u8], key: &[u8], entry: &Entry) {
+    let len = u32::try_from(record.len()).expect("records are smaller than 4 GB");
+    let key_len = u16::try_from(key.len()).expect("keys are short");
+    let value_len = u32::try_from(entry.value.len()).expect("values are smaller than 4 GB");
+    record[0..4].copy_from_slice(&len.to_le_bytes());
+    record[4] = 1;
+    record[5] = entry.stale.into();
+    record[6..8].copy_from_slice(&key_len.to_le_bytes());
+    record[8..12].copy_from_slice(&entry.flags.to_le_bytes());
+    record[12..16].copy_from_slice(&entry.expires_at.to_le_bytes());
+    record[16..24].copy_from_slice(&entry.cas.to_le_bytes());
+    record[24..28].copy_from_slice(&value_len.to_le_bytes());
+    let (key_bytes, rest) = record[RECORD_HEADER_LEN..].split_at_mut(key.len());
+    key_bytes.copy_from_slice(key);
+    entry.value.read(0, &mut rest[..entry.value.len()]);
+}
+
+fn parse_record(record: &[u8]) -> Option<(&[u8], Entry)> {
+    let key_len = u16::from_le_bytes([record[6], record[7]]) as usize;
+    let value_len = read_u32(record, 24) as usize;
+    let rest = &record[RECORD_HEADER_LEN..];
+    if key_len + value_len > rest.len() {
+        return None;
+    }
+    let (key, rest) = rest.split_at(key_len);
+    Some((
+        key,
+        Entry {
+            flags: read_u32(record, 8),
+            expires_at: read_u32(record, 12),
+            cas: read_u64(record, 16),
+            stale: record[5] != 0,
+            ..Entry::new(rest[..value_len].to_vec())
+        },
+    ))
+}
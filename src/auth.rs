@@ -0,0 +1,21 @@
+//! Checking credentials for SASL PLAIN.
+
+use core::fmt;
+
+/// Decides whether a user may connect. Supplied by the application, which knows where
+/// credentials live.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, user: &[u8], password: &[u8]) -> bool;
+}
+
+impl<F: Fn(&[u8], &[u8]) -> bool + Send + Sync> Authenticator for F {
+    fn authenticate(&self, user: &[u8], password: &[u8]) -> bool {
+        self(user, password)
+    }
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authenticator")
+    }
+}
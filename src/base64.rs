@@ -0,0 +1,61 @@
+//! Standard-alphabet, padded base64, as used by the meta protocol's `b` flag.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Length of the encoding of `len` bytes.
+pub const fn encoded_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+fn decode_digit(c: u8) -> Option<u32> {
+    Some(match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    } as u32)
+}
+
+/// Decodes `input` into `out`. Fails on malformed input or if the result doesn't fit.
+pub fn decode<const N: usize>(input: &[u8], out: &mut heapless::Vec<u8, N>) -> Result<(), ()> {
+    if input.is_empty() || !input.len().is_multiple_of(4) {
+        return Err(());
+    }
+    let last = input.len() / 4 - 1;
+    for (i, group) in input.chunks(4).enumerate() {
+        let padding = match group {
+            [_, _, b'=', b'='] if i == last => 2,
+            [_, _, _, b'='] if i == last => 1,
+            _ => 0,
+        };
+        let mut bits = 0;
+        for &c in &group[..4 - padding] {
+            bits = bits << 6 | decode_digit(c).ok_or(())?;
+        }
+        bits <<= 6 * padding;
+        let bytes = [(bits >> 16) as u8, (bits >> 8) as u8, bits as u8];
+        out.extend_from_slice(&bytes[..3 - padding])?;
+    }
+    Ok(())
+}
+
+/// Encodes `input` into `out`. Fails if the result doesn't fit.
+pub fn encode<const N: usize>(input: &[u8], out: &mut heapless::Vec<u8, N>) -> Result<(), ()> {
+    for group in input.chunks(3) {
+        let mut bits = 0;
+        for (i, &b) in group.iter().enumerate() {
+            bits |= (b as u32) << (16 - 8 * i);
+        }
+        for i in 0..4 {
+            let c = if i <= group.len() {
+                ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize]
+            } else {
+                b'='
+            };
+            out.push(c).map_err(|_| ())?;
+        }
+    }
+    Ok(())
+}
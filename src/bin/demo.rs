@@ -0,0 +1,2921 @@
+use incr_memcached::*;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Counts the bytes the demo holds on the heap, to measure what items cost.
+struct CountingAllocator;
+
+static ALLOCATED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+/// The most bytes asked for at once since it was last reset.
+static LARGEST: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), std::sync::atomic::Ordering::Relaxed);
+        LARGEST.fetch_max(layout.size(), std::sync::atomic::Ordering::Relaxed);
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        ALLOCATED.fetch_sub(layout.size(), std::sync::atomic::Ordering::Relaxed);
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+struct MockSocket {
+    rbuf: VecDeque<u8>,
+}
+
+impl MockSocket {
+    pub fn new() -> Self {
+        Self {
+            rbuf: Default::default(),
+        }
+    }
+}
+
+impl Socket for MockSocket {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if !self.rbuf.is_empty() {
+            let (taken, r) = f(self.rbuf.make_contiguous());
+            self.rbuf.drain(..taken);
+            Some(r)
+        } else {
+            None
+        }
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = [0; 64];
+        let (sent, r) = f(&mut buf);
+        println!("{}", String::from_utf8_lossy(&buf[..sent]));
+        Some(r)
+    }
+}
+
+/// A clock the demos move by hand.
+#[derive(Clone, Default)]
+struct ManualClock(Rc<Cell<u32>>);
+
+impl Clock for ManualClock {
+    fn now(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+/// Transmits `window` bytes at a time, 7 by default, collecting the output.
+struct NarrowSocket {
+    rbuf: VecDeque<u8>,
+    sent: Vec<u8>,
+    window: usize,
+}
+
+impl Default for NarrowSocket {
+    fn default() -> Self {
+        Self {
+            rbuf: Default::default(),
+            sent: Default::default(),
+            window: 7,
+        }
+    }
+}
+
+impl Socket for NarrowSocket {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.rbuf.make_contiguous());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = vec![0; self.window];
+        let (sent, r) = f(&mut buf);
+        self.sent.extend_from_slice(&buf[..sent]);
+        Some(r)
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    // With `--snapshot-file PATH`, a server restarts warm from PATH before the demos run
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--snapshot-file", Some(path)) => snapshot_file_demo(Path::new(&path)),
+            _ => {
+                eprintln!("usage: demo [--snapshot-file PATH]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let mut map = HashMap::new();
+    map.insert(b"foo"[..].into(), Entry::new(b"bar".to_vec()));
+    map.insert(b"bar"[..].into(), Entry::new([b'a'; 200].to_vec()));
+    let events = EventBus::default();
+    let mut handler = CommandHandler::with_settings(
+        map,
+        SystemClock,
+        Settings {
+            detail_delimiter: b'o',
+            events: events.clone(),
+            ..Default::default()
+        },
+    );
+
+    let mut s = MockSocket::new();
+
+    s.rbuf.extend(b"get foo\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"get bar\r\n");
+    while handler.poll(&mut s).progress {}
+
+    // Pipelining: both gets in one chunk, answered in order
+    s.rbuf.extend(b"get foo\r\nget bar\r\n");
+    while handler.poll(&mut s).progress {}
+    // Through 7-byte windows the second get is parsed while the first is still going out
+    let mut narrow = NarrowSocket::default();
+    narrow.rbuf.extend(b"get foo\r\nget bar\r\n");
+    handler.poll(&mut narrow);
+    println!(
+        "left in the socket: {:?}",
+        String::from_utf8_lossy(narrow.rbuf.make_contiguous())
+    );
+    while handler.poll(&mut narrow).progress {}
+    println!("{:?}", String::from_utf8_lossy(&narrow.sent));
+
+    // Ten pipelined gets in one chunk through 16-byte windows, answered in order
+    for max_pending_responses in [16, 4, 0] {
+        let mut pipelined = CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            Settings {
+                max_pending_responses,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        for i in 0..10 {
+            socket
+                .rbuf
+                .extend(format!("set k{i} 0 0 2\r\nv{i}\r\n").as_bytes());
+            while pipelined.poll(&mut socket).progress {}
+        }
+        socket.sent.clear();
+        let mut socket = NarrowSocket {
+            window: 16,
+            ..socket
+        };
+        for i in 0..10 {
+            socket.rbuf.extend(format!("get k{i}\r\n").as_bytes());
+        }
+        // Once the queue is full, the rest of the chunk waits in the socket
+        pipelined.poll(&mut socket);
+        println!(
+            "{max_pending_responses} pending at most, left in the socket: {:?}",
+            String::from_utf8_lossy(socket.rbuf.make_contiguous())
+        );
+        while pipelined.poll(&mut socket).progress {}
+        let expected: String = (0..10)
+            .map(|i| format!("VALUE k{i} 0 2\r\nv{i}\r\nEND\r\n"))
+            .collect();
+        assert_eq!(String::from_utf8_lossy(&socket.sent), expected);
+        println!("all ten answered in order");
+    }
+
+    s.rbuf.extend(b"ge");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"t no");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ooo");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"pe\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"toolongcommand\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats reset\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats sizes\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats conns\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"stats detail on\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"get foo\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"get nope\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"stats detail dump\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"mg foo s v f t\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg foo t s\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg nope v\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"ms new 5 F7\r\nhello\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ms new 6 MA\r\n world\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ms new 1 ME\r\n!\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg new v f\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"md new q O123\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"md new q O456\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"ma counter N0 J10 v\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ma counter MD D20 v\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ma foo v\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"mn\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"me bar\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"ms a2V5IHdpdGggc3BhY2Vz 3 b\r\nabc\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg a2V5IHdpdGggc3BhY2Vz b v\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"me a2V5IHdpdGggc3BhY2Vz b\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg a2V5*** b v\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"mg nope q Oa1 k\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg foo q Oa2 k s\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ms bmV3 2 b k q O3\r\nhi\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ms new 2 ME k O4\r\nhi\r\n");
+    while handler.poll(&mut s).progress {}
+
+    s.rbuf.extend(b"mg herd N30 v t\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"mg herd N30 v t\r\n");
+    while handler.poll(&mut s).progress {}
+
+    // Gated by default
+    s.rbuf.extend(b"shutdown\r\n");
+    while handler.poll(&mut s).progress {}
+    println!("shutdown requested: {:?}", handler.shutdown_requested());
+
+    let mut admin = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            enable_shutdown: true,
+            ..Default::default()
+        },
+    );
+    s.rbuf.extend(b"shutdown now\r\n");
+    while admin.poll(&mut s).progress {}
+    s.rbuf.extend(b"shutdown graceful\r\n");
+    while admin.poll(&mut s).progress {}
+    println!("shutdown requested: {:?}", admin.shutdown_requested());
+
+    let mut watching = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            events,
+            ..Default::default()
+        },
+    );
+    let mut w = MockSocket::new();
+    w.rbuf.extend(b"watch fetchers mutations\r\n");
+    while watching.poll(&mut w).progress {}
+    s.rbuf.extend(b"get foo\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"ms bmV3AWtleQ== 2 b\r\nhi\r\n");
+    while handler.poll(&mut s).progress {}
+    s.rbuf.extend(b"md new q\r\n");
+    while handler.poll(&mut s).progress {}
+    while watching.poll(&mut w).progress {}
+
+    let mut small = CommandHandler::new(HashMap::new());
+    for i in 0..40 {
+        s.rbuf
+            .extend(format!("ms key{} 3 q\r\nabc\r\n", i).as_bytes());
+        while small.poll(&mut s).progress {}
+    }
+    s.rbuf.extend(b"cache_memlimit 0\r\n");
+    small.poll(&mut s);
+    s.rbuf.extend(b"stats\r\n");
+    small.poll(&mut s);
+    while small.poll(&mut s).progress {}
+    s.rbuf.extend(b"cache_memlimit 64 noreply\r\nstats\r\n");
+    while small.poll(&mut s).progress {}
+
+    let mut dump = CommandHandler::new(HashMap::new());
+    // "key with spaces", "100%" and "plain"
+    for encoded in ["a2V5IHdpdGggc3BhY2Vz", "MTAwJQ==", "cGxhaW4="] {
+        s.rbuf.extend(b"ms ");
+        s.rbuf.extend(encoded.as_bytes());
+        s.rbuf.extend(b" 1 b q\r\nx\r\n");
+        while dump.poll(&mut s).progress {}
+    }
+    let mut narrow = NarrowSocket::default();
+    narrow.rbuf.extend(b"lru_crawler metadump all\r\n");
+    while dump.poll(&mut narrow).progress {}
+    narrow.rbuf.extend(b"lru_crawler metadump 2,3\r\n");
+    while dump.poll(&mut narrow).progress {}
+    println!("{}", String::from_utf8_lossy(&narrow.sent));
+
+    // Line endings: CRLF split over two reads, a key with a stray CR, then bare LF strict and lenient
+    for lenient_line_endings in [false, true] {
+        let mut endings = CommandHandler::with_settings(
+            HashMap::from([(b"foo"[..].into(), Entry::new(b"bar".to_vec()))]),
+            SystemClock,
+            Settings {
+                lenient_line_endings,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        for chunk in [&b"get foo\r"[..], b"\n", b"get f\roo\r\n", b"get foo\n"] {
+            socket.rbuf.extend(chunk);
+            while endings.poll(&mut socket).progress {}
+        }
+        println!(
+            "lenient={}: {:?}",
+            lenient_line_endings,
+            String::from_utf8_lossy(&socket.sent)
+        );
+    }
+
+    // Malformed lengths: with no telling where a data block would end, the next line is a command
+    let mut lengths = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set n 0 0 99999999999999999999\r\nget n\r\n"[..],
+        b"set n 0 0 abc\r\nget n\r\n",
+        b"set n 0 0 +3\r\nget n\r\n",
+        b"set n 0 0 -1\r\nget n\r\n",
+        b"ms n 1x\r\nx\r\n",
+        b"set n 0 0 003\r\nxyz\r\n",
+        b"get n\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while lengths.poll(&mut socket).progress {}
+    }
+    assert_eq!(
+        String::from_utf8_lossy(&socket.sent),
+        "CLIENT_ERROR bad data chunk\r\nEND\r\n\
+         CLIENT_ERROR bad command line format\r\nEND\r\n\
+         CLIENT_ERROR bad command line format\r\nEND\r\n\
+         CLIENT_ERROR bad command line format\r\nEND\r\n\
+         CLIENT_ERROR bad command line format\r\n\
+         STORED\r\nVALUE n 0 3\r\nxyz\r\nEND\r\n"
+    );
+
+    // Values are binary: one holding a command line, then one arriving a byte per read
+    let mut binary_safe = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"set blob 0 0 14\r\n\0\r\nget foo\r\n \n\r\n");
+    while binary_safe.poll(&mut socket).progress {}
+    for &c in b"set trickle 0 0 4\r\na\rb\n\r\n" {
+        socket.rbuf.push_back(c);
+        while binary_safe.poll(&mut socket).progress {}
+    }
+    for request in [&b"get blob\r\n"[..], b"get trickle\r\n"] {
+        socket.rbuf.extend(request);
+        while binary_safe.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Data blocks longer and shorter than announced aren't stored, and the connection recovers
+    let mut chunks = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set long 0 0 3\r\nabcde\r\n"[..],
+        b"set short 0 0 5\r\nabc\r\n",
+        b"get short\r\n",
+        b"get long\r\n",
+        b"get short\r\n",
+        b"set ok 0 0 3\r\nabc\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while chunks.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // A 1 KB response through 8-byte windows goes out in a single poll, given enough transmits
+    let mut map = HashMap::new();
+    map.insert(b"big"[..].into(), Entry::new(vec![b'x'; 1024]));
+    let mut segmented = CommandHandler::with_settings(
+        map,
+        SystemClock,
+        Settings {
+            max_transmits_per_poll: 256,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket {
+        window: 8,
+        ..Default::default()
+    };
+    socket.rbuf.extend(b"get big\r\n");
+    segmented.poll(&mut socket);
+    // The first poll read the request: its transmit half came too early for the response.
+    assert!(socket.sent.is_empty());
+    segmented.poll(&mut socket);
+    assert!(!segmented.needs_write());
+    assert!(socket.sent.ends_with(b"\r\nEND\r\n"));
+    println!("{} bytes in one poll", socket.sent.len());
+
+    // The same requests served out of a HashMap and a BTreeMap answer the same
+    let hashed = backend_transcript(HashMap::new());
+    let sorted = backend_transcript(std::collections::BTreeMap::new());
+    assert_eq!(hashed, sorted);
+    let fnv = backend_transcript(HashMap::with_hasher(FnvBuildHasher));
+    assert_eq!(fnv, sorted);
+    let mut fnv = CommandHandler::with_hasher(FnvBuildHasher);
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"set a 0 0 1\r\nx\r\nget a\r\n");
+    while fnv.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"STORED\r\nVALUE a 0 1\r\nx\r\nEND\r\n");
+    println!("{:?}", String::from_utf8_lossy(&sorted));
+
+    // Entries made up front are served with their flags, and show only the start of a long
+    // value when debugged
+    let mut data = HashMap::new();
+    data.insert(b"json"[..].into(), Entry::with_flags(b"{}".to_vec(), 2));
+    data.insert(b"big"[..].into(), Entry::new(vec![b'x'; 1 << 20]));
+    let mut handler = CommandHandler::new(data);
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get json\r\n");
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"VALUE json 2 2\r\n{}\r\nEND\r\n");
+    let big = format!("{:?}", Entry::new(vec![b'x'; 1 << 20]));
+    assert!(big.len() < 200 && big.contains("(1048576 bytes)"));
+    println!("{}", big);
+    let mut handler = CommandHandler::default();
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get nothing\r\n");
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"END\r\n");
+
+    // Items written from code are served over the protocol, and the protocol's writes read
+    // back from code, with CAS values and expiry kept as the commands keep them
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let mut socket = NarrowSocket::default();
+    let mut serve = |handler: &mut CommandHandler<_, _>, request: &[u8]| {
+        socket.sent.clear();
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+        String::from_utf8_lossy(&socket.sent).into_owned()
+    };
+    handler
+        .insert(b"n", Entry::with_flags(b"41".to_vec(), 7))
+        .unwrap();
+    assert_eq!(
+        serve(&mut handler, b"get n\r\n"),
+        "VALUE n 7 2\r\n41\r\nEND\r\n"
+    );
+    let before = handler.get(b"n").unwrap();
+    assert!(before.fetched());
+    assert_eq!(serve(&mut handler, b"incr n 1\r\n"), "42\r\n");
+    let after = handler.get(b"n").unwrap();
+    assert_eq!((&after.value()[..], after.flags()), (&b"42"[..], 7));
+    assert!(after.cas() > before.cas() && !after.fetched());
+    handler
+        .insert(b"n", Entry::with_expiry(b"soon".to_vec(), 0, 1005))
+        .unwrap();
+    assert!(handler.get(b"n").unwrap().cas() > after.cas());
+    assert!(serve(&mut handler, b"me n\r\n").contains("exp=5 "));
+    clock.0.set(1005);
+    assert!(!handler.contains_key(b"n"));
+    assert_eq!(serve(&mut handler, b"get n\r\n"), "END\r\n");
+    assert!(handler.insert(b"bad key", Entry::new(Vec::new())).is_err());
+    assert!(handler
+        .insert(b"huge", Entry::new(vec![0; 2 << 20]))
+        .is_err());
+    // A response already sending a value finishes it, whatever's written meanwhile
+    handler
+        .insert(b"big", Entry::new(vec![b'o'; 8000]))
+        .unwrap();
+    let mut narrow = NarrowSocket {
+        window: 1000,
+        ..Default::default()
+    };
+    narrow.rbuf.extend(b"get big\r\n");
+    handler.poll(&mut narrow);
+    handler.poll(&mut narrow);
+    handler.insert(b"big", Entry::new(b"new".to_vec())).unwrap();
+    let removed = handler.remove(b"big").unwrap();
+    assert_eq!(&removed.value()[..], b"new");
+    while handler.poll(&mut narrow).progress {}
+    assert!(narrow.sent.starts_with(b"VALUE big 0 8000\r\nooo"));
+    assert_eq!(narrow.sent.len(), 18 + 8000 + 7);
+    assert_eq!(serve(&mut handler, b"get big\r\n"), "END\r\n");
+    assert!(handler.is_empty() && handler.memory_stats().value_bytes == 0);
+    println!(
+        "{}",
+        serve(&mut handler, b"stats\r\n")
+            .lines()
+            .filter(|line| line.contains("cmd_set"))
+            .collect::<String>()
+    );
+
+    // Iterating sees what's stored through either path, and pruning half the items leaves
+    // the counts and memory accounting as deleting them would
+    let btree = iteration_demo(std::collections::BTreeMap::new());
+    let hashed = iteration_demo(HashMap::new());
+    assert_eq!(btree, hashed);
+    println!("{:?} left after pruning", btree);
+
+    // Counting per key prefix without `stats detail on`: keys without the delimiter aren't
+    // counted, and prefixes past the cap are counted together rather than dropped
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            detail_enabled: true,
+            detail_max_prefixes: 2,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(
+        b"set a:1 0 0 1\r\nx\r\nset b:1 0 0 1\r\nx\r\nset c:1 0 0 1\r\nx\r\n\
+        set plain 0 0 1\r\nx\r\nget a:1\r\nget a:2\r\nget b:1\r\nget c:1\r\nget d:1\r\n\
+        md a:1\r\nmd d:1\r\nget plain\r\nmg c:1 v\r\n",
+    );
+    while handler.poll(&mut socket).progress {}
+    handler.insert(b"b:2", Entry::new(b"y".to_vec())).unwrap();
+    let prefixes: Vec<_> = handler
+        .prefix_stats()
+        .map(|(prefix, c)| {
+            let prefix = String::from_utf8_lossy(prefix).into_owned();
+            (prefix, [c.get, c.hit, c.set, c.del])
+        })
+        .collect();
+    let other = handler.prefix_stats_other();
+    assert_eq!(
+        prefixes,
+        [("a".into(), [2, 1, 1, 1]), ("b".into(), [1, 1, 2, 0])]
+    );
+    assert_eq!([other.get, other.hit, other.set, other.del], [3, 2, 1, 1]);
+    println!("{:?}, other {:?}", prefixes, other);
+
+    // Handlers sharing one storage serve each other's items
+    let shared = Rc::new(std::cell::RefCell::new(HashMap::new()));
+    let mut a = CommandHandler::new(shared.clone());
+    let mut b = CommandHandler::new(shared.clone());
+    let mut socket_a = NarrowSocket::default();
+    let mut socket_b = NarrowSocket::default();
+    socket_a.rbuf.extend(b"set foo 0 0 3\r\nbar\r\n");
+    while a.poll(&mut socket_a).progress {}
+    socket_b.rbuf.extend(b"get foo\r\n");
+    while b.poll(&mut socket_b).progress {}
+    assert_eq!(socket_b.sent, b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+
+    // A response that's under way keeps sending the value it started with, whatever another
+    // handler does to the item meanwhile
+    let old = vec![b'o'; 1000];
+    shared
+        .borrow_mut()
+        .insert(b"big"[..].into(), Entry::new(old.clone()));
+    socket_a.sent.clear();
+    socket_a.rbuf.extend(b"get big\r\n");
+    a.poll(&mut socket_a);
+    a.poll(&mut socket_a);
+    assert!(a.needs_write());
+    socket_b
+        .rbuf
+        .extend(b"set big 0 0 3\r\nnew\r\nmd big\r\nget big\r\n");
+    while b.poll(&mut socket_b).progress {}
+    while a.poll(&mut socket_a).progress {}
+    let expected = [&b"VALUE big 0 1000\r\n"[..], &old, b"\r\nEND\r\n"].concat();
+    assert_eq!(socket_a.sent, expected);
+    println!(
+        "{:?} while the other sent {} intact bytes",
+        String::from_utf8_lossy(&socket_b.sent),
+        socket_a.sent.len()
+    );
+
+    // A reset handler forgets a connection abandoned mid-request, but keeps the items
+    let mut map = HashMap::new();
+    map.insert(b"kept"[..].into(), Entry::new(b"yes".to_vec()));
+    let mut pooled = CommandHandler::new(map);
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"get kept\r\nget kept\r\nset foo 0 0 5\r\nhel");
+    pooled.poll(&mut socket);
+    assert!(pooled.needs_write() && !pooled.is_idle());
+    pooled.reset();
+    assert!(!pooled.needs_write() && pooled.is_idle());
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get foo\r\nget kept\r\n");
+    while pooled.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"END\r\nVALUE kept 0 3\r\nyes\r\nEND\r\n");
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Handlers on other threads can share a storage behind a mutex
+    let locked = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let writer = std::thread::spawn({
+        let locked = locked.clone();
+        move || {
+            let mut handler = CommandHandler::new(locked);
+            let mut socket = NarrowSocket::default();
+            socket.rbuf.extend(b"ms threaded 5\r\nhello\r\n");
+            while handler.poll(&mut socket).progress {}
+        }
+    });
+    writer.join().unwrap();
+    let mut reader = CommandHandler::new(locked);
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"mg threaded v\r\n");
+    while reader.poll(&mut socket).progress {}
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Gets through 1-byte windows send exactly what they send through wide ones
+    let mut map = HashMap::new();
+    map.insert(b"empty"[..].into(), Entry::new(Vec::new()));
+    let mut trickling = CommandHandler::new(map);
+    let mut socket = NarrowSocket {
+        window: 1,
+        ..Default::default()
+    };
+    for request in [
+        &b"set flagged 4294967295 0 5\r\nhello\r\n"[..],
+        b"get flagged\r\n",
+        b"get empty\r\n",
+        b"get nope flagged\r\n",
+        b"get nope\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while trickling.poll(&mut socket).progress {}
+    }
+    assert_eq!(
+        String::from_utf8_lossy(&socket.sent),
+        "STORED\r\n\
+         VALUE flagged 4294967295 5\r\nhello\r\nEND\r\n\
+         VALUE empty 0 0\r\n\r\nEND\r\n\
+         VALUE flagged 4294967295 5\r\nhello\r\nEND\r\n\
+         END\r\n"
+    );
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // An event loop that only polls again after progress or new input, so it never spins
+    let mut map = HashMap::new();
+    map.insert(b"foo"[..].into(), Entry::new(b"bar".to_vec()));
+    let mut looping = CommandHandler::new(map);
+    let mut socket = NarrowSocket::default();
+    let mut arriving = [&b"get foo\r\n"[..], b"get nope\r\n"].into_iter();
+    loop {
+        let result = looping.poll(&mut socket);
+        if result.progress {
+            continue;
+        }
+        if result.closed {
+            break;
+        }
+        // Here a real loop would wait for the socket to become readable (or writable, if
+        // result.wants_transmit).
+        match arriving.next() {
+            Some(request) => socket.rbuf.extend(request),
+            None => break,
+        }
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // After quit, what's pending still goes out but nothing more is parsed
+    let mut map = HashMap::new();
+    map.insert(b"foo"[..].into(), Entry::new(b"bar".to_vec()));
+    let mut quitting = CommandHandler::new(map);
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"get foo\r\nget foo\r\nquit\r\nset foo 0 0 1\r\nx\r\nget foo\r\n");
+    let result = quitting.poll(&mut socket);
+    assert!(result.should_close && !result.closed && !result.can_receive);
+    assert!(socket.rbuf.is_empty());
+    let mut result = result;
+    while !result.closed {
+        result = quitting.poll(&mut socket);
+    }
+    assert_eq!(socket.sent, b"VALUE foo 0 3\r\nbar\r\nEND\r\n".repeat(2));
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // A peer that isn't speaking memcached gets hung up on
+    let mut confused = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            protocol: Protocol::AsciiOnly,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket::default();
+    for _ in 0..20 {
+        socket.rbuf.extend(b"\x80\x01\x00\x03\r\n");
+    }
+    for _ in 0..100 {
+        if confused.poll(&mut socket).closed {
+            break;
+        }
+    }
+    assert!(confused.is_closed());
+    assert_eq!(socket.sent, b"ERROR\r\n".repeat(16));
+    println!("closed after {} errors", socket.sent.len() / 7);
+
+    // Commands the application registers, through windows narrower than their replies
+    let mut extended = CommandHandler::new(HashMap::new());
+    extended.register_command(PurgePrefix);
+    extended.register_command(Health);
+    let mut socket = NarrowSocket {
+        window: 3,
+        ..Default::default()
+    };
+    socket.rbuf.extend(
+        b"set user:1 0 0 1\r\na\r\nset user:2 0 0 1\r\nb\r\nset page:1 0 0 1\r\nc\r\n\
+          health\r\npurgeprefix user:\r\npurgeprefix\r\nget user:1 page:1\r\nhealth\r\n\
+          bogus\r\n",
+    );
+    while extended.poll(&mut socket).progress {}
+    assert_eq!(
+        String::from_utf8_lossy(&socket.sent),
+        "STORED\r\nSTORED\r\nSTORED\r\nOK 3\r\nPURGED 2\r\n\
+         CLIENT_ERROR bad command line format\r\nVALUE page:1 0 1\r\nc\r\nEND\r\nOK 1\r\n\
+         ERROR\r\n"
+    );
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // A work budget splits a long pipelined batch over many polls without changing a byte
+    let batch: Vec<u8> = (0..200)
+        .flat_map(|i| format!("set k{i} 0 0 3\r\nabc\r\nget k{i} nope\r\n").into_bytes())
+        .collect();
+    let mut transcripts = Vec::new();
+    for budget in [usize::MAX, 100] {
+        let mut handler = CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            Settings {
+                max_bytes_read_per_poll: budget,
+                max_bytes_written_per_poll: budget,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket {
+            window: 64,
+            ..Default::default()
+        };
+        socket.rbuf.extend(&batch);
+        let (mut polls, mut stopped_short) = (0, 0);
+        loop {
+            let (unread, sent) = (socket.rbuf.len(), socket.sent.len());
+            let result = handler.poll(&mut socket);
+            assert!(unread - socket.rbuf.len() <= budget);
+            assert!(socket.sent.len() - sent <= budget);
+            polls += 1;
+            stopped_short += result.more_pending as u32;
+            if !result.progress {
+                break;
+            }
+        }
+        println!("budget {budget}: {polls} polls, {stopped_short} stopped by it");
+        transcripts.push(socket.sent);
+    }
+    assert_eq!(transcripts[0], transcripts[1]);
+
+    // Malformed input reaching every error, as seen by an observer, and what each displays as
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let settings = Settings {
+        max_keys_per_get: 2,
+        item_size_max: 100,
+        max_items: 1,
+        evict_when_full: false,
+        on_error: Some(std::sync::Arc::new({
+            let seen = seen.clone();
+            move |error: &Error| seen.lock().unwrap().push(*error)
+        })),
+        ..Default::default()
+    };
+    let mut strict = CommandHandler::with_settings(HashMap::new(), SystemClock, settings.clone());
+    let mut locked = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            text_requires_auth: true,
+            ..settings
+        },
+    );
+    let long_key = format!("get {}\r\n", "k".repeat(MAX_KEY_LEN + 1));
+    let many_flags = format!("mg foo{}\r\n", " f".repeat(190));
+    for request in [
+        "set foo 0 0 3\r\nbar\r\n",
+        "bogus\r\n",
+        "abcdefghijklmnopqrstuvwxyz\r\n",
+        &long_key,
+        &format!("stats {}\r\n", "x".repeat(MAX_ARGS_LEN + 1)),
+        "get\r\n",
+        "stats bogus\r\n",
+        "mg foo !\r\n",
+        &many_flags,
+        "set foo 0 0 1\r\nxy\r\n",
+        "incr foo 1\r\n",
+        "incr foo x\r\n",
+        "mg !!! b\r\n",
+        "get a b c\r\n",
+        "get foo\n",
+        &format!("set foo 0 0 200\r\n{}\r\n", "x".repeat(200)),
+        "set bar 0 0 1\r\nx\r\n",
+    ] {
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(request.as_bytes());
+        while strict.poll(&mut socket).progress {}
+    }
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get foo\r\n");
+    while locked.poll(&mut socket).progress {}
+    let seen = seen.lock().unwrap();
+    for error in seen.iter() {
+        assert_eq!(
+            [error.to_string().as_bytes(), b"\r\n"].concat(),
+            error.response()
+        );
+        println!("{:?}: {}", error, error);
+    }
+    let variants: std::collections::HashSet<_> = seen.iter().map(std::mem::discriminant).collect();
+    assert_eq!(variants.len(), 17);
+
+    // exptime against a clock moved by hand: 10 seconds to live, and forever
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut expiring = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let mut socket = NarrowSocket::default();
+    for (now, request) in [
+        (1000, &b"set foo 0 10 3\r\nbar\r\n"[..]),
+        (1000, b"set forever 0 0 3\r\nbaz\r\n"),
+        (1009, b"get foo\r\n"),
+        (1010, b"get foo\r\n"),
+        (1010, b"get forever\r\n"),
+        // A negative exptime stores an item that's already gone, so add finds room for it
+        (1010, b"set forever 0 -1 3\r\nbaz\r\n"),
+        (1010, b"get forever\r\n"),
+        (1010, b"ms forever 3 ME T-1\r\nqux\r\n"),
+        (1010, b"ms forever 3 ME\r\nqux\r\n"),
+        (1010, b"get forever\r\n"),
+    ] {
+        clock.0.set(now);
+        socket.rbuf.extend(request);
+        while expiring.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Lazy expiry: an item set to live 10 seconds is served a second before, and is gone (and
+    // reclaimed) at and after its expiry. Expired items don't stop add, nor satisfy replace
+    for (probe, expect) in [(1009, true), (1010, false), (1011, false)] {
+        clock.0.set(1000);
+        let mut expiring = CommandHandler::with_clock(HashMap::new(), clock.clone());
+        let mut socket = NarrowSocket::default();
+        socket
+            .rbuf
+            .extend(b"set foo 0 10 3\r\nbar\r\nset bar 0 10 3\r\nbar\r\n");
+        while expiring.poll(&mut socket).progress {}
+        clock.0.set(probe);
+        socket.sent.clear();
+        socket.rbuf.extend(b"get foo\r\n");
+        while expiring.poll(&mut socket).progress {}
+        let hit = socket.sent.starts_with(b"VALUE foo");
+        assert_eq!(hit, expect, "get at {}", probe);
+        socket.sent.clear();
+        socket
+            .rbuf
+            .extend(b"ms bar 1 MR\r\nx\r\nms foo 1 ME\r\ny\r\nstats\r\n");
+        while expiring.poll(&mut socket).progress {}
+        let sent = String::from_utf8_lossy(&socket.sent).into_owned();
+        let stat = |name: &str| {
+            let line = sent
+                .lines()
+                .find(|l| l.starts_with(&format!("STAT {} ", name)));
+            line.expect("stat").rsplit(' ').next().unwrap().to_owned()
+        };
+        println!(
+            "{}: get {}, replace {}, add {}, expired {}, expired_unfetched {}, get_expired {}, curr_items {}",
+            probe,
+            if hit { "hit" } else { "miss" },
+            sent.lines().next().unwrap(),
+            sent.lines().nth(1).unwrap(),
+            stat("expired"),
+            stat("expired_unfetched"),
+            stat("get_expired"),
+            stat("curr_items"),
+        );
+    }
+
+    // Active expiry: of 10k items, every third lives 10 seconds, the rest 100 or forever. Once
+    // the first lot expire, ticks of 64 reclaim them all in one round without touching the rest
+    clock.0.set(1000);
+    let mut swept = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let mut socket = NarrowSocket {
+        window: 4096,
+        ..Default::default()
+    };
+    for i in 0..10_000 {
+        let ttl = [10, 100, 0][i % 3];
+        socket
+            .rbuf
+            .extend(format!("ms k{} 1 T{} q\r\nx\r\n", i, ttl).as_bytes());
+        while swept.poll(&mut socket).progress {}
+    }
+    assert_eq!(swept.tick(64), 0);
+    clock.0.set(1010);
+    let (mut ticks, mut reclaimed) = (0, 0);
+    while ticks < 10_000usize.div_ceil(64) {
+        reclaimed += swept.tick(64);
+        ticks += 1;
+    }
+    socket.rbuf.extend(b"stats\r\n");
+    while swept.poll(&mut socket).progress {}
+    let sent = String::from_utf8_lossy(&socket.sent).into_owned();
+    assert!(sent.contains("STAT curr_items 6666\r\n"));
+    assert!(sent.contains("STAT expired 3334\r\n"));
+    println!(
+        "{} ticks reclaimed {} items, {} left",
+        ticks,
+        reclaimed,
+        10_000 - reclaimed
+    );
+
+    // CAS values: every mutation, from either of two handlers sharing storage, leaves the item
+    // with a larger one than anything before
+    let shared = Rc::new(std::cell::RefCell::new(HashMap::new()));
+    let mut handlers = [
+        CommandHandler::new(shared.clone()),
+        CommandHandler::new(shared.clone()),
+    ];
+    let mut last = 0;
+    for (i, request) in [
+        &b"set n 0 0 1\r\n1\r\n"[..],
+        b"ms n 1 MR\r\n2\r\n",
+        b"ms m 1 ME\r\n3\r\n",
+        b"ms n 1 MA\r\n4\r\n",
+        b"ms n 1 MP\r\n5\r\n",
+        b"incr n 1\r\n",
+        b"decr n 1\r\n",
+        b"ma m\r\n",
+        b"set n 0 0 1\r\n6\r\n",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(request);
+        while handlers[i % 2].poll(&mut socket).progress {}
+        let key = &request[request.iter().position(|&c| c == b' ').unwrap() + 1..][..1];
+        let cas = shared.borrow().get(key).map(Entry::cas).unwrap();
+        assert!(
+            cas > last,
+            "{:?} left cas {}",
+            String::from_utf8_lossy(request),
+            cas
+        );
+        last = cas;
+        socket.rbuf.extend(b"me n\r\n");
+        while handlers[i % 2].poll(&mut socket).progress {}
+        print!("{}", String::from_utf8_lossy(&socket.sent));
+    }
+
+    // A cap of 3 items: a fourth evicts one, or is refused when evicting is off. Deleting an
+    // item makes room for the next
+    for evict_when_full in [true, false] {
+        // Ordered, so the item evicted is the same every run.
+        let mut capped = CommandHandler::with_settings(
+            std::collections::BTreeMap::new(),
+            SystemClock,
+            Settings {
+                max_items: 3,
+                evict_when_full,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        socket
+            .rbuf
+            .extend(b"set a 0 0 1\r\n1\r\nset b 0 0 1\r\n2\r\nset c 0 0 1\r\n3\r\n");
+        socket
+            .rbuf
+            .extend(b"set c 0 0 1\r\n4\r\nset d 0 0 1\r\n5\r\n");
+        socket
+            .rbuf
+            .extend(b"md d\r\nmd a\r\nmd b\r\nms e 1 ME\r\n6\r\nstats\r\n");
+        while capped.poll(&mut socket).progress {}
+        let sent = String::from_utf8_lossy(&socket.sent).into_owned();
+        let stats: Vec<_> = sent
+            .lines()
+            .filter(|l| {
+                ["curr_items", "limit_items", "evictions"]
+                    .contains(&l.split(' ').nth(1).unwrap_or(""))
+            })
+            .collect();
+        let replies: Vec<_> = sent.lines().filter(|l| !l.starts_with("STAT")).collect();
+        println!("evict {}: {:?} {:?}", evict_when_full, replies, stats);
+    }
+
+    // Values in slabs: store/delete churn at several sizes keeps reusing the same pages, and a
+    // class out of pages evicts its own items
+    let slab_settings = SlabSettings {
+        page_size: 4096,
+        min_chunk_size: 64,
+        growth_factor: 2.0,
+        max_pages: 16,
+    };
+    let mut slabbed = CommandHandler::new(Slabs::new(HashMap::new(), slab_settings.clone()));
+    let mut socket = NarrowSocket {
+        window: 4096,
+        ..Default::default()
+    };
+    let mut malloced = Vec::new();
+    for round in 0..200 {
+        for size in [50, 200, 900, 2000] {
+            let set = format!("set k{}-{} 0 0 {}\r\n", size, round % 4, size);
+            socket.rbuf.extend(set.as_bytes());
+            socket.rbuf.extend(vec![b'v'; size]);
+            socket
+                .rbuf
+                .extend(format!("\r\nmd k{}-{} q\r\n", size, (round + 2) % 4).as_bytes());
+        }
+        socket.rbuf.extend(b"stats slabs\r\n");
+        socket.sent.clear();
+        while slabbed.poll(&mut socket).progress {}
+        let sent = String::from_utf8(socket.sent.clone()).unwrap();
+        let line = sent.lines().find(|l| l.starts_with("STAT total_malloced"));
+        malloced.push(
+            line.unwrap()
+                .rsplit(' ')
+                .next()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap(),
+        );
+    }
+    assert!(malloced[10..].iter().all(|&m| m == malloced[10]));
+    let slab_lines: Vec<_> = String::from_utf8_lossy(&socket.sent)
+        .lines()
+        .filter(|l| l.starts_with("STAT"))
+        .map(str::to_owned)
+        .collect();
+    println!("after churn: {}", slab_lines.join(", "));
+    let mut crowded = CommandHandler::new(Slabs::new(
+        HashMap::new(),
+        SlabSettings {
+            max_pages: 1,
+            ..slab_settings
+        },
+    ));
+    let mut socket = NarrowSocket {
+        window: 4096,
+        ..Default::default()
+    };
+    for i in 0..6 {
+        socket
+            .rbuf
+            .extend(format!("set big{} 0 0 600 noreply\r\n", i).as_bytes());
+        socket.rbuf.extend(vec![b'0' + i; 600]);
+        socket.rbuf.extend(b"\r\n");
+    }
+    socket.rbuf.extend(b"mn\r\nstats slabs\r\n");
+    while crowded.poll(&mut socket).progress {}
+    println!("crowded: {:?}", String::from_utf8_lossy(&socket.sent));
+    assert!(String::from_utf8_lossy(&socket.sent).contains("STAT 5:evicted 2\r\n"));
+    // The oldest went first
+    for i in 0..6 {
+        assert_eq!(
+            crowded.get(format!("big{}", i).as_bytes()).is_some(),
+            i >= 2
+        );
+    }
+
+    // Reading an item left on the heap leaves it there, rather than evicting another to make
+    // room: a page holds one chunk, taken by w once v's second value found no room
+    let mut lone = CommandHandler::new(Slabs::new(
+        HashMap::new(),
+        SlabSettings {
+            max_pages: 1,
+            ..slab_settings
+        },
+    ));
+    let mut socket = NarrowSocket::default();
+    for key in ["v", "v", "w"] {
+        socket
+            .rbuf
+            .extend(format!("set {} 0 0 3000 noreply\r\n", key).as_bytes());
+        socket.rbuf.extend([b'x'; 3000]);
+        socket.rbuf.extend(b"\r\n");
+    }
+    socket
+        .rbuf
+        .extend(b"mg v t\r\nget v\r\nmg v v\r\nstats slabs\r\n");
+    while lone.poll(&mut socket).progress {}
+    let sent = String::from_utf8_lossy(&socket.sent);
+    assert!(sent.contains("STAT 7:used_chunks 1\r\n"), "{}", sent);
+    assert!(sent.contains("STAT 7:evicted 0\r\n"), "{}", sent);
+    assert!(lone.get(b"w").is_some());
+
+    // A value being sent keeps its chunk, however often the item is overwritten meanwhile
+    let shared = Rc::new(std::cell::RefCell::new(Slabs::new(
+        HashMap::new(),
+        SlabSettings {
+            max_pages: 1,
+            ..slab_settings
+        },
+    )));
+    let (mut reader, mut writer) = (
+        CommandHandler::new(shared.clone()),
+        CommandHandler::new(shared.clone()),
+    );
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"set v 0 0 600\r\n");
+    socket.rbuf.extend([b'a'; 600]);
+    socket.rbuf.extend(b"\r\nget v\r\n");
+    for i in 0..8 {
+        while socket.sent.len() < 60 * (i + 1) {
+            reader.poll(&mut socket);
+        }
+        let mut other = NarrowSocket::default();
+        other.rbuf.extend(b"set v 0 0 600 noreply\r\n");
+        other.rbuf.extend([b'b'; 600]);
+        other.rbuf.extend(b"\r\n");
+        while writer.poll(&mut other).progress {}
+    }
+    while reader.poll(&mut socket).progress {}
+    let sent = String::from_utf8(socket.sent).unwrap();
+    assert!(sent.contains(&format!("VALUE v 0 600\r\n{}\r\nEND", "a".repeat(600))));
+    println!("sent intact while overwritten: true");
+
+    // What an item costs: 100k items with 8-byte keys and 8-byte values, on the heap, less
+    // the map's own table
+    let mut small = CommandHandler::new(HashMap::with_capacity(100_000));
+    let mut socket = NarrowSocket {
+        window: 4096,
+        ..Default::default()
+    };
+    let before = ALLOCATED.load(std::sync::atomic::Ordering::Relaxed);
+    for i in 0..100_000 {
+        socket
+            .rbuf
+            .extend(format!("set k{:07} 0 0 8 noreply\r\nv{:07}\r\n", i, i).as_bytes());
+        if socket.rbuf.len() > 64 * 1024 {
+            while small.poll(&mut socket).progress {}
+        }
+    }
+    while small.poll(&mut socket).progress {}
+    let per_item = (ALLOCATED.load(std::sync::atomic::Ordering::Relaxed) - before) / 100_000;
+    let slot = std::mem::size_of::<(Box<[u8]>, Entry)>();
+    println!(
+        "{} heap bytes per item, plus {} for its slot in the table",
+        per_item, slot
+    );
+    assert!(per_item + slot <= 88);
+    drop(small);
+
+    // incr/decr: wrap at 2^64, stop at zero, and refuse anything but plain decimals
+    for (value, incr, delta) in [
+        (&b"41"[..], true, 1),
+        (b"18446744073709551615", true, 2),
+        (b"5", false, 7),
+        (b"18446744073709551615", false, 0),
+        (b"18446744073709551616", true, 1),
+        (b"000000000000000000001", true, 1),
+        (b" 1", true, 1),
+        (b"-1", true, 1),
+        (b"", true, 1),
+    ] {
+        let mut handler = CommandHandler::new(HashMap::new());
+        let mut socket = NarrowSocket::default();
+        let mut set = format!("set n 0 0 {}\r\n", value.len()).into_bytes();
+        set.extend(value);
+        set.extend(b"\r\n");
+        socket.rbuf.extend(set);
+        while handler.poll(&mut socket).progress {}
+        socket.sent.clear();
+        let command = if incr { "incr" } else { "decr" };
+        socket
+            .rbuf
+            .extend(format!("{} n {}\r\n", command, delta).into_bytes());
+        while handler.poll(&mut socket).progress {}
+        println!(
+            "{:?} {} {}: {:?}",
+            String::from_utf8_lossy(value),
+            if incr { "+" } else { "-" },
+            delta,
+            String::from_utf8_lossy(&socket.sent)
+        );
+    }
+    let mut counting = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set n 0 0 3\r\n100\r\n"[..],
+        b"decr n 1\r\n",
+        b"incr n 18446744073709551615\r\n",
+        b"incr n 18446744073709551616\r\n",
+        b"incr n -1\r\n",
+        b"incr missing 1\r\n",
+        b"set s 0 0 3\r\nabc\r\n",
+        b"incr s 1\r\n",
+        b"incr n\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while counting.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Empty values, including one whose CRLF comes in two pieces
+    let mut empty = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set foo 0 0 0\r\n\r\n"[..],
+        b"get foo\r\n",
+        b"ms foo 3 MA\r\nbar\r\n",
+        b"get foo\r\n",
+        b"set foo 0 0 0\r\n\r",
+        b"\n",
+        b"get foo\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while empty.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Items over item_size_max are turned away, and their data block skipped
+    let mut limited = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            item_size_max: 100,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket::default();
+    let fits = 100 - "foo".len() - ITEM_OVERHEAD;
+    for len in [fits, fits + 1] {
+        let mut request = format!("set foo 0 0 {}\r\n", len).into_bytes();
+        request.extend(std::iter::repeat_n(b'x', len));
+        request.extend(b"\r\n");
+        socket.rbuf.extend(request);
+        while limited.poll(&mut socket).progress {}
+        socket.rbuf.extend(b"get foo\r\n");
+        while limited.poll(&mut socket).progress {}
+    }
+    socket.rbuf.extend(b"stats settings\r\n");
+    while limited.poll(&mut socket).progress {}
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // A 10 MB line is cut off at max_line_len and skipped without being buffered, and too many
+    // keys for one get are refused
+    let mut bounded = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            max_keys_per_get: 2,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get ");
+    for _ in 0..160 {
+        socket.rbuf.extend([b'k'; 64 * 1024]);
+        while bounded.poll(&mut socket).progress {}
+    }
+    for request in [
+        &b"\r\n"[..],
+        b"get a b c\r\n",
+        b"set foo 0 0 3\r\nbar\r\n",
+        b"get foo\r\n",
+        b"stats\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while bounded.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // A bad line is answered once, after all of it is in, whatever it looks like inside
+    let mut typo = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"set foo 0 0 3\r\nbar\r\n");
+    while typo.poll(&mut socket).progress {}
+    socket.rbuf.extend(b"bogus command with get foo\r");
+    while typo.poll(&mut socket).progress {}
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+    for request in [&b"\n"[..], b"get foo\r\n"] {
+        socket.rbuf.extend(request);
+        while typo.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Misses in a multi-get move on to the next key
+    let mut multi = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set foo 0 0 3\r\nbar\r\n"[..],
+        b"get nope foo\r\n",
+        b"get nope nope2\r\n",
+        b"get nope \r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while multi.poll(&mut socket).progress {}
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Blank lines before, between and after commands, skipped or answered like upstream
+    for ignore_empty_lines in [true, false] {
+        let mut blank = CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            Settings {
+                ignore_empty_lines,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        for request in [
+            &b"\r\n"[..],
+            b"set foo 0 0 3\r\nbar\r\n",
+            b"\r\n",
+            b"  \r\n",
+            b"get foo\r\n",
+            b"\r\n",
+        ] {
+            socket.rbuf.extend(request);
+            while blank.poll(&mut socket).progress {}
+        }
+        println!("{:?}", String::from_utf8_lossy(&socket.sent));
+    }
+
+    // Uppercase commands, for clients that need them; keys keep their case either way
+    for case_insensitive_commands in [false, true] {
+        let mut cased = CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            Settings {
+                case_insensitive_commands,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        for request in [
+            &b"set foo 0 0 3\r\nbar\r\n"[..],
+            b"SET FOO 0 0 3\r\nBAR\r\n",
+            b"GET foo\r\n",
+            b"Get FOO\r\n",
+        ] {
+            socket.rbuf.extend(request);
+            while cased.poll(&mut socket).progress {}
+        }
+        println!("{:?}", String::from_utf8_lossy(&socket.sent));
+    }
+
+    // noreply only counts as the last token, spelled exactly, and errors are still answered
+    for pieces in [
+        &[&b"set key 0 0 1 noreply\r\nx\r\n"[..]][..],
+        &[b"set key 0 0 1 noreply \r\nx\r\n"],
+        &[b"set key 0 0 1   noreply\r\nx\r\n"],
+        &[b"set key 0 0 1 nore", b"ply\r", b"\nx\r\n"],
+        &[b"set key 0 0 1 \r\nx\r\n"],
+        &[b"set key 0 0 1 noreplyx\r\nx\r\n"],
+        &[b"set key 0 0 1 NOREPLY\r\nx\r\n"],
+        &[b"set key 0 0 1 noreply junk\r\nx\r\n"],
+        &[b"set key 0 0 1 noreply\r\nxy\r\n"],
+        &[b"incr num 1 noreply\r\n"],
+        &[b"incr num 1 noreply \r\n"],
+        &[b"incr nope 1 noreply\r\n"],
+        &[b"incr num 1 noreply\rx\r\n"],
+        &[b"incr num 1 nore", b"ply\r\n"],
+    ] {
+        let mut map = HashMap::new();
+        map.insert(b"num"[..].into(), Entry::new(b"5".to_vec()));
+        let mut quiet = CommandHandler::new(map);
+        let mut socket = NarrowSocket::default();
+        for piece in pieces {
+            socket.rbuf.extend(*piece);
+            while quiet.poll(&mut socket).progress {}
+        }
+        for request in [&b"get key\r\n"[..], b"get num\r\n"] {
+            socket.rbuf.extend(request);
+            while quiet.poll(&mut socket).progress {}
+        }
+        println!(
+            "{:?}: {:?}",
+            String::from_utf8_lossy(&pieces.concat()),
+            String::from_utf8_lossy(&socket.sent)
+        );
+    }
+
+    // A response keeps the value it's sending even if its entry goes away halfway through
+    let mut map = HashMap::new();
+    map.insert(b"big"[..].into(), Entry::new(vec![b'b'; 40]));
+    let mut streaming = CommandHandler::new(map);
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"get big\r\n");
+    for _ in 0..3 {
+        streaming.poll(&mut socket);
+    }
+    streaming.flush_all();
+    while streaming.poll(&mut socket).progress {}
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Up to 30 days an exptime is relative, above it's an absolute Unix time
+    for (request, me) in [
+        (&b"set rel 0 100 1\r\nx\r\n"[..], &b"me rel\r\n"[..]),
+        (b"set edge 0 2592000 1\r\nx\r\n", b"me edge\r\n"),
+        (b"set abs 0 3000100 1\r\nx\r\n", b"me abs\r\n"),
+        (b"set past 0 2592001 1\r\nx\r\n", b"me past\r\n"),
+    ] {
+        let clock = ManualClock::default();
+        clock.0.set(3_000_000);
+        let mut absolute = CommandHandler::with_clock(HashMap::new(), clock);
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(request);
+        while absolute.poll(&mut socket).progress {}
+        socket.rbuf.extend(me);
+        while absolute.poll(&mut socket).progress {}
+        println!("{:?}", String::from_utf8_lossy(&socket.sent));
+    }
+
+    s.rbuf.extend(b"slabs automove 1\r\n");
+    while dump.poll(&mut s).progress {}
+    s.rbuf.extend(b"slabs automove 7\r\n");
+    while dump.poll(&mut s).progress {}
+    s.rbuf.extend(b"slabs reassign 1 1\r\n");
+    while dump.poll(&mut s).progress {}
+    s.rbuf.extend(b"slabs reassign -1 5\r\n");
+    while dump.poll(&mut s).progress {}
+    s.rbuf.extend(b"slabs reassign x 5\r\n");
+    while dump.poll(&mut s).progress {}
+
+    // Snapshots: a warm restart keeps values, flags, CAS values and TTLs, including an empty
+    // value and a key with high-bit bytes, but not what expired meanwhile. A snapshot cut short
+    // loads nothing
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut saved = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"ms plain 5 F7\r\nhello\r\nms empty 0\r\n\r\n");
+    socket
+        .rbuf
+        .extend(b"ms caf\xc3\xa9\xff 1 T100\r\nx\r\nms soon 1 T5\r\ny\r\n");
+    while saved.poll(&mut socket).progress {}
+    let lookups = b"me plain\r\nme empty\r\nme caf\xc3\xa9\xff\r\nme soon\r\n\
+        mg plain v f\r\nmg empty v f\r\nmg caf\xc3\xa9\xff v\r\nmg soon v\r\n";
+    let mut snapshot = Vec::new();
+    saved.save_snapshot(&mut snapshot).unwrap();
+    clock.0.set(1010);
+    let mut before = NarrowSocket::default();
+    before.rbuf.extend(lookups);
+    while saved.poll(&mut before).progress {}
+    let mut loaded = CommandHandler::load_snapshot(
+        HashMap::new(),
+        clock.clone(),
+        Settings::default(),
+        &snapshot[..],
+    )
+    .unwrap();
+    let mut after = NarrowSocket::default();
+    after.rbuf.extend(lookups);
+    while loaded.poll(&mut after).progress {}
+    // Loaded items haven't been accessed.
+    assert_eq!(mask(&before.sent, &["la="]), mask(&after.sent, &["la="]));
+    println!("{:?}", String::from_utf8_lossy(&after.sent));
+    // New CAS values carry on from the loaded ones.
+    after.sent.clear();
+    after
+        .rbuf
+        .extend(b"ms fresh 1\r\nz\r\nme fresh\r\nme plain\r\n");
+    while loaded.poll(&mut after).progress {}
+    let cas: Vec<u64> = String::from_utf8_lossy(&after.sent)
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix("cas=")?.parse().ok())
+        .collect();
+    assert!(cas[0] > cas[1], "{:?}", cas);
+    for len in [snapshot.len() - 1, snapshot.len() / 2, 10, 0] {
+        let error = CommandHandler::load_snapshot(
+            HashMap::new(),
+            clock.clone(),
+            Settings::default(),
+            &snapshot[..len],
+        )
+        .err()
+        .unwrap();
+        println!("{} of {} bytes: {}", len, snapshot.len(), error);
+    }
+    let mut corrupt = snapshot.clone();
+    corrupt[20] ^= 1;
+    let error = CommandHandler::load_snapshot(
+        HashMap::new(),
+        clock.clone(),
+        Settings::default(),
+        &corrupt[..],
+    )
+    .err()
+    .unwrap();
+    println!("{}", error);
+
+    // Items in an arena survive a restart, moved and emptied ones too. An arena left in use,
+    // as by a killed process, or holding garbage, opens empty, and a full one evicts
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut memory = vec![0; 4096];
+    let mut arena =
+        CommandHandler::with_clock(Arena::open(&mut memory, HashMap::new()), clock.clone());
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"ms a 3 F3 T100\r\nabc\r\nms b 1\r\nx\r\nms b 1 MA\r\ny\r\n");
+    socket
+        .rbuf
+        .extend(b"ms n 2\r\n10\r\nincr n 5\r\nms gone 1\r\nz\r\nmd gone\r\nmd b I\r\n");
+    socket.rbuf.extend(b"ms e 0\r\n\r\nms big 40\r\n");
+    socket.rbuf.extend([b'1'; 40]);
+    socket.rbuf.extend(b"\r\nms big 120\r\n");
+    socket.rbuf.extend([b'3'; 120]);
+    socket.rbuf.extend(b"\r\n");
+    while arena.poll(&mut socket).progress {}
+    clock.0.set(1010);
+    let expected = arena_transcript(&mut arena);
+    println!("{:?}", String::from_utf8_lossy(&expected));
+    // Restored items haven't been accessed.
+    let expected = mask(&expected, &["la="]);
+    drop(arena);
+    let restored = Arena::open(&mut memory, HashMap::new());
+    assert!(restored.restored());
+    let mut arena = CommandHandler::with_clock(restored, clock.clone());
+    assert_eq!(mask(&arena_transcript(&mut arena), &["la="]), expected);
+    // Killed before it could drop the arena.
+    std::mem::forget(arena);
+    let crashed = Arena::open(&mut memory, HashMap::new());
+    println!(
+        "after a crash: restored {}, {} items",
+        crashed.restored(),
+        crashed.len()
+    );
+    drop(crashed);
+    memory.fill(0xab);
+    let garbage = Arena::open(&mut memory, HashMap::new());
+    println!(
+        "from garbage: restored {}, {} items",
+        garbage.restored(),
+        garbage.len()
+    );
+    drop(garbage);
+    let mut small = vec![0; 1024];
+    let mut full = CommandHandler::new(Arena::open(&mut small, HashMap::new()));
+    for i in 0..40 {
+        socket
+            .rbuf
+            .extend(format!("ms k{} 8\r\n{:08}\r\n", i, i).as_bytes());
+    }
+    while full.poll(&mut socket).progress {}
+    let mut stats = NarrowSocket::default();
+    stats.rbuf.extend(b"stats\r\n");
+    while full.poll(&mut stats).progress {}
+    let held = String::from_utf8_lossy(&stats.sent)
+        .lines()
+        .find_map(|line| line.strip_prefix("STAT curr_items ")?.parse::<usize>().ok())
+        .unwrap();
+    drop(full);
+    let reopened = Arena::open(&mut small, HashMap::new());
+    assert_eq!(reopened.len(), held);
+    println!(
+        "40 items in 1 KB: {} held, and all of them back after a restart",
+        held
+    );
+
+    // Access metadata: la ages with the clock and restarts on every read or write, and the
+    // fetched bit is set by reads and cleared by writes. mg h and l report them as they were
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut accessed = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let mut socket = NarrowSocket::default();
+    for (at, request, expected) in [
+        (1000, &b"set k 0 0 1\r\n7\r\n"[..], &b"STORED\r\n"[..]),
+        (
+            1000,
+            b"me k\r\n",
+            b"ME k exp=-1 la=0 cas=_ fetch=no cls=1 size=_\r\n",
+        ),
+        (
+            1005,
+            b"me k\r\n",
+            b"ME k exp=-1 la=5 cas=_ fetch=no cls=1 size=_\r\n",
+        ),
+        (1005, b"mg k h l\r\n", b"HD h0 l5\r\n"),
+        (1008, b"mg k h l\r\n", b"HD h1 l3\r\n"),
+        (
+            1009,
+            b"me k\r\n",
+            b"ME k exp=-1 la=1 cas=_ fetch=yes cls=1 size=_\r\n",
+        ),
+        (1010, b"incr k 1\r\n", b"8\r\n"),
+        (1012, b"mg k h l\r\n", b"HD h0 l2\r\n"),
+        (1020, b"get k\r\n", b"VALUE k 0 1\r\n8\r\nEND\r\n"),
+        (
+            1020,
+            b"me k\r\n",
+            b"ME k exp=-1 la=0 cas=_ fetch=yes cls=1 size=_\r\n",
+        ),
+        (1030, b"ms k 1 MA\r\n9\r\n", b"HD\r\n"),
+        (
+            1031,
+            b"me k\r\n",
+            b"ME k exp=-1 la=1 cas=_ fetch=no cls=1 size=_\r\n",
+        ),
+    ] {
+        clock.0.set(at);
+        socket.sent.clear();
+        socket.rbuf.extend(request);
+        while accessed.poll(&mut socket).progress {}
+        let sent = mask(&socket.sent, &["cas=", "size="]);
+        assert_eq!(
+            sent,
+            expected,
+            "{:?}",
+            String::from_utf8_lossy(&socket.sent)
+        );
+    }
+    println!("{:?}", String::from_utf8_lossy(&socket.sent));
+
+    // Invalidate, then recache: two clients sharing one handler's storage
+    let mut invalidated = CommandHandler::new(HashMap::new());
+    let (mut a, mut b) = (MockSocket::new(), MockSocket::new());
+    a.rbuf.extend(b"ms page 2\r\nv1\r\n");
+    while invalidated.poll(&mut a).progress {}
+    b.rbuf.extend(b"md page I T30\r\n");
+    while invalidated.poll(&mut b).progress {}
+    a.rbuf.extend(b"mg page v\r\n");
+    while invalidated.poll(&mut a).progress {}
+    b.rbuf.extend(b"mg page v\r\n");
+    while invalidated.poll(&mut b).progress {}
+    a.rbuf.extend(b"ms page 2\r\nv2\r\n");
+    while invalidated.poll(&mut a).progress {}
+    b.rbuf.extend(b"mg page v\r\n");
+    while invalidated.poll(&mut b).progress {}
+    a.rbuf.extend(b"ms page 2 I\r\nv3\r\n");
+    while invalidated.poll(&mut a).progress {}
+    b.rbuf.extend(b"mg page v\r\n");
+    while invalidated.poll(&mut b).progress {}
+
+    #[cfg(feature = "binary")]
+    binary_demo();
+    #[cfg(feature = "udp")]
+    udp_demo();
+    #[cfg(feature = "journal")]
+    journal_demo();
+    flush_demo();
+    flash_demo();
+    static_demo();
+    chunk_demo();
+    #[cfg(feature = "lz4")]
+    compression_demo();
+    // Where memory is tight, SKIP_LARGE_ITEMS skips the multi-megabyte items.
+    if std::env::var_os("SKIP_LARGE_ITEMS").is_none() {
+        large_item_demo();
+    }
+
+    // What a get with a long key costs, through 7-byte windows. Timings vary, so only
+    // printed when BENCH is set.
+    if std::env::var_os("BENCH").is_some() {
+        bench_get(100_000, MAX_KEY_LEN, 1, 7);
+        bench_get(10_000, 8, 16, 1460);
+    }
+
+    let mut text_auth = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            protocol: Protocol::AsciiOnly,
+            text_requires_auth: true,
+            ..Default::default()
+        },
+    );
+    s.rbuf.extend(b"get foo\r\n");
+    while text_auth.poll(&mut s).progress {}
+}
+
+/// A server that loads its items from `path` as it starts, if there's a snapshot there, and
+/// saves them back when a client shuts it down, counting its starts in `starts`. A snapshot it
+/// can't load stops it, rather than have it start without the items.
+fn snapshot_file_demo(path: &Path) {
+    let settings = Settings {
+        enable_shutdown: true,
+        ..Default::default()
+    };
+    let loaded =
+        match File::open(path) {
+            Ok(file) => CommandHandler::load_snapshot(
+                HashMap::new(),
+                SystemClock,
+                settings,
+                BufReader::new(file),
+            ),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(
+                CommandHandler::with_settings(HashMap::new(), SystemClock, settings),
+            ),
+            Err(error) => Err(error.into()),
+        };
+    let mut server = loaded.unwrap_or_else(|error| {
+        eprintln!("{}: {}", path.display(), error);
+        std::process::exit(1)
+    });
+    println!("loaded {} items from {}", server.len(), path.display());
+
+    let mut s = MockSocket::new();
+    s.rbuf
+        .extend(b"add starts 0 0 1 noreply\r\n0\r\nincr starts 1\r\nshutdown\r\n");
+    while server.poll(&mut s).progress {}
+    if server.shutdown_requested().is_none() {
+        return;
+    }
+    // Written aside and renamed over the old one, so a save cut short leaves that whole.
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let saved = File::create(&temp).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        server.save_snapshot(&mut out)?;
+        out.into_inner()?.sync_all()?;
+        fs::rename(&temp, path)
+    });
+    if let Err(error) = saved {
+        eprintln!("saving {}: {}", path.display(), error);
+        std::process::exit(1);
+    }
+    println!("saved {} items to {}", server.len(), path.display());
+}
+
+#[cfg(feature = "binary")]
+fn binary_demo() {
+    let mut bin = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            protocol: Protocol::BinaryOnly,
+            ..Default::default()
+        },
+    );
+    let packets = [
+        binary_request(
+            0x01,
+            &[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 0],
+            b"bin",
+            b"hello",
+            1,
+        ),
+        binary_request(0x00, &[], b"bin", b"", 2),
+        binary_request(0x04, &[], b"bin", b"", 3),
+        binary_request(0x00, &[], b"bin", b"", 4),
+        binary_request(0x00, &[1], b"bin", b"", 5),
+        binary_request(0x42, &[], b"", b"junk", 6),
+        binary_request(0x07, &[], b"", b"", 7),
+    ];
+    // Quiet multi-get: only hits answer, then NOOP marks the end
+    let quiet = [
+        binary_request(0x11, &[0, 0, 0, 0, 0, 0, 0, 0], b"k1", b"one", 8),
+        binary_request(0x11, &[0, 0, 0, 0, 0, 0, 0, 0], b"k3", b"three", 9),
+        binary_request(0x11, &[0, 0, 0, 0, 0, 0, 0, 0], b"k5", b"five", 10),
+        binary_request(0x0d, &[], b"k1", b"", 11),
+        binary_request(0x0d, &[], b"k2", b"", 12),
+        binary_request(0x0d, &[], b"k3", b"", 13),
+        binary_request(0x0d, &[], b"k4", b"", 14),
+        binary_request(0x0d, &[], b"k5", b"", 15),
+        binary_request(0x0a, &[], b"", b"", 16),
+        binary_request(0x14, &[], b"k2", b"", 17),
+        binary_request(0x14, &[], b"k1", b"", 18),
+        // Arithmetic: a miss creates the item with the initial value...
+        binary_request(0x05, &arith_extras(5, 40, 0), b"n", b"", 19),
+        binary_request(0x05, &arith_extras(5, 40, 0), b"n", b"", 20),
+        binary_request(0x06, &arith_extras(100, 0, 0), b"n", b"", 21),
+        // ...unless the expiration is all ones
+        binary_request(0x05, &arith_extras(1, 0, u32::MAX), b"m", b"", 22),
+        binary_request(0x0e, &[], b"n", b"1", 23),
+        binary_request(0x0f, &[], b"n", b"2", 24),
+        binary_request(0x00, &[], b"n", b"", 25),
+        binary_request(0x0e, &[], b"m", b"1", 26),
+        binary_request(0x0b, &[], b"", b"", 27),
+        binary_request(0x08, &[], b"", b"", 28),
+        binary_request(0x00, &[], b"n", b"", 29),
+    ];
+    for packet in quiet.into_iter().chain(packets) {
+        let mut bin_socket = NarrowSocket::default();
+        // Split at awkward offsets: mid-header, mid-extras, mid-key
+        for chunk in packet.chunks(5) {
+            bin_socket.rbuf.extend(chunk);
+            while bin.poll(&mut bin_socket).progress {}
+        }
+        println!("{:02x?}", bin_socket.sent);
+    }
+    println!("wants close: {}", bin.wants_close());
+
+    // Negotiation on the first byte, and forcing text
+    for (protocol, request) in [
+        (Protocol::Negotiating, b"version\r\n".to_vec()),
+        (
+            Protocol::Negotiating,
+            binary_request(0x0b, &[], b"", b"", 31),
+        ),
+        (Protocol::AsciiOnly, binary_request(0x0b, &[], b"", b"", 32)),
+        (Protocol::BinaryOnly, b"version\nversion\n".to_vec()),
+    ] {
+        let mut negotiating = CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            Settings {
+                protocol,
+                ..Default::default()
+            },
+        );
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(request);
+        while negotiating.poll(&mut socket).progress {}
+        println!(
+            "{:?} -> {:?}, close {}: {:02x?}",
+            protocol,
+            negotiating.protocol(),
+            negotiating.wants_close(),
+            socket.sent
+        );
+    }
+
+    // STAT streams a packet per stat through 7-byte windows
+    let mut stat_socket = NarrowSocket::default();
+    stat_socket
+        .rbuf
+        .extend(binary_request(0x10, &[], b"", b"", 30));
+    while bin.poll(&mut stat_socket).progress {}
+    let mut packets = stat_socket.sent.as_slice();
+    while packets.len() >= 24 {
+        let key_len = u16::from_be_bytes([packets[2], packets[3]]) as usize;
+        let body_len = u32::from_be_bytes(packets[8..12].try_into().unwrap()) as usize;
+        let body = &packets[24..24 + body_len];
+        println!(
+            "STAT packet {} = {}",
+            String::from_utf8_lossy(&body[..key_len]),
+            String::from_utf8_lossy(&body[key_len..])
+        );
+        packets = &packets[24 + body_len..];
+    }
+    // SASL PLAIN: a command before auth, a wrong password, then the right one
+    let mut sasl = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            protocol: Protocol::BinaryOnly,
+            authenticator: Some(std::sync::Arc::new(|user: &[u8], password: &[u8]| {
+                user == b"alice" && password == b"secret"
+            })),
+            ..Default::default()
+        },
+    );
+    for packet in [
+        binary_request(0x00, &[], b"foo", b"", 40),
+        binary_request(0x20, &[], b"", b"", 41),
+        binary_request(0x21, &[], b"PLAIN", b"\0alice\0wrong", 42),
+        binary_request(0x21, &[], b"PLAIN", b"\0alice\0secret", 43),
+        binary_request(0x00, &[], b"foo", b"", 44),
+    ] {
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(packet);
+        while sasl.poll(&mut socket).progress {}
+        println!("{:02x?}", socket.sent);
+    }
+    // An oversized AUTH is refused before anyone is authenticated, without buffering it
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(binary_request(0x21, &[], b"PLAIN", &[0; 4096], 45));
+    LARGEST.store(0, std::sync::atomic::Ordering::Relaxed);
+    while sasl.poll(&mut socket).progress {}
+    assert!(LARGEST.load(std::sync::atomic::Ordering::Relaxed) < 4096);
+    assert_eq!(socket.sent[6..8], [0x00, 0x04]);
+    // A header claiming a large value only gets memory as the value arrives
+    let mut bin: CommandHandler<_, _> = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            protocol: Protocol::BinaryOnly,
+            ..Default::default()
+        },
+    );
+    let mut header = binary_request(0x01, &[0; 8], b"big", b"", 46);
+    header[8..12].copy_from_slice(&(900_000u32 + 11).to_be_bytes());
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(header);
+    socket.rbuf.extend([b'v'; 100]);
+    LARGEST.store(0, std::sync::atomic::Ordering::Relaxed);
+    while bin.poll(&mut socket).progress {}
+    assert!(LARGEST.load(std::sync::atomic::Ordering::Relaxed) < 64 * 1024);
+    assert!(socket.sent.is_empty());
+}
+
+#[cfg(feature = "udp")]
+fn udp_demo() {
+    let mut map = HashMap::new();
+    map.insert(b"foo"[..].into(), Entry::new(b"bar".to_vec()));
+    let mut udp = UdpFraming::new(CommandHandler::new(map));
+    let mut datagrams = DatagramSocket {
+        mtu: 1400,
+        received: VecDeque::new(),
+        sent: Vec::new(),
+    };
+    datagrams
+        .received
+        .push_back(b"\x12\x34\x00\x00\x00\x01\x00\x00get foo\r\n".to_vec());
+    while udp.poll(&mut datagrams) {}
+    datagrams
+        .received
+        .push_back(b"\x56\x78\x00\x00\x00\x02\x00\x00get foo\r\n".to_vec());
+    while udp.poll(&mut datagrams) {}
+    for datagram in &datagrams.sent {
+        println!(
+            "{:02x?} {:?}",
+            &datagram[..8],
+            String::from_utf8_lossy(&datagram[8..])
+        );
+    }
+
+    // Responses split over datagrams: one landing exactly on a datagram boundary, one taking three
+    let mut map = HashMap::new();
+    map.insert(b"exact"[..].into(), Entry::new(vec![b'e'; 1365]));
+    map.insert(b"large"[..].into(), Entry::new(vec![b'l'; 4096]));
+    let mut udp = UdpFraming::new(CommandHandler::new(map));
+    for (request_id, key) in [(1u8, "exact"), (2, "large")] {
+        let mut datagrams = DatagramSocket {
+            mtu: 1400,
+            received: VecDeque::new(),
+            sent: Vec::new(),
+        };
+        let mut datagram = vec![0, request_id, 0, 0, 0, 1, 0, 0];
+        datagram.extend(format!("get {}\r\n", key).as_bytes());
+        datagrams.received.push_back(datagram);
+        while udp.poll(&mut datagrams) {}
+        for datagram in &datagrams.sent {
+            println!("{:02x?} {} bytes", &datagram[..8], datagram.len() - 8);
+        }
+    }
+}
+
+/// `purgeprefix <p>`: removes every item whose key starts with `p`.
+struct PurgePrefix;
+
+impl<S: Storage> CommandExtension<S> for PurgePrefix {
+    fn matches(&self, verb: &[u8]) -> bool {
+        verb == b"purgeprefix"
+    }
+
+    fn execute(
+        &mut self,
+        args: &Args<'_>,
+        storage: &mut S,
+        out: &mut ResponseSink,
+    ) -> Result<(), Error> {
+        let mut tokens = args.tokens();
+        let (Some(prefix), None) = (tokens.next(), tokens.next()) else {
+            return Err(Error::BadArgument);
+        };
+        let mut doomed = Vec::new();
+        let _ = storage.scan(|key, _| {
+            if key.starts_with(prefix) {
+                doomed.push(key.to_vec());
+            }
+            std::ops::ControlFlow::<()>::Continue(())
+        });
+        for key in &doomed {
+            storage.remove(key);
+        }
+        write!(out, "PURGED {}\r\n", doomed.len()).map_err(|_| Error::HeaderTooLong)
+    }
+}
+
+/// `health`: answers `OK` and how many items there are.
+struct Health;
+
+impl<S: Storage> CommandExtension<S> for Health {
+    fn matches(&self, verb: &[u8]) -> bool {
+        verb == b"health"
+    }
+
+    fn execute(
+        &mut self,
+        _: &Args<'_>,
+        storage: &mut S,
+        out: &mut ResponseSink,
+    ) -> Result<(), Error> {
+        write!(out, "OK {}\r\n", storage.len()).map_err(|_| Error::HeaderTooLong)
+    }
+}
+
+/// The mutation log: replaying it gets back every change, a record torn anywhere is dropped
+/// with everything before it applied, and compacting starts it over from a snapshot.
+#[cfg(feature = "journal")]
+fn journal_demo() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let log = SharedLog::default();
+    let settings = Settings {
+        journal: Some(Journal::new(log.clone())),
+        ..Default::default()
+    };
+    let mut journaled = CommandHandler::with_settings(HashMap::new(), clock.clone(), settings);
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"ms junk 1\r\nj\r\n");
+    while journaled.poll(&mut socket).progress {}
+    journaled.flush_all();
+    socket
+        .rbuf
+        .extend(b"ms a 3 F5 T100\r\nabc\r\nms b 1\r\nx\r\nms b 1 MA\r\ny\r\n");
+    socket
+        .rbuf
+        .extend(b"ms n 2\r\n10\r\nincr n 5\r\nms gone 1\r\nz\r\nmd gone\r\n");
+    socket.rbuf.extend(b"ms soon 1 T5\r\ns\r\nmd b I\r\n");
+    while journaled.poll(&mut socket).progress {}
+    journaled.flush_log().unwrap();
+    let before_last = log.0.lock().unwrap().len();
+    socket.rbuf.extend(b"ms last 1\r\nL\r\n");
+    while journaled.poll(&mut socket).progress {}
+    journaled.flush_log().unwrap();
+    let logged = log.0.lock().unwrap().clone();
+    clock.0.set(1010);
+    let lookups = b"me a\r\nme b\r\nme n\r\nme gone\r\nme soon\r\nme junk\r\n\
+        mg a v f\r\nmg b v\r\nmg n v\r\nmg gone v\r\nmg soon v\r\nmg junk v\r\n";
+    let transcript = |handler: &mut CommandHandler<HashMap<Box<[u8]>, Entry>, ManualClock>| {
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(lookups);
+        while handler.poll(&mut socket).progress {}
+        let mut last = NarrowSocket::default();
+        last.rbuf.extend(b"mg last v\r\n");
+        while handler.poll(&mut last).progress {}
+        // Replayed items haven't been accessed.
+        (mask(&socket.sent, &["la="]), last.sent)
+    };
+    let (expected, last) = transcript(&mut journaled);
+    println!("{:?}", String::from_utf8_lossy(&expected));
+    let mut replayed = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let applied = replayed.replay_log(&logged[..]).unwrap();
+    assert_eq!(transcript(&mut replayed), (expected.clone(), last));
+    println!("{} records replayed, {} bytes", applied, logged.len());
+    for len in before_last..logged.len() {
+        let mut replayed = CommandHandler::with_clock(HashMap::new(), clock.clone());
+        assert_eq!(replayed.replay_log(&logged[..len]).unwrap(), applied - 1);
+        assert_eq!(
+            transcript(&mut replayed),
+            (expected.clone(), b"EN\r\n".to_vec())
+        );
+    }
+    let mut damaged = logged.clone();
+    damaged[before_last + 10] ^= 1;
+    let mut replayed = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(replayed.replay_log(&damaged[..]).unwrap(), applied - 1);
+    let mut snapshot = Vec::new();
+    let fresh = SharedLog::default();
+    journaled.compact_log(&mut snapshot, fresh.clone()).unwrap();
+    socket.rbuf.extend(b"ms last 2\r\nL2\r\n");
+    while journaled.poll(&mut socket).progress {}
+    journaled.flush_log().unwrap();
+    let fresh = fresh.0.lock().unwrap().clone();
+    let mut reference = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    reference
+        .replay_log(&[&logged[..], &fresh[..]].concat()[..])
+        .unwrap();
+    let mut restarted = CommandHandler::load_snapshot(
+        HashMap::new(),
+        clock.clone(),
+        Settings::default(),
+        &snapshot[..],
+    )
+    .unwrap();
+    assert_eq!(restarted.replay_log(&fresh[..]).unwrap(), 1);
+    assert_eq!(transcript(&mut restarted), transcript(&mut reference));
+    println!(
+        "after compacting: {} bytes of snapshot, {} of log",
+        snapshot.len(),
+        fresh.len()
+    );
+}
+
+/// A log kept in memory, with a handle left to read it back.
+#[cfg(feature = "journal")]
+#[derive(Clone, Default)]
+struct SharedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(feature = "journal")]
+impl std::io::Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the arena demo's items look like from a client.
+fn arena_transcript<S: Storage>(handler: &mut CommandHandler<S, ManualClock>) -> Vec<u8> {
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"me a\r\nme b\r\nme n\r\nme gone\r\nme e\r\nme big\r\n");
+    socket
+        .rbuf
+        .extend(b"mg a v f\r\nmg b v\r\nmg n v\r\nmg gone v\r\nmg e v\r\nmg big v\r\n");
+    while handler.poll(&mut socket).progress {}
+    socket.sent
+}
+
+/// Runs a fixed set of requests against a handler storing into `data`.
+fn backend_transcript<S: Storage>(data: S) -> Vec<u8> {
+    let mut handler = CommandHandler::new(data);
+    let mut socket = NarrowSocket::default();
+    for request in [
+        &b"set foo 0 0 3\r\nbar\r\n"[..],
+        b"set num 5 0 2\r\n41\r\n",
+        b"incr num 1\r\n",
+        b"get num\r\n",
+        b"get nope\r\n",
+        b"mg num v f\r\n",
+        b"md num\r\n",
+        b"md num\r\n",
+        b"lru_crawler metadump all\r\n",
+        b"ms new 2\r\nhi\r\n",
+        b"mg new v\r\n",
+        b"get foo\r\n",
+    ] {
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+    }
+    // CAS values go on counting from one handler to the next, and the clock on ticking.
+    mask(&socket.sent, &["cas=", "la="])
+}
+
+/// Flushing a large cache: the flush frees nothing, lookups miss at once, and ticking removes
+/// the items a budget at a time.
+fn flush_demo() {
+    const ITEMS: usize = 200_000;
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            expiry_index: true,
+            ..Default::default()
+        },
+    );
+    for i in 0..ITEMS {
+        // Some of them in the expiry index, the rest only found by sweeping.
+        let expires_at = if i % 4 == 0 { 2000 } else { 0 };
+        let entry = Entry::with_expiry(b"value".to_vec(), 0, expires_at);
+        handler.insert(format!("k{}", i).as_bytes(), entry).unwrap();
+    }
+    let serve = |handler: &mut CommandHandler<_, _>, request: &[u8]| {
+        let mut socket = NarrowSocket::default();
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+        String::from_utf8_lossy(&socket.sent).into_owned()
+    };
+    let held = ALLOCATED.load(std::sync::atomic::Ordering::Relaxed);
+    handler.clear();
+    let freed = held.saturating_sub(ALLOCATED.load(std::sync::atomic::Ordering::Relaxed));
+    assert!(freed < 64 * 1024, "{} bytes freed by the flush", freed);
+    assert!(serve(&mut handler, b"stats\r\n").contains("STAT curr_items 0\r\n"));
+    assert!(handler.is_empty() && handler.memory_stats().value_bytes == 0);
+    assert_eq!(
+        serve(&mut handler, b"get k7\r\nmg k8 v\r\n"),
+        "END\r\nEN\r\n"
+    );
+    assert_eq!(serve(&mut handler, b"md k9\r\n"), "NF\r\n");
+    assert_eq!(serve(&mut handler, b"ms k10 1 MA\r\nx\r\n"), "NS\r\n");
+    assert_eq!(
+        serve(&mut handler, b"set k7 0 0 5\r\nagain\r\nget k7\r\n"),
+        "STORED\r\nVALUE k7 0 5\r\nagain\r\nEND\r\n"
+    );
+    assert_eq!(handler.len(), 1);
+    let mut ticks = 0;
+    loop {
+        let reclaimed = handler.tick(1000);
+        assert!(reclaimed <= 1000);
+        if reclaimed == 0 {
+            break;
+        }
+        ticks += 1;
+    }
+    assert_eq!(handler.len(), 1);
+    assert!(serve(&mut handler, b"stats\r\n").contains("STAT curr_items 1\r\n"));
+    assert_eq!(
+        serve(&mut handler, b"get k7\r\n"),
+        "VALUE k7 0 5\r\nagain\r\nEND\r\n"
+    );
+    let freed = held.saturating_sub(ALLOCATED.load(std::sync::atomic::Ordering::Relaxed));
+    assert!(freed > ITEMS * 20);
+    println!(
+        "flushed {} items at once, removed over {} ticks afterwards",
+        ITEMS, ticks
+    );
+}
+
+/// External flash, faked with a boxed array. Values are written one after the other, and only
+/// where they start is kept in RAM.
+#[derive(Clone)]
+struct FakeFlash(std::sync::Arc<std::sync::Mutex<Flash>>);
+
+struct Flash {
+    memory: Box<[u8]>,
+    used: usize,
+    /// The longest single read, to tell values are streamed rather than read whole.
+    longest_read: usize,
+}
+
+impl FakeFlash {
+    fn new(len: usize) -> Self {
+        Self(std::sync::Arc::new(std::sync::Mutex::new(Flash {
+            memory: vec![0xff; len].into_boxed_slice(),
+            used: 0,
+            longest_read: 0,
+        })))
+    }
+}
+
+/// A value in [`FakeFlash`].
+struct FlashValue {
+    flash: FakeFlash,
+    start: usize,
+    len: usize,
+}
+
+impl ValueSource for FlashValue {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        let mut flash = self.flash.0.lock().unwrap();
+        let n = out.len().min(self.len - offset);
+        let start = self.start + offset;
+        out[..n].copy_from_slice(&flash.memory[start..start + n]);
+        flash.longest_read = flash.longest_read.max(n);
+        n
+    }
+}
+
+/// Writes a value into [`FakeFlash`] as it arrives.
+struct FlashSink {
+    value: FlashValue,
+    written: usize,
+}
+
+impl ValueSink for FlashSink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut flash = self.value.flash.0.lock().unwrap();
+        let start = self.value.start + self.written;
+        flash.memory[start..start + bytes.len()].copy_from_slice(bytes);
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Box<dyn ValueSource>, Error> {
+        assert_eq!(self.written, self.value.len);
+        Ok(Box::new(self.value))
+    }
+}
+
+/// Values of 1 KB or more go to flash, while there's room.
+impl ValueBackend for FakeFlash {
+    fn sink(&self, _key: &[u8], len: usize) -> Option<Box<dyn ValueSink>> {
+        let mut flash = self.0.lock().unwrap();
+        if len < 1024 || flash.used + len > flash.memory.len() {
+            return None;
+        }
+        let start = flash.used;
+        flash.used += len;
+        let value = FlashValue {
+            flash: self.clone(),
+            start,
+            len,
+        };
+        Some(Box::new(FlashSink { value, written: 0 }))
+    }
+}
+
+/// A 256 KB value stored into fake flash over the protocol, and streamed back out of it
+/// through 64-byte windows without ever being in RAM whole.
+fn flash_demo() {
+    const LEN: usize = 256 * 1024;
+    let flash = FakeFlash::new(2 * LEN);
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            value_backend: Some(std::sync::Arc::new(flash.clone())),
+            ..Default::default()
+        },
+    );
+    let value: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut socket = NarrowSocket {
+        window: 64,
+        ..Default::default()
+    };
+    socket
+        .rbuf
+        .extend(format!("set blob 0 0 {}\r\n", LEN).as_bytes());
+    socket.rbuf.extend(&value);
+    socket.rbuf.extend(b"\r\nset small 0 0 5\r\nhello\r\n");
+    let held = ALLOCATED.load(std::sync::atomic::Ordering::Relaxed);
+    while handler.poll(&mut socket).progress {}
+    let grown = ALLOCATED
+        .load(std::sync::atomic::Ordering::Relaxed)
+        .saturating_sub(held);
+    assert!(grown < LEN / 4, "{} bytes allocated storing", grown);
+    assert_eq!(socket.sent, b"STORED\r\nSTORED\r\n");
+    assert_eq!(flash.0.lock().unwrap().used, LEN);
+    assert_eq!(&flash.0.lock().unwrap().memory[..LEN], &value[..]);
+
+    socket.sent.clear();
+    socket.rbuf.extend(b"get blob\r\nget small\r\n");
+    while handler.poll(&mut socket).progress {}
+    let mut expected = format!("VALUE blob 0 {}\r\n", LEN).into_bytes();
+    expected.extend(&value);
+    expected.extend(b"\r\nEND\r\nVALUE small 0 5\r\nhello\r\nEND\r\n");
+    assert!(socket.sent == expected);
+    assert_eq!(flash.0.lock().unwrap().longest_read, 64);
+    println!(
+        "{} KB streamed through flash 64 bytes at a time",
+        LEN / 1024
+    );
+
+    // Entries made from a source directly, and appended to, which reads them into RAM and
+    // writes the result to flash again
+    let entry = Entry::from_source(FlashValue {
+        flash: flash.clone(),
+        start: 0,
+        len: 2048,
+    });
+    handler.insert(b"prefix", entry).unwrap();
+    socket.sent.clear();
+    socket
+        .rbuf
+        .extend(b"ms prefix 3 MA\r\nend\r\nmg prefix s\r\n");
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"HD\r\nHD s2051\r\n");
+    let stored = handler.get(b"prefix").unwrap();
+    assert_eq!(&stored.value()[..2048], &value[..2048]);
+    assert_eq!(&stored.value()[2048..], b"end");
+    assert_eq!(flash.0.lock().unwrap().used, LEN + 2051);
+}
+
+/// A lookup table compiled into the program.
+static TABLE: [u8; 64 * 1024] = {
+    let mut table = [0; 64 * 1024];
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = (i % 251) as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Storage with no heap to spare for values, as on a target without an allocator.
+struct NoHeap(HashMap<Box<[u8]>, Entry>);
+
+impl Storage for NoHeap {
+    type Cursor = (usize, usize);
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.0.read(key, f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.0.update(key, f)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        Storage::insert(&mut self.0, key, entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        Storage::remove(&mut self.0, key)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    fn allocates_values(&self) -> bool {
+        false
+    }
+
+    fn scan_from<B>(
+        &self,
+        cursor: &mut Self::Cursor,
+        f: impl FnMut(&[u8], &Entry) -> std::ops::ControlFlow<B>,
+    ) -> std::ops::ControlFlow<B> {
+        self.0.scan_from(cursor, f)
+    }
+}
+
+/// What `handler` answers `request` with, through `window` byte windows.
+fn serve<S: Storage, C: Clock>(
+    handler: &mut CommandHandler<S, C>,
+    request: &[u8],
+    window: usize,
+) -> Vec<u8> {
+    let mut socket = NarrowSocket {
+        window,
+        ..Default::default()
+    };
+    socket.rbuf.extend(request);
+    while handler.poll(&mut socket).progress {}
+    socket.sent
+}
+
+/// Values borrowed from static data: served without copying them, copied on the first change,
+/// and left alone when there's no heap to copy them to.
+fn static_demo() {
+    let mut handler = CommandHandler::default();
+    let held = ALLOCATED.load(std::sync::atomic::Ordering::Relaxed);
+    handler
+        .insert(b"table", Entry::from_static(&TABLE))
+        .unwrap();
+    handler.insert(b"count", Entry::from_static(b"41")).unwrap();
+    handler
+        .insert(b"greeting", Entry::from_static(b"hello"))
+        .unwrap();
+    let grown = ALLOCATED
+        .load(std::sync::atomic::Ordering::Relaxed)
+        .saturating_sub(held);
+    assert!(grown < 1024, "{} bytes allocated for static values", grown);
+    let mut expected = format!("VALUE table 0 {}\r\n", TABLE.len()).into_bytes();
+    expected.extend(TABLE);
+    expected.extend(b"\r\nEND\r\n");
+    assert!(serve(&mut handler, b"get table\r\n", 64) == expected);
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"incr count 1\r\nms greeting 5 MA\r\n, you\r\nmg count v\r\nmg greeting v\r\n",
+            64
+        ),
+        b"42\r\nHD\r\nVA 2\r\n42\r\nVA 10\r\nhello, you\r\n"
+    );
+
+    let mut no_heap = CommandHandler::new(NoHeap(HashMap::new()));
+    no_heap.insert(b"count", Entry::from_static(b"41")).unwrap();
+    no_heap
+        .insert(b"greeting", Entry::from_static(b"hello"))
+        .unwrap();
+    let sent = serve(
+        &mut no_heap,
+        b"incr count 1\r\nms greeting 1 MA\r\n!\r\nmg count v\r\nmg greeting v\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"SERVER_ERROR out of memory storing object\r\n\
+        SERVER_ERROR out of memory storing object\r\nVA 2\r\n41\r\nVA 5\r\nhello\r\n"
+    );
+}
+
+/// Long values kept in chunks: stored without one long allocation, sent a byte at a time
+/// across chunk boundaries, and appended to across one.
+fn chunk_demo() {
+    const LEN: usize = 100 * 1024;
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            chunk_values_over: Some(8 * 1024),
+            value_chunk_size: 4096,
+            ..Default::default()
+        },
+    );
+    let value: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut socket = NarrowSocket {
+        window: 1,
+        ..Default::default()
+    };
+    socket
+        .rbuf
+        .extend(format!("set blob 0 0 {}\r\n", LEN).as_bytes());
+    socket.rbuf.extend(&value);
+    socket.rbuf.extend(b"\r\n");
+    LARGEST.store(0, std::sync::atomic::Ordering::Relaxed);
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"STORED\r\n");
+    let largest = LARGEST.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(
+        largest <= 4096,
+        "{} bytes allocated at once storing",
+        largest
+    );
+
+    socket.sent.clear();
+    socket.rbuf.extend(b"get blob\r\n");
+    while handler.poll(&mut socket).progress {}
+    let mut expected = format!("VALUE blob 0 {}\r\n", LEN).into_bytes();
+    expected.extend(&value);
+    expected.extend(b"\r\nEND\r\n");
+    assert!(socket.sent == expected);
+    println!("{} KB value sent from chunks 1 byte at a time", LEN / 1024);
+
+    // 4 bytes short of 3 chunks, so the first 4 bytes appended fill the last chunk and the
+    // rest start a new one
+    let edge = &value[..3 * 4096 - 4];
+    let mut request = format!("set edge 0 0 {}\r\n", edge.len()).into_bytes();
+    request.extend(edge);
+    request.extend(b"\r\nms edge 6 MA\r\nabcdef\r\nmg edge s\r\n");
+    let sent = serve(&mut handler, &request, 64);
+    assert_eq!(sent, b"STORED\r\nHD\r\nHD s12290\r\n");
+    let appended = [edge, b"abcdef"].concat();
+    let mut expected = format!("VALUE edge 0 {}\r\n", appended.len()).into_bytes();
+    expected.extend(&appended);
+    expected.extend(b"\r\nEND\r\n");
+    assert!(serve(&mut handler, b"get edge\r\n", 7) == expected);
+    assert!(handler.get(b"edge").unwrap().value() == appended);
+}
+
+/// What `stats` says compression saved.
+#[cfg(feature = "lz4")]
+fn compression_saved<S: Storage, C: Clock>(handler: &mut CommandHandler<S, C>) -> u64 {
+    let stats = String::from_utf8(serve(handler, b"stats\r\n", 1460)).unwrap();
+    let line = stats
+        .lines()
+        .find_map(|line| line.strip_prefix("STAT compression_saved_bytes "))
+        .unwrap();
+    line.parse().unwrap()
+}
+
+/// JSON compressed as it's stored, noise left as it is, and appending to compressed values.
+#[cfg(feature = "lz4")]
+fn compression_demo() {
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            compressor: Some(std::sync::Arc::new(Lz4)),
+            ..Default::default()
+        },
+    );
+    let mut json = String::from("[");
+    for i in 0..2000 {
+        write!(json, "{{\"sensor\":{},\"reading\":{}.5}},", i % 16, i % 100).unwrap();
+    }
+    json.push(']');
+    let mut request = format!("set readings 0 0 {}\r\n", json.len()).into_bytes();
+    request.extend(json.as_bytes());
+    request.extend(b"\r\n");
+    let held = ALLOCATED.load(std::sync::atomic::Ordering::Relaxed);
+    assert_eq!(serve(&mut handler, &request, 1460), b"STORED\r\n");
+    let grown = ALLOCATED
+        .load(std::sync::atomic::Ordering::Relaxed)
+        .saturating_sub(held);
+    assert!(
+        grown < json.len() / 4,
+        "{} bytes kept for {}",
+        grown,
+        json.len()
+    );
+    let saved = compression_saved(&mut handler);
+    assert!(saved > json.len() as u64 * 3 / 4, "saved {}", saved);
+    let mut expected = format!("VALUE readings 0 {}\r\n{}\r\nEND\r\n", json.len(), json);
+    assert!(serve(&mut handler, b"get readings\r\n", 7) == expected.as_bytes());
+    println!(
+        "{} bytes of JSON stored in {}",
+        json.len(),
+        json.len() as u64 - saved
+    );
+
+    // Noise doesn't compress, so it's kept as it came
+    let mut rng = 0x2545_f491_4f6c_dd1du64;
+    let noise: Vec<u8> = (0..4096)
+        .map(|_| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng as u8
+        })
+        .collect();
+    let mut request = b"set noise 0 0 4096\r\n".to_vec();
+    request.extend(&noise);
+    request.extend(b"\r\nmg noise s v\r\n");
+    let mut expected_noise = b"STORED\r\nVA 4096 s4096\r\n".to_vec();
+    expected_noise.extend(&noise);
+    expected_noise.extend(b"\r\n");
+    assert!(serve(&mut handler, &request, 64) == expected_noise);
+    assert_eq!(compression_saved(&mut handler), saved);
+
+    // Appending expands the value, and compresses the result again
+    let sent = serve(&mut handler, b"ms readings 4 MA\r\n[{}]\r\n", 64);
+    assert_eq!(sent, b"HD\r\n");
+    assert!(compression_saved(&mut handler) > saved * 2 - 64);
+    json.push_str("[{}]");
+    expected = format!("VALUE readings 0 {}\r\n{}\r\nEND\r\n", json.len(), json);
+    assert!(serve(&mut handler, b"get readings\r\n", 1460) == expected.as_bytes());
+    assert!(handler.get(b"readings").unwrap().value() == json.as_bytes());
+}
+
+/// FNV-1a, a hasher much cheaper than SipHash on short keys.
+#[derive(Clone, Copy, Default)]
+struct FnvBuildHasher;
+
+struct FnvHasher(u64);
+
+impl std::hash::BuildHasher for FnvBuildHasher {
+    type Hasher = FnvHasher;
+
+    fn build_hasher(&self) -> FnvHasher {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl std::hash::Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A 5 MB item past a raised `item_size_max`: stored in chunks without allocating anything
+/// near its size, and sent back through 1460-byte windows and over UDP.
+fn large_item_demo() {
+    const LEN: usize = 5 * 1024 * 1024;
+    let settings = Settings {
+        item_size_max: 8 * 1024 * 1024,
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(HashMap::new(), SystemClock, settings.clone());
+    let value: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+    let mut socket = NarrowSocket {
+        window: 1460,
+        ..Default::default()
+    };
+    socket
+        .rbuf
+        .extend(format!("set render 0 0 {}\r\n", LEN).as_bytes());
+    socket.rbuf.extend(&value);
+    socket.rbuf.extend(b"\r\n");
+    LARGEST.store(0, std::sync::atomic::Ordering::Relaxed);
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(socket.sent, b"STORED\r\n");
+    let largest = LARGEST.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(
+        largest <= 64 * 1024,
+        "{} bytes allocated at once storing",
+        largest
+    );
+
+    socket.sent.clear();
+    socket.rbuf.extend(b"get render\r\n");
+    while handler.poll(&mut socket).progress {}
+    let header = format!("VALUE render 0 {}\r\n", LEN).into_bytes();
+    assert!(socket.sent.starts_with(&header));
+    assert!(socket.sent.ends_with(b"\r\nEND\r\n"));
+    assert_eq!(socket.sent.len(), header.len() + LEN + 7);
+    assert!(socket.sent[header.len()..][..LEN] == value[..]);
+    println!(
+        "{} MB value sent through 1460-byte windows",
+        LEN / 1024 / 1024
+    );
+
+    // Past the limit, the data block is read and dropped
+    let mut request = format!("set huge 0 0 {}\r\n", 9 * 1024 * 1024).into_bytes();
+    request.resize(request.len() + 9 * 1024 * 1024, b'h');
+    request.extend(b"\r\nget huge\r\n");
+    assert_eq!(
+        serve(&mut handler, &request, 1460),
+        b"SERVER_ERROR object too large for cache\r\nEND\r\n"
+    );
+
+    // Over the binary protocol, without buffering the value
+    #[cfg(feature = "binary")]
+    {
+        let mut bin = CommandHandler::with_settings(HashMap::new(), SystemClock, settings.clone());
+        let huge = vec![b'h'; 9 * 1024 * 1024];
+        let mut socket = NarrowSocket {
+            rbuf: binary_request(0x01, &[0; 8], b"huge", &huge, 1).into(),
+            window: 1460,
+            ..Default::default()
+        };
+        LARGEST.store(0, std::sync::atomic::Ordering::Relaxed);
+        while bin.poll(&mut socket).progress {}
+        assert!(LARGEST.load(std::sync::atomic::Ordering::Relaxed) < 1024 * 1024);
+        assert_eq!(socket.sent[6..8], [0x00, 0x03]);
+        assert_eq!(&socket.sent[24..], b"Too large.");
+    }
+
+    #[cfg(feature = "udp")]
+    {
+        let mut udp = UdpFraming::new(CommandHandler::with_settings(
+            HashMap::new(),
+            SystemClock,
+            settings,
+        ));
+        udp.handler
+            .insert(b"render", Entry::new(value.clone()))
+            .unwrap();
+        let mut datagrams = DatagramSocket {
+            mtu: 1400,
+            received: VecDeque::new(),
+            sent: Vec::new(),
+        };
+        datagrams
+            .received
+            .push_back(b"\x00\x07\x00\x00\x00\x01\x00\x00get render\r\n".to_vec());
+        while udp.poll(&mut datagrams) {}
+        let total = datagrams.sent.len();
+        let mut response = Vec::new();
+        for (sequence, datagram) in datagrams.sent.iter().enumerate() {
+            assert_eq!(datagram[2..4], (sequence as u16).to_be_bytes());
+            assert_eq!(datagram[4..6], (total as u16).to_be_bytes());
+            response.extend(&datagram[8..]);
+        }
+        assert!(response.starts_with(&header));
+        assert!(response.ends_with(b"\r\nEND\r\n"));
+        assert_eq!(response.len(), header.len() + LEN + 7);
+        println!("{} MB value sent in {} datagrams", LEN / 1024 / 1024, total);
+    }
+}
+
+/// Fills a handler over `data` half from code and half over the protocol, prunes the odd keys,
+/// and returns the sorted keys left.
+fn iteration_demo<S: Storage>(data: S) -> Vec<String> {
+    let mut handler = CommandHandler::new(data);
+    let mut socket = NarrowSocket::default();
+    for i in 0..20 {
+        let key = format!("t{}:{}", i % 2, i);
+        if i < 10 {
+            handler
+                .insert(key.as_bytes(), Entry::new(vec![b'x'; i]))
+                .unwrap();
+        } else {
+            socket
+                .rbuf
+                .extend(format!("set {} 0 0 {}\r\n{}\r\n", key, i, "x".repeat(i)).as_bytes());
+        }
+    }
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(handler.iter().count(), 20);
+    assert!(handler.iter().all(|(key, entry)| {
+        let i: usize = String::from_utf8_lossy(&key[3..]).parse().unwrap();
+        entry.len() == i
+    }));
+    let removed = handler.retain(|key, _| key.starts_with(b"t0:"));
+    assert_eq!((removed, handler.len()), (10, 10));
+    let memory = handler.memory_stats();
+    let (key_bytes, value_bytes) = handler.iter().fold((0, 0), |(k, v), (key, entry)| {
+        (k + key.len() as u64, v + entry.len() as u64)
+    });
+    assert_eq!(
+        (memory.items, memory.key_bytes, memory.value_bytes),
+        (10, key_bytes, value_bytes)
+    );
+    let mut keys: Vec<_> = handler
+        .keys()
+        .map(|key| String::from_utf8_lossy(&key).into_owned())
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Replaces the values of words starting with any of `prefixes` with `_`, up to the end of the
+/// line if it's the last.
+fn mask(transcript: &[u8], prefixes: &[&str]) -> Vec<u8> {
+    let sent = String::from_utf8_lossy(transcript);
+    let masked: Vec<_> = sent
+        .split(' ')
+        .map(
+            |word| match prefixes.iter().find(|&&prefix| word.starts_with(prefix)) {
+                Some(prefix) => format!(
+                    "{}_{}",
+                    prefix,
+                    &word[word.find('\r').unwrap_or(word.len())..]
+                ),
+                None => word.to_string(),
+            },
+        )
+        .collect();
+    masked.join(" ").into_bytes()
+}
+
+/// Times `rounds` batches of `pipelined` gets of a `key_len` byte key, through `window` byte
+/// windows.
+fn bench_get(rounds: u32, key_len: usize, pipelined: u32, window: usize) {
+    let key = vec![b'k'; key_len];
+    let mut map = HashMap::new();
+    map.insert(key.clone().into(), Entry::new(b"v".to_vec()));
+    let mut handler = CommandHandler::new(map);
+    let mut socket = NarrowSocket {
+        window,
+        ..Default::default()
+    };
+    let request = [&b"get "[..], &key, b"\r\n"]
+        .concat()
+        .repeat(pipelined as usize);
+    let start = std::time::Instant::now();
+    for _ in 0..rounds {
+        socket.rbuf.extend(&request);
+        while handler.poll(&mut socket).progress {}
+        socket.sent.clear();
+    }
+    println!(
+        "get of a {key_len}-byte key, {pipelined} at a time through {window}-byte windows: {:?} each",
+        start.elapsed() / (rounds * pipelined)
+    );
+}
+
+/// Hands out one datagram per `receive` and collects one per `transmit`.
+#[cfg(feature = "udp")]
+struct DatagramSocket {
+    mtu: usize,
+    received: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "udp")]
+impl Socket for DatagramSocket {
+    /// Datagrams are dequeued whole, so whatever of one isn't taken is lost.
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        let datagram = self.received.pop_front()?;
+        Some(f(&datagram).1)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut datagram = vec![0; self.mtu];
+        let (len, r) = f(&mut datagram);
+        if len > 0 {
+            datagram.truncate(len);
+            self.sent.push(datagram);
+        }
+        Some(r)
+    }
+}
+
+#[cfg(feature = "binary")]
+fn arith_extras(delta: u64, initial: u64, expiration: u32) -> Vec<u8> {
+    let mut extras = delta.to_be_bytes().to_vec();
+    extras.extend(initial.to_be_bytes());
+    extras.extend(expiration.to_be_bytes());
+    extras
+}
+
+#[cfg(feature = "binary")]
+fn binary_request(opcode: u8, extras: &[u8], key: &[u8], value: &[u8], opaque: u32) -> Vec<u8> {
+    let mut packet = vec![0x80, opcode];
+    packet.extend((key.len() as u16).to_be_bytes());
+    packet.extend([extras.len() as u8, 0, 0, 0]);
+    packet.extend(((extras.len() + key.len() + value.len()) as u32).to_be_bytes());
+    packet.extend(opaque.to_be_bytes());
+    packet.extend([0; 8]);
+    packet.extend(extras);
+    packet.extend(key);
+    packet.extend(value);
+    packet
+}
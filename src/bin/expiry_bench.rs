@@ -0,0 +1,86 @@
+//! Times reclaiming expired items by sweeping against the expiry index, on a million items of
+//! which a thousand expire each second. Run with `cargo run --release --bin expiry_bench`.
+
+use incr_memcached::*;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const ITEMS: usize = 1_000_000;
+const EXPIRING_PER_SECOND: usize = 1_000;
+const SECONDS: u32 = 10;
+
+#[derive(Clone, Default)]
+struct ManualClock(Rc<Cell<u32>>);
+
+impl Clock for ManualClock {
+    fn now(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+/// Takes requests and drops responses.
+#[derive(Default)]
+struct Sink {
+    rbuf: VecDeque<u8>,
+}
+
+impl Socket for Sink {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.rbuf.make_contiguous());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = [0; 4096];
+        Some(f(&mut buf).1)
+    }
+}
+
+/// Fills a handler, then ticks it once a second. Returns the time spent ticking.
+fn run(expiry_index: bool) -> Duration {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            expiry_index,
+            memory_limit: u64::MAX,
+            ..Default::default()
+        },
+    );
+    let mut socket = Sink::default();
+    for i in 0..ITEMS {
+        // A thousand items for each second of TTL.
+        let ttl = 1 + i / EXPIRING_PER_SECOND;
+        socket
+            .rbuf
+            .extend(format!("ms k{} 1 T{} q\r\nx\r\n", i, ttl).as_bytes());
+        if socket.rbuf.len() > 64 * 1024 {
+            while handler.poll(&mut socket).progress {}
+        }
+    }
+    while handler.poll(&mut socket).progress {}
+    let mut spent = Duration::ZERO;
+    for _ in 0..SECONDS {
+        clock.0.set(clock.0.get() + 1);
+        let start = Instant::now();
+        let reclaimed = handler.tick(usize::MAX);
+        spent += start.elapsed();
+        assert_eq!(reclaimed, EXPIRING_PER_SECOND);
+    }
+    spent
+}
+
+fn main() {
+    for (name, expiry_index) in [("sweep", false), ("index", true)] {
+        let spent = run(expiry_index);
+        println!("{}: {:?} per tick", name, spent / SECONDS);
+    }
+}
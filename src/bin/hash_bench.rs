@@ -0,0 +1,90 @@
+//! Times looking up short keys in a map hashed with SipHash, the default, against one hashed
+//! with FNV-1a. Run with `cargo run --release --bin hash_bench`.
+
+use incr_memcached::*;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+const ITEMS: usize = 100_000;
+const ROUNDS: usize = 10;
+
+/// FNV-1a, as the `fnv` crate has it.
+#[derive(Clone, Copy, Default)]
+struct FnvBuildHasher;
+
+struct FnvHasher(u64);
+
+impl BuildHasher for FnvBuildHasher {
+    type Hasher = FnvHasher;
+
+    fn build_hasher(&self) -> FnvHasher {
+        FnvHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Takes requests and drops responses.
+#[derive(Default)]
+struct Sink {
+    rbuf: VecDeque<u8>,
+}
+
+impl Socket for Sink {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.rbuf.make_contiguous());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = [0; 4096];
+        Some(f(&mut buf).1)
+    }
+}
+
+/// Fills a map hashed by `hasher`, then looks every key up through a handler `ROUNDS` times.
+/// Returns the time spent on the lookups.
+fn run<H: BuildHasher>(hasher: H) -> Duration {
+    let mut handler = CommandHandler::with_hasher(hasher);
+    let mut socket = Sink::default();
+    for i in 0..ITEMS {
+        socket
+            .rbuf
+            .extend(format!("ms k{} 1 q\r\nx\r\n", i).as_bytes());
+    }
+    while handler.poll(&mut socket).progress {}
+    let gets: Vec<u8> = (0..ITEMS)
+        .flat_map(|i| format!("mg k{} v q\r\n", i).into_bytes())
+        .collect();
+    let mut spent = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        socket.rbuf.extend(&gets);
+        let start = Instant::now();
+        while handler.poll(&mut socket).progress {}
+        spent += start.elapsed();
+    }
+    spent
+}
+
+fn main() {
+    let lookups = (ITEMS * ROUNDS) as u32;
+    let sip = run(std::collections::hash_map::RandomState::new());
+    println!("siphash: {:?} per lookup", sip / lookups);
+    let fnv = run(FnvBuildHasher);
+    println!("fnv: {:?} per lookup", fnv / lookups);
+}
@@ -0,0 +1,668 @@
+//! The binary protocol: fixed 24-byte headers followed by extras, key and value.
+
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use log::*;
+
+use crate::clock::Clock;
+use crate::slab::Bytes;
+use crate::stats::Stats;
+use crate::storage::Storage;
+use crate::value::read_slice;
+use crate::{CommandHandler, Error, State, Store, StoreMode, ITEM_OVERHEAD};
+
+pub const HEADER_LEN: usize = 24;
+/// Longest extras of any opcode: delta, initial value and expiration of INCREMENT/DECREMENT.
+pub const MAX_EXTRAS_LEN: usize = 20;
+/// Longest SASL mechanism name, per RFC 4422.
+const MAX_SASL_MECH_LEN: usize = 20;
+/// Longest SASL AUTH payload: a PLAIN one's authorization identity, user and password. The
+/// request is read before the connection is authenticated, so it mustn't be any longer.
+const MAX_SASL_PAYLOAD_LEN: usize = 512;
+/// How much of a value is reserved for when its request arrives. The rest is grown into as
+/// the bytes do, so a header alone can't claim a value's worth of memory.
+const VALUE_RESERVE: usize = 4096;
+
+pub const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Get,
+    Set,
+    Delete,
+    Quit,
+    GetQ,
+    Noop,
+    GetK,
+    GetKQ,
+    SetQ,
+    DeleteQ,
+    Increment,
+    Decrement,
+    Flush,
+    Version,
+    Append,
+    Prepend,
+    Stat,
+    SaslListMechs,
+    SaslAuth,
+}
+
+impl Opcode {
+    fn from_byte(b: u8) -> Option<Self> {
+        Some(match b {
+            0x00 => Self::Get,
+            0x01 => Self::Set,
+            0x04 => Self::Delete,
+            0x05 => Self::Increment,
+            0x06 => Self::Decrement,
+            0x07 => Self::Quit,
+            0x08 => Self::Flush,
+            0x09 => Self::GetQ,
+            0x0a => Self::Noop,
+            0x0b => Self::Version,
+            0x0c => Self::GetK,
+            0x0d => Self::GetKQ,
+            0x0e => Self::Append,
+            0x0f => Self::Prepend,
+            0x10 => Self::Stat,
+            0x20 => Self::SaslListMechs,
+            0x21 => Self::SaslAuth,
+            0x11 => Self::SetQ,
+            0x14 => Self::DeleteQ,
+            _ => return None,
+        })
+    }
+
+    /// Quiet opcodes only respond when there's something worth saying: gets stay silent on a
+    /// miss, everything else on success.
+    fn is_quiet(self) -> bool {
+        matches!(self, Self::GetQ | Self::GetKQ | Self::SetQ | Self::DeleteQ)
+    }
+
+    /// Whether a request with these lengths is well-formed, per upstream's checks.
+    fn accepts(self, extras_len: usize, key_len: usize, value_len: usize) -> bool {
+        match self {
+            Self::Get | Self::GetQ | Self::GetK | Self::GetKQ | Self::Delete | Self::DeleteQ => {
+                extras_len == 0 && key_len > 0 && value_len == 0
+            }
+            Self::Set | Self::SetQ => extras_len == 8 && key_len > 0,
+            Self::Quit | Self::Noop | Self::Version | Self::SaslListMechs => {
+                extras_len == 0 && key_len == 0 && value_len == 0
+            }
+            Self::Increment | Self::Decrement => extras_len == 20 && key_len > 0 && value_len == 0,
+            Self::Flush => (extras_len == 0 || extras_len == 4) && key_len == 0 && value_len == 0,
+            Self::Append | Self::Prepend => extras_len == 0 && key_len > 0,
+            Self::Stat => extras_len == 0 && value_len == 0,
+            Self::SaslAuth => extras_len == 0 && (1..=MAX_SASL_MECH_LEN).contains(&key_len),
+        }
+    }
+
+    /// The longest value a request with a `key_len` key may carry: what fits in an item for
+    /// stores, a SASL payload for AUTH, and nothing for the rest.
+    fn max_value_len(self, key_len: usize, item_size_max: usize) -> usize {
+        match self {
+            _ if self.stores() => item_size_max.saturating_sub(key_len + ITEM_OVERHEAD),
+            Self::SaslAuth => MAX_SASL_PAYLOAD_LEN,
+            _ => 0,
+        }
+    }
+
+    /// Whether the request's value becomes an item's, and so counts toward its size.
+    fn stores(self) -> bool {
+        matches!(self, Self::Set | Self::SetQ | Self::Append | Self::Prepend)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    NoError = 0x00,
+    KeyNotFound = 0x01,
+    TooLarge = 0x03,
+    InvalidArguments = 0x04,
+    ItemNotStored = 0x05,
+    NonNumeric = 0x06,
+    AuthError = 0x20,
+    UnknownCommand = 0x81,
+    OutOfMemory = 0x82,
+}
+
+impl Status {
+    /// Body of an error response. Same wording as upstream.
+    fn message(self) -> &'static [u8] {
+        match self {
+            Self::NoError => b"",
+            Self::KeyNotFound => b"Not found",
+            Self::TooLarge => b"Too large.",
+            Self::InvalidArguments => b"Invalid arguments",
+            Self::ItemNotStored => b"Not stored.",
+            Self::NonNumeric => b"Non-numeric server-side value for incr or decr",
+            Self::AuthError => b"Auth failure.",
+            Self::UnknownCommand => b"Unknown command",
+            Self::OutOfMemory => b"Out of memory",
+        }
+    }
+}
+
+/// The fields of a request header we care about.
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    opcode: u8,
+    key_len: usize,
+    extras_len: usize,
+    body_len: usize,
+    opaque: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8; HEADER_LEN]) -> Self {
+        let be_u16 = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+        let be_u32 =
+            |i: usize| u32::from_be_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        Self {
+            opcode: bytes[1],
+            key_len: be_u16(2).into(),
+            extras_len: bytes[4].into(),
+            body_len: be_u32(8) as usize,
+            opaque: be_u32(12),
+        }
+    }
+}
+
+/// A request whose body is being read.
+#[derive(Debug)]
+pub struct Request<const KEY_LEN: usize> {
+    header: Header,
+    /// Set once the request is known to be bad. The body is still consumed, then this is
+    /// reported.
+    error: Option<Status>,
+    extras: heapless::Vec<u8, MAX_EXTRAS_LEN>,
+    key: heapless::Vec<u8, KEY_LEN>,
+    value: Vec<u8>,
+    read: usize,
+}
+
+impl<const KEY_LEN: usize> Request<KEY_LEN> {
+    /// A request with `header`, whose value is read into memory of its own, contiguous unlike
+    /// a text data block's. A body longer than its opcode allows, such as a store whose item
+    /// would exceed `item_size_max`, is turned away before any of it is buffered.
+    fn new(header: Header, item_size_max: usize) -> Self {
+        let value_len = header
+            .body_len
+            .checked_sub(header.key_len + header.extras_len);
+        let error = match (Opcode::from_byte(header.opcode), value_len) {
+            (None, _) => Some(Status::UnknownCommand),
+            (_, None) => Some(Status::InvalidArguments),
+            _ if header.key_len > KEY_LEN || header.extras_len > MAX_EXTRAS_LEN => {
+                Some(Status::InvalidArguments)
+            }
+            (Some(opcode), Some(value_len)) => {
+                if !opcode.accepts(header.extras_len, header.key_len, value_len) {
+                    Some(Status::InvalidArguments)
+                } else if value_len > opcode.max_value_len(header.key_len, item_size_max) {
+                    Some(if opcode.stores() {
+                        Status::TooLarge
+                    } else {
+                        Status::InvalidArguments
+                    })
+                } else {
+                    None
+                }
+            }
+        };
+        let value = match value_len {
+            Some(len) if error.is_none() => Vec::with_capacity(len.min(VALUE_RESERVE)),
+            _ => Vec::new(),
+        };
+        Self {
+            header,
+            error,
+            extras: Default::default(),
+            key: Default::default(),
+            value,
+            read: 0,
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.error.is_none() {
+            let key_end = self.header.extras_len + self.header.key_len;
+            if self.read < self.header.extras_len {
+                self.extras.push(c).expect("extras length checked");
+            } else if self.read < key_end {
+                self.key.push(c).expect("key length checked");
+            } else {
+                self.value.push(c);
+            }
+        }
+        self.read += 1;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.read == self.header.body_len
+    }
+}
+
+#[derive(Debug)]
+enum Value {
+    Static(&'static [u8]),
+    /// An entry's value, held on to so the entry may go away while it's sent.
+    Entry(Bytes),
+    /// Short bodies made up on the spot: stat values and counters.
+    Inline(heapless::Vec<u8, 20>),
+}
+
+impl Value {
+    fn len(&self) -> usize {
+        match self {
+            Self::Static(value) => value.len(),
+            Self::Entry(value) => value.len(),
+            Self::Inline(value) => value.len(),
+        }
+    }
+
+    fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        match self {
+            Self::Static(value) => read_slice(value, offset, out),
+            Self::Entry(value) => value.read(offset, out),
+            Self::Inline(value) => read_slice(value, offset, out),
+        }
+    }
+}
+
+/// A response packet being sent.
+#[derive(Debug)]
+pub struct Response<const KEY_LEN: usize> {
+    status: Status,
+    header: [u8; HEADER_LEN],
+    extras: heapless::Vec<u8, MAX_EXTRAS_LEN>,
+    key: heapless::Vec<u8, KEY_LEN>,
+    value: Value,
+    sent: usize,
+}
+
+impl<const KEY_LEN: usize> Response<KEY_LEN> {
+    fn new(request: &Header, status: Status, extras: &[u8], key: &[u8], value: Value) -> Self {
+        let mut response = Self {
+            status,
+            header: [0; HEADER_LEN],
+            extras: heapless::Vec::from_slice(extras).expect("extras fit"),
+            key: heapless::Vec::from_slice(key).expect("key fits"),
+            value,
+            sent: 0,
+        };
+        let body_len = response.extras.len() + response.key.len() + response.value.len();
+        let header = &mut response.header;
+        header[0] = RESPONSE_MAGIC;
+        header[1] = request.opcode;
+        header[2..4].copy_from_slice(&(response.key.len() as u16).to_be_bytes());
+        header[4] = response.extras.len() as u8;
+        header[6..8].copy_from_slice(&(status as u16).to_be_bytes());
+        header[8..12].copy_from_slice(&(body_len as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&request.opaque.to_be_bytes());
+        response
+    }
+
+    /// A response with no body, or just the error message for failures.
+    fn status(request: &Header, status: Status) -> Self {
+        Self::new(request, status, &[], &[], Value::Static(status.message()))
+    }
+
+    /// Copies as much of the rest of the packet as fits. Returns how much was copied.
+    pub fn fill(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        let mut offset = self.sent;
+        for segment in [&self.header[..], &self.extras, &self.key] {
+            if offset >= segment.len() {
+                offset -= segment.len();
+                continue;
+            }
+            written += read_slice(segment, offset, &mut buf[written..]);
+            offset = 0;
+        }
+        // The value is read rather than sliced, as it may not be in memory.
+        written += self.value.read(offset, &mut buf[written..]);
+        self.sent += written;
+        written
+    }
+
+    /// Bytes left to send.
+    pub fn remaining(&self) -> usize {
+        HEADER_LEN + self.extras.len() + self.key.len() + self.value.len() - self.sent
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+/// Longest stat name, which STAT responses carry as their key.
+const MAX_STAT_NAME_LEN: usize = 32;
+
+/// Response to STAT: one packet per stat, then one with an empty key.
+#[derive(Debug)]
+pub struct StatStream {
+    request: Header,
+    next: usize,
+    response: Response<MAX_STAT_NAME_LEN>,
+    last: bool,
+}
+
+impl StatStream {
+    fn new(request: Header, stats: &Stats) -> Self {
+        let mut stream = Self {
+            request,
+            next: 0,
+            response: Response::status(&request, Status::NoError),
+            last: false,
+        };
+        stream.advance(stats);
+        stream
+    }
+
+    fn advance(&mut self, stats: &Stats) {
+        self.response = match stats.stat(self.next) {
+            Some((name, value)) => {
+                let mut digits = heapless::Vec::new();
+                write!(digits, "{}", value).expect("formatting stat");
+                Response::new(
+                    &self.request,
+                    Status::NoError,
+                    &[],
+                    name.as_bytes(),
+                    Value::Inline(digits),
+                )
+            }
+            None => {
+                self.last = true;
+                Response::status(&self.request, Status::NoError)
+            }
+        };
+        self.next += 1;
+    }
+
+    /// Like [`Response::fill`], moving on to the next stat's packet as each one is sent.
+    pub fn fill(&mut self, buf: &mut [u8], stats: &Stats) -> usize {
+        let mut written = 0;
+        loop {
+            written += self.response.fill(&mut buf[written..]);
+            if !self.response.is_done() || self.last {
+                return written;
+            }
+            self.advance(stats);
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.last && self.response.is_done()
+    }
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Feeds one received byte to the binary protocol parser.
+    pub(crate) fn receive_binary(&mut self, c: u8) {
+        self.state = match core::mem::take(&mut self.state) {
+            State::ReadingCommand(_) if c != REQUEST_MAGIC => {
+                // Text (or garbage) on a binary connection: answer once, then hang up.
+                error!("Invalid binary request magic {:#x}", c);
+                self.closing = true;
+                let header = Header {
+                    opcode: 0,
+                    key_len: 0,
+                    extras_len: 0,
+                    body_len: 0,
+                    opaque: 0,
+                };
+                State::SendingBinary(Box::new(Response::status(&header, Status::UnknownCommand)))
+            }
+            State::ReadingCommand(_) => {
+                let mut header = [0; HEADER_LEN];
+                header[0] = c;
+                State::ReadingBinaryHeader { header, len: 1 }
+            }
+            State::ReadingBinaryHeader { mut header, len } => {
+                header[len] = c;
+                if len + 1 < HEADER_LEN {
+                    State::ReadingBinaryHeader {
+                        header,
+                        len: len + 1,
+                    }
+                } else {
+                    self.start_binary(&header)
+                }
+            }
+            State::ReadingBinaryBody(mut request) => {
+                request.push(c);
+                if request.is_complete() {
+                    self.execute_binary(*request)
+                } else {
+                    State::ReadingBinaryBody(request)
+                }
+            }
+            state => unreachable!("{:?} doesn't read binary requests", state),
+        };
+    }
+
+    fn start_binary(&mut self, bytes: &[u8; HEADER_LEN]) -> State<KEY_LEN> {
+        let request = Box::new(Request::new(Header::parse(bytes), self.item_size_max));
+        if request.is_complete() {
+            self.execute_binary(*request)
+        } else {
+            State::ReadingBinaryBody(request)
+        }
+    }
+
+    fn execute_binary(&mut self, request: Request<KEY_LEN>) -> State<KEY_LEN> {
+        let header = &request.header;
+        if let Some(status) = request.error {
+            return State::SendingBinary(Box::new(Response::status(header, status)));
+        }
+        let key = request.key.as_slice();
+        let opcode = Opcode::from_byte(header.opcode).expect("opcode validated");
+        let sasl = matches!(opcode, Opcode::SaslListMechs | Opcode::SaslAuth);
+        let status = match &self.authenticator {
+            None if sasl => Some(Status::UnknownCommand),
+            // Like upstream, VERSION is allowed before authenticating too.
+            Some(_) if !self.authenticated && !sasl && opcode != Opcode::Version => {
+                Some(Status::AuthError)
+            }
+            _ => None,
+        };
+        if let Some(status) = status {
+            return State::SendingBinary(Box::new(Response::status(header, status)));
+        }
+        let response = match opcode {
+            Opcode::Get | Opcode::GetQ | Opcode::GetK | Opcode::GetKQ => {
+                self.read_through(key);
+                let now = self.clock.now();
+                Self::lookup(&mut self.data, &mut self.stats, now, key, |entry| {
+                    Response::new(
+                        header,
+                        Status::NoError,
+                        &entry.flags.to_be_bytes(),
+                        if let Opcode::GetK | Opcode::GetKQ = opcode {
+                            key
+                        } else {
+                            &[]
+                        },
+                        Value::Entry(entry.value.expanded()),
+                    )
+                })
+                .unwrap_or_else(|| Response::status(header, Status::KeyNotFound))
+            }
+            Opcode::Set | Opcode::SetQ => {
+                let extras = &request.extras;
+                let store = Store {
+                    mode: StoreMode::Set,
+                    key: request.key.clone(),
+                    flags: u32::from_be_bytes([extras[0], extras[1], extras[2], extras[3]]),
+                    stale: false,
+                    exptime: u32::from_be_bytes([extras[4], extras[5], extras[6], extras[7]])
+                        .into(),
+                    noreply: false,
+                    meta: Default::default(),
+                };
+                let stored = self
+                    .keep_value(key, request.value)
+                    .and_then(|value| self.store(&store, value));
+                match stored {
+                    Ok(true) => Response::status(header, Status::NoError),
+                    // Turned away by a tombstone.
+                    Ok(false) => Response::status(header, Status::ItemNotStored),
+                    Err(_) => Response::status(header, Status::OutOfMemory),
+                }
+            }
+            Opcode::Delete | Opcode::DeleteQ => {
+                if self.delete(key) {
+                    Response::status(header, Status::NoError)
+                } else {
+                    Response::status(header, Status::KeyNotFound)
+                }
+            }
+            Opcode::Quit => {
+                self.closing = true;
+                Response::status(header, Status::NoError)
+            }
+            Opcode::Noop => Response::status(header, Status::NoError),
+            Opcode::Increment | Opcode::Decrement => {
+                let extras = &request.extras;
+                let delta = u64::from_be_bytes(extras[0..8].try_into().expect("8 bytes"));
+                let initial = u64::from_be_bytes(extras[8..16].try_into().expect("8 bytes"));
+                let expiration = u32::from_be_bytes(extras[16..20].try_into().expect("4 bytes"));
+                let incr = opcode == Opcode::Increment;
+                let value = match self.arithmetic(key, incr, delta) {
+                    Ok(Some(n)) => Some(n),
+                    // Unlike the text protocol, a miss creates the item unless the expiration
+                    // is all ones.
+                    Ok(None) if expiration != u32::MAX => {
+                        let store = Store {
+                            mode: StoreMode::Add,
+                            key: request.key.clone(),
+                            flags: 0,
+                            stale: false,
+                            exptime: expiration.into(),
+                            noreply: false,
+                            meta: Default::default(),
+                        };
+                        let stored = self
+                            .keep_value(key, initial.to_string().into_bytes())
+                            .and_then(|value| self.store(&store, value));
+                        let status = match stored {
+                            Ok(true) => None,
+                            Ok(false) => Some(Status::ItemNotStored),
+                            Err(_) => Some(Status::OutOfMemory),
+                        };
+                        if let Some(status) = status {
+                            return State::SendingBinary(Box::new(Response::status(
+                                header, status,
+                            )));
+                        }
+                        Some(initial)
+                    }
+                    Ok(None) => None,
+                    Err(Error::NonNumericValue) => {
+                        return State::SendingBinary(Box::new(Response::status(
+                            header,
+                            Status::NonNumeric,
+                        )))
+                    }
+                    // The new value couldn't be kept.
+                    Err(_) => {
+                        return State::SendingBinary(Box::new(Response::status(
+                            header,
+                            Status::OutOfMemory,
+                        )))
+                    }
+                };
+                match value {
+                    Some(n) => Response::new(
+                        header,
+                        Status::NoError,
+                        &[],
+                        &[],
+                        Value::Inline(
+                            heapless::Vec::from_slice(&n.to_be_bytes()).expect("8 bytes"),
+                        ),
+                    ),
+                    None => Response::status(header, Status::KeyNotFound),
+                }
+            }
+            Opcode::Append | Opcode::Prepend => {
+                let store = Store {
+                    mode: if opcode == Opcode::Append {
+                        StoreMode::Append
+                    } else {
+                        StoreMode::Prepend
+                    },
+                    key: request.key.clone(),
+                    flags: 0,
+                    stale: false,
+                    exptime: 0,
+                    noreply: false,
+                    meta: Default::default(),
+                };
+                match self.store(&store, request.value.into()) {
+                    Ok(true) => Response::status(header, Status::NoError),
+                    Ok(false) => Response::status(header, Status::ItemNotStored),
+                    Err(_) => Response::status(header, Status::OutOfMemory),
+                }
+            }
+            Opcode::Flush => {
+                let delay = match request.extras.as_slice() {
+                    [a, b, c, d] => u32::from_be_bytes([*a, *b, *c, *d]),
+                    _ => 0,
+                };
+                self.flush_after(delay.into());
+                Response::status(header, Status::NoError)
+            }
+            Opcode::Version => Response::new(
+                header,
+                Status::NoError,
+                &[],
+                &[],
+                Value::Static(env!("CARGO_PKG_VERSION").as_bytes()),
+            ),
+            // Only the general stats so far
+            Opcode::Stat if key.is_empty() => {
+                return State::SendingBinaryStats(Box::new(StatStream::new(*header, &self.stats)))
+            }
+            Opcode::Stat => Response::status(header, Status::KeyNotFound),
+            Opcode::SaslListMechs => {
+                Response::new(header, Status::NoError, &[], &[], Value::Static(b"PLAIN"))
+            }
+            Opcode::SaslAuth => {
+                let authenticator = self.authenticator.as_ref().expect("checked above");
+                // PLAIN is `authzid\0user\0password`.
+                let mut fields = request.value.split(|&c| c == 0);
+                self.authenticated = match (key, fields.next(), fields.next(), fields.next()) {
+                    (b"PLAIN", Some(_authzid), Some(user), Some(password)) => {
+                        fields.next().is_none() && authenticator.authenticate(user, password)
+                    }
+                    _ => false,
+                };
+                if self.authenticated {
+                    Response::new(
+                        header,
+                        Status::NoError,
+                        &[],
+                        &[],
+                        Value::Static(b"Authenticated"),
+                    )
+                } else {
+                    Response::status(header, Status::AuthError)
+                }
+            }
+        };
+        let silent = match opcode {
+            Opcode::GetQ | Opcode::GetKQ => response.status == Status::KeyNotFound,
+            _ => opcode.is_quiet() && response.status == Status::NoError,
+        };
+        if silent {
+            return State::default();
+        }
+        State::SendingBinary(Box::new(response))
+    }
+}
@@ -0,0 +1,41 @@
+#[cfg(feature = "std")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, in whole seconds.
+pub trait Clock {
+    fn now(&self) -> u32;
+}
+
+/// Wall-clock time in seconds since the Unix epoch.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as u32)
+    }
+}
+
+/// The clock handlers use unless given another one.
+#[cfg(feature = "std")]
+pub type DefaultClock = SystemClock;
+/// The clock handlers use unless given another one. Without `std` there's no time to default
+/// to, so this one can't be made and every handler needs a clock of its own.
+#[cfg(not(feature = "std"))]
+pub type DefaultClock = NoClock;
+
+/// Can't be constructed. Stands in as [`DefaultClock`] without `std`.
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub enum NoClock {}
+
+#[cfg(not(feature = "std"))]
+impl Clock for NoClock {
+    fn now(&self) -> u32 {
+        match *self {}
+    }
+}
@@ -0,0 +1,93 @@
+//! Values compressed as they're stored, and expanded again to be served.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::slab::Bytes;
+
+/// Compresses values and expands them again, see
+/// [`Settings::compressor`](crate::Settings::compressor).
+pub trait Compressor: Send + Sync {
+    /// `value` compressed, or `None` to store it as it is. Kept only if it's shorter.
+    fn compress(&self, value: &[u8]) -> Option<Vec<u8>>;
+    /// `compressed` expanded back into the `len` bytes it was made from, or `None` if it
+    /// can't be.
+    fn expand(&self, compressed: &[u8], len: usize) -> Option<Vec<u8>>;
+}
+
+impl fmt::Debug for dyn Compressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Compressor")
+    }
+}
+
+/// LZ4's block format, quick enough to run on every store.
+#[cfg(feature = "lz4")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn compress(&self, value: &[u8]) -> Option<Vec<u8>> {
+        Some(lz4_flex::block::compress(value))
+    }
+
+    fn expand(&self, compressed: &[u8], len: usize) -> Option<Vec<u8>> {
+        lz4_flex::block::decompress(compressed, len).ok()
+    }
+}
+
+/// A value kept compressed, with what it takes to expand it.
+pub(crate) struct Compressed {
+    bytes: Box<[u8]>,
+    /// Of the value as it was stored.
+    len: usize,
+    codec: Arc<dyn Compressor>,
+}
+
+impl Compressed {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The value as it was stored. One that won't expand, which only a faulty codec causes,
+    /// comes out as zeros, so that it's still as long as it was said to be.
+    pub(crate) fn expand(&self) -> Vec<u8> {
+        match self.codec.expand(&self.bytes, self.len) {
+            Some(value) if value.len() == self.len => value,
+            _ => {
+                log::error!("compressed value of {} bytes doesn't expand", self.len);
+                alloc::vec![0; self.len]
+            }
+        }
+    }
+}
+
+/// `value` compressed by `codec` if it's longer than `over` bytes, on the heap, and comes
+/// out shorter. Returns it along with the bytes saved.
+pub(crate) fn compress(
+    codec: Option<&Arc<dyn Compressor>>,
+    over: usize,
+    value: Bytes,
+) -> (Bytes, usize) {
+    let Some(codec) = codec.filter(|_| value.len() > over) else {
+        return (value, 0);
+    };
+    let Bytes::Heap(bytes) = &value else {
+        return (value, 0);
+    };
+    match codec.compress(bytes) {
+        Some(compressed) if compressed.len() < bytes.len() => {
+            let saved = bytes.len() - compressed.len();
+            let compressed = Compressed {
+                bytes: compressed.into(),
+                len: bytes.len(),
+                codec: codec.clone(),
+            };
+            (Bytes::compressed(compressed), saved)
+        }
+        _ => (value, 0),
+    }
+}
@@ -0,0 +1,178 @@
+//! Reading and changing items from the program the handler is embedded in, rather than through
+//! a connection.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::ControlFlow;
+
+use crate::clock::Clock;
+use crate::storage::Storage;
+use crate::watch::EventKind;
+use crate::{validate_key, CommandHandler, Entry, Error, Found, RemovalCause, ITEM_OVERHEAD};
+
+/// Items are changed as the equivalent commands would change them, counted in the same stats,
+/// logged to the same journal and watched by the same watchers. Responses already sending an
+/// item's value when it's replaced or removed keep sending the value they started with, as
+/// they do when another connection changes it.
+///
+/// ```
+/// use incr_memcached::{Clock, CommandHandler, Entry, Map};
+///
+/// # struct Uptime;
+/// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+/// let data: Map<_, _> = Map::default();
+/// let mut handler = CommandHandler::with_clock(data, Uptime);
+/// handler.insert(b"greeting", Entry::with_flags(b"hello".to_vec(), 1)).unwrap();
+/// let entry = handler.get(b"greeting").unwrap();
+/// assert_eq!((&entry.value()[..], entry.flags()), (&b"hello"[..], 1));
+/// assert!(handler.remove(b"greeting").is_some());
+/// assert!(!handler.contains_key(b"greeting"));
+/// ```
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Stores `entry` at `key` as `set` would, with its value, flags, expiry and staleness and
+    /// a new CAS value. An entry that's already expired removes the item instead. Like a
+    /// store over the protocol, going past the memory limit evicts on the next poll, and a
+    /// tombstone turns it away with [`Error::NotStored`] if
+    /// [`Settings::tombstones_block_set`](crate::Settings::tombstones_block_set).
+    pub fn insert(&mut self, key: &[u8], entry: Entry) -> Result<(), Error> {
+        if key.len() > KEY_LEN {
+            return Err(Error::KeyTooLong { limit: KEY_LEN });
+        }
+        validate_key(key)?;
+        let size = key.len() + entry.value.len() + ITEM_OVERHEAD;
+        if size > self.item_size_max {
+            return Err(Error::TooLarge {
+                size,
+                limit: self.item_size_max,
+            });
+        }
+        let now = self.clock.now();
+        let found = self.start_set(key, now);
+        if found == Found::Tombstone && self.tombstones_block_set {
+            self.stats.counters.tombstone_blocked += 1;
+            return Err(Error::NotStored);
+        }
+        if entry.is_expired(now) {
+            if let Some(old) = self.data.remove(key) {
+                self.forget(key, &old);
+                Self::removed(
+                    &self.data,
+                    &self.stats,
+                    now,
+                    key,
+                    &old,
+                    RemovalCause::Replaced,
+                );
+            }
+        } else {
+            self.put(key, entry, found != Found::Nothing)?;
+        }
+        #[cfg(feature = "journal")]
+        self.log_item(key);
+        let kind = EventKind::Store {
+            cmd: "set",
+            stored: true,
+        };
+        self.stats.publish(now, kind, key);
+        Ok(())
+    }
+
+    /// A copy of the item at `key`, sharing its value, unless there's none or it's expired.
+    /// Unlike a `get`, it isn't counted, and doesn't mark the item fetched.
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        self.data
+            .read(key, |entry| entry.clone())
+            .filter(|entry| entry.is_live(now, flushed))
+    }
+
+    /// Removes the item at `key` as `delete` would, returning it unless it had expired. Like
+    /// `delete`, it leaves a tombstone if [`Settings::tombstone_secs`](crate::Settings::tombstone_secs)
+    /// says to.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        self.take(key).filter(|entry| entry.is_live(now, flushed))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        self.data
+            .read(key, |entry| entry.is_live(now, flushed))
+            .unwrap_or(false)
+    }
+
+    /// The keys of the items that haven't expired, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = Box<[u8]>> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Copies of the items that haven't expired, sharing their values, in no particular
+    /// order. Storage only lends items out one call at a time, so they're all copied before
+    /// the first is returned, and changes made meanwhile aren't seen.
+    pub fn iter(&self) -> impl Iterator<Item = (Box<[u8]>, Entry)> {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let mut items = Vec::new();
+        let _ = self.data.scan(|key, entry| {
+            if entry.is_live(now, flushed) {
+                items.push((key.into(), entry.clone()));
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        items.into_iter()
+    }
+
+    /// Removes the items `keep` returns false for, as `delete` would, such as all those with
+    /// a tenant's prefix. Expired items aren't asked about, and are left for the usual
+    /// reclaiming. Returns how many were removed. A metadump under way may skip some items
+    /// for it, as it would for a `delete`.
+    ///
+    /// ```
+    /// use incr_memcached::{Clock, CommandHandler, Entry, Map};
+    ///
+    /// # struct Uptime;
+    /// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+    /// let data: Map<_, _> = Map::default();
+    /// let mut handler = CommandHandler::with_clock(data, Uptime);
+    /// for key in ["a:1", "a:2", "b:1"] {
+    ///     handler.insert(key.as_bytes(), Entry::new(b"x".to_vec())).unwrap();
+    /// }
+    /// assert_eq!(handler.retain(|key, _| !key.starts_with(b"a:")), 2);
+    /// assert_eq!(handler.keys().collect::<Vec<_>>(), [b"b:1"[..].into()]);
+    /// ```
+    pub fn retain(&mut self, mut keep: impl FnMut(&[u8], &Entry) -> bool) -> usize {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let mut doomed = Vec::new();
+        let _ = self.data.scan(|key, entry| {
+            if entry.is_live(now, flushed) && !keep(key, entry) {
+                doomed.push(Box::<[u8]>::from(key));
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        for key in &doomed {
+            self.take(key);
+        }
+        doomed.len()
+    }
+
+    /// Removes every item, as [`flush_all`](Self::flush_all) does: at once however many
+    /// there are, leaving them to be reclaimed later.
+    pub fn clear(&mut self) {
+        self.flush_all();
+    }
+
+    /// How many items there are, counting expired ones not yet reclaimed but not flushed ones
+    /// or tombstones.
+    pub fn len(&self) -> usize {
+        let other = self.stats.generation.left() + self.stats.curr_tombstones as usize;
+        self.data.len().saturating_sub(other)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
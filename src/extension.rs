@@ -0,0 +1,63 @@
+//! Text commands the application adds to the ones the handler knows.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{Error, MAX_EXTENSION_REPLY_LEN};
+
+/// A text command implemented by the application, such as `health` or `purgeprefix <p>`.
+/// Registered with [`CommandHandler::register_command`](crate::CommandHandler::register_command),
+/// it's asked about command names the handler doesn't know before they're answered with
+/// `ERROR`. It gets the handler's storage, `S`, to work on.
+pub trait CommandExtension<S> {
+    /// Whether this is the extension for the command named `verb`.
+    fn matches(&self, verb: &[u8]) -> bool;
+    /// Carries out the command. What it wrote to `out` is the reply, or an error is answered
+    /// like a built-in command's would be. Writing nothing leaves the command unanswered.
+    fn execute(
+        &mut self,
+        args: &Args<'_>,
+        storage: &mut S,
+        out: &mut ResponseSink,
+    ) -> Result<(), Error>;
+}
+
+/// The rest of a command's line after its name, up to [`MAX_ARGS_LEN`](crate::MAX_ARGS_LEN)
+/// bytes.
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Args<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// The arguments themselves, as separated by whitespace.
+    pub fn tokens(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.0
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty())
+    }
+}
+
+/// Where an extension writes its reply, which goes out as the socket takes it. It holds up to
+/// [`MAX_EXTENSION_REPLY_LEN`] bytes.
+#[derive(Debug, Default)]
+pub struct ResponseSink(pub(crate) Vec<u8>);
+
+impl ResponseSink {
+    /// Adds `bytes` to the reply, or fails with [`Error::HeaderTooLong`] if they don't fit.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.0.len() + bytes.len() > MAX_EXTENSION_REPLY_LEN {
+            return Err(Error::HeaderTooLong);
+        }
+        self.0.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+impl fmt::Write for ResponseSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
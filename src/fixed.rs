@@ -0,0 +1,113 @@
+//! Storage of fixed capacity, for targets with little heap to spare: its table and keys take
+//! the same room however many items there are, and it never grows. Values, and the handler's
+//! own queues and buffers, still take the heap, so the crate needs an allocator with it too.
+
+use core::ops::ControlFlow;
+
+use heapless::FnvIndexMap;
+
+use crate::storage::Storage;
+use crate::{Entry, MAX_KEY_LEN};
+
+/// Storage holding up to `N` items, a power of two, with keys of up to `K` bytes kept inline
+/// in a `heapless` map. Nothing is allocated for the table or the keys, so it can live in a
+/// `static` or on the stack, and its size is known when the program is linked.
+///
+/// The handler holds no more items than the storage does: past `N`, new items are refused with
+/// `SERVER_ERROR out of memory storing object`, or others evicted for them with
+/// [`Settings::evict_when_full`](crate::Settings::evict_when_full). So are items with keys
+/// longer than `K`, unless the handler takes no keys that long, with
+/// [`CommandHandler::with_key_len`](crate::CommandHandler::with_key_len). Values are kept as
+/// they come, on the heap unless they're static, so reads still send them without copying.
+///
+/// A walk such as `lru_crawler metadump` may miss an item when another is removed meanwhile,
+/// as the last item takes the removed one's place.
+///
+/// ```
+/// use incr_memcached::{Clock, CommandHandler, Fixed};
+///
+/// # struct Uptime;
+/// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+/// let handler: CommandHandler<Fixed<64>, _> = CommandHandler::with_clock(Fixed::new(), Uptime);
+/// assert!(handler.is_empty());
+/// ```
+pub struct Fixed<const N: usize, const K: usize = MAX_KEY_LEN> {
+    items: FnvIndexMap<heapless::Vec<u8, K>, Entry, N>,
+}
+
+impl<const N: usize, const K: usize> Fixed<N, K> {
+    /// Empty storage. Fails to compile unless `N` is a power of two greater than 1.
+    pub const fn new() -> Self {
+        Self {
+            items: FnvIndexMap::new(),
+        }
+    }
+}
+
+impl<const N: usize, const K: usize> Default for Fixed<N, K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `key` as the map holds it, unless it's longer than any it holds.
+fn inline<const K: usize>(key: &[u8]) -> Option<heapless::Vec<u8, K>> {
+    heapless::Vec::from_slice(key).ok()
+}
+
+impl<const N: usize, const K: usize> Storage for Fixed<N, K> {
+    /// Items passed, in the order they're held.
+    type Cursor = usize;
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.items.get(&inline::<K>(key)?).map(f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.items.get_mut(&inline::<K>(key)?).map(f)
+    }
+
+    /// # Panics
+    ///
+    /// If `key` is longer than `K`, or the storage is full and `key` isn't in it. The handler
+    /// stores through [`try_insert`](Storage::try_insert), which refuses those instead.
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        self.try_insert(key, entry)
+            .unwrap_or_else(|_| panic!("no room in the storage for the item"))
+    }
+
+    fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+        let Some(key) = inline(key) else {
+            return Err(entry);
+        };
+        self.items.insert(key, entry).map_err(|(_, entry)| entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.items.remove(&inline::<K>(key)?)
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn clear(&mut self) {
+        self.items.clear()
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        Some(N)
+    }
+
+    fn scan_from<B>(
+        &self,
+        passed: &mut usize,
+        mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        for (key, entry) in self.items.iter().skip(*passed) {
+            f(key, entry)?;
+            *passed += 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
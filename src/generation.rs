@@ -0,0 +1,69 @@
+//! Flushing every item without visiting them: a flush leaves a mark among CAS values, and items
+//! written before it, whose CAS values are below it, are gone from then on.
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::next_cas;
+
+/// What the last flush did away with. Share it between handlers sharing storage, like
+/// [`EventBus`](crate::EventBus), so that a flush by one is seen by all.
+///
+/// Items flushed are left in storage, to be removed as they're come across, by a lookup, a
+/// store or [`tick`](crate::CommandHandler::tick). Meanwhile they're no longer served or
+/// counted in `curr_items`.
+#[derive(Debug, Clone, Default)]
+pub struct Generation(Arc<Inner>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The CAS value items need to be served.
+    #[cfg(target_has_atomic = "64")]
+    flushed_before: core::sync::atomic::AtomicU64,
+    #[cfg(not(target_has_atomic = "64"))]
+    flushed_before: spin::Mutex<u64>,
+    /// Items flushed but still in storage.
+    left: AtomicUsize,
+}
+
+impl Generation {
+    /// Items with a CAS value below this are gone.
+    pub(crate) fn flushed_before(&self) -> u64 {
+        #[cfg(target_has_atomic = "64")]
+        return self.0.flushed_before.load(Ordering::Relaxed);
+        #[cfg(not(target_has_atomic = "64"))]
+        *self.0.flushed_before.lock()
+    }
+
+    /// Does away with every item there is, `items` of them.
+    pub(crate) fn flush(&self, items: usize) {
+        let mark = next_cas();
+        #[cfg(target_has_atomic = "64")]
+        self.0.flushed_before.store(mark, Ordering::Relaxed);
+        #[cfg(not(target_has_atomic = "64"))]
+        {
+            *self.0.flushed_before.lock() = mark;
+        }
+        self.0.left.store(items, Ordering::Relaxed);
+    }
+
+    /// How many items flushed are still in storage.
+    pub(crate) fn left(&self) -> usize {
+        self.0.left.load(Ordering::Relaxed)
+    }
+
+    /// Counts the flushed items removed from storage with the rest.
+    pub(crate) fn cleared(&self) {
+        self.0.left.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts a flushed item removed from storage.
+    pub(crate) fn removed(&self) {
+        let _ = self
+            .0
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            });
+    }
+}
@@ -0,0 +1,279 @@
+//! A log of every change to the items, replayed on startup to get back what a crash lost.
+//!
+//! Each record is its payload's length (`u32`), the payload's CRC-32 (`u32`), then the payload:
+//! a tag byte and the change. An item stored or changed in any way is logged whole, as tag 1,
+//! key length (`u16`), key, flags (`u32`), expiry in clock seconds (`u32`), CAS value (`u64`),
+//! whether it's stale (`u8`) and the rest its value. A removed item is tag 2 and its key, and
+//! [`flush_all`](CommandHandler::flush_all) is tag 3 alone. All numbers are little-endian.
+
+use std::boxed::Box;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::vec::Vec;
+
+use log::*;
+
+use crate::clock::Clock;
+use crate::snapshot::crc32;
+use crate::storage::Storage;
+use crate::{bump_cas, CommandHandler, Entry};
+
+const SET: u8 = 1;
+const DELETE: u8 = 2;
+const CLEAR: u8 = 3;
+
+/// A change to log.
+pub(crate) enum Record<'a> {
+    Set(&'a [u8], &'a Entry),
+    Delete(&'a [u8]),
+    Clear,
+}
+
+struct Inner {
+    out: Box<dyn Write + Send>,
+    /// Records not yet handed to `out`.
+    pending: Vec<u8>,
+}
+
+/// Where [`Settings::journal`](crate::Settings::journal) sends records of changes. Clones
+/// share the same log, so handlers sharing storage should share one too.
+///
+/// Records are held in memory until [`CommandHandler::flush_log`], so what's lost in a crash is
+/// whatever changed since it was last called. With storage shared between threads, the records
+/// of simultaneous changes to the same item may be logged in the other order.
+#[derive(Clone)]
+pub struct Journal(Arc<Mutex<Inner>>);
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Journal")
+    }
+}
+
+impl Journal {
+    /// Logs to `out`, after whatever it holds already. Typically a file opened for appending.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            out: Box::new(out),
+            pending: Vec::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.0.lock().expect("journal poisoned")
+    }
+
+    pub(crate) fn append(&self, record: Record<'_>) {
+        let mut payload = Vec::new();
+        match record {
+            Record::Set(key, entry) => {
+                payload.push(SET);
+                let key_len = u16::try_from(key.len()).expect("keys are short");
+                payload.extend(key_len.to_le_bytes());
+                payload.extend(key);
+                payload.extend(entry.flags.to_le_bytes());
+                payload.extend(entry.expires_at.to_le_bytes());
+                payload.extend(entry.cas.to_le_bytes());
+                payload.push(entry.stale.into());
+                payload.extend(&entry.value.contiguous()[..]);
+            }
+            Record::Delete(key) => {
+                payload.push(DELETE);
+                payload.extend(key);
+            }
+            Record::Clear => payload.push(CLEAR),
+        }
+        let len = u32::try_from(payload.len()).expect("items are smaller than 4 GB");
+        let mut inner = self.lock();
+        inner.pending.extend(len.to_le_bytes());
+        inner.pending.extend(crc32(&payload).to_le_bytes());
+        inner.pending.extend(payload);
+    }
+
+    fn flush(inner: &mut Inner) -> io::Result<()> {
+        let Inner { out, pending } = inner;
+        out.write_all(pending)?;
+        pending.clear();
+        out.flush()
+    }
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Writes out the records of changes made since the last call. Does nothing without
+    /// [`Settings::journal`](crate::Settings::journal). Meant to be called from the event loop,
+    /// as often as losing that much in a crash is acceptable.
+    pub fn flush_log(&mut self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => Journal::flush(&mut journal.lock()),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies the records read from `r`, a log written through
+    /// [`Settings::journal`](crate::Settings::journal), on top of the items there are, which
+    /// would come from the snapshot the log was started after. Returns how many were applied.
+    ///
+    /// A record cut short or damaged ends the replay, with everything before it applied: it's
+    /// taken for the one being written when the log stopped. The changes replayed aren't
+    /// logged again.
+    pub fn replay_log(&mut self, mut r: impl Read) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let journal = self.journal.take();
+        let mut input = &bytes[..];
+        let mut applied = 0;
+        while !input.is_empty() {
+            let Some(payload) = next_record(&mut input) else {
+                warn!(
+                    "Log ends in {} bytes that aren't a whole record, after {} applied",
+                    input.len(),
+                    applied
+                );
+                break;
+            };
+            if !self.apply(payload) {
+                warn!(
+                    "Stopping at an unreadable log record, after {} applied",
+                    applied
+                );
+                break;
+            }
+            applied += 1;
+        }
+        self.journal = journal;
+        Ok(applied)
+    }
+
+    /// Starts the log over: writes out the records so far, saves a snapshot to `snapshot`,
+    /// then sends further records to `log` instead, which would be a new file. Once the
+    /// snapshot is safely stored, the old log can go, and a restart loads the snapshot and
+    /// replays the new log. Changes wait meanwhile, so that each lands in one or the other.
+    ///
+    /// If anything fails, the old log is kept on. Without
+    /// [`Settings::journal`](crate::Settings::journal), only saves the snapshot.
+    pub fn compact_log(
+        &mut self,
+        snapshot: impl Write,
+        log: impl Write + Send + 'static,
+    ) -> io::Result<()> {
+        let Some(journal) = self.journal.clone() else {
+            return self.save_snapshot(snapshot);
+        };
+        let mut inner = journal.lock();
+        Journal::flush(&mut inner)?;
+        self.save_snapshot(snapshot)?;
+        inner.out = Box::new(log);
+        Ok(())
+    }
+
+    /// Logs what's become of `key`: stored, or gone.
+    pub(crate) fn log_item(&mut self, key: &[u8]) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        // Copied out, so that storage isn't locked while the journal is. Tombstones aren't
+        // logged, so they don't outlive a crash.
+        let entry = self
+            .data
+            .read(key, |entry| {
+                (!entry.tombstone).then(|| Entry {
+                    flags: entry.flags,
+                    value: entry.value.clone(),
+                    expires_at: entry.expires_at,
+                    stale: entry.stale,
+                    cas: entry.cas,
+                    ..Entry::new(Vec::new())
+                })
+            })
+            .flatten();
+        match entry {
+            Some(entry) => journal.append(Record::Set(key, &entry)),
+            None => journal.append(Record::Delete(key)),
+        }
+    }
+
+    /// Applies a record's payload. Returns whether it made sense.
+    fn apply(&mut self, payload: &[u8]) -> bool {
+        let Some((&tag, rest)) = payload.split_first() else {
+            return false;
+        };
+        match tag {
+            SET => {
+                let Some((key, entry)) = parse_set(rest) else {
+                    return false;
+                };
+                bump_cas(entry.cas);
+                if entry.is_expired(self.clock.now()) {
+                    // Gone by now, which may be while the log was stopped.
+                    if let Some(old) = self.data.remove(key) {
+                        self.forget(key, &old);
+                    }
+                    return true;
+                }
+                let (expires_at, len) = (entry.expires_at, entry.value.len());
+                // Storage with less room than before drops what it can't hold.
+                let Ok(old) = self.data.try_insert(key, entry) else {
+                    return true;
+                };
+                self.stats.add_item(key, len);
+                if let Some(old) = old {
+                    self.forget(key, &old);
+                }
+                if let Some(wheel) = self.wheel.as_mut().filter(|_| expires_at != 0) {
+                    wheel.insert(key, expires_at);
+                }
+                self.stats.count_items(self.data.len());
+            }
+            DELETE => {
+                if let Some(old) = self.data.remove(rest) {
+                    self.forget(rest, &old);
+                }
+            }
+            // Not a flush, whose mark would be past the CAS values of the items that follow.
+            CLEAR if rest.is_empty() => self.clear_items(),
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Takes the next record off `input` and returns its payload, unless it's incomplete or its
+/// checksum is off.
+fn next_record<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (len, rest) = input.split_first_chunk::<4>()?;
+    let (crc, rest) = rest.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (payload, rest) = rest.split_at(len);
+    if crc32(payload) != u32::from_le_bytes(*crc) {
+        return None;
+    }
+    *input = rest;
+    Some(payload)
+}
+
+fn parse_set(payload: &[u8]) -> Option<(&[u8], Entry)> {
+    let (key_len, rest) = payload.split_first_chunk::<2>()?;
+    let key_len = u16::from_le_bytes(*key_len).into();
+    if rest.len() < key_len {
+        return None;
+    }
+    let (key, rest) = rest.split_at(key_len);
+    let (flags, rest) = rest.split_first_chunk::<4>()?;
+    let (expires_at, rest) = rest.split_first_chunk::<4>()?;
+    let (cas, rest) = rest.split_first_chunk::<8>()?;
+    let (&stale, value) = rest.split_first()?;
+    Some((
+        key,
+        Entry {
+            flags: u32::from_le_bytes(*flags),
+            expires_at: u32::from_le_bytes(*expires_at),
+            cas: u64::from_le_bytes(*cas),
+            stale: stale != 0,
+            ..Entry::new(value.to_vec())
+        },
+    ))
+}
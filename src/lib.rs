@@ -0,0 +1,3746 @@
+//! A memcached server that keeps no buffers of its own: requests are parsed straight out of
+//! whatever the [`Socket`] has received, and responses are written straight into its transmit
+//! space.
+//!
+//! Without the `std` feature the crate is `no_std` and only needs `alloc`. Items are then kept
+//! in a [`Map`] keyed by a `SeededState` rather than a random one, and handlers need a
+//! [`Clock`] of their own.
+
+// The example serves a `HashMap` and takes the system's time, so it needs `std`.
+#![cfg_attr(
+    feature = "std",
+    doc = r#"
+```
+use incr_memcached::{CommandHandler, Entry, Socket};
+use std::collections::HashMap;
+
+struct Loopback {
+    request: &'static [u8],
+    sent: Vec<u8>,
+}
+
+impl Socket for Loopback {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.request.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.request);
+        self.request = &self.request[taken..];
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = [0; 64];
+        let (len, r) = f(&mut buf);
+        self.sent.extend(&buf[..len]);
+        Some(r)
+    }
+}
+
+let mut data = HashMap::new();
+data.insert(b"greeting"[..].into(), Entry::new(b"hello".to_vec()));
+let mut handler = CommandHandler::new(data);
+let mut socket = Loopback {
+    request: b"get greeting\r\n",
+    sent: Vec::new(),
+};
+while handler.poll(&mut socket).progress {}
+assert_eq!(socket.sent, b"VALUE greeting 0 5\r\nhello\r\nEND\r\n");
+```
+"#
+)]
+#![cfg_attr(not(feature = "std"), no_std)]
+// Every unsafe block says why it's sound, as Miri can't check what the tests don't reach.
+#![warn(clippy::undocumented_unsafe_blocks)]
+
+extern crate alloc;
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::*;
+
+mod arena;
+#[cfg(feature = "binary")]
+mod auth;
+mod base64;
+#[cfg(feature = "binary")]
+mod binary;
+mod clock;
+mod compress;
+mod direct;
+mod extension;
+mod fixed;
+mod generation;
+#[cfg(feature = "journal")]
+mod journal;
+mod meta;
+#[cfg(feature = "std")]
+mod shard;
+mod slab;
+#[cfg(feature = "std")]
+mod snapshot;
+mod stats;
+mod storage;
+#[cfg(feature = "udp")]
+mod udp;
+mod value;
+mod watch;
+mod wheel;
+
+pub use arena::Arena;
+#[cfg(feature = "binary")]
+pub use auth::Authenticator;
+#[cfg(feature = "binary")]
+use binary::{Response, StatStream};
+#[cfg(not(feature = "std"))]
+pub use clock::NoClock;
+#[cfg(feature = "std")]
+pub use clock::SystemClock;
+pub use clock::{Clock, DefaultClock};
+pub use compress::Compressor;
+#[cfg(feature = "lz4")]
+pub use compress::Lz4;
+pub use extension::{Args, CommandExtension, ResponseSink};
+pub use fixed::Fixed;
+pub use generation::Generation;
+#[cfg(feature = "journal")]
+pub use journal::Journal;
+use meta::{MetaCommand, MetaHeader, MetaReturn};
+#[cfg(feature = "std")]
+pub use shard::Sharded;
+use slab::Bytes;
+pub use slab::{ClassStats, ReassignError, SlabSettings, SlabStats, Slabs};
+#[cfg(feature = "std")]
+pub use snapshot::SnapshotError;
+pub use stats::MemoryStats;
+pub use stats::PrefixCounters;
+use stats::{ConnectionStats, ItemTally, PrefixStats, Report, Stats};
+pub use storage::Storage;
+#[cfg(feature = "udp")]
+pub use udp::UdpFraming;
+use value::{Chunked, Chunking, Incoming};
+pub use value::{ValueBackend, ValueSink, ValueSource};
+pub use watch::EventBus;
+use watch::{EventKind, Topics, Watcher};
+use wheel::TimerWheel;
+
+/// The map items are kept in, unless a handler is given another [`Storage`]. Hashed with
+/// SipHash, unless given another hasher `H`. It's the map std's is built on, which unlike std's
+/// lets `lru_crawler metadump` pick up where it left off.
+#[cfg(feature = "std")]
+pub type Map<K, V, H = std::collections::hash_map::RandomState> = hashbrown::HashMap<K, V, H>;
+/// The map items are kept in, unless a handler is given another [`Storage`]. Hashed with
+/// SipHash keyed by a [`SeededState`], unless given another hasher `H`.
+#[cfg(not(feature = "std"))]
+pub type Map<K, V, H = SeededState> = hashbrown::HashMap<K, V, H>;
+
+/// Hashes a [`Map`]'s keys with SipHash, keyed by a seed, where there's no `std` to seed it
+/// at random. The default seed is fixed, so where clients may choose keys to collide, seed it
+/// from the device's RNG or unique ID instead.
+///
+/// ```
+/// use incr_memcached::{Map, SeededState};
+///
+/// let map: Map<u32, u32> = Map::with_hasher(SeededState::new(0x2545f491, 0x9e3779b9));
+/// # drop(map);
+/// ```
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeededState {
+    keys: (u64, u64),
+}
+
+#[cfg(not(feature = "std"))]
+impl SeededState {
+    pub const fn new(k0: u64, k1: u64) -> Self {
+        Self { keys: (k0, k1) }
+    }
+}
+
+// Core's SipHasher is deprecated only in favour of std's, which is out of reach here.
+#[cfg(not(feature = "std"))]
+#[allow(deprecated)]
+impl core::hash::BuildHasher for SeededState {
+    type Hasher = core::hash::SipHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        core::hash::SipHasher::new_with_keys(self.keys.0, self.keys.1)
+    }
+}
+
+/// Longest command name.
+pub const MAX_COMMAND_LEN: usize = 16;
+/// Longest key, like upstream's `KEY_MAX_LENGTH`.
+pub const MAX_KEY_LEN: usize = 250;
+pub const MAX_FLAGS_DIGITS_LEN: usize = 10;
+pub const MAX_SIZE_DIGITS_LEN: usize = 20;
+/// Longest argument list after the key of commands other than storage ones.
+pub const MAX_ARGS_LEN: usize = 32;
+/// Longest reply a [`CommandExtension`] may write.
+pub const MAX_EXTENSION_REPLY_LEN: usize = 4096;
+/// `<flags> <exptime> <bytes> [noreply]` of a classic storage command.
+const MAX_STORE_ARGS_LEN: usize = 64;
+const MAX_STAT_LINE_LEN: usize = MAX_KEY_LEN + 128;
+const MAX_META_ARGS_LEN: usize = base64::encoded_len(MAX_KEY_LEN) + 64;
+const MAX_META_HEADER_LEN: usize = base64::encoded_len(MAX_KEY_LEN) + 128;
+// A get's `VALUE` line is built in a meta header's buffer too.
+const _: () = assert!(
+    b"VALUE ".len() + MAX_KEY_LEN + 1 + MAX_FLAGS_DIGITS_LEN + 1 + MAX_SIZE_DIGITS_LEN + 2
+        <= MAX_META_HEADER_LEN
+);
+const MAX_OPAQUE_LEN: usize = 32;
+/// Longest exptime taken as seconds to live. Anything above is an absolute Unix time.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+// Keys are percent-encoded in `watch` and `lru_crawler metadump` output, tripling them at worst.
+const MAX_WATCH_LINE_LEN: usize = 3 * MAX_KEY_LEN + 128;
+const MAX_DUMP_LINE_LEN: usize = 3 * MAX_KEY_LEN + 128;
+
+/// The class every item is in, unless the storage keeps values in slabs. See
+/// [`Storage::class_of`].
+const ITEM_CLASS: u32 = 1;
+
+/// Items evicted per `poll` when over the memory limit, so shrinking it doesn't stall.
+const EVICTIONS_PER_POLL: usize = 16;
+/// Most items evicted per `poll` to make room for a large one, which may take the place of
+/// many small ones.
+const MAX_EVICTIONS_PER_POLL: usize = 1024;
+/// Items `stats sizes` counts per `poll`, so that a large map doesn't stall the others.
+const ITEMS_SIZED_PER_POLL: usize = 1024;
+
+/// Bookkeeping cost of an item on top of its key and value, as reported by `stats sizes`.
+pub const ITEM_OVERHEAD: usize = core::mem::size_of::<Entry>();
+
+const ERROR_RESPONSE: &[u8] = b"ERROR\r\n";
+
+/// Where the connection is in a request or its response. Every transition moves it, and idle
+/// connections keep one each, so buffers bigger than a few words are boxed.
+#[derive(Debug)]
+enum State<const KEY_LEN: usize> {
+    /// Waiting for the first byte, which picks the protocol. See [`Protocol::Negotiating`].
+    Detecting,
+    ReadingCommand(heapless::Vec<u8, MAX_COMMAND_LEN>),
+    ReadingKey {
+        cmd: CommandWithKey,
+        key: Box<heapless::Vec<u8, KEY_LEN>>,
+        /// Bytes past the handler's key length were dropped, so the key gets rejected once it ends.
+        too_long: bool,
+    },
+    ReadingArgs {
+        cmd: CommandWithArgs,
+        args: heapless::Vec<u8, MAX_ARGS_LEN>,
+    },
+    /// The rest of a classic storage command's line, after the key.
+    ReadingStoreArgs {
+        mode: StoreMode,
+        /// The key, or why it was rejected: the data block gets skipped either way.
+        key: Result<Box<heapless::Vec<u8, KEY_LEN>>, Error>,
+        args: Box<heapless::Vec<u8, MAX_STORE_ARGS_LEN>>,
+    },
+    /// The rest of an `incr`, `decr`, `touch` or `delete` line, after the key.
+    ReadingKeyArgs {
+        cmd: CommandWithKey,
+        key: Box<heapless::Vec<u8, KEY_LEN>>,
+        args: heapless::Vec<u8, MAX_ARGS_LEN>,
+    },
+    ReadingMetaArgs {
+        cmd: MetaCommand,
+        args: Box<heapless::Vec<u8, MAX_META_ARGS_LEN>>,
+    },
+    ReadingValue {
+        /// What to do with the value once read, or the error to report after discarding it.
+        store: Result<Box<Store<KEY_LEN>>, Error>,
+        value: Incoming,
+        /// Bytes of the data block still to come.
+        remaining: usize,
+    },
+    /// Reading the two bytes after a data block, which had better be `\r\n`.
+    ReadingValueEnd {
+        store: Result<Box<Store<KEY_LEN>>, Error>,
+        value: Incoming,
+        /// The first of the two bytes, once it's in.
+        terminator: Option<u8>,
+    },
+    SendingError {
+        remaining: &'static [u8],
+        /// For the [`ErrorObserver`], once it's sent.
+        error: Error,
+    },
+    /// Skipping the rest of a bad request line, to answer with `error` once it's over. Like
+    /// upstream, a line gets one reply, after all of it is in.
+    FlushLine {
+        error: Error,
+    },
+    SendingGetData {
+        value: Bytes,
+        sent: usize,
+        trailer: &'static [u8],
+    },
+    SendingEnd {
+        remaining: &'static [u8],
+    },
+    SendingReply {
+        remaining: &'static [u8],
+    },
+    /// A response line, such as a get's `VALUE` line or a meta command's, built up front.
+    SendingHeader {
+        header: Box<MetaHeader>,
+        sent: usize,
+        /// The value that follows the header, if any, and what goes after it.
+        value: Option<(Bytes, &'static [u8])>,
+    },
+    SendingStats {
+        report: Box<Report>,
+        line: Box<heapless::Vec<u8, MAX_STAT_LINE_LEN>>,
+        sent: usize,
+        next_line: usize,
+    },
+    /// Streaming events after `watch`. The connection takes no further commands.
+    Watching {
+        watcher: Watcher,
+        line: Box<heapless::Vec<u8, MAX_WATCH_LINE_LEN>>,
+        sent: usize,
+    },
+    #[cfg(feature = "binary")]
+    ReadingBinaryHeader {
+        header: [u8; binary::HEADER_LEN],
+        len: usize,
+    },
+    #[cfg(feature = "binary")]
+    ReadingBinaryBody(Box<binary::Request<KEY_LEN>>),
+    #[cfg(feature = "binary")]
+    SendingBinary(Box<Response<KEY_LEN>>),
+    #[cfg(feature = "binary")]
+    SendingBinaryStats(Box<StatStream>),
+    /// Streaming `lru_crawler metadump` lines, walking the map from the handler's cursor on.
+    Dumping {
+        line: Box<heapless::Vec<u8, MAX_DUMP_LINE_LEN>>,
+        sent: usize,
+        /// The classes to dump, bit `n` for class `n`. Classes past 63 are only dumped with
+        /// the rest by `all`.
+        classes: u64,
+        /// Whether the cursor was set to the start, which waits for responses ahead to be sent.
+        started: bool,
+    },
+    /// Counting items by size for `stats sizes`, a poll's worth at a time, walking the map
+    /// like [`Dumping`](Self::Dumping). Then the counts are sent.
+    Sizing {
+        sizes: BTreeMap<usize, u64>,
+        started: bool,
+    },
+    /// Counting the items served for `stats items`, walking the map like
+    /// [`Sizing`](Self::Sizing).
+    Itemizing {
+        tally: ItemTally,
+        started: bool,
+    },
+}
+
+const _: () = assert!(core::mem::size_of::<State<MAX_KEY_LEN>>() <= 64);
+
+impl<const KEY_LEN: usize> State<KEY_LEN> {
+    fn wants_to_send(&self) -> bool {
+        #[cfg(feature = "binary")]
+        if let Self::SendingBinary(_) | Self::SendingBinaryStats(_) = self {
+            return true;
+        }
+        matches!(
+            self,
+            Self::SendingError { .. }
+                | Self::SendingGetData { .. }
+                | Self::SendingEnd { .. }
+                | Self::SendingReply { .. }
+                | Self::SendingHeader { .. }
+                | Self::SendingStats { .. }
+                | Self::Dumping { .. }
+                | Self::Sizing { .. }
+                | Self::Itemizing { .. }
+        ) || matches!(self, Self::Watching { watcher, line, sent } if *sent < line.len() || watcher.pending())
+    }
+
+    /// Whether the state walks the map, so entries mustn't be removed under it. Responses hold on
+    /// to the values they send, so they don't mind.
+    fn walks_map(&self) -> bool {
+        matches!(
+            self,
+            Self::Dumping { .. } | Self::Sizing { .. } | Self::Itemizing { .. }
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Detecting => "idle",
+            Self::ReadingCommand(cmd) if cmd.is_empty() => "idle",
+            Self::Watching { .. } => "watching",
+            _ if self.wants_to_send() => "sending",
+            _ => "reading",
+        }
+    }
+
+    /// How many more bytes the response being sent has, if that's known up front.
+    #[cfg(feature = "udp")]
+    fn remaining_len(&self, stats: &Stats) -> Option<usize> {
+        Some(match self {
+            Self::SendingError { remaining, .. }
+            | Self::SendingEnd { remaining }
+            | Self::SendingReply { remaining } => remaining.len(),
+            Self::SendingGetData {
+                value,
+                sent,
+                trailer,
+            } => value.len() - sent + trailer.len(),
+            Self::SendingHeader {
+                header,
+                sent,
+                value,
+            } => {
+                let body = value.as_ref();
+                header.len() - sent + body.map_or(0, |(value, trailer)| value.len() + trailer.len())
+            }
+            Self::SendingStats {
+                report,
+                line,
+                sent,
+                next_line,
+            } => {
+                let mut len = line.len() - sent + b"END\r\n".len();
+                let mut scratch = heapless::Vec::<u8, MAX_STAT_LINE_LEN>::new();
+                for index in *next_line.. {
+                    scratch.clear();
+                    if !report.write_line(stats, index, &mut scratch) {
+                        break;
+                    }
+                    len += scratch.len();
+                }
+                len
+            }
+            #[cfg(feature = "binary")]
+            Self::SendingBinary(response) => response.remaining(),
+            _ => return None,
+        })
+    }
+
+    /// Where a connection starts.
+    fn initial(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Negotiating => State::Detecting,
+            Protocol::AsciiOnly => State::default(),
+            #[cfg(feature = "binary")]
+            Protocol::BinaryOnly => State::default(),
+        }
+    }
+
+    fn stats(report: Report) -> Self {
+        Self::SendingStats {
+            report: Box::new(report),
+            line: Default::default(),
+            sent: 0,
+            next_line: 0,
+        }
+    }
+
+    fn reading_value(store: Result<Store<KEY_LEN>, Error>, value: Incoming, len: usize) -> Self {
+        let store = store.map(Box::new);
+        if len == 0 {
+            Self::ReadingValueEnd {
+                store,
+                value,
+                terminator: None,
+            }
+        } else {
+            Self::ReadingValue {
+                store,
+                value,
+                remaining: len,
+            }
+        }
+    }
+
+    /// Reading the next key of a `get` line.
+    fn reading_keys() -> Self {
+        Self::ReadingKey {
+            cmd: CommandWithKey::Get,
+            key: Default::default(),
+            too_long: false,
+        }
+    }
+
+    fn error(error: Error) -> Self {
+        Self::SendingError {
+            remaining: error.response(),
+            error,
+        }
+    }
+
+    /// Answers with `error` once the request line is over, which it is if `c` ended it.
+    fn error_after_line(error: Error, c: u8) -> Self {
+        if c == b'\n' {
+            Self::error(error)
+        } else {
+            Self::FlushLine { error }
+        }
+    }
+}
+
+impl<const KEY_LEN: usize> Default for State<KEY_LEN> {
+    fn default() -> Self {
+        Self::ReadingCommand(Default::default())
+    }
+}
+
+/// Why a request was refused. Each one maps to the line sent back in its place, which is also
+/// what it displays as, less the `\r\n`. The detail some of them carry isn't sent, as upstream
+/// doesn't send it either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    UnknownCommand,
+    CommandTooLong,
+    KeyTooLong {
+        /// Longest key the handler takes.
+        limit: usize,
+    },
+    /// The request line, or the part of it that's buffered, is too long.
+    ArgsTooLong {
+        limit: usize,
+    },
+    MissingArgument,
+    BadArgument,
+    InvalidFlag {
+        /// The first character of the flag.
+        flag: u8,
+    },
+    HeaderTooLong,
+    BadDataChunk,
+    NonNumericValue,
+    BadToken,
+    Unauthenticated,
+    BadCommandLine,
+    InvalidDelta,
+    InvalidExptime,
+    TooLarge {
+        /// Size of the item, counting its key and overhead.
+        size: usize,
+        /// [`Settings::item_size_max`].
+        limit: usize,
+    },
+    TooManyKeys {
+        limit: usize,
+    },
+    /// A new item would be one too many, and there's no evicting to make room.
+    OutOfMemory,
+    /// A tombstone turned the item away, with [`Settings::tombstones_block_set`].
+    NotStored,
+}
+
+impl Error {
+    /// The reply line: `ERROR` for commands that aren't understood at all, `CLIENT_ERROR` for
+    /// malformed ones and `SERVER_ERROR` for ones the server can't carry out.
+    pub fn response(&self) -> &'static [u8] {
+        match self {
+            Self::UnknownCommand | Self::CommandTooLong | Self::MissingArgument => ERROR_RESPONSE,
+            Self::KeyTooLong { .. } | Self::BadArgument | Self::BadCommandLine => {
+                b"CLIENT_ERROR bad command line format\r\n"
+            }
+            Self::ArgsTooLong { .. } => b"CLIENT_ERROR line too long\r\n",
+            Self::TooManyKeys { .. } => b"CLIENT_ERROR too many keys\r\n",
+            Self::InvalidFlag { .. } => b"CLIENT_ERROR invalid flag\r\n",
+            Self::BadDataChunk => b"CLIENT_ERROR bad data chunk\r\n",
+            Self::InvalidExptime => b"CLIENT_ERROR invalid exptime argument\r\n",
+            Self::NonNumericValue => {
+                b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+            }
+            Self::InvalidDelta => b"CLIENT_ERROR invalid numeric delta argument\r\n",
+            Self::BadToken => b"CLIENT_ERROR bad token\r\n",
+            Self::Unauthenticated => b"CLIENT_ERROR unauthenticated\r\n",
+            Self::HeaderTooLong => b"SERVER_ERROR output line too long\r\n",
+            Self::TooLarge { .. } => b"SERVER_ERROR object too large for cache\r\n",
+            Self::OutOfMemory => b"SERVER_ERROR out of memory storing object\r\n",
+            Self::NotStored => b"NOT_STORED\r\n",
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let line = self
+            .response()
+            .strip_suffix(b"\r\n")
+            .expect("replies end a line");
+        f.write_str(core::str::from_utf8(line).expect("replies are ASCII"))
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Told about every request a handler refuses, once the error has been sent. Supplied by the
+/// application, to log or count them.
+pub trait ErrorObserver: Send + Sync {
+    fn observe(&self, error: &Error);
+}
+
+impl<F: Fn(&Error) + Send + Sync> ErrorObserver for F {
+    fn observe(&self, error: &Error) {
+        self(error)
+    }
+}
+
+impl core::fmt::Debug for dyn ErrorObserver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("ErrorObserver")
+    }
+}
+
+/// Asked for the item at a key a retrieval command is about to miss, to read it through from
+/// somewhere else, such as a sensor table or flash. Supplied by the application.
+///
+/// It's called in the middle of serving the request, so it had better be quick and bounded:
+/// anything slow, such as waiting on the network, belongs somewhere the handler isn't waiting
+/// on it, storing the item once it's ready.
+pub trait MissHandler: Send + Sync {
+    /// The item to store at `key`, and answer with, or `None` to miss after all. `now` is the
+    /// time by the handler's [`Clock`], for an entry [`with_expiry`](Entry::with_expiry) some
+    /// seconds after it.
+    fn miss(&self, key: &[u8], now: u32) -> Option<Entry>;
+}
+
+impl<F: Fn(&[u8], u32) -> Option<Entry> + Send + Sync> MissHandler for F {
+    fn miss(&self, key: &[u8], now: u32) -> Option<Entry> {
+        self(key, now)
+    }
+}
+
+impl core::fmt::Debug for dyn MissHandler {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("MissHandler")
+    }
+}
+
+/// Why an item was removed, as a [`RemovalObserver`] is told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// To make room for others.
+    Evicted,
+    /// Found past its expiry, by a command or [`CommandHandler::tick`].
+    Expired,
+    /// By `delete`, or from code.
+    Deleted,
+    /// Done away with by a flush, when it's removed: at once, or as it's come across if the
+    /// flush left it to be reclaimed later.
+    Flushed,
+    /// By a store to its key.
+    Replaced,
+}
+
+/// Told about every item a handler removes, there and then, such as to pass invalidations
+/// on to another cache. Supplied by the application.
+///
+/// It mustn't change the storage. It may be told in the middle of a walk over the items, with
+/// storage behind a `RefCell` or lock borrowed, and elsewhere debug builds check that the
+/// number of items didn't change. Items storage removes by itself, such as [`Slabs`] evicting
+/// for room in a class, and items removed while replaying a journal, aren't told about.
+pub trait RemovalObserver: Send + Sync {
+    fn removed(&self, key: &[u8], entry: &Entry, cause: RemovalCause);
+}
+
+impl<F: Fn(&[u8], &Entry, RemovalCause) + Send + Sync> RemovalObserver for F {
+    fn removed(&self, key: &[u8], entry: &Entry, cause: RemovalCause) {
+        self(key, entry, cause)
+    }
+}
+
+impl core::fmt::Debug for dyn RemovalObserver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("RemovalObserver")
+    }
+}
+
+enum Command {
+    WithKey(CommandWithKey),
+    WithArgs(CommandWithArgs),
+    Meta(MetaCommand),
+}
+
+impl Command {
+    fn parse(name: &[u8]) -> Option<Self> {
+        Some(match name {
+            b"get" => Self::WithKey(CommandWithKey::Get),
+            b"gets" => Self::WithKey(CommandWithKey::Gets),
+            b"set" => Self::WithKey(CommandWithKey::Store(StoreMode::Set)),
+            b"add" => Self::WithKey(CommandWithKey::Store(StoreMode::Add)),
+            b"replace" => Self::WithKey(CommandWithKey::Store(StoreMode::Replace)),
+            b"append" => Self::WithKey(CommandWithKey::Store(StoreMode::Append)),
+            b"prepend" => Self::WithKey(CommandWithKey::Store(StoreMode::Prepend)),
+            b"incr" => Self::WithKey(CommandWithKey::Incr),
+            b"decr" => Self::WithKey(CommandWithKey::Decr),
+            b"touch" => Self::WithKey(CommandWithKey::Touch),
+            b"delete" => Self::WithKey(CommandWithKey::Delete),
+            b"gat" => Self::WithKey(CommandWithKey::Gat),
+            b"gats" => Self::WithKey(CommandWithKey::Gats),
+            b"mg" => Self::Meta(MetaCommand::Get),
+            b"ms" => Self::Meta(MetaCommand::Set),
+            b"md" => Self::Meta(MetaCommand::Delete),
+            b"ma" => Self::Meta(MetaCommand::Arithmetic),
+            b"me" => Self::Meta(MetaCommand::Debug),
+            b"mn" => Self::WithArgs(CommandWithArgs::MetaNoop),
+            b"stats" => Self::WithArgs(CommandWithArgs::Stats),
+            b"shutdown" => Self::WithArgs(CommandWithArgs::Shutdown),
+            b"watch" => Self::WithArgs(CommandWithArgs::Watch),
+            b"version" => Self::WithArgs(CommandWithArgs::Version),
+            b"cache_memlimit" => Self::WithArgs(CommandWithArgs::CacheMemlimit),
+            b"lru_crawler" => Self::WithArgs(CommandWithArgs::LruCrawler),
+            b"slabs" => Self::WithArgs(CommandWithArgs::Slabs),
+            b"quit" => Self::WithArgs(CommandWithArgs::Quit),
+            b"flush_all" => Self::WithArgs(CommandWithArgs::FlushAll),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CommandWithKey {
+    Get,
+    Store(StoreMode),
+    Incr,
+    Decr,
+    Touch,
+    Delete,
+    /// Its first token is the exptime, the keys follow as a `get`'s do.
+    Gat,
+    /// `get` and `gat` answering with CAS values too. Read as those are.
+    Gets,
+    Gats,
+}
+
+/// What a store finds at its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Found {
+    Nothing,
+    Item,
+    Tombstone,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum StoreMode {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+}
+
+impl StoreMode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Set => "set",
+            Self::Add => "add",
+            Self::Replace => "replace",
+            Self::Append => "append",
+            Self::Prepend => "prepend",
+        }
+    }
+}
+
+/// A storage command waiting for its data block.
+#[derive(Debug)]
+struct Store<const KEY_LEN: usize> {
+    mode: StoreMode,
+    key: heapless::Vec<u8, KEY_LEN>,
+    flags: u32,
+    /// Store the value already marked stale (the meta `I` flag).
+    stale: bool,
+    /// Seconds to live, 0 for forever. Negative stores an item that's expired at birth.
+    exptime: i64,
+    /// The classic `noreply`: the outcome isn't reported, though errors still are.
+    noreply: bool,
+    /// How a meta command wants to be answered, or `None` for `STORED`/`NOT_STORED`.
+    meta: Option<MetaReturn>,
+}
+
+/// A text request with all of it in: its line, and its data block if it has one.
+#[derive(Debug)]
+enum Request<'a, const KEY_LEN: usize> {
+    /// One key of a `get` line. `last` if the line ends after it.
+    Get {
+        key: Box<heapless::Vec<u8, KEY_LEN>>,
+        last: bool,
+    },
+    Store {
+        store: Box<Store<KEY_LEN>>,
+        value: Bytes,
+    },
+    Delta {
+        incr: bool,
+        key: &'a [u8],
+        args: &'a [u8],
+    },
+    Touch {
+        key: &'a [u8],
+        args: &'a [u8],
+    },
+    Delete {
+        key: &'a [u8],
+        args: &'a [u8],
+    },
+    WithArgs {
+        cmd: CommandWithArgs,
+        args: &'a [u8],
+    },
+    Meta {
+        cmd: MetaCommand,
+        args: &'a [u8],
+    },
+}
+
+fn parse_number<T: core::str::FromStr>(token: &[u8]) -> Option<T> {
+    core::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// Checks a key as the text protocols want it: 1 to [`MAX_KEY_LEN`] bytes, with no spaces or
+/// control characters.
+fn validate_key(key: &[u8]) -> Result<(), Error> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(Error::KeyTooLong { limit: MAX_KEY_LEN });
+    }
+    if key.is_empty() || key.iter().any(|&c| c < 0x21 || c == 0x7f) {
+        return Err(Error::BadCommandLine);
+    }
+    Ok(())
+}
+
+/// Parses the `<bytes>` token of a storage command: plain decimal digits, leading zeros
+/// allowed.
+fn parse_len(token: &[u8]) -> Result<usize, Error> {
+    if token.is_empty() || !token.iter().all(u8::is_ascii_digit) {
+        return Err(Error::BadCommandLine);
+    }
+    token
+        .iter()
+        .try_fold(0usize, |len, &c| {
+            len.checked_mul(10)?.checked_add(usize::from(c - b'0'))
+        })
+        .ok_or(Error::BadDataChunk)
+}
+
+/// Parses an exptime token into seconds to live, 0 for forever and negative for already expired.
+fn parse_exptime(token: &[u8]) -> Option<i64> {
+    parse_number(token)
+}
+
+/// When an item stored at `now` with `exptime` stops being served, 0 for never, or `None` if it's
+/// expired already.
+fn expires_at(exptime: i64, now: u32) -> Option<u32> {
+    match exptime {
+        0 => Some(0),
+        ..=-1 => None,
+        1..=MAX_RELATIVE_EXPTIME => Some(now.saturating_add(exptime as u32)),
+        absolute => {
+            let absolute = u32::try_from(absolute).unwrap_or(u32::MAX);
+            (absolute > now).then_some(absolute)
+        }
+    }
+}
+
+/// Whether an `lru_crawler metadump` of `classes`, bit `n` for class `n`, dumps an item of
+/// `class`. All of them set stands for `all`, which takes in classes past 63 as well.
+fn dumps_class(classes: u64, class: u32) -> bool {
+    classes == u64::MAX
+        || 1u64
+            .checked_shl(class)
+            .is_some_and(|bit| classes & bit != 0)
+}
+
+/// Writes `key` with unsafe bytes (anything but printable ASCII, and `%` itself) as `%XX`.
+fn write_url_encoded<const N: usize>(out: &mut heapless::Vec<u8, N>, key: &[u8]) {
+    for &c in key {
+        if c.is_ascii_graphic() && c != b'%' {
+            out.push(c).expect("formatting key");
+        } else {
+            write!(out, "%{:02X}", c).expect("formatting key");
+        }
+    }
+}
+
+/// Applies an increment or decrement to a stored decimal value.
+///
+/// Increments wrap around at 2^64, decrements stop at zero. Returns `None` if the value isn't a
+/// plain unsigned decimal number of at most 20 digits, the length of `u64::MAX`.
+fn apply_delta(value: &[u8], incr: bool, delta: u64) -> Option<u64> {
+    if value.is_empty() || value.len() > 20 || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let current: u64 = parse_number(value)?;
+    Some(if incr {
+        current.wrapping_add(delta)
+    } else {
+        current.saturating_sub(delta)
+    })
+}
+
+/// Parses what's left of a classic command's line after its arguments: nothing, or `noreply`
+/// alone.
+fn parse_noreply<'a>(mut tokens: impl Iterator<Item = &'a [u8]>) -> Result<bool, Error> {
+    match (tokens.next(), tokens.next()) {
+        (None, _) => Ok(false),
+        (Some(b"noreply"), None) => Ok(true),
+        _ => Err(Error::BadCommandLine),
+    }
+}
+
+/// Parses the delta of `incr`, `decr` or `ma`: a plain unsigned decimal that fits 64 bits.
+fn parse_delta(token: &[u8]) -> Result<u64, Error> {
+    if token.is_empty() || !token.iter().all(u8::is_ascii_digit) {
+        return Err(Error::InvalidDelta);
+    }
+    parse_number(token).ok_or(Error::InvalidDelta)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CommandWithArgs {
+    Stats,
+    MetaNoop,
+    Shutdown,
+    Watch,
+    CacheMemlimit,
+    LruCrawler,
+    Slabs,
+    Version,
+    Quit,
+    FlushAll,
+    /// The registered extension at this index.
+    Extension(usize),
+}
+
+/// How a client asked the server to stop, see [`CommandHandler::shutdown_requested`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Stop right away.
+    Immediate,
+    /// Stop accepting connections, flush pending responses, then stop.
+    Graceful,
+}
+
+/// Which wire protocol a connection speaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Protocol {
+    /// Decide on the first byte like upstream: 0x80 starts a binary request, anything else text.
+    #[default]
+    Negotiating,
+    AsciiOnly,
+    #[cfg(feature = "binary")]
+    BinaryOnly,
+}
+
+/// Tunables of a [`CommandHandler`].
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Separates the prefix from the rest of the key for `stats detail`.
+    pub detail_delimiter: u8,
+    /// How many distinct prefixes `stats detail` tracks before counting new ones together.
+    pub detail_max_prefixes: usize,
+    /// Count commands per key prefix from the start, as after `stats detail on`.
+    pub detail_enabled: bool,
+    /// Whether clients may use `shutdown`. Off by default, like upstream's `-A`.
+    pub enable_shutdown: bool,
+    pub protocol: Protocol,
+    /// Enables SASL PLAIN: binary connections must authenticate before anything else.
+    #[cfg(feature = "binary")]
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Turns away every text command until the connection has authenticated.
+    pub text_requires_auth: bool,
+    /// Also accept request lines ending in a bare `\n`, which is handy when typing into telnet.
+    /// Otherwise only `\r\n` ends a line.
+    pub lenient_line_endings: bool,
+    /// Skip request lines with nothing on them, as telnet sessions and some clients send.
+    /// Upstream answers them with `ERROR`.
+    pub ignore_empty_lines: bool,
+    /// Match command names regardless of case, for clients that send `GET`. Keys stay case
+    /// sensitive. Upstream only knows lowercase commands.
+    pub case_insensitive_commands: bool,
+    /// Bytes of keys and values held before items get evicted. `cache_memlimit` changes it.
+    pub memory_limit: u64,
+    /// Most items held at once. Storage of fixed capacity lowers it to what it holds.
+    pub max_items: usize,
+    /// Make room for a new item past [`max_items`](Settings::max_items) by evicting one.
+    /// Otherwise the new item is refused with [`Error::OutOfMemory`], like upstream's `-M`.
+    pub evict_when_full: bool,
+    /// Largest item a client may store, counting its key and per-item overhead along with the
+    /// value, like upstream's `-I`. Raising it past the default of 1 MB is fine:
+    /// [`chunk_values_over`](Self::chunk_values_over) keeps the larger values in chunks. The
+    /// binary protocol still reads a value into one allocation before it's chunked.
+    pub item_size_max: usize,
+    /// Longest request line, data blocks aside. Anything longer is answered with an error and
+    /// skipped up to its end.
+    pub max_line_len: usize,
+    /// Most keys a single `get` may ask for.
+    pub max_keys_per_get: usize,
+    /// Most responses held back while an earlier one is still going out, so a client
+    /// pipelining requests gets them parsed ahead of its reads. Once that many wait, input is
+    /// left in the socket until one is sent. Each takes a few words, plus the key or report it
+    /// has boxed. 0 answers one request at a time.
+    pub max_pending_responses: usize,
+    /// Most windows one poll fills while the socket keeps taking full ones, so a big response
+    /// goes out in several segments without one connection hogging the loop. At least 1.
+    pub max_transmits_per_poll: usize,
+    /// Most received bytes one [`poll`](CommandHandler::poll) parses, so a client sending a
+    /// long pipelined batch can't keep a single-threaded loop from its other connections. The
+    /// rest is left in the socket for the next poll. Unlimited by default.
+    pub max_bytes_read_per_poll: usize,
+    /// Most bytes one [`poll`](CommandHandler::poll) sends, the write side of
+    /// [`max_bytes_read_per_poll`](Self::max_bytes_read_per_poll). Unlimited by default.
+    pub max_bytes_written_per_poll: usize,
+    /// Unknown commands in a row after which a text connection is closed, as it's most likely
+    /// not speaking memcached at all. 0 never closes it.
+    pub max_unknown_commands: usize,
+    /// Told about every error a connection is sent.
+    pub on_error: Option<Arc<dyn ErrorObserver>>,
+    /// Asked for items `get`, `mg` and the binary gets would miss, one key at a time. One it
+    /// hands back is stored, as [`CommandHandler::insert`] would, and the get hits it.
+    pub on_miss: Option<Arc<dyn MissHandler>>,
+    /// Told about every item removed, and why.
+    pub on_removal: Option<Arc<dyn RemovalObserver>>,
+    /// Index items by when they expire, so that [`CommandHandler::tick`] goes straight to the
+    /// expired ones instead of looking through them all. Costs a copy of each expiring key.
+    ///
+    /// Each handler indexes the items it stores, so with shared storage, tick them all.
+    pub expiry_index: bool,
+    /// Where `watch` connections get their events from. Share it between handlers to watch
+    /// them all from one connection.
+    pub events: EventBus,
+    /// What [`CommandHandler::flush_all`] did away with. Share it between handlers sharing
+    /// storage, so that they all see it.
+    pub generation: Generation,
+    /// Logs every change to the items, for [`CommandHandler::replay_log`] to bring back after a
+    /// crash. Changes made beneath the handler, such as [`Slabs`] evicting, aren't logged.
+    #[cfg(feature = "journal")]
+    pub journal: Option<Journal>,
+    /// Where values are kept, if not all of them in memory, such as in external flash with
+    /// only the index in RAM.
+    pub value_backend: Option<Arc<dyn ValueBackend>>,
+    /// Keep values longer than this in chunks of [`value_chunk_size`](Self::value_chunk_size)
+    /// bytes rather than in one allocation, which a fragmented heap may have no room for
+    /// however much is free. Values the [`value_backend`](Self::value_backend) takes aren't
+    /// chunked. By default, only values past upstream's default
+    /// [`item_size_max`](Self::item_size_max) are, so that raising it doesn't call for
+    /// allocations as large as its items.
+    pub chunk_values_over: Option<usize>,
+    /// Bytes in each chunk of a chunked value.
+    pub value_chunk_size: usize,
+    /// Compresses values longer than [`compress_values_over`](Self::compress_values_over) as
+    /// they're stored, if that makes them shorter. A compressed value is expanded whole before
+    /// it's sent, into memory of its own that's held until it's sent, so each response in
+    /// flight may hold up to [`item_size_max`](Self::item_size_max) more. Appending or
+    /// prepending to one expands it, and compresses the result again. Values in chunks or
+    /// kept by the [`value_backend`](Self::value_backend) aren't compressed.
+    pub compressor: Option<Arc<dyn Compressor>>,
+    /// Shorter values aren't worth compressing.
+    pub compress_values_over: usize,
+    /// Leave a tombstone for this many seconds where `md`, a binary DELETE or
+    /// [`CommandHandler::remove`] deletes a key, whether or not there was an item there. Until
+    /// it expires, the key is missed and an add (`ms` in mode `E`) isn't stored, so that a
+    /// cache fill racing the delete can't bring the old value back. Nor is it read through the
+    /// [`on_miss`](Self::on_miss) handler. Tombstones are reclaimed like expired items, aren't
+    /// journaled, and `stats` counts them apart from items, as `curr_tombstones`.
+    pub tombstone_secs: Option<u32>,
+    /// Turn away `set` as well as `add` while there's a tombstone. Otherwise a `set` takes
+    /// its place.
+    pub tombstones_block_set: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            detail_delimiter: b':',
+            detail_max_prefixes: 256,
+            detail_enabled: false,
+            enable_shutdown: false,
+            protocol: Default::default(),
+            #[cfg(feature = "binary")]
+            authenticator: None,
+            text_requires_auth: false,
+            lenient_line_endings: false,
+            ignore_empty_lines: true,
+            case_insensitive_commands: false,
+            memory_limit: 64 * 1024 * 1024,
+            max_items: usize::MAX,
+            evict_when_full: true,
+            item_size_max: 1024 * 1024,
+            max_line_len: 8 * 1024,
+            max_keys_per_get: 1024,
+            max_pending_responses: 16,
+            max_transmits_per_poll: 16,
+            max_bytes_read_per_poll: usize::MAX,
+            max_bytes_written_per_poll: usize::MAX,
+            max_unknown_commands: 16,
+            on_error: None,
+            on_miss: None,
+            on_removal: None,
+            expiry_index: false,
+            events: Default::default(),
+            generation: Default::default(),
+            #[cfg(feature = "journal")]
+            journal: None,
+            value_backend: None,
+            chunk_values_over: Some(1024 * 1024),
+            value_chunk_size: 4096,
+            compressor: None,
+            compress_values_over: 256,
+            tombstone_secs: None,
+            tombstones_block_set: false,
+        }
+    }
+}
+
+/// Where CAS values come from. There's one for every handler, so values never repeat however
+/// handlers share storage. Starts at 1, as 0 is no value.
+#[cfg(target_has_atomic = "64")]
+static NEXT_CAS: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1);
+#[cfg(not(target_has_atomic = "64"))]
+static NEXT_CAS: spin::Mutex<u64> = spin::Mutex::new(1);
+
+/// A CAS value greater than any handed out before.
+fn next_cas() -> u64 {
+    #[cfg(target_has_atomic = "64")]
+    return NEXT_CAS.fetch_add(1, Ordering::Relaxed);
+    #[cfg(not(target_has_atomic = "64"))]
+    {
+        let mut next = NEXT_CAS.lock();
+        *next += 1;
+        *next - 1
+    }
+}
+
+/// Makes sure CAS values handed out from now on are greater than `cas`, which came from
+/// elsewhere, such as a snapshot.
+fn bump_cas(cas: u64) {
+    #[cfg(target_has_atomic = "64")]
+    NEXT_CAS.fetch_max(cas + 1, Ordering::Relaxed);
+    #[cfg(not(target_has_atomic = "64"))]
+    {
+        let mut next = NEXT_CAS.lock();
+        *next = (*next).max(cas + 1);
+    }
+}
+
+/// Source of connection ids reported by `stats conns`.
+/// A `usize` as many 32-bit targets lack 64-bit atomics.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl ConnectionStats {
+    /// Counters for a connection that starts now.
+    fn next(now: u32) -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u64,
+            last_activity: now,
+            ..Default::default()
+        }
+    }
+}
+
+/// Serves one connection out of an item [`Storage`], which other handlers may share. Keys
+/// longer than `KEY_LEN` are refused.
+pub struct CommandHandler<
+    S: Storage = Map<Box<[u8]>, Entry>,
+    C: Clock = DefaultClock,
+    const KEY_LEN: usize = MAX_KEY_LEN,
+> {
+    state: State<KEY_LEN>,
+    /// Responses to requests parsed while an earlier one was going out, oldest first. They go
+    /// out before one left in `state`, which is always the newest.
+    responses: VecDeque<State<KEY_LEN>>,
+    max_pending_responses: usize,
+    max_transmits_per_poll: usize,
+    max_bytes_read_per_poll: usize,
+    max_bytes_written_per_poll: usize,
+    /// The last poll stopped short at one of these.
+    over_budget: bool,
+    data: S,
+    stats: Stats,
+    conn: ConnectionStats,
+    clock: C,
+    enable_shutdown: bool,
+    shutdown: Option<Shutdown>,
+    memory_limit: u64,
+    max_items: usize,
+    evict_when_full: bool,
+    item_size_max: usize,
+    max_line_len: usize,
+    max_keys_per_get: usize,
+    max_unknown_commands: usize,
+    /// Unknown commands since the last known one.
+    unknown_commands: usize,
+    /// Bytes of the request line so far.
+    line_len: usize,
+    /// Keys of the `get` line so far.
+    line_keys: usize,
+    /// The hit in `state` isn't the last key of its `get` line, so once it's queued or sent,
+    /// the parser goes back to reading keys rather than a command.
+    get_continues: bool,
+    /// Whether the connection speaks the binary protocol. Settled by the first byte when
+    /// negotiating.
+    #[cfg(feature = "binary")]
+    binary: bool,
+    /// The protocol setting, to start over with on [`reset`](CommandHandler::reset).
+    negotiation: Protocol,
+    #[cfg(feature = "binary")]
+    authenticator: Option<Arc<dyn Authenticator>>,
+    text_requires_auth: bool,
+    authenticated: bool,
+    lenient_line_endings: bool,
+    ignore_empty_lines: bool,
+    case_insensitive_commands: bool,
+    /// The request line so far ends in a `\r`, which only a `\n` may follow.
+    line_cr: bool,
+    closing: bool,
+    /// When a delayed binary FLUSH takes effect.
+    flush_at: Option<u32>,
+    /// The exptime of the `gat` line being read, for its hits to take.
+    line_exptime: Option<i64>,
+    /// The `get` line being read is a `gets` or `gats`, whose hits come with their CAS value.
+    line_cas: bool,
+    extensions: Vec<Box<dyn CommandExtension<S>>>,
+    on_error: Option<Arc<dyn ErrorObserver>>,
+    on_miss: Option<Arc<dyn MissHandler>>,
+    /// Where the next [`tick`](CommandHandler::tick) picks up its sweep.
+    sweep_cursor: S::Cursor,
+    /// Where the response walking the map, `lru_crawler metadump` or `stats sizes`, picks up
+    /// next. There's one at a time, as responses are sent in order.
+    walk_cursor: S::Cursor,
+    /// With [`Settings::expiry_index`], what the sweep looks at instead.
+    wheel: Option<TimerWheel>,
+    #[cfg(feature = "journal")]
+    journal: Option<Journal>,
+    value_backend: Option<Arc<dyn ValueBackend>>,
+    chunking: Option<Chunking>,
+    compressor: Option<Arc<dyn Compressor>>,
+    compress_values_over: usize,
+    tombstone_secs: Option<u32>,
+    tombstones_block_set: bool,
+}
+
+#[cfg(feature = "std")]
+impl<S: Storage> CommandHandler<S> {
+    pub fn new(data: S) -> Self {
+        Self::with_clock(data, SystemClock)
+    }
+}
+
+/// A handler keeping its items in a map of their own.
+#[cfg(feature = "std")]
+impl Default for CommandHandler {
+    fn default() -> Self {
+        Self::new(Map::default())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<H: core::hash::BuildHasher> CommandHandler<Map<Box<[u8]>, Entry, H>> {
+    /// A handler keeping its items in a map of their own, hashed by `hasher`: a faster one than
+    /// SipHash where keys aren't chosen by an attacker, or one seeded by the deployment.
+    ///
+    /// ```
+    /// use incr_memcached::CommandHandler;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let handler = CommandHandler::with_hasher(RandomState::new());
+    /// # drop(handler);
+    /// ```
+    pub fn with_hasher(hasher: H) -> Self {
+        Self::new(Map::with_hasher(hasher))
+    }
+}
+
+impl<S: Storage, C: Clock> CommandHandler<S, C> {
+    pub fn with_clock(data: S, clock: C) -> Self {
+        Self::with_settings(data, clock, Default::default())
+    }
+
+    pub fn with_settings(data: S, clock: C, settings: Settings) -> Self {
+        Self::with_key_len(data, clock, settings)
+    }
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Like [`with_settings`](CommandHandler::with_settings), for a handler taking keys of up to
+    /// `KEY_LEN` bytes, which can't be more than [`MAX_KEY_LEN`]. The buffers holding a key
+    /// shrink with it.
+    ///
+    /// ```
+    /// use incr_memcached::{Clock, CommandHandler, Map, Settings};
+    ///
+    /// # struct Uptime;
+    /// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+    /// let data: Map<_, _> = Map::default();
+    /// let handler = CommandHandler::<_, _, 32>::with_key_len(data, Uptime, Settings::default());
+    /// # drop(handler);
+    /// ```
+    pub fn with_key_len(data: S, clock: C, settings: Settings) -> Self {
+        const {
+            assert!(
+                KEY_LEN <= MAX_KEY_LEN,
+                "keys can't be longer than MAX_KEY_LEN"
+            )
+        };
+        let max_items = data.capacity().map_or(settings.max_items, |capacity| {
+            capacity.min(settings.max_items)
+        });
+        let (mut key_bytes, mut value_bytes, mut tombstones) = (0, 0, 0);
+        let mut wheel = settings.expiry_index.then(|| TimerWheel::new(clock.now()));
+        let flushed = settings.generation.flushed_before();
+        let _ = data.scan(|key, entry| {
+            if entry.cas < flushed {
+                return ControlFlow::Continue(());
+            }
+            if entry.tombstone {
+                tombstones += 1;
+            } else {
+                key_bytes += key.len() as u64;
+                value_bytes += entry.value.len() as u64;
+            }
+            if let Some(wheel) = wheel.as_mut().filter(|_| entry.expires_at != 0) {
+                wheel.insert(key, entry.expires_at);
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        let mut detail = PrefixStats::new(settings.detail_delimiter, settings.detail_max_prefixes);
+        detail.enabled = settings.detail_enabled;
+        let stats = Stats {
+            detail,
+            events: settings.events,
+            on_removal: settings.on_removal,
+            curr_items: data
+                .len()
+                .saturating_sub(settings.generation.left() + tombstones)
+                as u64,
+            curr_tombstones: tombstones as u64,
+            generation: settings.generation,
+            limit_items: max_items as u64,
+            key_bytes,
+            value_bytes,
+            ..Default::default()
+        };
+        let conn = ConnectionStats::next(clock.now());
+        Self {
+            state: State::initial(settings.protocol),
+            responses: VecDeque::new(),
+            max_pending_responses: settings.max_pending_responses,
+            max_transmits_per_poll: settings.max_transmits_per_poll,
+            max_bytes_read_per_poll: settings.max_bytes_read_per_poll,
+            max_bytes_written_per_poll: settings.max_bytes_written_per_poll,
+            over_budget: false,
+            data,
+            stats,
+            conn,
+            clock,
+            enable_shutdown: settings.enable_shutdown,
+            shutdown: None,
+            memory_limit: settings.memory_limit,
+            max_items,
+            evict_when_full: settings.evict_when_full,
+            item_size_max: settings.item_size_max,
+            max_line_len: settings.max_line_len,
+            max_keys_per_get: settings.max_keys_per_get,
+            max_unknown_commands: settings.max_unknown_commands,
+            unknown_commands: 0,
+            line_len: 0,
+            line_keys: 0,
+            get_continues: false,
+            #[cfg(feature = "binary")]
+            binary: settings.protocol == Protocol::BinaryOnly,
+            negotiation: settings.protocol,
+            #[cfg(feature = "binary")]
+            authenticator: settings.authenticator,
+            text_requires_auth: settings.text_requires_auth,
+            authenticated: false,
+            lenient_line_endings: settings.lenient_line_endings,
+            ignore_empty_lines: settings.ignore_empty_lines,
+            case_insensitive_commands: settings.case_insensitive_commands,
+            line_cr: false,
+            closing: false,
+            flush_at: None,
+            line_exptime: None,
+            line_cas: false,
+            extensions: Vec::new(),
+            on_error: settings.on_error,
+            on_miss: settings.on_miss,
+            sweep_cursor: Default::default(),
+            walk_cursor: Default::default(),
+            wheel,
+            #[cfg(feature = "journal")]
+            journal: settings.journal,
+            value_backend: settings.value_backend,
+            chunking: settings.chunk_values_over.map(|over| Chunking {
+                over,
+                size: settings.value_chunk_size.max(1),
+            }),
+            compressor: settings.compressor,
+            compress_values_over: settings.compress_values_over,
+            tombstone_secs: settings.tombstone_secs,
+            tombstones_block_set: settings.tombstones_block_set,
+        }
+    }
+
+    /// Forgets the connection, so that the handler can serve another one: whatever was half
+    /// read or still waiting to be sent is dropped, along with a dump half walked and a delayed
+    /// `flush_all` yet to take effect. The connection has to authenticate and negotiate its
+    /// protocol again, and it gets a new id and `stats conns` counters. Items, settings and
+    /// server-wide stats are left alone. Can be called at any point.
+    pub fn reset(&mut self) {
+        self.state = State::initial(self.negotiation);
+        self.responses.clear();
+        #[cfg(feature = "binary")]
+        {
+            self.binary = self.negotiation == Protocol::BinaryOnly;
+        }
+        self.unknown_commands = 0;
+        self.line_len = 0;
+        self.line_keys = 0;
+        self.get_continues = false;
+        self.line_cr = false;
+        self.authenticated = false;
+        self.closing = false;
+        self.over_budget = false;
+        self.flush_at = None;
+        self.walk_cursor = Default::default();
+        self.conn = ConnectionStats::next(self.clock.now());
+    }
+
+    /// Adds a text command. Extensions are asked in the order they were registered, and only
+    /// about names no built-in command has.
+    pub fn register_command(&mut self, extension: impl CommandExtension<S> + 'static) {
+        self.extensions.push(Box::new(extension));
+    }
+
+    /// Set once a client issued an accepted `shutdown`. The handler can't stop the process
+    /// itself, so the server loop is expected to check this after polling.
+    pub fn shutdown_requested(&self) -> Option<Shutdown> {
+        self.shutdown
+    }
+
+    /// The protocol the connection speaks: [`Protocol::Negotiating`] until its first byte
+    /// arrives, if it was left to negotiate.
+    pub fn protocol(&self) -> Protocol {
+        if let State::Detecting = self.state {
+            return Protocol::Negotiating;
+        }
+        #[cfg(feature = "binary")]
+        if self.binary {
+            return Protocol::BinaryOnly;
+        }
+        Protocol::AsciiOnly
+    }
+
+    /// Set after a `quit` or an accepted `shutdown`, after too many unknown commands, or once a
+    /// binary-only connection got something that isn't a binary request. From then on input is
+    /// dropped unparsed, while the responses already queued still go out.
+    pub fn wants_close(&self) -> bool {
+        self.closing
+    }
+
+    /// Set once a connection that [`wants_close`](Self::wants_close) sent everything it had
+    /// to. The handler can't close the socket itself, so the server loop is expected to close
+    /// it now.
+    pub fn is_closed(&self) -> bool {
+        self.closing && !self.needs_write()
+    }
+
+    /// Whether there's a response to send, so the socket is worth watching for write readiness.
+    pub fn needs_write(&self) -> bool {
+        !self.responses.is_empty() || self.state.wants_to_send()
+    }
+
+    /// Whether the handler takes input now. It doesn't once
+    /// [`max_pending_responses`](Settings::max_pending_responses) responses wait to go out:
+    /// input is left in the socket until one is sent, which is the back-pressure on a client
+    /// pipelining ahead of its reads. A connection that's watching or closing takes input only
+    /// to drop it.
+    pub fn needs_read(&self) -> bool {
+        !self.closing && !self.holds_input() && !matches!(self.state, State::Watching { .. })
+    }
+
+    /// Whether the connection is between requests, with nothing half-read and nothing to send,
+    /// so it can be put to sleep or closed without losing anything.
+    pub fn is_idle(&self) -> bool {
+        let between_requests = match &self.state {
+            State::Detecting => true,
+            State::ReadingCommand(cmd) => cmd.is_empty() && !self.line_cr,
+            _ => false,
+        };
+        between_requests && self.responses.is_empty() && !self.closing
+    }
+
+    /// Length of the rest of the response being sent, if it can be told without sending it.
+    #[cfg(feature = "udp")]
+    pub(crate) fn response_len(&self) -> Option<usize> {
+        let mut len = 0;
+        for response in self.responses.iter().chain([&self.state]) {
+            len += response.remaining_len(&self.stats)?;
+        }
+        Some(len)
+    }
+
+    /// Whether a response walks the map, so entries mustn't be removed under it.
+    fn walks_map(&self) -> bool {
+        self.state.walks_map() || self.responses.iter().any(State::walks_map)
+    }
+
+    /// Whether the next byte belongs to a text request line, as opposed to a value or a binary
+    /// packet.
+    fn reads_text_line(&self) -> bool {
+        #[cfg(feature = "binary")]
+        if self.binary {
+            return false;
+        }
+        matches!(
+            self.state,
+            State::ReadingCommand(_)
+                | State::ReadingKey { .. }
+                | State::ReadingArgs { .. }
+                | State::ReadingStoreArgs { .. }
+                | State::ReadingKeyArgs { .. }
+                | State::ReadingMetaArgs { .. }
+        )
+    }
+
+    /// Gives the [`MissHandler`] the chance to store an item at `key` before a retrieval
+    /// command looks it up, if there isn't one, nor a tombstone. One it can't store is
+    /// dropped, and missed.
+    fn read_through(&mut self, key: &[u8]) {
+        let Some(on_miss) = self.on_miss.clone() else {
+            return;
+        };
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        if self.data.read(key, |entry| !entry.is_gone(now, flushed)) == Some(true) {
+            return;
+        }
+        if let Some(entry) = on_miss.miss(key, now) {
+            let _ = self.insert(key, entry);
+        }
+    }
+
+    /// Looks up `key` for a retrieval command, counting the hit or miss, and calls `f` with the
+    /// entry if there is one, before marking it fetched and accessed now. An expired entry is a
+    /// miss, and is removed there and then. A tombstone is a miss too, but stays.
+    fn lookup<R>(
+        data: &mut S,
+        stats: &mut Stats,
+        now: u32,
+        key: &[u8],
+        f: impl FnOnce(&mut Entry) -> R,
+    ) -> Option<R> {
+        let flushed = stats.generation.flushed_before();
+        // Err(true) for an entry that's gone, Err(false) for a tombstone.
+        let found = data.update(key, |entry| {
+            if !entry.is_live(now, flushed) {
+                return Err(entry.is_gone(now, flushed));
+            }
+            let r = f(entry);
+            entry.fetched = true;
+            entry.last_access = now;
+            Ok(r)
+        });
+        let hit = match found {
+            Some(Ok(r)) => Some(r),
+            Some(Err(true)) => {
+                if Self::reclaim(data, stats, now, key) {
+                    stats.counters.get_expired += 1;
+                }
+                None
+            }
+            Some(Err(false)) | None => None,
+        };
+        let found = hit.is_some();
+        stats.counters.cmd_get += 1;
+        if found {
+            stats.counters.get_hits += 1;
+        } else {
+            stats.counters.get_misses += 1;
+        }
+        stats.detail.record_get(key, found);
+        stats.publish(now, EventKind::Get { found }, key);
+        hit
+    }
+
+    /// Applies a storage command. Returns whether the value was stored.
+    fn store(&mut self, store: &Store<KEY_LEN>, value: Bytes) -> Result<bool, Error> {
+        let stored = self.write_entry(store, value);
+        #[cfg(feature = "journal")]
+        if stored == Ok(true) {
+            self.log_item(&store.key);
+        }
+        let cmd = store.mode.name();
+        let kind = EventKind::Store {
+            cmd,
+            stored: stored == Ok(true),
+        };
+        self.stats.publish(self.clock.now(), kind, &store.key);
+        stored
+    }
+
+    fn write_entry(&mut self, store: &Store<KEY_LEN>, value: Bytes) -> Result<bool, Error> {
+        let key = store.key.as_slice();
+        let now = self.clock.now();
+        let found = self.start_set(key, now);
+        if found == Found::Tombstone {
+            let blocked = match store.mode {
+                StoreMode::Add => true,
+                StoreMode::Set => self.tombstones_block_set,
+                StoreMode::Replace | StoreMode::Append | StoreMode::Prepend => false,
+            };
+            if blocked {
+                self.stats.counters.tombstone_blocked += 1;
+                return Ok(false);
+            }
+        }
+        match (store.mode, found == Found::Item) {
+            (StoreMode::Add, true)
+            | (StoreMode::Replace | StoreMode::Append | StoreMode::Prepend, false) => {
+                return Ok(false)
+            }
+            (StoreMode::Append | StoreMode::Prepend, true) => {
+                let added = value.len();
+                let (backend, chunking) = (self.value_backend.as_deref(), self.chunking);
+                let (compressor, over) = (self.compressor.as_ref(), self.compress_values_over);
+                let allocates = self.data.allocates_values();
+                let limit = self.item_size_max;
+                let written = self.data.update(key, |entry| {
+                    if entry.value.is_static() && !allocates {
+                        return Err(Error::OutOfMemory);
+                    }
+                    let size = entry.value.len() + added + key.len() + ITEM_OVERHEAD;
+                    if size > limit {
+                        return Err(Error::TooLarge { size, limit });
+                    }
+                    let (first, second) = if let StoreMode::Append = store.mode {
+                        (&entry.value, &value)
+                    } else {
+                        (&value, &entry.value)
+                    };
+                    let joined = value::join(backend, chunking, key, first, second)?;
+                    let (joined, saved) = compress::compress(compressor, over, joined);
+                    entry.value = joined;
+                    entry.stale |= store.stale;
+                    entry.fetched = false;
+                    entry.last_access = now;
+                    entry.cas = next_cas();
+                    Ok(saved)
+                });
+                // Gone since `start_set` saw it, if another connection shares the storage
+                let Some(saved) = written.transpose()? else {
+                    return Ok(false);
+                };
+                self.stats.counters.compression_saved += saved as u64;
+                self.stats.value_bytes += added as u64;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        let Some(expires_at) = expires_at(store.exptime, now) else {
+            // Expired at birth: the store succeeds but nothing is left to serve.
+            if let Some(old) = self.data.remove(key) {
+                self.forget(key, &old);
+                Self::removed(
+                    &self.data,
+                    &self.stats,
+                    now,
+                    key,
+                    &old,
+                    RemovalCause::Replaced,
+                );
+            }
+            return Ok(true);
+        };
+        let compressor = self.compressor.as_ref();
+        let (value, saved) = compress::compress(compressor, self.compress_values_over, value);
+        let entry = Entry {
+            flags: store.flags,
+            stale: store.stale,
+            expires_at,
+            ..Entry::from_bytes(value)
+        };
+        self.put(key, entry, found != Found::Nothing)?;
+        self.stats.counters.compression_saved += saved as u64;
+        Ok(true)
+    }
+
+    /// `value`, to be stored at `key`, written to wherever [`Settings::value_backend`] keeps
+    /// values like it.
+    fn keep_value(&self, key: &[u8], value: Vec<u8>) -> Result<Bytes, Error> {
+        value::keep(self.value_backend.as_deref(), self.chunking, key, value)
+    }
+
+    /// Where to write the data block of `store`, `len` bytes long: a sink from
+    /// [`Settings::value_backend`] if it wants one, else chunks if it's long enough for
+    /// [`Settings::chunk_values_over`], else memory. Appended and prepended bytes are joined
+    /// with the old value first, so they always start in memory.
+    fn incoming(&self, store: &Result<Store<KEY_LEN>, Error>, len: usize) -> Incoming {
+        let Ok(store) = store else {
+            return Incoming::default();
+        };
+        if let StoreMode::Append | StoreMode::Prepend = store.mode {
+            return Incoming::default();
+        }
+        if let Some(backend) = &self.value_backend {
+            if let Some(sink) = backend.sink(&store.key, len) {
+                return Incoming::Sink(sink);
+            }
+        }
+        match self.chunking.filter(|chunking| chunking.applies(len)) {
+            Some(chunking) => Incoming::Chunked(Box::new(Chunked::new(chunking.size))),
+            None => Incoming::default(),
+        }
+    }
+
+    /// Counts a store to `key`, and reclaims what's there if it's expired. Returns what's
+    /// left there.
+    fn start_set(&mut self, key: &[u8], now: u32) -> Found {
+        self.stats.counters.cmd_set += 1;
+        self.stats.detail.record_set(key);
+        let flushed = self.stats.generation.flushed_before();
+        match self.data.read(key, |entry| {
+            (
+                entry.is_gone(now, flushed),
+                entry.tombstone,
+                S::class_of(entry),
+            )
+        }) {
+            Some((true, tombstone, class)) => {
+                Self::reclaim(&mut self.data, &mut self.stats, now, key);
+                if !tombstone {
+                    self.stats.counters.reclaimed += 1;
+                    self.stats.class(class).reclaimed += 1;
+                }
+                Found::Nothing
+            }
+            Some((false, true, _)) => Found::Tombstone,
+            Some((false, false, _)) => Found::Item,
+            None => Found::Nothing,
+        }
+    }
+
+    /// Stores `entry` at `key` in place of whatever's there, which `exists` says is an item
+    /// or a tombstone, as written now and with a new CAS value.
+    fn put(&mut self, key: &[u8], entry: Entry, exists: bool) -> Result<(), Error> {
+        if !exists && self.data.len() >= self.max_items {
+            self.make_room()?;
+        }
+        let (expires_at, len) = (entry.expires_at, entry.value.len());
+        let entry = Entry {
+            win_token: false,
+            fetched: false,
+            last_access: self.clock.now(),
+            cas: next_cas(),
+            ..entry
+        };
+        let old = self
+            .data
+            .try_insert(key, entry)
+            .map_err(|_| Error::OutOfMemory)?;
+        self.stats.add_item(key, len);
+        self.stats.counters.total_items += 1;
+        if let Some(old) = old {
+            self.forget(key, &old);
+            let now = self.clock.now();
+            Self::removed(
+                &self.data,
+                &self.stats,
+                now,
+                key,
+                &old,
+                RemovalCause::Replaced,
+            );
+        }
+        if let Some(wheel) = self.wheel.as_mut().filter(|_| expires_at != 0) {
+            wheel.insert(key, expires_at);
+        }
+        self.stats.count_items(self.data.len());
+        Ok(())
+    }
+
+    /// Evicts an item for a new one past [`Settings::max_items`], if that's allowed.
+    fn make_room(&mut self) -> Result<(), Error> {
+        if !self.evict_when_full || self.walks_map() || !self.evict_one() {
+            return Err(Error::OutOfMemory);
+        }
+        Ok(())
+    }
+
+    /// Stops counting an item that was removed or replaced. With shared storage it may have
+    /// been counted by another handler, so the byte counts (and with them the memory limit)
+    /// only approximate this handler's share, while `curr_items` is always taken from the
+    /// storage.
+    fn forget(&mut self, key: &[u8], old: &Entry) {
+        Self::forgotten(&mut self.stats, &mut self.wheel, key, old);
+        self.stats.count_items(self.data.len());
+    }
+
+    /// As [`forget`](Self::forget), for while the storage is busy removing, but leaves
+    /// `curr_items` to be counted after.
+    fn forgotten(stats: &mut Stats, wheel: &mut Option<TimerWheel>, key: &[u8], old: &Entry) {
+        if old.cas < stats.generation.flushed_before() {
+            // Stopped counting when it was flushed.
+            stats.generation.removed();
+        } else if old.tombstone {
+            stats.curr_tombstones = stats.curr_tombstones.saturating_sub(1);
+        } else {
+            stats.remove_item(key, old.value.len());
+        }
+        if let Some(wheel) = wheel.as_mut().filter(|_| old.expires_at != 0) {
+            wheel.remove(key, old.expires_at);
+        }
+    }
+
+    /// Tells the [`RemovalObserver`] that `old` is gone from `key` for `cause`, or for its
+    /// expiry or a flush if it was gone by `now` already. Tombstones aren't items, so it isn't
+    /// told about them.
+    fn removed(data: &S, stats: &Stats, now: u32, key: &[u8], old: &Entry, cause: RemovalCause) {
+        if stats.on_removal.is_none() {
+            return;
+        }
+        let len = data.len();
+        Self::tell_removed(stats, now, key, old, cause);
+        debug_assert_eq!(data.len(), len, "a RemovalObserver changed the storage");
+    }
+
+    /// As [`removed`](Self::removed), for while the storage is busy removing.
+    fn tell_removed(stats: &Stats, now: u32, key: &[u8], old: &Entry, cause: RemovalCause) {
+        let Some(on_removal) = stats.on_removal.as_ref().filter(|_| !old.tombstone) else {
+            return;
+        };
+        let cause = if old.cas < stats.generation.flushed_before() {
+            RemovalCause::Flushed
+        } else if old.is_expired(now) {
+            RemovalCause::Expired
+        } else {
+            cause
+        };
+        on_removal.removed(key, old, cause);
+    }
+
+    /// Removes `key`, found to have expired or been flushed by `now`. Responses already
+    /// sending its value keep their share of it. Returns whether it was an item that had
+    /// expired.
+    fn reclaim(data: &mut S, stats: &mut Stats, now: u32, key: &[u8]) -> bool {
+        let Some(old) = data.remove(key) else {
+            return false;
+        };
+        Self::removed(data, stats, now, key, &old, RemovalCause::Expired);
+        if old.cas < stats.generation.flushed_before() {
+            stats.generation.removed();
+            stats.count_items(data.len());
+            return false;
+        }
+        if old.tombstone {
+            stats.curr_tombstones = stats.curr_tombstones.saturating_sub(1);
+            stats.count_items(data.len());
+            return false;
+        }
+        stats.counters.expired += 1;
+        if !old.fetched {
+            stats.counters.expired_unfetched += 1;
+            stats.class(S::class_of(&old)).expired_unfetched += 1;
+        }
+        // As `forget`, which needs all of `self`.
+        stats.remove_item(key, old.value.len());
+        stats.count_items(data.len());
+        true
+    }
+
+    /// Marks `key` stale rather than removing it, so it's served until someone recaches it,
+    /// and with `exptime` given, sets when it expires as a touch would. Returns whether it was
+    /// present.
+    fn invalidate(&mut self, key: &[u8], exptime: Option<i64>) -> bool {
+        self.stats.detail.record_delete(key);
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let at = exptime.map(|exptime| expires_at(exptime, now));
+        let found = self.data.update(key, |entry| {
+            if entry.cas < flushed || entry.tombstone {
+                return false;
+            }
+            entry.stale = true;
+            entry.win_token = false;
+            if let Some(Some(at)) = at {
+                entry.expires_at = at;
+            }
+            true
+        }) == Some(true);
+        match at {
+            Some(at) if found => self.touched(key, at),
+            #[cfg(feature = "journal")]
+            _ if found => self.log_item(key),
+            _ => {}
+        }
+        found
+    }
+
+    /// Removes `key`. Returns whether it was present.
+    fn delete(&mut self, key: &[u8]) -> bool {
+        self.take(key).is_some()
+    }
+
+    /// Removes `key`, returning what it was unless it was a tombstone, and leaves a
+    /// tombstone if [`Settings::tombstone_secs`] says to.
+    fn take(&mut self, key: &[u8]) -> Option<Entry> {
+        self.stats.detail.record_delete(key);
+        let flushed = self.stats.generation.flushed_before();
+        let old = self.data.remove(key);
+        let now = self.clock.now();
+        if let Some(old) = &old {
+            self.forget(key, old);
+            #[cfg(feature = "journal")]
+            self.log_item(key);
+            Self::removed(
+                &self.data,
+                &self.stats,
+                now,
+                key,
+                old,
+                RemovalCause::Deleted,
+            );
+        }
+        self.bury(key, now);
+        let old = old.filter(|old| old.cas >= flushed && !old.tombstone);
+        let found = old.is_some();
+        self.stats.publish(now, EventKind::Delete { found }, key);
+        old
+    }
+
+    /// Sets when `key` expires, as a store's `exptime` would. Returns whether there was an item
+    /// to touch.
+    fn touch(&mut self, key: &[u8], exptime: i64) -> bool {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let at = expires_at(exptime, now);
+        let found = self.data.update(key, |entry| {
+            if !entry.is_live(now, flushed) {
+                return Err(entry.is_gone(now, flushed));
+            }
+            if let Some(at) = at {
+                entry.expires_at = at;
+            }
+            entry.last_access = now;
+            Ok(())
+        });
+        match found {
+            Some(Ok(())) => {
+                self.touched(key, at);
+                true
+            }
+            Some(Err(true)) => {
+                Self::reclaim(&mut self.data, &mut self.stats, now, key);
+                false
+            }
+            Some(Err(false)) | None => false,
+        }
+    }
+
+    /// Follows up a touch that left the item at `key` expiring at `at`, or expired already if
+    /// `None`, in which case it's reclaimed there and then.
+    fn touched(&mut self, key: &[u8], at: Option<u32>) {
+        match at {
+            Some(at) => {
+                if let Some(wheel) = self.wheel.as_mut().filter(|_| at != 0) {
+                    wheel.insert(key, at);
+                }
+            }
+            None => {
+                let now = self.clock.now();
+                Self::reclaim(&mut self.data, &mut self.stats, now, key);
+            }
+        }
+        #[cfg(feature = "journal")]
+        self.log_item(key);
+    }
+
+    /// Leaves a tombstone at `key`, now free, for [`Settings::tombstone_secs`], if it's set
+    /// and there's room.
+    fn bury(&mut self, key: &[u8], now: u32) {
+        let Some(secs) = self.tombstone_secs.filter(|&secs| secs > 0) else {
+            return;
+        };
+        if self.data.len() >= self.max_items && self.make_room().is_err() {
+            return;
+        }
+        let expires_at = now.saturating_add(secs);
+        let tombstone = Entry {
+            expires_at,
+            tombstone: true,
+            last_access: now,
+            cas: next_cas(),
+            ..Entry::from_static(b"")
+        };
+        // Another handler may have stored an item there meanwhile.
+        let Ok(old) = self.data.try_insert(key, tombstone) else {
+            return;
+        };
+        if let Some(old) = old {
+            self.forget(key, &old);
+            Self::removed(
+                &self.data,
+                &self.stats,
+                now,
+                key,
+                &old,
+                RemovalCause::Deleted,
+            );
+        }
+        self.stats.curr_tombstones += 1;
+        self.stats.counters.total_tombstones += 1;
+        self.stats.count_items(self.data.len());
+        if let Some(wheel) = &mut self.wheel {
+            wheel.insert(key, expires_at);
+        }
+    }
+
+    /// Increments or decrements the number stored at `key`. Returns the new value, or `None` if
+    /// there's no such key.
+    fn arithmetic(&mut self, key: &[u8], incr: bool, delta: u64) -> Result<Option<u64>, Error> {
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let (backend, chunking) = (self.value_backend.as_deref(), self.chunking);
+        let allocates = self.data.allocates_values();
+        let result = self.data.update(key, |entry| {
+            if !entry.is_live(now, flushed) {
+                return Ok(None);
+            }
+            if entry.value.is_static() && !allocates {
+                return Err(Error::OutOfMemory);
+            }
+            let current = entry.value.contiguous();
+            let n = apply_delta(&current, incr, delta).ok_or(Error::NonNumericValue)?;
+            let value = value::keep(backend, chunking, key, n.to_string().into_bytes())?;
+            let grown = (value.len(), entry.value.len());
+            entry.value = value;
+            entry.fetched = false;
+            entry.last_access = now;
+            entry.cas = next_cas();
+            Ok(Some((n, grown)))
+        });
+        let Some((n, (new, old))) = result.transpose()?.flatten() else {
+            return Ok(None);
+        };
+        self.stats.resize_value(old, new);
+        #[cfg(feature = "journal")]
+        self.log_item(key);
+        Ok(Some(n))
+    }
+
+    /// Looks at up to `budget` more items for ones past their expiry, and removes them, so
+    /// that items nobody asks for again don't hold on to memory. Returns how many were removed.
+    ///
+    /// Meant to be called on a timer. Each call carries on from the storage's cursor where the
+    /// last left off, however far into the items that is, and wraps around at the end, so
+    /// every item is looked at once per `len / budget` calls or so. Items inserted or removed
+    /// in between may be looked at twice or missed for a round, never more. With
+    /// [`Settings::expiry_index`], only items due are looked at, in order of expiry, until
+    /// there are items left over from a flush. Flushed items are removed along with expired
+    /// ones. Does nothing while `lru_crawler metadump` or `stats sizes` walks the items.
+    pub fn tick(&mut self, mut budget: usize) -> usize {
+        if self.walks_map() {
+            return 0;
+        }
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let mut reclaimed = 0;
+        if let Some(wheel) = &mut self.wheel {
+            let due = wheel.due(now, budget);
+            budget -= due.len();
+            for key in due {
+                // It may have been reclaimed already, or stored again by another handler.
+                if self.data.read(&key, |entry| entry.is_gone(now, flushed)) == Some(true) {
+                    Self::reclaim(&mut self.data, &mut self.stats, now, &key);
+                    reclaimed += 1;
+                }
+            }
+            if self.stats.generation.left() == 0 {
+                return reclaimed;
+            }
+        }
+        let mut seen = 0;
+        let mut expired = Vec::new();
+        let flow = self.data.scan_from(&mut self.sweep_cursor, |key, entry| {
+            if seen == budget {
+                return ControlFlow::Break(());
+            }
+            seen += 1;
+            if entry.is_gone(now, flushed) {
+                expired.push(key.to_vec());
+            }
+            ControlFlow::Continue(())
+        });
+        if flow.is_continue() {
+            self.sweep_cursor = Default::default();
+        }
+        for key in &expired {
+            Self::reclaim(&mut self.data, &mut self.stats, now, key);
+        }
+        reclaimed + expired.len()
+    }
+
+    /// Gets, hits, sets and deletes per key prefix, as `stats detail dump` reports them, while
+    /// [`Settings::detail_enabled`] or `stats detail on`. Keys without
+    /// [`Settings::detail_delimiter`] aren't counted.
+    ///
+    /// Prefixes past [`Settings::detail_max_prefixes`] are counted together, in
+    /// [`prefix_stats_other`](Self::prefix_stats_other).
+    pub fn prefix_stats(&self) -> impl Iterator<Item = (&[u8], PrefixCounters)> {
+        self.stats.detail.iter()
+    }
+
+    /// Counters for the prefixes past [`Settings::detail_max_prefixes`].
+    pub fn prefix_stats_other(&self) -> PrefixCounters {
+        self.stats.detail.other()
+    }
+
+    /// How much memory the items take, as `stats` reports it, counting only what this handler
+    /// stored and removed.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.stats.memory()
+    }
+
+    /// Drops every item. Unless the storage says otherwise, it takes no longer however many
+    /// there are: they're no longer served or counted from now on, and removed a few at a time,
+    /// as they're come across and by [`tick`](Self::tick).
+    pub fn flush_all(&mut self) {
+        if self.data.flush_lazily() {
+            self.stats.generation.flush(self.data.len());
+            self.stats.curr_items = 0;
+            self.stats.curr_tombstones = 0;
+            self.stats.key_bytes = 0;
+            self.stats.value_bytes = 0;
+        } else {
+            if let Some(on_removal) = &self.stats.on_removal {
+                let now = self.clock.now();
+                let flushed = self.stats.generation.flushed_before();
+                let _ = self.data.scan(|key, entry| {
+                    let cause = if entry.cas >= flushed && entry.is_expired(now) {
+                        RemovalCause::Expired
+                    } else {
+                        RemovalCause::Flushed
+                    };
+                    on_removal.removed(key, entry, cause);
+                    ControlFlow::<()>::Continue(())
+                });
+            }
+            self.clear_items();
+        }
+        #[cfg(feature = "journal")]
+        if let Some(journal) = &self.journal {
+            journal.append(crate::journal::Record::Clear);
+        }
+    }
+
+    /// Drops every item once `delay` is up, an exptime as a store takes: right away for 0, or
+    /// once an item stored now with it would have expired. Replaces a flush still waiting.
+    fn flush_after(&mut self, delay: i64) {
+        match expires_at(delay, self.clock.now()) {
+            Some(0) | None => {
+                self.flush_at = None;
+                self.flush_all();
+            }
+            at => self.flush_at = at,
+        }
+    }
+
+    /// Removes every item there and then.
+    fn clear_items(&mut self) {
+        self.data.clear();
+        if let Some(wheel) = &mut self.wheel {
+            wheel.clear();
+        }
+        self.stats.generation.cleared();
+        self.stats.curr_items = 0;
+        self.stats.curr_tombstones = 0;
+        self.stats.key_bytes = 0;
+        self.stats.value_bytes = 0;
+    }
+
+    /// Evicts up to `max` items while over the memory limit. Returns how many were evicted.
+    fn evict(&mut self, max: usize) -> usize {
+        if self.walks_map() {
+            return 0;
+        }
+        let mut evicted = 0;
+        while evicted < max && self.stats.bytes() > self.memory_limit && self.evict_one() {
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Evicts an item, unless there are none. Mustn't be called while walking the map.
+    ///
+    /// There's no LRU yet, so which item goes is arbitrary.
+    fn evict_one(&mut self) -> bool {
+        let first = self.data.scan(|key, _| ControlFlow::Break(key.to_vec()));
+        let ControlFlow::Break(key) = first else {
+            return false;
+        };
+        let old = self.data.remove(&key).expect("evicting a listed key");
+        let flushed = old.cas < self.stats.generation.flushed_before();
+        self.forget(&key, &old);
+        #[cfg(feature = "journal")]
+        self.log_item(&key);
+        let now = self.clock.now();
+        Self::removed(
+            &self.data,
+            &self.stats,
+            now,
+            &key,
+            &old,
+            RemovalCause::Evicted,
+        );
+        if !flushed && !old.tombstone {
+            self.stats.counters.evictions += 1;
+            self.stats.class(S::class_of(&old)).evicted += 1;
+            self.stats.publish(now, EventKind::Evict, &key);
+        }
+        true
+    }
+
+    /// Moves a slab page from class `src` to class `dst`, counting the items evicted from it
+    /// as [`evict_one`](Self::evict_one) counts its own. Not while walking the map, which
+    /// would miss them.
+    fn reassign(&mut self, src: Option<u32>, dst: u32) -> Result<(), ReassignError> {
+        if self.walks_map() {
+            return Err(ReassignError::Busy);
+        }
+        let now = self.clock.now();
+        let (stats, wheel) = (&mut self.stats, &mut self.wheel);
+        #[cfg(feature = "journal")]
+        let mut gone = Vec::new();
+        let moved = self.data.reassign(src, dst, |key, old| {
+            let flushed = old.cas < stats.generation.flushed_before();
+            Self::forgotten(stats, wheel, key, old);
+            Self::tell_removed(stats, now, key, old, RemovalCause::Evicted);
+            if !flushed && !old.tombstone {
+                stats.counters.evictions += 1;
+                stats.class(S::class_of(old)).evicted += 1;
+                stats.publish(now, EventKind::Evict, key);
+            }
+            #[cfg(feature = "journal")]
+            gone.push(Box::<[u8]>::from(key));
+        });
+        self.stats.count_items(self.data.len());
+        #[cfg(feature = "journal")]
+        for key in gone {
+            self.log_item(&key);
+        }
+        moved
+    }
+
+    /// Carries out a request, returning the state that answers it.
+    fn execute(&mut self, request: Request<'_, KEY_LEN>) -> State<KEY_LEN> {
+        match request {
+            Request::Get { key, last } => {
+                self.read_through(&key);
+                let now = self.clock.now();
+                let cas = self.line_cas;
+                let hit = Self::lookup(&mut self.data, &mut self.stats, now, &key, |entry| {
+                    let mut header = Box::new(MetaHeader::new());
+                    header.extend_from_slice(b"VALUE ").expect("header fits");
+                    header.extend_from_slice(&key).expect("header fits");
+                    write!(header, " {} {}", entry.flags, entry.value.len()).expect("header fits");
+                    if cas {
+                        write!(header, " {}", entry.cas).expect("header fits");
+                    }
+                    header.extend_from_slice(b"\r\n").expect("header fits");
+                    let trailer: &[u8] = if last { b"\r\nEND\r\n" } else { b"\r\n" };
+                    State::SendingHeader {
+                        header,
+                        sent: 0,
+                        value: Some((entry.value.expanded(), trailer)),
+                    }
+                });
+                match hit {
+                    Some(state) => {
+                        if let Some(exptime) = self.line_exptime {
+                            // The state holds on to the value, so it's sent this once even if
+                            // the gat expires the item.
+                            self.touch(&key, exptime);
+                        }
+                        self.get_continues = !last;
+                        state
+                    }
+                    None if last => State::SendingEnd {
+                        remaining: b"END\r\n",
+                    },
+                    // A miss: on to the next key.
+                    None => State::reading_keys(),
+                }
+            }
+            Request::Store { store, value } => match (self.store(&store, value), &store.meta) {
+                (Err(error), _) => State::error(error),
+                (Ok(true), Some(meta)) => meta.reply(b"HD", true, &store.key),
+                (Ok(false), Some(meta)) => meta.reply(b"NS", false, &store.key),
+                (_, None) if store.noreply => Default::default(),
+                (Ok(true), None) => State::SendingReply {
+                    remaining: b"STORED\r\n",
+                },
+                (Ok(false), None) => State::SendingReply {
+                    remaining: b"NOT_STORED\r\n",
+                },
+            },
+            Request::Delta { incr, key, args } => self.execute_delta(incr, key, args),
+            Request::Touch { key, args } => self.execute_touch(key, args),
+            Request::Delete { key, args } => self.execute_delete(key, args),
+            Request::WithArgs { cmd, args } => self.execute_with_args(cmd, args),
+            Request::Meta { cmd, args } => self.execute_meta(cmd, args),
+        }
+    }
+
+    /// Starts reading the data block of a classic storage command, given the arguments after
+    /// its key.
+    fn execute_store(
+        &self,
+        mode: StoreMode,
+        key: Result<Box<heapless::Vec<u8, KEY_LEN>>, Error>,
+        args: &[u8],
+    ) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        let (Some(flags), Some(exptime), Some(len)) = (tokens.next(), tokens.next(), tokens.next())
+        else {
+            return State::error(Error::MissingArgument);
+        };
+        let len = match parse_len(len) {
+            Ok(len) => len,
+            // There's no telling how long the data block is, or whether one was sent, so the
+            // next line is read as a command, as upstream does.
+            Err(error) => return State::error(error),
+        };
+        // The data block has to be read even if the rest of the line is bad.
+        let noreply = parse_noreply(tokens);
+        let store = match (key, parse_number(flags), parse_exptime(exptime), noreply) {
+            (Err(error), ..) => Err(error),
+            (Ok(key), Some(flags), Some(exptime), Ok(noreply)) => Ok(Store {
+                mode,
+                key: *key,
+                flags,
+                stale: false,
+                exptime,
+                noreply,
+                meta: None,
+            }),
+            _ => Err(Error::BadCommandLine),
+        };
+        let store = store.and_then(|store| self.check_item_size(store, len));
+        let value = self.incoming(&store, len);
+        State::reading_value(store, value, len)
+    }
+
+    /// Turns away a store whose item would exceed `item_size_max`. `len` is as the client
+    /// sent it, so may be anything up to `usize::MAX`.
+    fn check_item_size(&self, store: Store<KEY_LEN>, len: usize) -> Result<Store<KEY_LEN>, Error> {
+        let size = len.saturating_add(store.key.len() + ITEM_OVERHEAD);
+        if size > self.item_size_max {
+            return Err(Error::TooLarge {
+                size,
+                limit: self.item_size_max,
+            });
+        }
+        Ok(store)
+    }
+
+    /// Runs `incr` or `decr`, answering with the new value.
+    fn execute_delta(&mut self, incr: bool, key: &[u8], args: &[u8]) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        let Some(delta) = tokens.next() else {
+            return State::error(Error::MissingArgument);
+        };
+        let (delta, noreply) = match (parse_delta(delta), parse_noreply(tokens)) {
+            (Ok(delta), Ok(noreply)) => (delta, noreply),
+            (Err(error), _) | (_, Err(error)) => return State::error(error),
+        };
+        let n = match self.arithmetic(key, incr, delta) {
+            Ok(_) if noreply => return Default::default(),
+            Ok(Some(n)) => n,
+            Ok(None) => {
+                return State::SendingReply {
+                    remaining: b"NOT_FOUND\r\n",
+                }
+            }
+            Err(error) => return State::error(error),
+        };
+        let mut header = Box::new(MetaHeader::new());
+        write!(header, "{}\r\n", n).expect("formatting header");
+        State::SendingHeader {
+            header,
+            sent: 0,
+            value: None,
+        }
+    }
+
+    /// Runs `touch`, answering whether there was an item to touch.
+    fn execute_touch(&mut self, key: &[u8], args: &[u8]) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        let Some(exptime) = tokens.next() else {
+            return State::error(Error::MissingArgument);
+        };
+        let (exptime, noreply) = match (parse_exptime(exptime), parse_noreply(tokens)) {
+            (Some(exptime), Ok(noreply)) => (exptime, noreply),
+            (None, _) => return State::error(Error::InvalidExptime),
+            (_, Err(error)) => return State::error(error),
+        };
+        let touched = self.touch(key, exptime);
+        State::SendingReply {
+            remaining: match touched {
+                _ if noreply => return Default::default(),
+                true => b"TOUCHED\r\n",
+                false => b"NOT_FOUND\r\n",
+            },
+        }
+    }
+
+    /// Runs `delete`, answering whether there was an item to delete. Like upstream, a `0` is
+    /// let through where the time to hold the key off for used to go.
+    fn execute_delete(&mut self, key: &[u8], args: &[u8]) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty())
+            .peekable();
+        tokens.next_if(|&token| token == b"0");
+        let noreply = match parse_noreply(tokens) {
+            Ok(noreply) => noreply,
+            Err(error) => return State::error(error),
+        };
+        let deleted = self.delete(key);
+        State::SendingReply {
+            remaining: match deleted {
+                _ if noreply => return Default::default(),
+                true => b"DELETED\r\n",
+                false => b"NOT_FOUND\r\n",
+            },
+        }
+    }
+
+    fn execute_with_args(&mut self, cmd: CommandWithArgs, args: &[u8]) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        match cmd {
+            CommandWithArgs::MetaNoop => State::SendingReply {
+                remaining: b"MN\r\n",
+            },
+            CommandWithArgs::Watch => {
+                let mut topics = Topics::default();
+                for token in tokens {
+                    match Topics::parse(token) {
+                        Some(topic) => topics.insert(topic),
+                        None => return State::error(Error::BadArgument),
+                    }
+                }
+                if topics == Topics::default() {
+                    topics = Topics::FETCHERS;
+                }
+                let mut line = Box::new(heapless::Vec::new());
+                line.extend_from_slice(b"OK\r\n").expect("formatting reply");
+                State::Watching {
+                    watcher: self.stats.events.subscribe(topics),
+                    line,
+                    sent: 0,
+                }
+            }
+            CommandWithArgs::CacheMemlimit => {
+                let Some(megabytes) = tokens.next().and_then(parse_number::<u64>) else {
+                    return State::error(Error::BadArgument);
+                };
+                let noreply = match tokens.next() {
+                    None => false,
+                    Some(b"noreply") => true,
+                    Some(_) => return State::error(Error::BadArgument),
+                };
+                // Shrinking evicts gradually over the following polls.
+                self.memory_limit = megabytes.saturating_mul(1024 * 1024);
+                if noreply {
+                    Default::default()
+                } else {
+                    State::SendingReply {
+                        remaining: b"OK\r\n",
+                    }
+                }
+            }
+            // Like upstream, whatever follows on the line is ignored, and there's no reply.
+            CommandWithArgs::Quit => {
+                self.closing = true;
+                State::default()
+            }
+            CommandWithArgs::Extension(index) => {
+                let mut out = ResponseSink::default();
+                let result = self.extensions[index].execute(&Args(args), &mut self.data, &mut out);
+                // The extension may have added or removed items.
+                self.stats.count_items(self.data.len());
+                match result {
+                    Ok(()) if out.0.is_empty() => State::default(),
+                    Ok(()) => State::SendingGetData {
+                        value: out.0.into(),
+                        sent: 0,
+                        trailer: b"",
+                    },
+                    Err(error) => State::error(error),
+                }
+            }
+            CommandWithArgs::FlushAll => {
+                let mut tokens = tokens.peekable();
+                let delay = match tokens.next_if(|&token| token != b"noreply") {
+                    None => 0,
+                    Some(token) => match parse_exptime(token) {
+                        Some(delay) => delay,
+                        None => return State::error(Error::BadCommandLine),
+                    },
+                };
+                let noreply = match parse_noreply(tokens) {
+                    Ok(noreply) => noreply,
+                    Err(error) => return State::error(error),
+                };
+                self.flush_after(delay);
+                if noreply {
+                    Default::default()
+                } else {
+                    State::SendingReply {
+                        remaining: b"OK\r\n",
+                    }
+                }
+            }
+            CommandWithArgs::Version => State::SendingReply {
+                remaining: concat!("VERSION ", env!("CARGO_PKG_VERSION"), "\r\n").as_bytes(),
+            },
+            CommandWithArgs::Slabs => match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some(b"automove"), Some(level), None) => match parse_number::<u8>(level) {
+                    // There's no automover, so the level is only validated.
+                    Some(0..=2) => State::SendingReply {
+                        remaining: b"OK\r\n",
+                    },
+                    _ => State::error(Error::BadArgument),
+                },
+                (Some(b"reassign"), Some(src), Some(dst)) if tokens.next().is_none() => {
+                    let (Some(src), Some(dst)) =
+                        (parse_number::<i32>(src), parse_number::<i32>(dst))
+                    else {
+                        return State::error(Error::BadArgument);
+                    };
+                    if src == dst {
+                        return State::SendingReply {
+                            remaining: b"SAME src and dst class are identical\r\n",
+                        };
+                    }
+                    // A `src` of -1 picks any class, as upstream's does.
+                    let src = match src {
+                        -1 => Ok(None),
+                        src => u32::try_from(src).map(Some),
+                    };
+                    let moved = match (src, u32::try_from(dst)) {
+                        (Ok(src), Ok(dst)) => self.reassign(src, dst),
+                        _ => Err(ReassignError::BadClass),
+                    };
+                    State::SendingReply {
+                        remaining: match moved {
+                            Ok(()) => b"OK\r\n",
+                            Err(ReassignError::BadClass) => {
+                                b"BADCLASS invalid src or dst class id\r\n"
+                            }
+                            Err(ReassignError::NotFull) => {
+                                b"NOTFULL source class has no spare pages\r\n"
+                            }
+                            Err(ReassignError::Busy) => {
+                                b"BUSY currently processing reassign request\r\n"
+                            }
+                        },
+                    }
+                }
+                _ => State::error(Error::BadArgument),
+            },
+            CommandWithArgs::LruCrawler => {
+                let classes = match (tokens.next(), tokens.next(), tokens.next()) {
+                    (Some(b"metadump"), Some(b"all" | b"hash"), None) => u64::MAX,
+                    (Some(b"metadump"), Some(classes), None) => {
+                        let mut dump = 0;
+                        for class in classes.split(|&c| c == b',') {
+                            match parse_number::<u32>(class) {
+                                Some(class) => dump |= 1u64.checked_shl(class).unwrap_or(0),
+                                None => return State::error(Error::BadArgument),
+                            }
+                        }
+                        dump
+                    }
+                    _ => return State::error(Error::BadArgument),
+                };
+                // A connection runs one dump at a time. Others may dump meanwhile.
+                if self.walks_map() {
+                    return State::SendingReply {
+                        remaining: b"BUSY currently processing crawler request\r\n",
+                    };
+                }
+                if classes == 0 {
+                    return State::SendingEnd {
+                        remaining: b"END\r\n",
+                    };
+                }
+                State::Dumping {
+                    line: Default::default(),
+                    sent: 0,
+                    classes,
+                    started: false,
+                }
+            }
+            CommandWithArgs::Shutdown if !self.enable_shutdown => State::SendingReply {
+                remaining: b"ERROR: shutdown not enabled\r\n",
+            },
+            CommandWithArgs::Shutdown => {
+                let mode = match (tokens.next(), tokens.next()) {
+                    (None, _) => Shutdown::Immediate,
+                    (Some(b"graceful"), None) => Shutdown::Graceful,
+                    _ => return State::error(Error::BadArgument),
+                };
+                self.shutdown = Some(mode);
+                // The server is going away, so this connection is done whatever the mode.
+                self.closing = true;
+                State::SendingReply {
+                    remaining: b"OK\r\n",
+                }
+            }
+            CommandWithArgs::Stats => match (tokens.next(), tokens.next(), tokens.next()) {
+                (None, ..) => State::stats(Report::General),
+                (Some(b"sizes"), None, _) => State::Sizing {
+                    sizes: BTreeMap::new(),
+                    started: false,
+                },
+                (Some(b"items"), None, _) => State::Itemizing {
+                    tally: ItemTally::default(),
+                    started: false,
+                },
+                (Some(b"settings"), None, _) => State::stats(Report::Settings {
+                    maxbytes: self.memory_limit,
+                    item_size_max: self.item_size_max,
+                }),
+                // Without slabs, there are none to report.
+                (Some(b"slabs"), None, _) => {
+                    State::stats(Report::Slabs(self.data.slab_stats().unwrap_or_default()))
+                }
+                (Some(b"sizes_enable" | b"sizes_disable"), None, _) => State::SendingReply {
+                    remaining: b"OK\r\n",
+                },
+                (Some(b"conns"), None, _) => State::stats(Report::Conns(
+                    ConnectionStats {
+                        state: self.state.name(),
+                        ..self.conn
+                    },
+                    self.clock.now(),
+                )),
+                (Some(b"detail"), Some(toggle @ (b"on" | b"off")), None) => {
+                    self.stats.detail.enabled = toggle == b"on";
+                    State::SendingReply {
+                        remaining: b"OK\r\n",
+                    }
+                }
+                (Some(b"detail"), Some(b"dump"), None) => {
+                    State::stats(Report::Detail(self.stats.detail.snapshot()))
+                }
+                (Some(b"reset"), None, _) => {
+                    self.stats.reset();
+                    State::SendingReply {
+                        remaining: b"RESET\r\n",
+                    }
+                }
+                _ => State::error(Error::BadArgument),
+            },
+        }
+    }
+}
+
+/// A stored item. Clones share its value.
+#[derive(Clone)]
+pub struct Entry {
+    flags: u32,
+    /// Shared with responses still sending it. Replaced rather than changed in place.
+    value: Bytes,
+    /// When the entry stops being served, in [`Clock`] seconds. 0 for never.
+    expires_at: u32,
+    /// Marked as out of date, but still served until someone recaches it.
+    stale: bool,
+    /// A client was handed the right to recache this entry (the meta `W` flag).
+    win_token: bool,
+    /// Served to a retrieval since it was last written.
+    fetched: bool,
+    /// Left by a delete to block `add` until it expires, rather than an item to serve. See
+    /// [`Settings::tombstone_secs`].
+    tombstone: bool,
+    /// When it was last written or served, in [`Clock`] seconds.
+    last_access: u32,
+    /// Changes whenever the entry does, to a value it never had. 0 until it's first stored.
+    cas: u64,
+}
+
+impl Entry {
+    /// An entry with no flags that never expires, such as to fill storage with before handing
+    /// it to a handler.
+    ///
+    /// ```
+    /// use incr_memcached::Entry;
+    ///
+    /// let entry = Entry::new(b"hello".to_vec());
+    /// assert_eq!((&entry.value()[..], entry.flags(), entry.len()), (&b"hello"[..], 0, 5));
+    /// ```
+    pub fn new(value: Vec<u8>) -> Self {
+        Self::from_bytes(value.into())
+    }
+
+    /// An entry whose value is read from `source` as it's sent, such as a blob in external
+    /// flash, rather than kept in memory.
+    ///
+    /// ```
+    /// use incr_memcached::Entry;
+    ///
+    /// let entry = Entry::from_source(b"in flash".to_vec());
+    /// assert_eq!((entry.len(), &entry.value()[..]), (8, &b"in flash"[..]));
+    /// ```
+    pub fn from_source(source: impl ValueSource + 'static) -> Self {
+        Self::from_bytes(Bytes::external(Box::new(source)))
+    }
+
+    /// An entry whose value is borrowed from data compiled into the program, such as a lookup
+    /// table, rather than copied to the heap. Changing the value by `append` or `incr` copies it
+    /// first, unless the storage doesn't [allocate values](Storage::allocates_values).
+    ///
+    /// ```
+    /// use incr_memcached::Entry;
+    ///
+    /// static TABLE: [u8; 4] = [1, 2, 4, 8];
+    /// assert_eq!(&Entry::from_static(&TABLE).value()[..], TABLE);
+    /// ```
+    pub fn from_static(value: &'static [u8]) -> Self {
+        Self::from_bytes(Bytes::borrowed(value))
+    }
+
+    fn from_bytes(value: Bytes) -> Self {
+        Self {
+            flags: 0,
+            value,
+            expires_at: 0,
+            stale: false,
+            win_token: false,
+            fetched: false,
+            tombstone: false,
+            last_access: 0,
+            cas: 0,
+        }
+    }
+
+    /// An entry with the client flags `set` would have stored with it.
+    ///
+    /// ```
+    /// use incr_memcached::Entry;
+    ///
+    /// assert_eq!(Entry::with_flags(b"{}".to_vec(), 2).flags(), 2);
+    /// ```
+    pub fn with_flags(value: Vec<u8>, flags: u32) -> Self {
+        Self {
+            flags,
+            ..Self::new(value)
+        }
+    }
+
+    /// An entry that stops being served at `expires_at`, in the [`Clock`] seconds of the
+    /// handlers it's served by, or never if it's 0.
+    ///
+    /// ```
+    /// use incr_memcached::{Clock, Entry};
+    ///
+    /// # struct Uptime;
+    /// # impl Clock for Uptime { fn now(&self) -> u32 { 1000 } }
+    /// let entry = Entry::with_expiry(b"soon gone".to_vec(), 0, Uptime.now() + 60);
+    /// assert!(entry.expires_at() > 0);
+    /// ```
+    pub fn with_expiry(value: Vec<u8>, flags: u32, expires_at: u32) -> Self {
+        Self {
+            expires_at,
+            ..Self::with_flags(value, flags)
+        }
+    }
+
+    /// The value, read into memory if it's kept out of it, by a [`ValueSource`].
+    pub fn value(&self) -> Cow<'_, [u8]> {
+        self.value.contiguous()
+    }
+
+    /// Copies the value from `offset` on into `out`, as much as fits, without reading the
+    /// rest of it. Returns how many bytes it copied.
+    pub fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        self.value.read(offset, out)
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// The length of the value.
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// When the entry stops being served, in [`Clock`] seconds. 0 for never.
+    pub fn expires_at(&self) -> u32 {
+        self.expires_at
+    }
+
+    /// The entry's CAS value, which every change to it makes larger. Unique among all
+    /// entries, whatever handler stored them.
+    pub fn cas(&self) -> u64 {
+        self.cas
+    }
+
+    /// When the entry was last written or served, in [`Clock`] seconds. 0 for entries
+    /// brought back from a snapshot, log or [`Arena`] and not accessed since.
+    pub fn last_access(&self) -> u32 {
+        self.last_access
+    }
+
+    /// Whether the entry was served since it was last written.
+    pub fn fetched(&self) -> bool {
+        self.fetched
+    }
+
+    fn is_expired(&self, now: u32) -> bool {
+        self.expires_at != 0 && now >= self.expires_at
+    }
+
+    /// Whether the entry has expired by `now`, or was written before the flush that left
+    /// `flushed_before`.
+    fn is_gone(&self, now: u32, flushed_before: u64) -> bool {
+        self.is_expired(now) || self.cas < flushed_before
+    }
+
+    /// Whether the entry is an item to serve: not gone, nor a tombstone.
+    fn is_live(&self, now: u32, flushed_before: u64) -> bool {
+        !self.tombstone && !self.is_gone(now, flushed_before)
+    }
+}
+
+/// Shows no more than the start of a long value, with its length.
+impl core::fmt::Debug for Entry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        const SHOWN: usize = 32;
+        let mut shown = [0; SHOWN];
+        let len = self.value.read(0, &mut shown);
+        let value = &shown[..len];
+        let mut entry = f.debug_struct("Entry");
+        if value.len() < self.value.len() {
+            entry.field(
+                "value",
+                &format_args!(
+                    "b\"{}\"... ({} bytes)",
+                    value.escape_ascii(),
+                    self.value.len()
+                ),
+            );
+        } else {
+            entry.field("value", &format_args!("b\"{}\"", value.escape_ascii()));
+        }
+        entry
+            .field("flags", &self.flags)
+            .field("expires_at", &self.expires_at)
+            .field("stale", &self.stale)
+            .field("win_token", &self.win_token)
+            .field("fetched", &self.fetched)
+            .field("tombstone", &self.tombstone)
+            .field("last_access", &self.last_access)
+            .field("cas", &self.cas)
+            .finish()
+    }
+}
+
+/// What came of a [`CommandHandler::poll`], and what the handler wants next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PollResult {
+    /// Something was sent, received or evicted, so polling again right away may do more.
+    /// Once it's false, there's no point polling before the socket becomes ready again.
+    pub progress: bool,
+    /// There's a response to send. See [`CommandHandler::needs_write`].
+    pub wants_transmit: bool,
+    /// Input is taken. See [`CommandHandler::needs_read`].
+    pub can_receive: bool,
+    /// The connection should be closed once there's nothing left to send. See
+    /// [`CommandHandler::wants_close`].
+    pub should_close: bool,
+    /// There's nothing left to send either, so the connection should be closed now. See
+    /// [`CommandHandler::is_closed`].
+    pub closed: bool,
+    /// The poll stopped at [`max_bytes_read_per_poll`](Settings::max_bytes_read_per_poll) or
+    /// [`max_bytes_written_per_poll`](Settings::max_bytes_written_per_poll) with work left,
+    /// or `stats sizes` has more items to count. Poll again once other connections have had
+    /// their turn.
+    pub more_pending: bool,
+}
+
+/// The connection a [`CommandHandler`] serves.
+pub trait Socket {
+    /// Calls `f` with the bytes received and not yet taken, or returns `None` if there are
+    /// none. `f` returns how many it took: exactly those are dequeued, like smoltcp's `recv`,
+    /// and the rest are handed over again next time.
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R>;
+    /// Calls `f` with space to send from, or returns `None` if there's none. `f` returns how
+    /// many bytes it filled.
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R>;
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Writes as much of the response being sent as fits in `buf`. Returns how much it wrote.
+    /// An empty `buf` leaves the state as it is.
+    fn write_response(&mut self, mut buf: &mut [u8]) -> usize {
+        let mut bytes_produced = 0;
+        // Arms writing a single byte, such as a space, rely on there being room for one.
+        while !buf.is_empty() {
+            info!("{:?}", self.state);
+            match &mut self.state {
+                State::SendingError { remaining, error } => {
+                    let n = core::cmp::min(buf.len(), remaining.len());
+                    if n > 0 {
+                        buf[..n].copy_from_slice(&remaining[..n]);
+                        buf = &mut buf[n..];
+                        bytes_produced += n;
+                        *remaining = &remaining[n..];
+                        if remaining.is_empty() {
+                            if let Some(observer) = &self.on_error {
+                                observer.observe(error);
+                            }
+                            self.state = Default::default();
+                        }
+                    }
+                }
+                State::SendingGetData {
+                    sent,
+                    value,
+                    trailer,
+                } => {
+                    let n = value.read(*sent, buf);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    *sent += n;
+                    // Checked even when nothing was copied, so empty values finish too.
+                    if *sent == value.len() {
+                        self.state = match trailer {
+                            [] => Default::default(),
+                            _ => State::SendingEnd { remaining: trailer },
+                        };
+                    } else if n == 0 {
+                        // The value's source came up short. Try again next poll.
+                        break;
+                    }
+                }
+                State::SendingEnd { remaining } | State::SendingReply { remaining } => {
+                    let n = core::cmp::min(buf.len(), remaining.len());
+                    if n > 0 {
+                        buf[..n].copy_from_slice(&remaining[..n]);
+                        *remaining = &remaining[n..];
+                        buf = &mut buf[n..];
+                        bytes_produced += n;
+                        if remaining.is_empty() {
+                            self.state = Default::default();
+                        }
+                    }
+                }
+                State::SendingHeader {
+                    header,
+                    sent,
+                    value,
+                } => {
+                    let remaining = &header.as_slice()[*sent..];
+                    let n = core::cmp::min(buf.len(), remaining.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    *sent += n;
+                    if *sent == header.len() {
+                        self.state = match value.take() {
+                            Some((value, trailer)) => State::SendingGetData {
+                                value,
+                                sent: 0,
+                                trailer,
+                            },
+                            None => Default::default(),
+                        };
+                    }
+                }
+                State::SendingStats {
+                    report,
+                    line,
+                    sent,
+                    next_line,
+                } => {
+                    if *sent == line.len() {
+                        line.clear();
+                        *sent = 0;
+                        if !report.write_line(&self.stats, *next_line, line) {
+                            self.state = State::SendingEnd {
+                                remaining: b"END\r\n",
+                            };
+                            continue;
+                        }
+                        *next_line += 1;
+                    }
+                    let remaining = &line.as_slice()[*sent..];
+                    let n = core::cmp::min(buf.len(), remaining.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    *sent += n;
+                }
+                #[cfg(feature = "binary")]
+                State::SendingBinary(response) => {
+                    let n = response.fill(buf);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    if response.is_done() {
+                        self.state = Default::default();
+                    }
+                }
+                #[cfg(feature = "binary")]
+                State::SendingBinaryStats(stream) => {
+                    let n = stream.fill(buf, &self.stats);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    if stream.is_done() {
+                        self.state = Default::default();
+                    }
+                }
+                State::Dumping {
+                    line,
+                    sent,
+                    classes,
+                    started,
+                } => {
+                    if !core::mem::replace(started, true) {
+                        self.walk_cursor = Default::default();
+                    }
+                    let mut send_line = |buf: &mut &mut [u8], line: &[u8], sent: &mut usize| {
+                        let remaining = &line[*sent..];
+                        let n = core::cmp::min(buf.len(), remaining.len());
+                        buf[..n].copy_from_slice(&remaining[..n]);
+                        *buf = &mut core::mem::take(buf)[n..];
+                        bytes_produced += n;
+                        *sent += n;
+                    };
+                    send_line(&mut buf, line, sent);
+                    if buf.is_empty() {
+                        break;
+                    }
+                    let now = self.clock.now();
+                    let flushed = self.stats.generation.flushed_before();
+                    let flow = self.data.scan_from(&mut self.walk_cursor, |key, entry| {
+                        // Items are only taken on while there's room to start their line.
+                        if buf.is_empty() {
+                            return ControlFlow::Break(());
+                        }
+                        // Expired items not yet reaped are as good as gone.
+                        let class = S::class_of(entry);
+                        if !entry.is_live(now, flushed) || !dumps_class(*classes, class) {
+                            return ControlFlow::Continue(());
+                        }
+                        line.clear();
+                        *sent = 0;
+                        line.extend_from_slice(b"key=").expect("formatting dump");
+                        write_url_encoded(&mut **line, key);
+                        // Like upstream, the time it expires at rather than the seconds left.
+                        let exp = match entry.expires_at {
+                            0 => -1,
+                            expires_at => i64::from(expires_at),
+                        };
+                        write!(
+                            line,
+                            " exp={} la={} cas={} fetch={} cls={} size={}\r\n",
+                            exp,
+                            entry.last_access,
+                            entry.cas,
+                            if entry.fetched { "yes" } else { "no" },
+                            class,
+                            key.len() + entry.value.len() + ITEM_OVERHEAD
+                        )
+                        .expect("formatting dump");
+                        send_line(&mut buf, line, sent);
+                        ControlFlow::Continue(())
+                    });
+                    if flow.is_continue() && *sent == line.len() {
+                        self.state = State::SendingEnd {
+                            remaining: b"END\r\n",
+                        };
+                    }
+                }
+                State::Watching {
+                    watcher,
+                    line,
+                    sent,
+                } => {
+                    if *sent == line.len() {
+                        line.clear();
+                        *sent = 0;
+                        if !watcher.write_next(&mut **line) {
+                            break;
+                        }
+                    }
+                    let remaining = &line.as_slice()[*sent..];
+                    let n = core::cmp::min(buf.len(), remaining.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    buf = &mut buf[n..];
+                    bytes_produced += n;
+                    *sent += n;
+                }
+                _ => break,
+            }
+        }
+        bytes_produced
+    }
+
+    /// Sends what it can of the pending response, then handles what the socket received.
+    pub fn poll(&mut self, s: &mut impl Socket) -> PollResult {
+        self.over_budget = false;
+        let maintained = self.maintain();
+        let transmitted = self.transmit_response(s);
+        let received = self.receive_requests(s);
+        self.poll_result(maintained || transmitted || received)
+    }
+
+    /// The sending half of [`poll`](Self::poll), for when the socket became writable. Does
+    /// nothing if there's no response to send.
+    pub fn poll_transmit(&mut self, s: &mut impl Socket) -> PollResult {
+        self.over_budget = false;
+        let maintained = self.maintain();
+        let transmitted = self.transmit_response(s);
+        self.poll_result(maintained || transmitted)
+    }
+
+    /// The receiving half of [`poll`](Self::poll), for when the socket became readable.
+    pub fn poll_receive(&mut self, s: &mut impl Socket) -> PollResult {
+        self.over_budget = false;
+        let maintained = self.maintain();
+        let received = self.receive_requests(s);
+        self.poll_result(maintained || received)
+    }
+
+    fn poll_result(&self, progress: bool) -> PollResult {
+        PollResult {
+            progress,
+            wants_transmit: self.needs_write(),
+            can_receive: self.needs_read(),
+            should_close: self.wants_close(),
+            closed: self.is_closed(),
+            more_pending: self.over_budget,
+        }
+    }
+
+    /// Work due whatever the socket is up to: a delayed flush, evicting while over the
+    /// memory limit, and counting items for `stats sizes` or `stats items`. Returns whether
+    /// anything was evicted or counted.
+    fn maintain(&mut self) -> bool {
+        if self.flush_at.is_some_and(|at| self.clock.now() >= at) && !self.walks_map() {
+            self.flush_at = None;
+            self.flush_all();
+        }
+        let evicted = self.evict(self.eviction_budget()) > 0;
+        self.size_items() || evicted
+    }
+
+    /// Counts up to [`ITEMS_SIZED_PER_POLL`] items for `stats sizes` or `stats items`, if
+    /// that's the next response to send, and sends the counts once they're all in. Returns
+    /// whether it counted.
+    fn size_items(&mut self) -> bool {
+        let state = self.responses.front_mut().unwrap_or(&mut self.state);
+        let (mut sizes, mut tally, started) = match state {
+            State::Sizing { sizes, started } => (Some(sizes), None, started),
+            State::Itemizing { tally, started } => (None, Some(tally), started),
+            _ => return false,
+        };
+        if !core::mem::replace(started, true) {
+            self.walk_cursor = Default::default();
+        }
+        let now = self.clock.now();
+        let flushed = self.stats.generation.flushed_before();
+        let mut budget = ITEMS_SIZED_PER_POLL;
+        let flow = self.data.scan_from(&mut self.walk_cursor, |key, entry| {
+            if budget == 0 {
+                return ControlFlow::Break(());
+            }
+            budget -= 1;
+            if let Some(sizes) = sizes.as_deref_mut() {
+                if entry.cas >= flushed && !entry.tombstone {
+                    Report::count_size(sizes, key.len() + entry.value.len() + ITEM_OVERHEAD);
+                }
+            } else if let Some(tally) = tally.as_deref_mut() {
+                if entry.is_live(now, flushed) {
+                    tally.count(S::class_of(entry), entry.last_access);
+                }
+            }
+            ControlFlow::Continue(())
+        });
+        if flow.is_break() {
+            self.over_budget = true;
+        } else if let Some(sizes) = sizes {
+            *state = State::stats(Report::Sizes(core::mem::take(sizes).into_iter().collect()));
+        } else if let Some(tally) = tally {
+            *state = State::stats(tally.report(now));
+        }
+        true
+    }
+
+    /// How many items to evict this poll: as many as it takes to get under the memory limit
+    /// if they're of the average size, at least [`EVICTIONS_PER_POLL`] and at most
+    /// [`MAX_EVICTIONS_PER_POLL`], so that a multi-megabyte item makes room for itself
+    /// within a poll or two.
+    fn eviction_budget(&self) -> usize {
+        let bytes = self.stats.bytes();
+        let over = bytes.saturating_sub(self.memory_limit);
+        let average = (bytes / self.stats.curr_items.max(1)).max(1);
+        usize::try_from(over.div_ceil(average))
+            .unwrap_or(usize::MAX)
+            .clamp(EVICTIONS_PER_POLL, MAX_EVICTIONS_PER_POLL)
+    }
+
+    /// Fills windows for as long as the socket takes whole ones, up to
+    /// [`max_transmits_per_poll`](Settings::max_transmits_per_poll) of them and
+    /// [`max_bytes_written_per_poll`](Settings::max_bytes_written_per_poll) bytes. Returns
+    /// whether the socket took anything. An empty window, which smoltcp hands out while its
+    /// buffer is full, doesn't count.
+    fn transmit_response(&mut self, s: &mut impl Socket) -> bool {
+        let mut transmitted = false;
+        let mut budget = self.max_bytes_written_per_poll;
+        for _ in 0..self.max_transmits_per_poll.max(1) {
+            if !self.needs_write() {
+                break;
+            }
+            if budget == 0 {
+                self.over_budget = true;
+                break;
+            }
+            let Some((bytes_produced, window)) = s.transmit(|buf| {
+                let len = core::cmp::min(buf.len(), budget);
+                let bytes_produced = self.write_responses(&mut buf[..len]);
+                (bytes_produced, (bytes_produced, buf.len()))
+            }) else {
+                break;
+            };
+            if bytes_produced == 0 {
+                break;
+            }
+            self.stats.counters.bytes_written += bytes_produced as u64;
+            self.conn.bytes_written += bytes_produced as u64;
+            self.conn.last_activity = self.clock.now();
+            transmitted = true;
+            budget -= bytes_produced;
+            if budget == 0 && self.needs_write() {
+                self.over_budget = true;
+                break;
+            }
+            if bytes_produced < window {
+                break;
+            }
+        }
+        transmitted
+    }
+
+    /// Writes the queued responses in order, then the one in `state`, as far as `buf` goes.
+    /// Returns how much it wrote.
+    fn write_responses(&mut self, buf: &mut [u8]) -> usize {
+        let mut written = 0;
+        while let Some(response) = self.responses.pop_front() {
+            // `write_response` works on `state`, so the parser's is set aside meanwhile.
+            let parser = core::mem::replace(&mut self.state, response);
+            written += self.write_response(&mut buf[written..]);
+            let response = core::mem::replace(&mut self.state, parser);
+            if response.wants_to_send() {
+                self.responses.push_front(response);
+                return written;
+            }
+        }
+        written += self.write_response(&mut buf[written..]);
+        if !self.state.wants_to_send() && core::mem::take(&mut self.get_continues) {
+            self.state = State::reading_keys();
+        }
+        written
+    }
+
+    /// Returns whether anything was taken from the socket.
+    fn receive_requests(&mut self, s: &mut impl Socket) -> bool {
+        if self.holds_input() {
+            return false;
+        }
+        s.receive(|data| {
+            let budget = self.max_bytes_read_per_poll;
+            if data.len() > budget {
+                self.over_budget = true;
+            }
+            let n = self.receive_bytes(&data[..core::cmp::min(data.len(), budget)]);
+            self.stats.counters.bytes_read += n as u64;
+            self.conn.bytes_read += n as u64;
+            self.conn.last_activity = self.clock.now();
+            (n, n)
+        })
+        .is_some_and(|n| n > 0)
+    }
+
+    /// Counts a command that isn't one, closing the connection after too many in a row.
+    fn unknown_command(&mut self) {
+        self.unknown_commands += 1;
+        if self.max_unknown_commands != 0 && self.unknown_commands >= self.max_unknown_commands {
+            warn!("Closing after {} unknown commands", self.unknown_commands);
+            self.closing = true;
+        }
+    }
+
+    /// Whether `state` holds a response, which has to be queued before the next request can be
+    /// parsed. A watching connection ignores input rather than wait.
+    fn has_response(&self) -> bool {
+        self.state.wants_to_send() && !matches!(self.state, State::Watching { .. })
+    }
+
+    /// Whether input has to wait for a response to go out, the queue being full.
+    fn holds_input(&self) -> bool {
+        self.has_response() && self.responses.len() >= self.max_pending_responses
+    }
+
+    /// Handles received bytes, queuing the responses, until the queue is full. Returns how many
+    /// bytes it took.
+    fn receive_bytes(&mut self, data: &[u8]) -> usize {
+        let mut rest = data;
+        while let Some((&c, tail)) = rest.split_first() {
+            if self.closing {
+                // Whatever follows a QUIT or a protocol violation is never answered.
+                return data.len();
+            }
+            if self.holds_input() {
+                break;
+            }
+            if self.has_response() {
+                self.responses.push_back(core::mem::take(&mut self.state));
+                if core::mem::take(&mut self.get_continues) {
+                    self.state = State::reading_keys();
+                }
+            }
+            rest = tail;
+            if let State::Detecting = self.state {
+                // The byte goes on to the chosen parser below, so nothing is lost.
+                #[cfg(feature = "binary")]
+                {
+                    self.binary = c == binary::REQUEST_MAGIC;
+                }
+                self.state = Default::default();
+            }
+            if self.reads_text_line() {
+                if let State::ReadingCommand(cmd) = &self.state {
+                    if cmd.is_empty() {
+                        self.line_len = 0;
+                        self.line_keys = 0;
+                    }
+                }
+                self.line_len += 1;
+                if self.line_len > self.max_line_len {
+                    self.line_cr = false;
+                    let error = Error::ArgsTooLong {
+                        limit: self.max_line_len,
+                    };
+                    self.state = State::error_after_line(error, c);
+                    continue;
+                }
+                if core::mem::take(&mut self.line_cr) {
+                    if c != b'\n' {
+                        // A `\r` inside a token, such as a key.
+                        self.state = State::FlushLine {
+                            error: Error::BadCommandLine,
+                        };
+                        continue;
+                    }
+                } else if c == b'\r' {
+                    self.line_cr = true;
+                    continue;
+                } else if c == b'\n' && !self.lenient_line_endings {
+                    self.state = State::error(Error::BadCommandLine);
+                    continue;
+                }
+            }
+            info!("{:?} {:?}", self.state, c as char);
+            match (&mut self.state, c) {
+                (State::Detecting, _) => unreachable!("protocol settled above"),
+                #[cfg(feature = "binary")]
+                (State::ReadingCommand(cmd), _) if self.binary && cmd.is_empty() => {
+                    self.receive_binary(c);
+                }
+                #[cfg(feature = "binary")]
+                (State::ReadingBinaryHeader { .. } | State::ReadingBinaryBody(_), _) => {
+                    self.receive_binary(c);
+                }
+                // Runs of spaces delimit tokens just like a single one.
+                (State::ReadingCommand(cmd), b' ') if cmd.is_empty() => {}
+                (State::ReadingCommand(cmd), b'\n')
+                    if cmd.is_empty() && self.ignore_empty_lines => {}
+                (State::ReadingCommand(_), b' ' | b'\n')
+                    if self.text_requires_auth && !self.authenticated =>
+                {
+                    self.state = State::error_after_line(Error::Unauthenticated, c);
+                }
+                (State::ReadingCommand(cmd), b' ' | b'\n') => {
+                    self.line_exptime = None;
+                    self.line_cas = false;
+                    if self.case_insensitive_commands {
+                        cmd.make_ascii_lowercase();
+                    }
+                    let extensions = &self.extensions;
+                    let command = Command::parse(cmd).or_else(|| {
+                        let index = extensions.iter().position(|ext| ext.matches(cmd))?;
+                        Some(Command::WithArgs(CommandWithArgs::Extension(index)))
+                    });
+                    if command.is_some() {
+                        self.unknown_commands = 0;
+                    }
+                    let cmd = match command {
+                        Some(Command::WithKey(CommandWithKey::Gets)) => {
+                            self.line_cas = true;
+                            CommandWithKey::Get
+                        }
+                        Some(Command::WithKey(CommandWithKey::Gats)) => {
+                            self.line_cas = true;
+                            CommandWithKey::Gat
+                        }
+                        Some(Command::WithKey(cmd)) => cmd,
+                        Some(Command::Meta(cmd)) => {
+                            self.state = if c == b'\n' {
+                                self.execute(Request::Meta { cmd, args: &[] })
+                            } else {
+                                State::ReadingMetaArgs {
+                                    cmd,
+                                    args: Default::default(),
+                                }
+                            };
+                            continue;
+                        }
+                        Some(Command::WithArgs(cmd)) => {
+                            self.state = if c == b'\n' {
+                                self.execute(Request::WithArgs { cmd, args: &[] })
+                            } else {
+                                State::ReadingArgs {
+                                    cmd,
+                                    args: Default::default(),
+                                }
+                            };
+                            continue;
+                        }
+                        None => {
+                            self.unknown_command();
+                            self.state = State::error_after_line(Error::UnknownCommand, c);
+                            continue;
+                        }
+                    };
+                    if c == b'\n' {
+                        self.state = State::error(Error::MissingArgument);
+                        continue;
+                    }
+                    self.state = State::ReadingKey {
+                        cmd,
+                        key: Default::default(),
+                        too_long: false,
+                    };
+                }
+                (State::ReadingCommand(cmd), _) => {
+                    if cmd.push(c).is_err() {
+                        self.unknown_command();
+                        self.state = State::FlushLine {
+                            error: Error::CommandTooLong,
+                        };
+                        continue;
+                    }
+                }
+                (State::ReadingKey { key, .. }, b' ') if key.is_empty() => {}
+                // Trailing spaces after the last key of a `get`.
+                (
+                    State::ReadingKey {
+                        cmd: CommandWithKey::Get,
+                        key,
+                        ..
+                    },
+                    b'\n',
+                ) if key.is_empty() && self.line_keys > 0 => {
+                    self.state = State::SendingEnd {
+                        remaining: b"END\r\n",
+                    };
+                }
+                (State::ReadingKey { .. }, b' ' | b'\n') => {
+                    // Taken, so the key's buffer can move on to what comes next.
+                    let State::ReadingKey { cmd, key, too_long } = core::mem::take(&mut self.state)
+                    else {
+                        unreachable!()
+                    };
+                    // We read a key, process it with the command
+                    let checked = if too_long {
+                        Err(Error::KeyTooLong { limit: KEY_LEN })
+                    } else {
+                        validate_key(&key)
+                    };
+                    match cmd {
+                        CommandWithKey::Get | CommandWithKey::Gets => {
+                            self.line_keys += 1;
+                            if self.line_keys > self.max_keys_per_get {
+                                let limit = self.max_keys_per_get;
+                                let error = Error::TooManyKeys { limit };
+                                self.state = State::error_after_line(error, c);
+                                continue;
+                            }
+                            if let Err(error) = checked {
+                                self.state = State::error_after_line(error, c);
+                                continue;
+                            }
+                            self.state = self.execute(Request::Get {
+                                key,
+                                last: c == b'\n',
+                            });
+                        }
+                        CommandWithKey::Store(mode) => {
+                            self.state = if c == b'\n' {
+                                State::error(Error::MissingArgument)
+                            } else {
+                                State::ReadingStoreArgs {
+                                    mode,
+                                    key: checked.map(|()| key),
+                                    args: Default::default(),
+                                }
+                            };
+                        }
+                        CommandWithKey::Incr | CommandWithKey::Decr | CommandWithKey::Touch => {
+                            self.state = match checked {
+                                _ if c == b'\n' => State::error(Error::MissingArgument),
+                                Err(error) => State::FlushLine { error },
+                                Ok(()) => State::ReadingKeyArgs {
+                                    cmd,
+                                    key,
+                                    args: Default::default(),
+                                },
+                            };
+                        }
+                        CommandWithKey::Delete => {
+                            self.state = match checked {
+                                Err(error) => State::error_after_line(error, c),
+                                Ok(()) if c == b'\n' => self.execute(Request::Delete {
+                                    key: &key,
+                                    args: &[],
+                                }),
+                                Ok(()) => State::ReadingKeyArgs {
+                                    cmd,
+                                    key,
+                                    args: Default::default(),
+                                },
+                            };
+                        }
+                        CommandWithKey::Gat | CommandWithKey::Gats => {
+                            self.state = match parse_exptime(&key) {
+                                _ if c == b'\n' => State::error(Error::MissingArgument),
+                                None => State::FlushLine {
+                                    error: Error::InvalidExptime,
+                                },
+                                Some(exptime) => {
+                                    self.line_exptime = Some(exptime);
+                                    State::reading_keys()
+                                }
+                            };
+                        }
+                    }
+                }
+                (State::ReadingKey { key, too_long, .. }, _) => {
+                    if key.push(c).is_err() {
+                        *too_long = true;
+                    }
+                }
+                (State::ReadingArgs { cmd, args }, b'\n') => {
+                    let cmd = *cmd;
+                    let args = core::mem::take(args);
+                    self.state = self.execute(Request::WithArgs { cmd, args: &args });
+                }
+                (State::ReadingArgs { args, .. }, _) => {
+                    if args.push(c).is_err() {
+                        self.state = State::FlushLine {
+                            error: Error::ArgsTooLong {
+                                limit: args.capacity(),
+                            },
+                        };
+                        continue;
+                    }
+                }
+                (State::ReadingStoreArgs { .. }, b'\n') => {
+                    let State::ReadingStoreArgs { mode, key, args } =
+                        core::mem::take(&mut self.state)
+                    else {
+                        unreachable!()
+                    };
+                    self.state = self.execute_store(mode, key, &args);
+                }
+                (State::ReadingStoreArgs { args, .. }, _) => {
+                    if args.push(c).is_err() {
+                        self.state = State::FlushLine {
+                            error: Error::ArgsTooLong {
+                                limit: args.capacity(),
+                            },
+                        };
+                        continue;
+                    }
+                }
+                (State::ReadingKeyArgs { .. }, b'\n') => {
+                    let State::ReadingKeyArgs { cmd, key, args } = core::mem::take(&mut self.state)
+                    else {
+                        unreachable!()
+                    };
+                    self.state = self.execute(match cmd {
+                        CommandWithKey::Touch => Request::Touch {
+                            key: &key,
+                            args: &args,
+                        },
+                        CommandWithKey::Delete => Request::Delete {
+                            key: &key,
+                            args: &args,
+                        },
+                        cmd => Request::Delta {
+                            incr: matches!(cmd, CommandWithKey::Incr),
+                            key: &key,
+                            args: &args,
+                        },
+                    });
+                }
+                (State::ReadingKeyArgs { args, .. }, _) => {
+                    if args.push(c).is_err() {
+                        self.state = State::FlushLine {
+                            error: Error::ArgsTooLong {
+                                limit: args.capacity(),
+                            },
+                        };
+                        continue;
+                    }
+                }
+                (State::ReadingMetaArgs { .. }, b'\n') => {
+                    let State::ReadingMetaArgs { cmd, args } = core::mem::take(&mut self.state)
+                    else {
+                        unreachable!()
+                    };
+                    self.state = self.execute(Request::Meta { cmd, args: &args });
+                }
+                (
+                    State::ReadingValue {
+                        store,
+                        value,
+                        remaining,
+                    },
+                    _,
+                ) => {
+                    // Values are binary, so the block is taken by count alone, and as
+                    // much of it as arrived at once. One that won't be stored is only
+                    // counted off.
+                    let n = core::cmp::min(*remaining - 1, rest.len());
+                    if store.is_ok() {
+                        // The byte already split off is just before the rest.
+                        let bytes = &data[data.len() - rest.len() - 1..][..n + 1];
+                        if let Err(error) = value.write(bytes) {
+                            *store = Err(error);
+                        }
+                    }
+                    rest = &rest[n..];
+                    *remaining -= n + 1;
+                    if *remaining == 0 {
+                        self.state = State::ReadingValueEnd {
+                            store: core::mem::replace(store, Err(Error::BadDataChunk)),
+                            value: core::mem::take(value),
+                            terminator: None,
+                        };
+                    }
+                }
+                (
+                    State::ReadingValueEnd {
+                        terminator: terminator @ None,
+                        ..
+                    },
+                    _,
+                ) if c != b'\n' || !self.lenient_line_endings => {
+                    *terminator = Some(c);
+                }
+                (
+                    State::ReadingValueEnd {
+                        store,
+                        value,
+                        terminator,
+                    },
+                    _,
+                ) => {
+                    // Like upstream, exactly two bytes are taken either way, and whatever
+                    // follows a bad pair is read as the next command.
+                    let well_formed = matches!((*terminator, c), (Some(b'\r') | None, b'\n'));
+                    let store = core::mem::replace(store, Err(Error::BadDataChunk));
+                    let value = core::mem::take(value);
+                    self.state = match store {
+                        Ok(_) if !well_formed => State::error(Error::BadDataChunk),
+                        Ok(store) => match value.finish() {
+                            Ok(value) => self.execute(Request::Store { store, value }),
+                            Err(error) => State::error(error),
+                        },
+                        Err(error) => State::error(error),
+                    };
+                }
+                (State::ReadingMetaArgs { args, .. }, _) => {
+                    if args.push(c).is_err() {
+                        self.state = State::FlushLine {
+                            error: Error::ArgsTooLong {
+                                limit: args.capacity(),
+                            },
+                        };
+                        continue;
+                    }
+                }
+                (State::SendingError { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::FlushLine { error }, c) => {
+                    self.stats.counters.discarded_bytes += 1;
+                    if c == b'\n' {
+                        self.state = State::error(*error);
+                    }
+                }
+                (State::SendingGetData { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::SendingEnd { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::SendingReply { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::SendingHeader { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::SendingStats { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                #[cfg(feature = "binary")]
+                (State::SendingBinary(_) | State::SendingBinaryStats(_), _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::Dumping { .. } | State::Sizing { .. } | State::Itemizing { .. }, _) => {
+                    error!("Skipping received data in Sending state");
+                }
+                (State::Watching { .. }, _) => {
+                    warn!("Ignoring input on a watching connection");
+                }
+            }
+        }
+        data.len() - rest.len()
+    }
+}
@@ -0,0 +1,457 @@
+//! The meta text protocol: `mg`, `ms`, `md`, `ma` and `me`.
+
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::clock::Clock;
+use crate::storage::Storage;
+use crate::{
+    base64, parse_delta, parse_exptime, parse_len, parse_number, validate_key, CommandHandler,
+    Error, State, Store, StoreMode, ITEM_OVERHEAD, MAX_META_HEADER_LEN, MAX_OPAQUE_LEN,
+};
+
+pub type MetaHeader = heapless::Vec<u8, MAX_META_HEADER_LEN>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MetaCommand {
+    Get,
+    Set,
+    Delete,
+    Arithmetic,
+    Debug,
+}
+
+/// Flags that mean the same thing on every meta command: `q`, `b`, `k` and `O`.
+#[derive(Debug, Default)]
+pub struct MetaReturn {
+    /// Suppress the nominal response (`EN` for `mg`, `HD` for the others).
+    quiet: bool,
+    base64: bool,
+    return_key: bool,
+    opaque: Option<heapless::Vec<u8, MAX_OPAQUE_LEN>>,
+}
+
+impl MetaReturn {
+    fn parse_flag(&mut self, flag: &[u8]) -> Result<(), Error> {
+        match flag.split_first() {
+            Some((b'q', b"")) => self.quiet = true,
+            Some((b'b', b"")) => self.base64 = true,
+            Some((b'k', b"")) => self.return_key = true,
+            Some((b'O', token)) => {
+                let error = Error::InvalidFlag { flag: b'O' };
+                self.opaque = Some(heapless::Vec::from_slice(token).map_err(|()| error)?)
+            }
+            _ => return Err(Error::InvalidFlag { flag: flag[0] }),
+        }
+        Ok(())
+    }
+
+    /// Turns the key token into a key, decoding it if the `b` flag is set.
+    fn key<const KEY_LEN: usize>(&self, token: &[u8]) -> Result<heapless::Vec<u8, KEY_LEN>, Error> {
+        if self.base64 {
+            let mut key = heapless::Vec::new();
+            if token.len() > base64::encoded_len(KEY_LEN) {
+                return Err(Error::KeyTooLong { limit: KEY_LEN });
+            }
+            base64::decode(token, &mut key).map_err(|()| Error::BadToken)?;
+            Ok(key)
+        } else {
+            validate_key(token)?;
+            heapless::Vec::from_slice(token).map_err(|()| Error::KeyTooLong { limit: KEY_LEN })
+        }
+    }
+
+    /// Appends the `k` and `O` return flags, in that order.
+    fn write_flags(&self, key: &[u8], header: &mut MetaHeader) -> Result<(), ()> {
+        if self.return_key {
+            header.extend_from_slice(b" k")?;
+            if self.base64 {
+                base64::encode(key, header)?;
+            } else {
+                header.extend_from_slice(key)?;
+            }
+        }
+        if let Some(opaque) = &self.opaque {
+            header.extend_from_slice(b" O")?;
+            header.extend_from_slice(opaque)?;
+        }
+        Ok(())
+    }
+
+    /// A response line consisting of `status` and the return flags. A nominal status is
+    /// suppressed in quiet mode.
+    pub fn reply<const KEY_LEN: usize>(
+        &self,
+        status: &[u8],
+        nominal: bool,
+        key: &[u8],
+    ) -> State<KEY_LEN> {
+        if nominal && self.quiet {
+            return Default::default();
+        }
+        let mut header = Box::new(MetaHeader::new());
+        let written = header
+            .extend_from_slice(status)
+            .and_then(|()| self.write_flags(key, &mut header))
+            .and_then(|()| header.extend_from_slice(b"\r\n"));
+        match written {
+            Ok(()) => State::SendingHeader {
+                header,
+                sent: 0,
+                value: None,
+            },
+            Err(()) => State::error(Error::HeaderTooLong),
+        }
+    }
+}
+
+/// Flags of `ma`.
+struct MetaArithmetic {
+    incr: bool,
+    delta: u64,
+    initial: u64,
+    /// The TTL of the item to create on a miss, if any.
+    vivify: Option<i64>,
+    with_value: bool,
+    ret: MetaReturn,
+}
+
+impl MetaArithmetic {
+    fn parse<'a>(flags: impl Iterator<Item = &'a [u8]>) -> Result<Self, Error> {
+        let mut ma = Self {
+            incr: true,
+            delta: 1,
+            initial: 0,
+            vivify: None,
+            with_value: false,
+            ret: Default::default(),
+        };
+        for flag in flags {
+            match flag.split_first() {
+                Some((b'M', b"I" | b"i" | b"+")) => ma.incr = true,
+                Some((b'M', b"D" | b"d" | b"-")) => ma.incr = false,
+                Some((b'D', n)) => ma.delta = parse_delta(n)?,
+                Some((b'J', n)) => ma.initial = parse_number(n).ok_or(Error::BadArgument)?,
+                Some((b'N', n)) => ma.vivify = Some(parse_exptime(n).ok_or(Error::BadArgument)?),
+                Some((b'v', b"")) => ma.with_value = true,
+                _ => ma.ret.parse_flag(flag)?,
+            }
+        }
+        Ok(ma)
+    }
+}
+
+/// Like memcached, the seconds an item expiring at `expires_at` has left by `now`, rather
+/// than the absolute expiry, and -1 for one that never expires.
+fn ttl_left(expires_at: u32, now: u32) -> i64 {
+    match expires_at {
+        0 => -1,
+        expires_at => i64::from(expires_at.saturating_sub(now)),
+    }
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Parses the flags of `ms`. Only mode, client flags, TTL and invalidation are understood so
+    /// far.
+    fn meta_store<'a>(
+        key: &[u8],
+        flags: impl Iterator<Item = &'a [u8]>,
+    ) -> Result<Store<KEY_LEN>, Error> {
+        let mut mode = StoreMode::Set;
+        let mut client_flags = 0;
+        let mut stale = false;
+        let mut exptime = 0;
+        let mut ret = MetaReturn::default();
+        for flag in flags {
+            match flag.split_first() {
+                Some((b'F', n)) => client_flags = parse_number(n).ok_or(Error::BadArgument)?,
+                Some((b'I', b"")) => stale = true,
+                Some((b'T', n)) => exptime = parse_exptime(n).ok_or(Error::BadArgument)?,
+                Some((b'M', m)) => {
+                    mode = match m {
+                        b"S" | b"s" => StoreMode::Set,
+                        b"E" | b"e" => StoreMode::Add,
+                        b"R" | b"r" => StoreMode::Replace,
+                        b"A" | b"a" => StoreMode::Append,
+                        b"P" | b"p" => StoreMode::Prepend,
+                        _ => return Err(Error::InvalidFlag { flag: b'M' }),
+                    }
+                }
+                _ => ret.parse_flag(flag)?,
+            }
+        }
+        Ok(Store {
+            mode,
+            key: ret.key(key)?,
+            flags: client_flags,
+            stale,
+            exptime,
+            noreply: false,
+            meta: Some(ret),
+        })
+    }
+
+    pub(crate) fn execute_meta(&mut self, cmd: MetaCommand, args: &[u8]) -> State<KEY_LEN> {
+        let mut tokens = args
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|token| !token.is_empty());
+        let Some(key) = tokens.next() else {
+            return State::error(Error::MissingArgument);
+        };
+        if let MetaCommand::Set = cmd {
+            // The data block has to be read even if the rest of the line is bad.
+            return match tokens.next().map(parse_len) {
+                Some(Ok(len)) => {
+                    let store = Self::meta_store(key, tokens)
+                        .and_then(|store| self.check_item_size(store, len));
+                    let value = self.incoming(&store, len);
+                    State::reading_value(store, value, len)
+                }
+                // There's no telling how long the data block is, so skip a line of it.
+                Some(Err(error)) => State::FlushLine { error },
+                None => State::error(Error::BadArgument),
+            };
+        }
+        self.meta(cmd, key, tokens).unwrap_or_else(State::error)
+    }
+
+    fn meta<'a>(
+        &mut self,
+        cmd: MetaCommand,
+        key: &[u8],
+        flags: impl Iterator<Item = &'a [u8]> + Clone,
+    ) -> Result<State<KEY_LEN>, Error> {
+        match cmd {
+            MetaCommand::Get => {
+                let mut ret = MetaReturn::default();
+                let mut with_value = false;
+                // The TTL of the stub to create on a miss, if any.
+                let mut vivify = None;
+                // Seconds left below which an item is to be recached.
+                let mut recache = None;
+                for flag in flags.clone() {
+                    match flag.split_first() {
+                        Some((b'v', b"")) => with_value = true,
+                        Some((b'f' | b't' | b's' | b'h' | b'l', b"")) => {}
+                        Some((b'N', n)) => {
+                            vivify = Some(parse_exptime(n).ok_or(Error::BadArgument)?)
+                        }
+                        Some((b'R', n)) => {
+                            recache = Some(parse_number::<u32>(n).ok_or(Error::BadArgument)?)
+                        }
+                        _ => ret.parse_flag(flag)?,
+                    }
+                }
+                let key = ret.key::<KEY_LEN>(key)?;
+                self.read_through(&key);
+                let now = self.clock.now();
+                let hit = Self::lookup(&mut self.data, &mut self.stats, now, &key, |entry| {
+                    // The first client to see a stale entry, or one about to expire, wins the
+                    // right to recache it.
+                    let expiring = recache.is_some_and(|secs| {
+                        entry.expires_at != 0 && entry.expires_at.saturating_sub(now) < secs
+                    });
+                    let won = (entry.stale || expiring) && !entry.win_token;
+                    let already_won = entry.win_token;
+                    entry.win_token |= won;
+                    let entry = &*entry;
+                    let mut header = Box::new(MetaHeader::new());
+                    let write_header = || -> core::fmt::Result {
+                        if with_value {
+                            write!(header, "VA {}", entry.value.len())?;
+                        } else {
+                            write!(header, "HD")?;
+                        }
+                        // Returned flags are echoed in the order they were requested.
+                        for flag in flags {
+                            match flag {
+                                b"f" => write!(header, " f{}", entry.flags)?,
+                                b"t" => write!(header, " t{}", ttl_left(entry.expires_at, now))?,
+                                b"s" => write!(header, " s{}", entry.value.len())?,
+                                // As they were before this fetch.
+                                b"h" => write!(header, " h{}", u8::from(entry.fetched))?,
+                                b"l" => {
+                                    write!(header, " l{}", now.saturating_sub(entry.last_access))?
+                                }
+                                _ => {}
+                            }
+                        }
+                        if won {
+                            write!(header, " W")?;
+                        }
+                        if entry.stale {
+                            write!(header, " X")?;
+                        }
+                        if already_won {
+                            write!(header, " Z")?;
+                        }
+                        ret.write_flags(&key, &mut header)
+                            .map_err(|()| core::fmt::Error)?;
+                        write!(header, "\r\n")
+                    };
+                    write_header().map_err(|_| Error::HeaderTooLong)?;
+                    Ok(State::SendingHeader {
+                        header,
+                        sent: 0,
+                        value: with_value.then(|| (entry.value.expanded(), b"\r\n".as_slice())),
+                    })
+                });
+                if let Some(state) = hit {
+                    return state;
+                }
+                let Some(exptime) = vivify else {
+                    return Ok(ret.reply(b"EN", true, &key));
+                };
+                // Create an empty stub so that everyone else waits for the winner to fill it,
+                // or for it to expire if the winner never does.
+                let store = Store {
+                    mode: StoreMode::Add,
+                    key: key.clone(),
+                    flags: 0,
+                    stale: false,
+                    exptime,
+                    noreply: false,
+                    meta: Default::default(),
+                };
+                // A tombstone there turns the stub away, and with it the right to recache.
+                if !self.store(&store, Vec::new().into())? {
+                    return Ok(ret.reply(b"EN", true, &key));
+                }
+                self.data
+                    .update(key.as_slice(), |entry| entry.win_token = true);
+                Ok(ret.reply(b"EN W", false, &key))
+            }
+            MetaCommand::Set => unreachable!("ms is handled by execute_meta"),
+            MetaCommand::Arithmetic => {
+                let ma = MetaArithmetic::parse(flags)?;
+                let key = ma.ret.key(key)?;
+                match (self.arithmetic(&key, ma.incr, ma.delta)?, ma.vivify) {
+                    (Some(_), _) => {}
+                    (None, Some(exptime)) => {
+                        let store = Store {
+                            mode: StoreMode::Add,
+                            key: key.clone(),
+                            flags: 0,
+                            stale: false,
+                            exptime,
+                            noreply: false,
+                            meta: Default::default(),
+                        };
+                        let value = self.keep_value(&key, ma.initial.to_string().into_bytes())?;
+                        if !self.store(&store, value)? {
+                            return Ok(ma.ret.reply(b"NS", false, &key));
+                        }
+                    }
+                    (None, None) => return Ok(ma.ret.reply(b"NF", false, &key)),
+                }
+                if !ma.with_value {
+                    return Ok(ma.ret.reply(b"HD", true, &key));
+                }
+                // Not stored after all if the new item is already expired, say.
+                let Some(value) = self
+                    .data
+                    .read(key.as_slice(), |entry| entry.value.expanded())
+                else {
+                    return Ok(ma.ret.reply(b"NF", false, &key));
+                };
+                let mut header = Box::new(MetaHeader::new());
+                let mut write_header = || {
+                    write!(header, "VA {}", value.len())?;
+                    ma.ret
+                        .write_flags(&key, &mut header)
+                        .map_err(|()| core::fmt::Error)?;
+                    write!(header, "\r\n")
+                };
+                write_header().map_err(|_| Error::HeaderTooLong)?;
+                Ok(State::SendingHeader {
+                    header,
+                    sent: 0,
+                    value: Some((value, b"\r\n")),
+                })
+            }
+            MetaCommand::Debug => {
+                let mut ret = MetaReturn::default();
+                for flag in flags {
+                    match flag {
+                        b"b" => ret.parse_flag(flag)?,
+                        _ => return Err(Error::InvalidFlag { flag: flag[0] }),
+                    }
+                }
+                let key = ret.key::<KEY_LEN>(key)?;
+                let now = self.clock.now();
+                let flushed = self.stats.generation.flushed_before();
+                let found = self.data.read(key.as_slice(), |entry| {
+                    entry.is_live(now, flushed).then(|| {
+                        let la = now.saturating_sub(entry.last_access);
+                        let fetch = if entry.fetched { "yes" } else { "no" };
+                        let class = S::class_of(entry);
+                        (
+                            entry.expires_at,
+                            la,
+                            entry.cas,
+                            fetch,
+                            class,
+                            entry.value.len(),
+                        )
+                    })
+                });
+                let Some((expires_at, la, cas, fetch, class, value_len)) = found.flatten() else {
+                    return Ok(ret.reply(b"EN", false, &key));
+                };
+                let exp = ttl_left(expires_at, now);
+                let mut header = Box::new(MetaHeader::new());
+                let mut write_header = || {
+                    write!(header, "ME ")?;
+                    if ret.base64 {
+                        base64::encode(&key, &mut header).map_err(|()| core::fmt::Error)?;
+                    } else {
+                        header
+                            .extend_from_slice(&key)
+                            .map_err(|()| core::fmt::Error)?;
+                    }
+                    let size = key.len() + value_len + ITEM_OVERHEAD;
+                    write!(
+                        header,
+                        " exp={} la={} cas={} fetch={} cls={} size={}\r\n",
+                        exp, la, cas, fetch, class, size
+                    )
+                };
+                write_header().map_err(|_| Error::HeaderTooLong)?;
+                Ok(State::SendingHeader {
+                    header,
+                    sent: 0,
+                    value: None,
+                })
+            }
+            MetaCommand::Delete => {
+                let mut ret = MetaReturn::default();
+                let mut invalidate = false;
+                // Only applies to invalidated items, which are to be recached soon.
+                let mut exptime = None;
+                for flag in flags {
+                    match flag.split_first() {
+                        Some((b'I', b"")) => invalidate = true,
+                        Some((b'T', n)) => {
+                            exptime = Some(parse_exptime(n).ok_or(Error::BadArgument)?)
+                        }
+                        _ => ret.parse_flag(flag)?,
+                    }
+                }
+                let key = ret.key::<KEY_LEN>(key)?;
+                let found = if invalidate {
+                    self.invalidate(&key, exptime)
+                } else {
+                    self.delete(&key)
+                };
+                // Quiet mode only hides successes; misses are still reported.
+                Ok(if found {
+                    ret.reply(b"HD", true, &key)
+                } else {
+                    ret.reply(b"NF", false, &key)
+                })
+            }
+        }
+    }
+}
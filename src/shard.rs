@@ -0,0 +1,215 @@
+//! Storage split into shards behind locks of their own, so that handlers on several threads
+//! only wait for each other when their keys land in the same shard.
+
+use alloc::sync::Arc;
+use core::hash::BuildHasher;
+use core::ops::ControlFlow;
+use std::collections::hash_map::RandomState;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::storage::Storage;
+use crate::{Entry, ReassignError, SlabStats};
+
+/// Storage made of a power of two of shards, each a storage of its own, such as [`Slabs`]
+/// over a map, with its own lock and its own evictions. A key's hash picks its shard. Clones
+/// share the shards, so give every handler one, along with the same
+/// [`Generation`](crate::Generation) and [`EventBus`](crate::EventBus).
+///
+/// `flush_all` marks every shard's items flushed at once, as it does any storage's, if the
+/// shards flush lazily. Otherwise it clears them one after the other, and an item stored in a
+/// shard already cleared meanwhile survives it. `lru_crawler metadump` walks the shards in
+/// turn, and sizes, capacities and `stats slabs` add up those of every shard.
+///
+/// [`Slabs`]: crate::Slabs
+///
+/// ```
+/// use std::collections::HashMap;
+/// use incr_memcached::{CommandHandler, Entry, Settings, Sharded, SystemClock};
+///
+/// let storage = Sharded::new(4, HashMap::new);
+/// let settings = Settings::default();
+/// let mut a = CommandHandler::with_settings(storage.clone(), SystemClock, settings.clone());
+/// let b = CommandHandler::with_settings(storage, SystemClock, settings);
+/// a.insert(b"key", Entry::new(b"value".to_vec())).unwrap();
+/// assert_eq!(&b.get(b"key").unwrap().value()[..], b"value");
+/// ```
+#[derive(Debug)]
+pub struct Sharded<S, H = RandomState> {
+    shards: Arc<[Mutex<S>]>,
+    hasher: Arc<H>,
+}
+
+impl<S> Sharded<S> {
+    /// `shards` shards, each made by `make`. Panics unless `shards` is a power of two.
+    pub fn new(shards: usize, make: impl FnMut() -> S) -> Self {
+        Self::with_hasher(shards, make, RandomState::new())
+    }
+}
+
+impl<S, H: BuildHasher> Sharded<S, H> {
+    /// Like [`new`](Self::new), picking shards by `hasher`'s hashes.
+    pub fn with_hasher(shards: usize, mut make: impl FnMut() -> S, hasher: H) -> Self {
+        assert!(shards.is_power_of_two(), "shards must be a power of two");
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(make())).collect(),
+            hasher: Arc::new(hasher),
+        }
+    }
+
+    /// Locks the shard `key` is in.
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, S> {
+        let index = self.hasher.hash_one(key) as usize & (self.shards.len() - 1);
+        lock(&self.shards[index])
+    }
+
+    /// Locks every shard in turn, for as long as `f` keeps going.
+    fn each<B>(&self, mut f: impl FnMut(&mut S) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.shards.iter().try_for_each(|shard| f(&mut lock(shard)))
+    }
+
+    /// Calls `f` with each shard in turn, such as to report on them one by one.
+    pub fn for_each_shard(&self, mut f: impl FnMut(&S)) {
+        let _ = self.each(|shard| {
+            f(shard);
+            ControlFlow::<()>::Continue(())
+        });
+    }
+}
+
+impl<S, H> Clone for Sharded<S, H> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+fn lock<S>(shard: &Mutex<S>) -> MutexGuard<'_, S> {
+    shard.lock().expect("shard poisoned")
+}
+
+impl<S: Storage, H: BuildHasher> Storage for Sharded<S, H> {
+    type Cursor = (usize, S::Cursor);
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.shard(key).read(key, f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.shard(key).update(key, f)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        self.shard(key).insert(key, entry)
+    }
+
+    fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+        self.shard(key).try_insert(key, entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.shard(key).remove(key)
+    }
+
+    fn len(&self) -> usize {
+        let mut len = 0;
+        self.for_each_shard(|shard| len += shard.len());
+        len
+    }
+
+    fn clear(&mut self) {
+        let _ = self.each(|shard| {
+            shard.clear();
+            ControlFlow::<()>::Continue(())
+        });
+    }
+
+    /// The sum of the shards', if they all have one. Items don't spread perfectly evenly, so a
+    /// shard may fill up before this many are stored.
+    fn capacity(&self) -> Option<usize> {
+        let mut capacity = Some(0);
+        self.for_each_shard(|shard| {
+            capacity = capacity.zip(shard.capacity()).map(|(sum, n)| sum + n);
+        });
+        capacity
+    }
+
+    fn class_of(entry: &Entry) -> u32 {
+        S::class_of(entry)
+    }
+
+    /// Moves a page in every shard that can spare one, as the shards' pages are their own.
+    /// Fails as the first shard did if none could.
+    fn reassign(
+        &mut self,
+        src: Option<u32>,
+        dst: u32,
+        mut evicted: impl FnMut(&[u8], &Entry),
+    ) -> Result<(), ReassignError> {
+        let mut moved = None;
+        let _ = self.each(|shard| {
+            let result = shard.reassign(src, dst, &mut evicted);
+            if moved.is_none() || result.is_ok() {
+                moved = Some(result);
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        moved.unwrap_or(Err(ReassignError::BadClass))
+    }
+
+    /// The shards' classes added up, if any of them has slabs.
+    fn slab_stats(&self) -> Option<SlabStats> {
+        let mut total: Option<SlabStats> = None;
+        self.for_each_shard(|shard| {
+            let Some(stats) = shard.slab_stats() else {
+                return;
+            };
+            let total = total.get_or_insert_with(Default::default);
+            total.total_malloced += stats.total_malloced;
+            for (index, class) in stats.classes {
+                match total.classes.binary_search_by_key(&index, |&(i, _)| i) {
+                    Ok(at) => {
+                        let sum = &mut total.classes[at].1;
+                        sum.total_pages += class.total_pages;
+                        sum.used_chunks += class.used_chunks;
+                        sum.free_chunks += class.free_chunks;
+                        sum.evicted += class.evicted;
+                    }
+                    Err(at) => total.classes.insert(at, (index, class)),
+                }
+            }
+        });
+        total
+    }
+
+    fn flush_lazily(&self) -> bool {
+        let mut lazily = true;
+        self.for_each_shard(|shard| lazily &= shard.flush_lazily());
+        lazily
+    }
+
+    fn allocates_values(&self) -> bool {
+        let mut allocates = true;
+        self.for_each_shard(|shard| allocates &= shard.allocates_values());
+        allocates
+    }
+
+    fn scan<B>(&self, mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.each(|shard| shard.scan(&mut f))
+    }
+
+    /// Walks the shards in turn, the cursor being a shard and one in it.
+    fn scan_from<B>(
+        &self,
+        (shard, cursor): &mut Self::Cursor,
+        mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        while let Some(items) = self.shards.get(*shard) {
+            lock(items).scan_from(cursor, &mut f)?;
+            *shard += 1;
+            *cursor = Default::default();
+        }
+        ControlFlow::Continue(())
+    }
+}
@@ -0,0 +1,762 @@
+//! Values kept in chunks carved out of fixed-size pages, like upstream's slab allocator, so that
+//! a long-running server doesn't fragment its heap with values of every size.
+
+use alloc::borrow::Cow;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{ControlFlow, Deref};
+use core::ptr::NonNull;
+
+use crate::compress::Compressed;
+use crate::storage::Storage;
+use crate::value::{read_slice, Chunked, ValueSource};
+use crate::Error;
+use crate::{Entry, Map};
+
+/// Victims tried in a class before giving up and leaving a value on the heap. A victim whose
+/// value is still being sent doesn't free its chunk.
+const MAX_EVICTION_ATTEMPTS: usize = 8;
+
+/// An item's value. Cloning shares it, so that a response can carry on sending it whatever
+/// happens to the item.
+#[derive(Clone)]
+pub(crate) enum Bytes {
+    Heap(Arc<[u8]>),
+    /// Anywhere else. Behind a thin pointer, so that values take no more room in an entry
+    /// than the heap's.
+    Backed(Arc<Backing>),
+}
+
+/// Where a value not on the heap is.
+pub(crate) enum Backing {
+    /// Back on its class's freelist once the last clone is dropped.
+    Chunk(Chunk),
+    /// Kept out of memory by a [`ValueBackend`](crate::ValueBackend), and read a window at a
+    /// time.
+    Source(Box<dyn ValueSource>),
+    /// Borrowed from data compiled into the program, such as a lookup table.
+    Static(&'static [u8]),
+    /// Too long for one allocation, see
+    /// [`Settings::chunk_values_over`](crate::Settings::chunk_values_over).
+    Chunked(Chunked),
+    /// Compressed as it was stored, see [`Settings::compressor`](crate::Settings::compressor).
+    Compressed(Compressed),
+}
+
+impl Bytes {
+    pub(crate) fn external(source: Box<dyn ValueSource>) -> Self {
+        Self::Backed(Arc::new(Backing::Source(source)))
+    }
+
+    pub(crate) fn borrowed(bytes: &'static [u8]) -> Self {
+        Self::Backed(Arc::new(Backing::Static(bytes)))
+    }
+
+    pub(crate) fn chunked(chunked: Chunked) -> Self {
+        Self::Backed(Arc::new(Backing::Chunked(chunked)))
+    }
+
+    pub(crate) fn compressed(compressed: Compressed) -> Self {
+        Self::Backed(Arc::new(Backing::Compressed(compressed)))
+    }
+
+    fn compressed_value(&self) -> Option<&Compressed> {
+        match self {
+            Self::Backed(backing) => match &**backing {
+                Backing::Compressed(compressed) => Some(compressed),
+                _ => None,
+            },
+            Self::Heap(_) => None,
+        }
+    }
+
+    /// The bytes, ready to be sent a window at a time: expanded into memory of their own if
+    /// they're compressed, else shared.
+    pub(crate) fn expanded(&self) -> Self {
+        match self.compressed_value() {
+            Some(compressed) => compressed.expand().into(),
+            None => self.clone(),
+        }
+    }
+
+    /// The class and address of the chunk the bytes are in, if they are.
+    fn chunk(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Backed(backing) => match &**backing {
+                Backing::Chunk(chunk) => Some((chunk.class, chunk.ptr.as_ptr() as usize)),
+                _ => None,
+            },
+            Self::Heap(_) => None,
+        }
+    }
+
+    fn source(&self) -> Option<&dyn ValueSource> {
+        match self {
+            Self::Backed(backing) => match &**backing {
+                Backing::Source(source) => Some(&**source),
+                _ => None,
+            },
+            Self::Heap(_) => None,
+        }
+    }
+
+    pub(crate) fn as_chunked(&self) -> Option<&Chunked> {
+        match self {
+            Self::Backed(backing) => match &**backing {
+                Backing::Chunked(chunked) => Some(chunked),
+                _ => None,
+            },
+            Self::Heap(_) => None,
+        }
+    }
+
+    /// Whether the bytes are borrowed from static data, so changing them means copying them.
+    pub(crate) fn is_static(&self) -> bool {
+        matches!(self, Self::Backed(backing) if matches!(**backing, Backing::Static(_)))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        if let Some(chunked) = self.as_chunked() {
+            return chunked.len();
+        }
+        if let Some(compressed) = self.compressed_value() {
+            return compressed.len();
+        }
+        match self.source() {
+            Some(source) => source.len(),
+            None => self.as_slice().map_or(0, <[u8]>::len),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The bytes, unless they're kept out of memory, in chunks or compressed.
+    pub(crate) fn as_slice(&self) -> Option<&[u8]> {
+        match self {
+            Self::Heap(bytes) => Some(bytes),
+            Self::Backed(backing) => match &**backing {
+                Backing::Chunk(chunk) => Some(chunk),
+                Backing::Static(bytes) => Some(bytes),
+                _ => None,
+            },
+        }
+    }
+
+    /// Copies bytes from `offset` on into `out`, as many as fit. Returns how many it copied.
+    pub(crate) fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        if let Some(chunked) = self.as_chunked() {
+            return chunked.read(offset, out);
+        }
+        if let Some(compressed) = self.compressed_value() {
+            // Expanded whole for every read, so values are expanded once before they're sent.
+            return read_slice(&compressed.expand(), offset, out);
+        }
+        match self.source() {
+            Some(source) => source.read(offset, out),
+            None => read_slice(self.as_slice().unwrap_or_default(), offset, out),
+        }
+    }
+
+    /// Hands `f` the bytes in as few pieces as they're kept in, or a window at a time if
+    /// they're kept out of memory, stopping at the first error.
+    pub(crate) fn pieces(
+        &self,
+        mut f: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        if let Some(bytes) = self.as_slice() {
+            return f(bytes);
+        }
+        if let Some(chunked) = self.as_chunked() {
+            return chunked.chunks().try_for_each(f);
+        }
+        if let Some(compressed) = self.compressed_value() {
+            return f(&compressed.expand());
+        }
+        let mut window = [0; 256];
+        let mut read = 0;
+        while read < self.len() {
+            match self.read(read, &mut window) {
+                0 => break,
+                n => {
+                    f(&window[..n])?;
+                    read += n;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes, read into one piece of memory if they're kept out of it, in chunks or
+    /// compressed.
+    pub(crate) fn contiguous(&self) -> Cow<'_, [u8]> {
+        if let Some(bytes) = self.as_slice() {
+            return Cow::Borrowed(bytes);
+        }
+        if let Some(compressed) = self.compressed_value() {
+            return Cow::Owned(compressed.expand());
+        }
+        let mut bytes = vec![0; self.len()];
+        let mut read = 0;
+        while read < bytes.len() {
+            match self.read(read, &mut bytes[read..]) {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Cow::Owned(bytes)
+    }
+
+    /// Where the bytes are, which tells them apart from any that aren't a clone.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        match self {
+            Self::Heap(bytes) => bytes.as_ptr(),
+            Self::Backed(backing) => Arc::as_ptr(backing) as *const u8,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Heap(bytes.into())
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_slice() {
+            Some(bytes) => bytes.fmt(f),
+            None if self.as_chunked().is_some() => write!(f, "<{} bytes in chunks>", self.len()),
+            None => write!(f, "<{} bytes out of memory>", self.len()),
+        }
+    }
+}
+
+/// How [`Slabs`] carves up memory, after upstream's `-I`, `-n`, `-f` and `-m`.
+#[derive(Debug, Clone)]
+pub struct SlabSettings {
+    /// Bytes in a page. Values larger than a page are kept on the heap.
+    pub page_size: usize,
+    /// Chunk size of the smallest class.
+    pub min_chunk_size: usize,
+    /// How much larger each class's chunks are than the one before.
+    pub growth_factor: f32,
+    /// Pages allocated at most. Once they're all assigned, a class evicts its own items to
+    /// make room.
+    pub max_pages: usize,
+}
+
+impl Default for SlabSettings {
+    fn default() -> Self {
+        Self {
+            page_size: 1024 * 1024,
+            min_chunk_size: 96,
+            growth_factor: 1.25,
+            max_pages: 64,
+        }
+    }
+}
+
+/// What `stats slabs` reports about a class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClassStats {
+    pub chunk_size: usize,
+    pub chunks_per_page: usize,
+    pub total_pages: usize,
+    pub used_chunks: usize,
+    pub free_chunks: usize,
+    /// Items evicted to make room in this class.
+    pub evicted: u64,
+}
+
+/// Why a page couldn't be moved to another class, see [`Storage::reassign`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReassignError {
+    /// A class that doesn't exist, or no class to take a page from.
+    BadClass,
+    /// The class to take a page from has no more than the one.
+    NotFull,
+    /// The page's items are evicted, but some of their values are still being sent, so the
+    /// page isn't free yet. Moving it again once they're sent works.
+    Busy,
+}
+
+/// What `stats slabs` reports.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    /// Classes that have pages, with their index among all classes, smallest first.
+    pub classes: Vec<(usize, ClassStats)>,
+    /// Bytes of pages allocated.
+    pub total_malloced: u64,
+}
+
+#[derive(Debug)]
+struct Class {
+    stats: ClassStats,
+    free: Vec<NonNull<u8>>,
+    /// Where the class's pages start.
+    pages: Vec<NonNull<u8>>,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    classes: Vec<Class>,
+    /// Every page allocated, freed along with the pool.
+    pages: Vec<NonNull<[u8]>>,
+    max_pages: usize,
+}
+
+/// The pages, shared by [`Slabs`] and every chunk, so that they outlive both.
+#[derive(Debug)]
+struct Pool {
+    page_size: usize,
+    state: spin::Mutex<PoolState>,
+}
+
+// SAFETY: the pool owns its pages like a `Box<[u8]>` would, and its pointers are only to those
+// pages. It never reads or writes through them, so it may move to and be shared with other
+// threads as the boxes could. The pointers themselves are only touched behind the lock.
+unsafe impl Send for Pool {}
+// SAFETY: as for `Send`. A chunk given back on one thread and taken on another is handed
+// over through the lock, so the reads before are ordered before the writes after.
+unsafe impl Sync for Pool {}
+
+impl Pool {
+    fn new(settings: &SlabSettings) -> Self {
+        // Chunks are a multiple of 8 bytes. The largest class takes a whole page.
+        let mut sizes = Vec::new();
+        let mut size = settings.min_chunk_size.max(8).next_multiple_of(8);
+        while size <= settings.page_size / 2 {
+            sizes.push(size);
+            let next = (size as f32 * settings.growth_factor) as usize;
+            size = next.next_multiple_of(8).max(size + 8);
+        }
+        sizes.push(settings.page_size);
+        let classes = sizes
+            .into_iter()
+            .map(|chunk_size| Class {
+                stats: ClassStats {
+                    chunk_size,
+                    chunks_per_page: settings.page_size / chunk_size,
+                    total_pages: 0,
+                    used_chunks: 0,
+                    free_chunks: 0,
+                    evicted: 0,
+                },
+                free: Vec::new(),
+                pages: Vec::new(),
+            })
+            .collect();
+        Self {
+            page_size: settings.page_size,
+            state: spin::Mutex::new(PoolState {
+                classes,
+                pages: Vec::new(),
+                max_pages: settings.max_pages,
+            }),
+        }
+    }
+
+    /// The class whose chunks fit `len` bytes best, if any do.
+    fn class_for(&self, len: usize) -> Option<usize> {
+        let state = self.state.lock();
+        state
+            .classes
+            .iter()
+            .position(|class| class.stats.chunk_size >= len)
+    }
+
+    /// A free chunk of `class`, from a new page if need be, or `None` once out of pages.
+    fn take(&self, class: usize) -> Option<NonNull<u8>> {
+        let mut state = self.state.lock();
+        if state.classes[class].free.is_empty() {
+            if state.pages.len() >= state.max_pages {
+                return None;
+            }
+            let page = NonNull::from(Box::leak(vec![0; self.page_size].into_boxed_slice()));
+            state.pages.push(page);
+            state.classes[class].cut(page.cast());
+        }
+        let class = &mut state.classes[class];
+        class.stats.free_chunks -= 1;
+        class.stats.used_chunks += 1;
+        class.free.pop()
+    }
+
+    /// Where a page of class `src` that isn't its only one starts, to be emptied for
+    /// [`move_page`](Self::move_page).
+    fn spare_page(&self, src: usize) -> Result<usize, ReassignError> {
+        let state = self.state.lock();
+        match &state.classes[src].pages[..] {
+            [_, .., last] => Ok(last.as_ptr() as usize),
+            _ => Err(ReassignError::NotFull),
+        }
+    }
+
+    /// Moves the page of class `src` starting at `start` to class `dst`, if all its chunks
+    /// are free.
+    fn move_page(&self, src: usize, dst: usize, start: usize) -> Result<(), ReassignError> {
+        let mut state = self.state.lock();
+        let in_page =
+            |ptr: &NonNull<u8>| (start..start + self.page_size).contains(&(ptr.as_ptr() as usize));
+        let class = &mut state.classes[src];
+        let free = class.free.iter().filter(|ptr| in_page(ptr)).count();
+        if free < class.stats.chunks_per_page {
+            return Err(ReassignError::Busy);
+        }
+        class.free.retain(|ptr| !in_page(ptr));
+        class.stats.free_chunks -= free;
+        class.stats.total_pages -= 1;
+        let at = class
+            .pages
+            .iter()
+            .position(|page| page.as_ptr() as usize == start);
+        let page = class
+            .pages
+            .swap_remove(at.expect("moving a page of the class"));
+        state.classes[dst].cut(page);
+        Ok(())
+    }
+
+    fn give_back(&self, class: usize, ptr: NonNull<u8>) {
+        let mut state = self.state.lock();
+        let class = &mut state.classes[class];
+        class.stats.free_chunks += 1;
+        class.stats.used_chunks -= 1;
+        class.free.push(ptr);
+    }
+
+    fn stats(&self) -> SlabStats {
+        let state = self.state.lock();
+        SlabStats {
+            classes: state
+                .classes
+                .iter()
+                .enumerate()
+                .filter(|(_, class)| class.stats.total_pages > 0)
+                .map(|(i, class)| (i, class.stats))
+                .collect(),
+            total_malloced: (state.pages.len() * self.page_size) as u64,
+        }
+    }
+}
+
+impl Class {
+    /// Cuts the page starting at `page`, free of chunks, into chunks of the class.
+    fn cut(&mut self, page: NonNull<u8>) {
+        for i in (0..self.stats.chunks_per_page).rev() {
+            // SAFETY: `i` is less than `chunks_per_page`, which is `page_size / chunk_size`,
+            // so the chunk starts, and ends, within the page.
+            self.free
+                .push(unsafe { page.add(i * self.stats.chunk_size) });
+        }
+        self.pages.push(page);
+        self.stats.total_pages += 1;
+        self.stats.free_chunks += self.stats.chunks_per_page;
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for page in self.state.get_mut().pages.drain(..) {
+            // SAFETY: `take` leaked the page from a boxed slice of the same length, and pushed it
+            // once, so it's freed once. Every chunk holds the pool, so none is left to point
+            // into the page.
+            drop(unsafe { Box::from_raw(page.as_ptr()) });
+        }
+    }
+}
+
+/// A value's bytes in a chunk of its class.
+pub(crate) struct Chunk {
+    pool: Arc<Pool>,
+    class: usize,
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: a chunk is only written by `Slabs::allocate`, before it's made, and only read after,
+// so it may move between threads like a `Box<[u8]>`. Its pool is `Send` and `Sync`.
+unsafe impl Send for Chunk {}
+// SAFETY: as for `Send`. Shared, it's only ever read.
+unsafe impl Sync for Chunk {}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the chunk is at least `len` bytes, all of them written: the page was zeroed
+        // and `allocate` copied the value in. The chunk's own pool keeps the page alive, and
+        // it's off the freelist until dropped, so nothing writes to it meanwhile.
+        unsafe { core::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        self.pool.give_back(self.class, self.ptr);
+    }
+}
+
+/// Storage keeping values in slabs: pages of [`SlabSettings::page_size`] bytes, each assigned
+/// to a class and cut into chunks of its size. A value goes in the smallest chunk it fits, and
+/// a freed chunk goes back to its class for the next value of about the same size.
+///
+/// Once [`SlabSettings::max_pages`] are assigned, a class that's out of chunks evicts one of
+/// its own items rather than take memory from another class. Those evictions happen beneath
+/// the handler, so count them with `stats slabs` rather than `stats`, and set
+/// [`Settings::memory_limit`](crate::Settings::memory_limit) out of the way. Values no class
+/// fits, or that couldn't be made room for, stay on the heap.
+///
+/// ```
+/// use incr_memcached::{Clock, CommandHandler, Map, SlabSettings, Slabs};
+///
+/// # struct Uptime;
+/// # impl Clock for Uptime { fn now(&self) -> u32 { 0 } }
+/// let slabs = Slabs::new(Map::default(), SlabSettings::default());
+/// let handler: CommandHandler<Slabs, _> = CommandHandler::with_clock(slabs, Uptime);
+/// # drop(handler);
+/// ```
+pub struct Slabs<S = Map<Box<[u8]>, Entry>> {
+    items: S,
+    pool: Arc<Pool>,
+    /// For every class, the keys given one of its chunks, and its address, oldest first: the
+    /// items to evict from it. A key whose value has moved since is passed over.
+    victims: Vec<VecDeque<(Box<[u8]>, usize)>>,
+}
+
+impl<S: Storage> Slabs<S> {
+    /// Keeps the values of `items`, and any stored later, in slabs.
+    pub fn new(items: S, settings: SlabSettings) -> Self {
+        let pool = Pool::new(&settings);
+        let classes = pool.state.lock().classes.len();
+        let mut slabs = Self {
+            items,
+            pool: Arc::new(pool),
+            victims: (0..classes).map(|_| VecDeque::new()).collect(),
+        };
+        let mut keys = Vec::new();
+        let _ = slabs.items.scan(|key, _| {
+            keys.push(key.to_vec());
+            ControlFlow::<()>::Continue(())
+        });
+        for key in keys {
+            slabs.rehome(&key);
+        }
+        slabs
+    }
+
+    /// Copies `bytes` into a chunk of their class, evicting items other than `key` from the
+    /// class to make room. `None` if they're to stay on the heap.
+    fn allocate(&mut self, key: &[u8], bytes: &[u8]) -> Option<Bytes> {
+        if bytes.is_empty() {
+            return None;
+        }
+        let class = self.pool.class_for(bytes.len())?;
+        for _ in 0..MAX_EVICTION_ATTEMPTS {
+            if let Some(ptr) = self.pool.take(class) {
+                // SAFETY: the class's chunks are at least `bytes.len()` long, so it fits. The
+                // chunk was just taken off the freelist, so no other chunk points at it, and
+                // `bytes`, even if read from a chunk, can't overlap it.
+                unsafe {
+                    ptr.as_ptr()
+                        .copy_from_nonoverlapping(bytes.as_ptr(), bytes.len())
+                };
+                self.note_victim(class, key, ptr.as_ptr() as usize);
+                return Some(Bytes::Backed(Arc::new(Backing::Chunk(Chunk {
+                    pool: self.pool.clone(),
+                    class,
+                    ptr,
+                    len: bytes.len(),
+                }))));
+            }
+            let victim = self.next_victim(class, key)?;
+            self.items.remove(&victim);
+            self.pool.state.lock().classes[class].stats.evicted += 1;
+        }
+        None
+    }
+
+    /// `entry`, to be stored at `key`, with its value moved into a chunk if it's on the heap.
+    /// Values elsewhere, such as in static data, stay where they are.
+    fn place(&mut self, key: &[u8], mut entry: Entry) -> Entry {
+        if let Bytes::Heap(bytes) = &entry.value {
+            if let Some(chunk) = self.allocate(key, bytes) {
+                entry.value = chunk;
+            }
+        }
+        entry
+    }
+
+    /// Whether the value at `key` is still in the chunk of `class` at `addr`.
+    fn holds(&self, key: &[u8], class: usize, addr: usize) -> bool {
+        self.items.read(key, |entry| entry.value.chunk()) == Some(Some((class, addr)))
+    }
+
+    /// Adds `key` to the victims of `class`, its value being in the chunk at `addr`. Keys
+    /// whose values moved are dropped from the list once they're as many as the chunks in
+    /// use, so it stays in proportion to them.
+    fn note_victim(&mut self, class: usize, key: &[u8], addr: usize) {
+        let used = self.pool.state.lock().classes[class].stats.used_chunks;
+        let mut victims = core::mem::take(&mut self.victims[class]);
+        if victims.len() >= 2 * used {
+            victims.retain(|(key, addr)| self.holds(key, class, *addr));
+        }
+        victims.push_back((key.into(), addr));
+        self.victims[class] = victims;
+    }
+
+    /// The oldest item other than `key` with its value in a chunk of `class`, if any.
+    fn next_victim(&mut self, class: usize, key: &[u8]) -> Option<Box<[u8]>> {
+        let mut skipped = None;
+        let victim = loop {
+            let (victim, addr) = self.victims[class].pop_front()?;
+            if &*victim == key {
+                skipped = Some((victim, addr));
+            } else if self.holds(&victim, class, addr) {
+                break Some(victim);
+            }
+        };
+        // The key being stored keeps its place, for its old value may be in this class.
+        if let Some(skipped) = skipped {
+            self.victims[class].push_front(skipped);
+        }
+        victim
+    }
+
+    /// Moves the value at `key` into a chunk, if it's on the heap.
+    fn rehome(&mut self, key: &[u8]) {
+        let Some(Bytes::Heap(bytes)) = self.items.read(key, |entry| entry.value.clone()) else {
+            return;
+        };
+        if let Some(chunk) = self.allocate(key, &bytes) {
+            self.items.update(key, |entry| entry.value = chunk);
+        }
+    }
+}
+
+impl<S: Storage> Storage for Slabs<S> {
+    type Cursor = S::Cursor;
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.items.read(key, f)
+    }
+
+    /// A value `f` replaced goes into a chunk afterwards. One it left alone stays where it
+    /// is, so that changing an item's metadata, as reading it does, never evicts.
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        let (r, replaced) = self.items.update(key, |entry| {
+            let before = entry.value.as_ptr();
+            let r = f(entry);
+            (r, entry.value.as_ptr() != before)
+        })?;
+        if replaced {
+            self.rehome(key);
+        }
+        Some(r)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        let entry = self.place(key, entry);
+        self.items.insert(key, entry)
+    }
+
+    fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+        let entry = self.place(key, entry);
+        self.items.try_insert(key, entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.items.remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn clear(&mut self) {
+        self.items.clear()
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        self.items.capacity()
+    }
+
+    fn slab_stats(&self) -> Option<SlabStats> {
+        Some(self.pool.stats())
+    }
+
+    /// 0 for values kept elsewhere, such as on the heap as no class fits them.
+    fn class_of(entry: &Entry) -> u32 {
+        entry.value.chunk().map_or(0, |(class, _)| class as u32 + 1)
+    }
+
+    /// Like upstream, a class keeps its last page, and any picks the class with the most
+    /// pages. The page taken is the class's newest.
+    fn reassign(
+        &mut self,
+        src: Option<u32>,
+        dst: u32,
+        mut evicted: impl FnMut(&[u8], &Entry),
+    ) -> Result<(), ReassignError> {
+        let classes = self.pool.state.lock().classes.len();
+        let index = |class: u32| {
+            let index = (class as usize).checked_sub(1)?;
+            (index < classes).then_some(index)
+        };
+        let dst = index(dst).ok_or(ReassignError::BadClass)?;
+        let src = match src {
+            Some(src) => index(src).ok_or(ReassignError::BadClass)?,
+            None => {
+                let state = self.pool.state.lock();
+                (0..classes)
+                    .filter(|&class| class != dst && state.classes[class].pages.len() > 1)
+                    .max_by_key(|&class| state.classes[class].pages.len())
+                    .ok_or(ReassignError::BadClass)?
+            }
+        };
+        let start = self.pool.spare_page(src)?;
+        let page = start..start + self.pool.page_size;
+        let mut keys = Vec::new();
+        let _ = self.items.scan(|key, entry| {
+            if let Some((class, addr)) = entry.value.chunk() {
+                if class == src && page.contains(&addr) {
+                    keys.push(Box::<[u8]>::from(key));
+                }
+            }
+            ControlFlow::<()>::Continue(())
+        });
+        for key in keys {
+            if let Some(entry) = self.items.remove(&key) {
+                evicted(&key, &entry);
+            }
+        }
+        self.pool.move_page(src, dst, start)
+    }
+
+    fn flush_lazily(&self) -> bool {
+        self.items.flush_lazily()
+    }
+
+    fn allocates_values(&self) -> bool {
+        self.items.allocates_values()
+    }
+
+    fn scan<B>(&self, f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.items.scan(f)
+    }
+
+    fn scan_from<B>(
+        &self,
+        cursor: &mut Self::Cursor,
+        f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        self.items.scan_from(cursor, f)
+    }
+}
@@ -0,0 +1,233 @@
+//! Saving the items to a file and loading them back, for warm restarts.
+//!
+//! A snapshot is the magic `INCRSNAP`, a little-endian `u32` version, then for each item its key
+//! length (`u16`), key, flags (`u32`), expiry in clock seconds (`u32`, 0 for never), CAS value
+//! (`u64`), whether it's stale (`u8`), value length (`u32`) and value. It ends with the number of items (`u64`) and the
+//! CRC-32 of everything before it (`u32`).
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::vec::Vec;
+
+use crate::clock::Clock;
+use crate::storage::Storage;
+use crate::{bump_cas, CommandHandler, Entry, Settings};
+
+const MAGIC: &[u8; 8] = b"INCRSNAP";
+const VERSION: u32 = 2;
+
+/// Why a snapshot couldn't be loaded. Nothing is loaded from one that's refused.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// Not a snapshot at all.
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// It ends early, as a snapshot that was still being written would.
+    Truncated,
+    /// Its checksum or item count doesn't match what's in it.
+    Corrupt,
+    /// The storage has no room for all of its items, such as [`Fixed`](crate::Fixed) storage
+    /// that's too small.
+    NoRoom,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "reading snapshot: {}", error),
+            Self::BadMagic => f.write_str("not a snapshot"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            Self::Truncated => f.write_str("snapshot is truncated"),
+            Self::Corrupt => f.write_str("snapshot is corrupt"),
+            Self::NoRoom => f.write_str("no room for the snapshot's items"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl<S: Storage, C: Clock, const KEY_LEN: usize> CommandHandler<S, C, KEY_LEN> {
+    /// Writes every item that hasn't expired or been flushed to `w`, in the format described in the
+    /// [module docs](self). Items being changed by other handlers meanwhile may or may not
+    /// make it in.
+    pub fn save_snapshot(&self, mut w: impl Write) -> io::Result<()> {
+        let now = self.clock.now();
+        let mut out = Crc32Writer::new(&mut w);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        let flushed = self.stats.generation.flushed_before();
+        let mut items = 0u64;
+        let flow = self.data.scan(|key, entry| {
+            if !entry.is_live(now, flushed) {
+                return core::ops::ControlFlow::Continue(());
+            }
+            items += 1;
+            let written = (|| {
+                let key_len = u16::try_from(key.len()).expect("keys are short");
+                let value_len = u32::try_from(entry.value.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "value too long"))?;
+                out.write_all(&key_len.to_le_bytes())?;
+                out.write_all(key)?;
+                out.write_all(&entry.flags.to_le_bytes())?;
+                out.write_all(&entry.expires_at.to_le_bytes())?;
+                out.write_all(&entry.cas.to_le_bytes())?;
+                out.write_all(&[entry.stale.into()])?;
+                out.write_all(&value_len.to_le_bytes())?;
+                out.write_all(&entry.value.contiguous())
+            })();
+            match written {
+                Ok(()) => core::ops::ControlFlow::Continue(()),
+                Err(error) => core::ops::ControlFlow::Break(error),
+            }
+        });
+        if let core::ops::ControlFlow::Break(error) = flow {
+            return Err(error);
+        }
+        out.write_all(&items.to_le_bytes())?;
+        let crc = out.crc();
+        w.write_all(&crc.to_le_bytes())?;
+        w.flush()
+    }
+}
+
+impl<S: Storage, C: Clock> CommandHandler<S, C> {
+    /// Like [`with_settings`](CommandHandler::with_settings), with the items of the snapshot read
+    /// from `r` added to `data`, less those expired by now. The snapshot is checked whole
+    /// before anything is added, so none of a refused one is, though a snapshot `data` has no
+    /// room for is refused partway.
+    pub fn load_snapshot(
+        mut data: S,
+        clock: C,
+        settings: Settings,
+        mut r: impl Read,
+    ) -> Result<Self, SnapshotError> {
+        let mut bytes = Vec::new();
+        r.read_to_end(&mut bytes)?;
+        let items = parse(&bytes)?;
+        let now = clock.now();
+        let mut max_cas = 0;
+        for (key, entry) in items {
+            if !entry.is_expired(now) {
+                max_cas = max_cas.max(entry.cas);
+                data.try_insert(key, entry)
+                    .map_err(|_| SnapshotError::NoRoom)?;
+            }
+        }
+        bump_cas(max_cas);
+        Ok(Self::with_settings(data, clock, settings))
+    }
+}
+
+/// The items of a whole snapshot, or why it's refused.
+fn parse(bytes: &[u8]) -> Result<Vec<(&[u8], Entry)>, SnapshotError> {
+    let mut input = bytes;
+    if take(&mut input, MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = u32::from_le_bytes(take_array(&mut input)?);
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    // The trailer: item count and checksum.
+    let Some(body_len) = input.len().checked_sub(12) else {
+        return Err(SnapshotError::Truncated);
+    };
+    let (mut input, trailer) = input.split_at(body_len);
+    let (count, crc) = trailer.split_at(8);
+    if crc32(&bytes[..bytes.len() - 4]) != u32::from_le_bytes(crc.try_into().expect("4 bytes")) {
+        // Most likely cut short, but it can't be told apart from damage.
+        return Err(SnapshotError::Corrupt);
+    }
+    let count = u64::from_le_bytes(count.try_into().expect("8 bytes"));
+    let mut items = Vec::new();
+    while !input.is_empty() {
+        let key_len = u16::from_le_bytes(take_array(&mut input)?);
+        let key = take(&mut input, key_len.into())?;
+        let flags = u32::from_le_bytes(take_array(&mut input)?);
+        let expires_at = u32::from_le_bytes(take_array(&mut input)?);
+        let cas = u64::from_le_bytes(take_array(&mut input)?);
+        let [stale] = take_array(&mut input)?;
+        let value_len = u32::from_le_bytes(take_array(&mut input)?);
+        let value = take(&mut input, value_len as usize)?;
+        items.push((
+            key,
+            Entry {
+                flags,
+                expires_at,
+                cas,
+                stale: stale != 0,
+                ..Entry::new(value.to_vec())
+            },
+        ));
+    }
+    if items.len() as u64 != count {
+        return Err(SnapshotError::Corrupt);
+    }
+    Ok(items)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], SnapshotError> {
+    if input.len() < len {
+        return Err(SnapshotError::Truncated);
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn take_array<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], SnapshotError> {
+    Ok(take(input, N)?.try_into().expect("N bytes"))
+}
+
+/// Passes writes through, keeping the CRC-32 of what went by.
+struct Crc32Writer<W> {
+    inner: W,
+    crc: u32,
+}
+
+impl<W: Write> Crc32Writer<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, crc: !0 }
+    }
+
+    fn crc(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// CRC-32 as in zlib and PNG.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
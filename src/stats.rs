@@ -0,0 +1,482 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::watch::{EventBus, EventKind};
+use crate::{Generation, RemovalObserver, SlabStats, ITEM_OVERHEAD};
+
+/// Granularity of the `stats sizes` histogram.
+const SIZE_BUCKET: usize = 32;
+
+/// Counters zeroed by `stats reset`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counters {
+    pub cmd_get: u64,
+    pub cmd_set: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Items stored.
+    pub total_items: u64,
+    pub evictions: u64,
+    /// Stores that took the place of an item found past its expiry.
+    pub reclaimed: u64,
+    /// Items reclaimed because they were found past their expiry.
+    pub expired: u64,
+    /// Those of the `expired` items that were never fetched.
+    pub expired_unfetched: u64,
+    /// Retrievals that found their item, but expired.
+    pub get_expired: u64,
+    /// Events dropped because a `watch` connection couldn't keep up.
+    pub log_watcher_skipped: u64,
+    /// Bytes skipped to get past the rest of a bad request line.
+    pub discarded_bytes: u64,
+    /// Bytes saved by compressing values as they were stored.
+    pub compression_saved: u64,
+    /// Tombstones left by deletes.
+    pub total_tombstones: u64,
+    /// Stores turned away by a tombstone.
+    pub tombstone_blocked: u64,
+}
+
+/// The counters of a slab class that `stats items` reports, zeroed by `stats reset`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClassCounters {
+    pub evicted: u64,
+    pub reclaimed: u64,
+    pub expired_unfetched: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub counters: Counters,
+    /// Counters by [`Storage::class_of`](crate::Storage::class_of).
+    pub classes: BTreeMap<u32, ClassCounters>,
+    pub detail: PrefixStats,
+    pub events: EventBus,
+    pub generation: Generation,
+    pub on_removal: Option<Arc<dyn RemovalObserver>>,
+    // Gauges describing what's currently stored. `stats reset` leaves these alone.
+    pub curr_items: u64,
+    /// Tombstones in storage, which `curr_items` doesn't count.
+    pub curr_tombstones: u64,
+    pub limit_items: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+}
+
+/// Where the memory items take goes, and how items came and went, as
+/// [`CommandHandler::memory_stats`](crate::CommandHandler::memory_stats) reports it. The
+/// counters start over with `stats reset`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MemoryStats {
+    pub items: u64,
+    pub key_bytes: u64,
+    pub value_bytes: u64,
+    /// [`ITEM_OVERHEAD`] for each item.
+    pub overhead_bytes: u64,
+    /// Items stored.
+    pub total_items: u64,
+    pub evictions: u64,
+    /// Items found past their expiry that were never fetched.
+    pub expired_unfetched: u64,
+    /// Stores that took the place of an item found past its expiry.
+    pub reclaimed: u64,
+}
+
+impl Stats {
+    /// Bytes of keys and values, which the memory limit applies to.
+    pub fn bytes(&self) -> u64 {
+        self.key_bytes + self.value_bytes
+    }
+
+    /// Counts the items in storage, `len` of them, less those flushed and the tombstones.
+    pub fn count_items(&mut self, len: usize) {
+        let other = self.generation.left() + self.curr_tombstones as usize;
+        self.curr_items = len.saturating_sub(other) as u64;
+    }
+
+    /// Counts an item added.
+    pub fn add_item(&mut self, key: &[u8], value_len: usize) {
+        self.key_bytes += key.len() as u64;
+        self.value_bytes += value_len as u64;
+    }
+
+    /// Counts an item gone. With shared storage it may have been counted by another handler,
+    /// so this saturates rather than underflow.
+    pub fn remove_item(&mut self, key: &[u8], value_len: usize) {
+        self.key_bytes = self.key_bytes.saturating_sub(key.len() as u64);
+        self.value_bytes = self.value_bytes.saturating_sub(value_len as u64);
+    }
+
+    /// Counts an item's value replaced by one of another length.
+    pub fn resize_value(&mut self, old_len: usize, new_len: usize) {
+        self.value_bytes = (self.value_bytes + new_len as u64).saturating_sub(old_len as u64);
+    }
+
+    pub fn memory(&self) -> MemoryStats {
+        MemoryStats {
+            items: self.curr_items,
+            key_bytes: self.key_bytes,
+            value_bytes: self.value_bytes,
+            overhead_bytes: self.curr_items * ITEM_OVERHEAD as u64,
+            total_items: self.counters.total_items,
+            evictions: self.counters.evictions,
+            expired_unfetched: self.counters.expired_unfetched,
+            reclaimed: self.counters.reclaimed,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.counters = Default::default();
+        self.classes.clear();
+        self.detail.prefixes.clear();
+    }
+
+    /// The counters of slab class `class`.
+    pub fn class(&mut self, class: u32) -> &mut ClassCounters {
+        self.classes.entry(class).or_default()
+    }
+
+    /// Reports an event to `watch` connections.
+    pub fn publish(&mut self, ts: u32, kind: EventKind, key: &[u8]) {
+        self.counters.log_watcher_skipped += self.events.publish(ts, kind, key);
+    }
+
+    /// Name and value of general stat `index`, or `None` once past the last one.
+    pub fn stat(&self, index: usize) -> Option<(&'static str, u64)> {
+        let c = &self.counters;
+        Some(match index {
+            0 => ("curr_items", self.curr_items),
+            1 => ("total_items", c.total_items),
+            2 => ("limit_items", self.limit_items),
+            3 => ("bytes", self.bytes()),
+            4 => ("key_bytes", self.key_bytes),
+            5 => ("value_bytes", self.value_bytes),
+            6 => ("overhead_bytes", self.curr_items * ITEM_OVERHEAD as u64),
+            7 => ("cmd_get", c.cmd_get),
+            8 => ("cmd_set", c.cmd_set),
+            9 => ("get_hits", c.get_hits),
+            10 => ("get_misses", c.get_misses),
+            11 => ("bytes_read", c.bytes_read),
+            12 => ("bytes_written", c.bytes_written),
+            13 => ("evictions", c.evictions),
+            14 => ("reclaimed", c.reclaimed),
+            15 => ("expired", c.expired),
+            16 => ("expired_unfetched", c.expired_unfetched),
+            17 => ("get_expired", c.get_expired),
+            18 => ("log_watcher_skipped", c.log_watcher_skipped),
+            19 => ("discarded_bytes", c.discarded_bytes),
+            20 => ("compression_saved_bytes", c.compression_saved),
+            21 => ("curr_tombstones", self.curr_tombstones),
+            22 => ("total_tombstones", c.total_tombstones),
+            23 => ("tombstone_blocked", c.tombstone_blocked),
+            _ => return None,
+        })
+    }
+
+    fn write_line<const N: usize>(&self, index: usize, out: &mut heapless::Vec<u8, N>) -> bool {
+        let Some((name, value)) = self.stat(index) else {
+            return false;
+        };
+        write!(out, "STAT {} {}\r\n", name, value).expect("formatting stat");
+        true
+    }
+}
+
+/// Per-prefix counters reported by `stats detail dump` and
+/// [`CommandHandler::prefix_stats`](crate::CommandHandler::prefix_stats).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixCounters {
+    pub get: u64,
+    pub hit: u64,
+    pub set: u64,
+    pub del: u64,
+}
+
+/// Accounting of commands per key prefix, toggled with `stats detail on|off`.
+#[derive(Debug)]
+pub struct PrefixStats {
+    pub enabled: bool,
+    delimiter: u8,
+    max_prefixes: usize,
+    prefixes: BTreeMap<Vec<u8>, PrefixCounters>,
+    /// Counters for the prefixes past `max_prefixes`, lumped together.
+    other: PrefixCounters,
+}
+
+impl PrefixStats {
+    pub fn new(delimiter: u8, max_prefixes: usize) -> Self {
+        Self {
+            enabled: false,
+            delimiter,
+            max_prefixes,
+            prefixes: BTreeMap::new(),
+            other: Default::default(),
+        }
+    }
+
+    /// Counters for the prefix of `key`, if it has one. Like upstream, a key without the
+    /// delimiter has none.
+    ///
+    /// Once `max_prefixes` are tracked, new prefixes are counted in `other`.
+    fn counters(&mut self, key: &[u8]) -> Option<&mut PrefixCounters> {
+        if !self.enabled {
+            return None;
+        }
+        let prefix = &key[..key.iter().position(|&c| c == self.delimiter)?];
+        if !self.prefixes.contains_key(prefix) {
+            if self.prefixes.len() >= self.max_prefixes {
+                return Some(&mut self.other);
+            }
+            self.prefixes.insert(prefix.to_vec(), Default::default());
+        }
+        self.prefixes.get_mut(prefix)
+    }
+
+    pub fn record_get(&mut self, key: &[u8], hit: bool) {
+        if let Some(counters) = self.counters(key) {
+            counters.get += 1;
+            if hit {
+                counters.hit += 1;
+            }
+        }
+    }
+
+    pub fn record_set(&mut self, key: &[u8]) {
+        if let Some(counters) = self.counters(key) {
+            counters.set += 1;
+        }
+    }
+
+    pub fn record_delete(&mut self, key: &[u8]) {
+        if let Some(counters) = self.counters(key) {
+            counters.del += 1;
+        }
+    }
+
+    /// The tracked prefixes, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], PrefixCounters)> {
+        self.prefixes
+            .iter()
+            .map(|(prefix, counters)| (&prefix[..], *counters))
+    }
+
+    /// Counters for the prefixes that didn't fit.
+    pub fn other(&self) -> PrefixCounters {
+        self.other
+    }
+
+    /// Copy of the tracked prefixes, for streaming out with `stats detail dump`.
+    pub fn snapshot(&self) -> Vec<(Vec<u8>, PrefixCounters)> {
+        self.prefixes
+            .iter()
+            .map(|(prefix, counters)| (prefix.clone(), *counters))
+            .collect()
+    }
+}
+
+impl Default for PrefixStats {
+    fn default() -> Self {
+        Self::new(b':', 256)
+    }
+}
+
+/// Per-connection counters reported by `stats conns`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    pub id: u64,
+    pub state: &'static str,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub last_activity: u32,
+}
+
+/// The items served, as counted so far for `stats items`: by class, how many and the
+/// earliest time one was written or served.
+#[derive(Debug, Default, Clone)]
+pub struct ItemTally(BTreeMap<u32, (u64, u32)>);
+
+impl ItemTally {
+    /// Counts an item of slab class `class` last written or served at `last_access`.
+    pub fn count(&mut self, class: u32, last_access: u32) {
+        let (number, oldest) = self.0.entry(class).or_insert((0, last_access));
+        *number += 1;
+        *oldest = (*oldest).min(last_access);
+    }
+
+    /// The report of the items counted, as of `now`.
+    pub fn report(&self, now: u32) -> Report {
+        Report::Items(
+            self.0
+                .iter()
+                .map(|(&class, &(number, oldest))| ClassItems {
+                    class,
+                    number,
+                    age: now.saturating_sub(oldest),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A class's line of `stats items`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassItems {
+    class: u32,
+    number: u64,
+    /// Seconds since the oldest was touched.
+    age: u32,
+}
+
+/// A multi-line `STAT` response, produced one line at a time.
+#[derive(Debug)]
+pub enum Report {
+    General,
+    /// Item counts per size bucket, sorted by size.
+    Sizes(Vec<(usize, u64)>),
+    /// Snapshot of a connection, plus the time it was taken.
+    Conns(ConnectionStats, u32),
+    /// Limits the server runs with.
+    Settings {
+        maxbytes: u64,
+        item_size_max: usize,
+    },
+    /// Snapshot of the `stats detail` prefixes. Lines are `PREFIX ...` rather than `STAT ...`.
+    Detail(Vec<(Vec<u8>, PrefixCounters)>),
+    /// Snapshot of the slab classes in use, if values are kept in slabs.
+    Slabs(SlabStats),
+    /// Items served and how long since the oldest was touched, by class, sorted. The class
+    /// counters come from [`Stats`] as the lines are written. Empty without items, like
+    /// upstream's.
+    Items(Vec<ClassItems>),
+}
+
+impl Report {
+    /// Counts an item of `size` bytes into its bucket of `buckets`, its size rounded up to a
+    /// multiple of 32 bytes.
+    pub fn count_size(buckets: &mut BTreeMap<usize, u64>, size: usize) {
+        *buckets
+            .entry(size.div_ceil(SIZE_BUCKET) * SIZE_BUCKET)
+            .or_insert(0) += 1;
+    }
+
+    /// Writes line `index` of the report. Returns `false` once past the last line.
+    pub fn write_line<const N: usize>(
+        &self,
+        stats: &Stats,
+        index: usize,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> bool {
+        match self {
+            Self::General => stats.write_line(index, out),
+            Self::Sizes(buckets) => match buckets.get(index) {
+                Some((size, count)) => {
+                    write!(out, "STAT {} {}\r\n", size, count).expect("formatting stat");
+                    true
+                }
+                None => false,
+            },
+            Self::Detail(prefixes) => match prefixes.get(index) {
+                Some((prefix, c)) => {
+                    out.extend_from_slice(b"PREFIX ").expect("formatting stat");
+                    out.extend_from_slice(prefix).expect("formatting stat");
+                    write!(
+                        out,
+                        " get {} hit {} set {} del {}\r\n",
+                        c.get, c.hit, c.set, c.del
+                    )
+                    .expect("formatting stat");
+                    true
+                }
+                None => false,
+            },
+            Self::Settings {
+                maxbytes,
+                item_size_max,
+            } => {
+                match index {
+                    0 => write!(out, "STAT maxbytes {}\r\n", maxbytes),
+                    1 => write!(out, "STAT item_size_max {}\r\n", item_size_max),
+                    _ => return false,
+                }
+                .expect("formatting stat");
+                true
+            }
+            Self::Slabs(slabs) => {
+                const LINES_PER_CLASS: usize = 7;
+                let active = slabs.classes.len();
+                match slabs.classes.get(index / LINES_PER_CLASS) {
+                    // Numbered from 1, like upstream's.
+                    Some((i, c)) => {
+                        let id = i + 1;
+                        match index % LINES_PER_CLASS {
+                            0 => write!(out, "STAT {}:chunk_size {}\r\n", id, c.chunk_size),
+                            1 => {
+                                write!(out, "STAT {}:chunks_per_page {}\r\n", id, c.chunks_per_page)
+                            }
+                            2 => write!(out, "STAT {}:total_pages {}\r\n", id, c.total_pages),
+                            3 => write!(
+                                out,
+                                "STAT {}:total_chunks {}\r\n",
+                                id,
+                                c.total_pages * c.chunks_per_page
+                            ),
+                            4 => write!(out, "STAT {}:used_chunks {}\r\n", id, c.used_chunks),
+                            5 => write!(out, "STAT {}:free_chunks {}\r\n", id, c.free_chunks),
+                            _ => write!(out, "STAT {}:evicted {}\r\n", id, c.evicted),
+                        }
+                    }
+                    None => match index - active * LINES_PER_CLASS {
+                        0 => write!(out, "STAT active_slabs {}\r\n", active),
+                        1 => write!(out, "STAT total_malloced {}\r\n", slabs.total_malloced),
+                        _ => return false,
+                    },
+                }
+                .expect("formatting stat");
+                true
+            }
+            Self::Items(classes) => {
+                const LINES_PER_CLASS: usize = 5;
+                let Some(items) = classes.get(index / LINES_PER_CLASS) else {
+                    return false;
+                };
+                let c = stats.classes.get(&items.class).copied().unwrap_or_default();
+                let id = items.class;
+                match index % LINES_PER_CLASS {
+                    0 => write!(out, "STAT items:{}:number {}\r\n", id, items.number),
+                    1 => write!(out, "STAT items:{}:age {}\r\n", id, items.age),
+                    2 => write!(out, "STAT items:{}:evicted {}\r\n", id, c.evicted),
+                    3 => write!(out, "STAT items:{}:reclaimed {}\r\n", id, c.reclaimed),
+                    _ => write!(
+                        out,
+                        "STAT items:{}:expired_unfetched {}\r\n",
+                        id, c.expired_unfetched
+                    ),
+                }
+                .expect("formatting stat");
+                true
+            }
+            Self::Conns(conn, now) => {
+                let id = conn.id;
+                match index {
+                    0 => write!(out, "STAT {}:state {}\r\n", id, conn.state),
+                    1 => write!(out, "STAT {}:bytes_read {}\r\n", id, conn.bytes_read),
+                    2 => write!(out, "STAT {}:bytes_written {}\r\n", id, conn.bytes_written),
+                    3 => write!(
+                        out,
+                        "STAT {}:secs_since_last_cmd {}\r\n",
+                        id,
+                        now.saturating_sub(conn.last_activity)
+                    ),
+                    _ => return false,
+                }
+                .expect("formatting stat");
+                true
+            }
+        }
+    }
+}
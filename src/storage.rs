@@ -0,0 +1,373 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use core::cell::RefCell;
+use core::fmt::Debug;
+use core::hash::BuildHasher;
+use core::ops::{Bound, ControlFlow};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+
+use crate::{Entry, ReassignError, SlabStats, ITEM_CLASS};
+
+/// Where a [`CommandHandler`](crate::CommandHandler) keeps its items. Entries are only lent out
+/// to a closure, so that storage behind a lock can be shared: every handler given a clone of an
+/// `Rc<RefCell<_>>` or `Arc<Mutex<_>>` serves the same items.
+///
+/// Responses share an entry's value rather than copy it, and values are replaced rather than
+/// changed in place. A response that's already started keeps sending the value it started
+/// with, however other handlers change or remove the item meanwhile.
+pub trait Storage {
+    /// Calls `f` with the entry at `key`, if there is one.
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R>;
+    /// Calls `f` with the entry at `key`, if there is one, to change it.
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R>;
+    /// Returns the entry `key` had before, if any.
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry>;
+    /// Like [`insert`](Self::insert), but hands `entry` back rather than store it if there's
+    /// no room for it, as in fixed-capacity storage that's full or keeps shorter keys. The
+    /// handler stores items through this, refusing those handed back with
+    /// [`Error::OutOfMemory`](crate::Error::OutOfMemory).
+    fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+        Ok(self.insert(key, entry))
+    }
+    fn remove(&mut self, key: &[u8]) -> Option<Entry>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&mut self);
+    /// Most items the storage can hold, if it has a fixed capacity, such as a `heapless` map.
+    fn capacity(&self) -> Option<usize> {
+        None
+    }
+    /// How values are laid out in slabs, if they are, for `stats slabs`.
+    fn slab_stats(&self) -> Option<SlabStats> {
+        None
+    }
+    /// The slab class `entry`'s value is in, numbered from 1 like `stats slabs` numbers them,
+    /// for `me`, `lru_crawler` and `stats items`. Storage without slabs has the one class.
+    /// Takes no storage, so that a walk can tell while it's lent the entry.
+    fn class_of(entry: &Entry) -> u32 {
+        let _ = entry;
+        ITEM_CLASS
+    }
+    /// Moves a page from slab class `src` to class `dst`, numbered as [`class_of`](Self::class_of)
+    /// numbers them, for `slabs reassign`. A `src` of `None` picks a class with a page to
+    /// spare. The items in the page are evicted, and `evicted` told of each once it's removed.
+    /// Storage without slabs has no class to move a page to.
+    fn reassign(
+        &mut self,
+        src: Option<u32>,
+        dst: u32,
+        evicted: impl FnMut(&[u8], &Entry),
+    ) -> Result<(), ReassignError> {
+        let _ = (src, dst, evicted);
+        Err(ReassignError::BadClass)
+    }
+    /// Whether [`CommandHandler::flush_all`](crate::CommandHandler::flush_all) can leave the
+    /// items in place, to be removed a few at a time, rather than [`clear`](Self::clear). Not
+    /// for storage that outlives the handler, where they'd come back.
+    fn flush_lazily(&self) -> bool {
+        true
+    }
+    /// Whether values may be copied to the heap, as changing one borrowed from static data
+    /// takes. Storage that has no heap to spare for them says no, and such changes are then
+    /// refused with [`Error::OutOfMemory`](crate::Error::OutOfMemory).
+    fn allocates_values(&self) -> bool {
+        true
+    }
+    /// Where a [`scan_from`](Self::scan_from) left off. The default is the start.
+    type Cursor: Default + Debug;
+    /// Calls `f` with every item, until it breaks, and returns how it broke.
+    fn scan<B>(&self, f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.scan_from(&mut Self::Cursor::default(), f)
+    }
+    /// Like [`scan`](Self::scan), from where `cursor` left off, moving it past the items `f`
+    /// continues on. The item `f` breaks on comes first next time, so that a walk such as
+    /// `lru_crawler metadump` can stop when the transmit window is full and pick up in the
+    /// next one. Once every item came up, the cursor stays at the end.
+    ///
+    /// Resuming costs the same however far the walk got, and every item that's there
+    /// throughout comes up whatever else is inserted or removed meanwhile, unless the storage
+    /// says otherwise.
+    fn scan_from<B>(
+        &self,
+        cursor: &mut Self::Cursor,
+        f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B>;
+}
+
+/// With any hasher, like std's map, which is built on this one. The cursor is a bucket index,
+/// so an item may come up twice if the table grows during a walk. One kept past the bucket its
+/// hash picks, as that was taken, may be missed if a rehash moves it back past the cursor.
+impl<H: BuildHasher> Storage for hashbrown::HashMap<Box<[u8]>, Entry, H> {
+    type Cursor = usize;
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.get(key).map(f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.get_mut(key).map(f)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        hashbrown::HashMap::insert(self, key.into(), entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        hashbrown::HashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        hashbrown::HashMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        hashbrown::HashMap::clear(self)
+    }
+
+    fn scan<B>(&self, mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.iter().try_for_each(|(key, entry)| f(key, entry))
+    }
+
+    fn scan_from<B>(
+        &self,
+        bucket: &mut usize,
+        mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        let table = self.raw_table();
+        while *bucket < table.buckets() {
+            // SAFETY: `bucket` is less than the number of buckets, as checked just above.
+            if unsafe { table.is_bucket_full(*bucket) } {
+                // SAFETY: the bucket is in bounds and full, so holds an item, which `self`
+                // being borrowed keeps in place for as long as `f` borrows it.
+                let (key, entry) = unsafe { table.bucket(*bucket).as_ref() };
+                f(key, entry)?;
+            }
+            *bucket += 1;
+        }
+        ControlFlow::Continue(())
+    }
+}
+
+/// With any hasher, such as FNV for short keys that aren't chosen by an attacker, or
+/// `RandomState` seeded by the deployment.
+///
+/// std's map has no cursor of its own, so a walk resumes by skipping as many items as it
+/// passed, less as many as were removed since, taking longer the further it got. Items may be
+/// missed or repeated when others are inserted or removed meanwhile. [`Map`](crate::Map) has
+/// no such trouble.
+#[cfg(feature = "std")]
+impl<H: BuildHasher> Storage for HashMap<Box<[u8]>, Entry, H> {
+    /// Items passed, and how many there were then.
+    type Cursor = (usize, usize);
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.get(key).map(f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.get_mut(key).map(f)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        HashMap::insert(self, key.into(), entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        HashMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn scan<B>(&self, mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.iter().try_for_each(|(key, entry)| f(key, entry))
+    }
+
+    fn scan_from<B>(
+        &self,
+        (passed, len): &mut Self::Cursor,
+        mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        // Items removed since may have been among those passed, moving the rest back.
+        *passed = passed.saturating_sub(len.saturating_sub(self.len()));
+        *len = self.len();
+        self.iter().skip(*passed).try_for_each(|(key, entry)| {
+            f(key, entry)?;
+            *passed += 1;
+            ControlFlow::Continue(())
+        })
+    }
+}
+
+/// Walks in key order, the cursor being the last key passed.
+impl Storage for BTreeMap<Box<[u8]>, Entry> {
+    type Cursor = Option<Box<[u8]>>;
+
+    fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        self.get(key).map(f)
+    }
+
+    fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+        self.get_mut(key).map(f)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+        BTreeMap::insert(self, key.into(), entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self)
+    }
+
+    fn scan<B>(&self, mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+        self.iter().try_for_each(|(key, entry)| f(key, entry))
+    }
+
+    fn scan_from<B>(
+        &self,
+        after: &mut Option<Box<[u8]>>,
+        mut f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        let start = match after {
+            Some(key) => Bound::Excluded(&**key),
+            None => Bound::Unbounded,
+        };
+        let mut passed = None;
+        let flow = self
+            .range::<[u8], _>((start, Bound::Unbounded))
+            .try_for_each(|(key, entry)| {
+                f(key, entry)?;
+                passed = Some(key);
+                ControlFlow::Continue(())
+            });
+        if let Some(key) = passed {
+            *after = Some(key.clone());
+        }
+        flow
+    }
+}
+
+/// Forwards every call to the storage behind `$lock`, a guard over `self`.
+macro_rules! forward {
+    ($lock:ident) => {
+        type Cursor = S::Cursor;
+
+        fn read<R>(&self, key: &[u8], f: impl FnOnce(&Entry) -> R) -> Option<R> {
+            $lock(self).read(key, f)
+        }
+
+        fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut Entry) -> R) -> Option<R> {
+            $lock(self).update(key, f)
+        }
+
+        fn insert(&mut self, key: &[u8], entry: Entry) -> Option<Entry> {
+            $lock(self).insert(key, entry)
+        }
+
+        fn try_insert(&mut self, key: &[u8], entry: Entry) -> Result<Option<Entry>, Entry> {
+            $lock(self).try_insert(key, entry)
+        }
+
+        fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+            $lock(self).remove(key)
+        }
+
+        fn len(&self) -> usize {
+            $lock(self).len()
+        }
+
+        fn clear(&mut self) {
+            $lock(self).clear()
+        }
+
+        fn capacity(&self) -> Option<usize> {
+            $lock(self).capacity()
+        }
+
+        fn slab_stats(&self) -> Option<SlabStats> {
+            $lock(self).slab_stats()
+        }
+
+        fn class_of(entry: &Entry) -> u32 {
+            S::class_of(entry)
+        }
+
+        fn reassign(
+            &mut self,
+            src: Option<u32>,
+            dst: u32,
+            evicted: impl FnMut(&[u8], &Entry),
+        ) -> Result<(), ReassignError> {
+            $lock(self).reassign(src, dst, evicted)
+        }
+
+        fn flush_lazily(&self) -> bool {
+            $lock(self).flush_lazily()
+        }
+
+        fn allocates_values(&self) -> bool {
+            $lock(self).allocates_values()
+        }
+
+        fn scan<B>(&self, f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>) -> ControlFlow<B> {
+            $lock(self).scan(f)
+        }
+
+        fn scan_from<B>(
+            &self,
+            cursor: &mut Self::Cursor,
+            f: impl FnMut(&[u8], &Entry) -> ControlFlow<B>,
+        ) -> ControlFlow<B> {
+            $lock(self).scan_from(cursor, f)
+        }
+    };
+}
+
+/// Storage shared by the handlers of a single-threaded event loop. Entries are never lent out
+/// across a poll, so the borrows can't clash.
+impl<S: Storage> Storage for Rc<RefCell<S>> {
+    forward!(borrow);
+}
+
+fn borrow<S>(storage: &Rc<RefCell<S>>) -> core::cell::RefMut<'_, S> {
+    storage.borrow_mut()
+}
+
+/// Storage shared by handlers on several threads. The lock is only held for the length of one
+/// call, never while sending.
+#[cfg(feature = "std")]
+impl<S: Storage> Storage for Arc<std::sync::Mutex<S>> {
+    forward!(lock);
+}
+
+#[cfg(feature = "std")]
+fn lock<S>(storage: &Arc<std::sync::Mutex<S>>) -> std::sync::MutexGuard<'_, S> {
+    storage.lock().expect("storage poisoned")
+}
+
+/// Like `Arc<std::sync::Mutex<_>>`, for when there's no `std`.
+impl<S: Storage> Storage for Arc<spin::Mutex<S>> {
+    forward!(spin_lock);
+}
+
+fn spin_lock<S>(storage: &Arc<spin::Mutex<S>>) -> spin::MutexGuard<'_, S> {
+    storage.lock()
+}
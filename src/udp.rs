@@ -0,0 +1,132 @@
+//! Memcached over UDP: every datagram carries an 8-byte frame header ahead of the usual text.
+
+use alloc::boxed::Box;
+
+use crate::clock::{Clock, DefaultClock};
+use crate::storage::Storage;
+use crate::{CommandHandler, Entry, Map, Socket};
+
+pub const FRAME_HEADER_LEN: usize = 8;
+
+const MULTI_PACKET_ERROR: &[u8] = b"SERVER_ERROR multi-packet request not supported\r\n";
+
+/// Framing of the request being answered.
+#[derive(Debug, Default)]
+struct Frame {
+    request_id: u16,
+    /// Sequence number of the next response datagram.
+    sequence: u16,
+    /// Datagrams in the response, settled when its first one is sent. Zero if unknown.
+    total: u16,
+    /// Length of the response about to be sent, if known.
+    pending: Option<usize>,
+    /// Error to answer the request with instead of passing it to the handler.
+    error: Option<&'static [u8]>,
+}
+
+impl Frame {
+    /// Writes the header of the next response datagram, which has room for `capacity` bytes
+    /// of payload.
+    fn write_header(&mut self, buf: &mut [u8], capacity: usize) {
+        if self.sequence == 0 {
+            self.total = self.pending.map_or(0, |len| {
+                u16::try_from(len.div_ceil(capacity).max(1)).unwrap_or(0)
+            });
+        }
+        buf[0..2].copy_from_slice(&self.request_id.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.total.to_be_bytes());
+        buf[6..8].copy_from_slice(&[0, 0]);
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
+
+/// A datagram socket seen through the frame headers.
+struct Framed<'a, S> {
+    socket: &'a mut S,
+    frame: &'a mut Frame,
+}
+
+impl<S: Socket> Socket for Framed<'_, S> {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        let frame = &mut *self.frame;
+        self.socket
+            .receive(|datagram| {
+                if datagram.len() < FRAME_HEADER_LEN {
+                    return (datagram.len(), None);
+                }
+                let (header, payload) = datagram.split_at(FRAME_HEADER_LEN);
+                frame.request_id = u16::from_be_bytes([header[0], header[1]]);
+                frame.sequence = 0;
+                // Like upstream, requests must fit a single datagram.
+                if header[2..6] != [0, 0, 0, 1] {
+                    frame.error = Some(MULTI_PACKET_ERROR);
+                    return (datagram.len(), None);
+                }
+                let (taken, r) = f(payload);
+                (FRAME_HEADER_LEN + taken, Some(r))
+            })
+            .flatten()
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let frame = &mut *self.frame;
+        self.socket.transmit(|datagram| {
+            if datagram.len() <= FRAME_HEADER_LEN {
+                return f(&mut []);
+            }
+            let (header, payload) = datagram.split_at_mut(FRAME_HEADER_LEN);
+            let (n, r) = f(payload);
+            if n == 0 {
+                return (0, r);
+            }
+            frame.write_header(header, payload.len());
+            (FRAME_HEADER_LEN + n, r)
+        })
+    }
+}
+
+/// Serves a [`CommandHandler`] over a datagram socket, where each `receive` yields one whole
+/// datagram and each `transmit` fills one.
+pub struct UdpFraming<S: Storage = Map<Box<[u8]>, Entry>, C: Clock = DefaultClock> {
+    pub handler: CommandHandler<S, C>,
+    frame: Frame,
+}
+
+impl<S: Storage, C: Clock> UdpFraming<S, C> {
+    pub fn new(handler: CommandHandler<S, C>) -> Self {
+        Self {
+            handler,
+            frame: Default::default(),
+        }
+    }
+
+    pub fn poll(&mut self, s: &mut impl Socket) -> bool {
+        let mut error_sent = false;
+        if let Some(error) = self.frame.error.take() {
+            let frame = &mut self.frame;
+            frame.pending = Some(error.len());
+            error_sent = s
+                .transmit(|datagram| {
+                    if datagram.len() < FRAME_HEADER_LEN + error.len() {
+                        return (0, ());
+                    }
+                    let (header, payload) = datagram.split_at_mut(FRAME_HEADER_LEN);
+                    frame.write_header(header, payload.len());
+                    payload[..error.len()].copy_from_slice(error);
+                    (FRAME_HEADER_LEN + error.len(), ())
+                })
+                .is_some();
+        }
+        // Responses aren't buffered, so the datagram count has to come from the handler's estimate.
+        self.frame.pending = self.handler.response_len();
+        let handled = self
+            .handler
+            .poll(&mut Framed {
+                socket: s,
+                frame: &mut self.frame,
+            })
+            .progress;
+        error_sent || handled || self.frame.error.is_some()
+    }
+}
@@ -0,0 +1,288 @@
+//! Values kept outside of memory, such as in external flash with only the index in RAM, or in
+//! chunks rather than one allocation.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::slab::Bytes;
+use crate::Error;
+
+/// Where a value's bytes are read from when it's sent, a window at a time, so that it never
+/// has to be in memory all at once.
+///
+/// Bytes in memory are one, which is how values are kept by default.
+pub trait ValueSource: Send + Sync {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Copies bytes from `offset` on into `out`, as many as fit and the value has. Returns how
+    /// many it copied, which is short of that only if the source fails. A response left short
+    /// is retried on the next poll.
+    fn read(&self, offset: usize, out: &mut [u8]) -> usize;
+}
+
+/// Implements [`ValueSource`] for bytes in memory. Not for anything `AsRef<[u8]>`, nor for
+/// slices, as the trait's `len` would then stand in for that of arrays and slice references
+/// wherever it's in scope.
+macro_rules! in_memory {
+    ($($ty:ty),*) => {
+        $(impl ValueSource for $ty {
+            fn len(&self) -> usize {
+                <[u8]>::len(self)
+            }
+
+            fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+                read_slice(self, offset, out)
+            }
+        })*
+    };
+}
+
+in_memory!(Vec<u8>, Box<[u8]>, Arc<[u8]>);
+
+/// [`ValueSource::read`] for bytes in memory.
+pub(crate) fn read_slice(bytes: &[u8], offset: usize, out: &mut [u8]) -> usize {
+    let bytes = bytes.get(offset..).unwrap_or_default();
+    let n = core::cmp::min(out.len(), bytes.len());
+    out[..n].copy_from_slice(&bytes[..n]);
+    n
+}
+
+/// Where a value being stored is written as it arrives, handed out by a [`ValueBackend`].
+pub trait ValueSink: Send {
+    /// Writes the next `bytes` of the value. An error turns the store away with it, once the
+    /// rest of the value has been read past.
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error>;
+    /// Called once the whole value is written. Returns where to read it from.
+    fn finish(self: Box<Self>) -> Result<Box<dyn ValueSource>, Error>;
+}
+
+/// Collects the value in memory.
+impl ValueSink for Vec<u8> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Box<dyn ValueSource>, Error> {
+        Ok(self)
+    }
+}
+
+/// Decides where the values a handler stores are kept, see
+/// [`Settings::value_backend`](crate::Settings::value_backend).
+///
+/// Values made by changing others, by `append`, `prepend` and `incr`, are offered to the
+/// backend like any other. Appended and prepended ones are written to it a window at a time,
+/// old bytes and new.
+pub trait ValueBackend: Send + Sync {
+    /// A sink for a value of `len` bytes to be stored at `key`, or `None` to keep it in memory.
+    /// Returning one doesn't mean the value gets stored: the command may still fail.
+    fn sink(&self, key: &[u8], len: usize) -> Option<Box<dyn ValueSink>>;
+}
+
+impl fmt::Debug for dyn ValueBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ValueBackend")
+    }
+}
+
+/// A value being read off the connection.
+pub(crate) enum Incoming {
+    Memory(Vec<u8>),
+    Sink(Box<dyn ValueSink>),
+    /// Boxed, to keep the states reading values small.
+    Chunked(Box<Chunked>),
+}
+
+impl Incoming {
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match self {
+            Self::Memory(value) => {
+                value.extend_from_slice(bytes);
+                Ok(())
+            }
+            Self::Sink(sink) => sink.write(bytes),
+            Self::Chunked(chunked) => {
+                chunked.write(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<Bytes, Error> {
+        match self {
+            Self::Memory(value) => Ok(value.into()),
+            Self::Sink(sink) => Ok(Bytes::external(sink.finish()?)),
+            Self::Chunked(chunked) => Ok(Bytes::chunked(*chunked)),
+        }
+    }
+}
+
+impl Default for Incoming {
+    fn default() -> Self {
+        Self::Memory(Vec::new())
+    }
+}
+
+impl fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory(value) => write!(f, "{} bytes", value.len()),
+            Self::Sink(_) => f.write_str("ValueSink"),
+            Self::Chunked(chunked) => write!(f, "{} bytes in chunks", chunked.len()),
+        }
+    }
+}
+
+/// `value`, written to wherever `backend` keeps values like it, if anywhere, else split into
+/// chunks if it's long enough for `chunking`.
+pub(crate) fn keep(
+    backend: Option<&dyn ValueBackend>,
+    chunking: Option<Chunking>,
+    key: &[u8],
+    value: Vec<u8>,
+) -> Result<Bytes, Error> {
+    if let Some(mut sink) = backend.and_then(|backend| backend.sink(key, value.len())) {
+        sink.write(&value)?;
+        return Ok(Bytes::external(sink.finish()?));
+    }
+    match chunking.filter(|chunking| chunking.applies(value.len())) {
+        Some(chunking) => {
+            let mut chunked = Chunked::new(chunking.size);
+            chunked.write(&value);
+            Ok(Bytes::chunked(chunked))
+        }
+        None => Ok(value.into()),
+    }
+}
+
+/// `first` followed by `second`, kept as [`keep`] would keep them. A chunked `first` shares
+/// its full chunks with the result, so appending copies no more than its last one.
+pub(crate) fn join(
+    backend: Option<&dyn ValueBackend>,
+    chunking: Option<Chunking>,
+    key: &[u8],
+    first: &Bytes,
+    second: &Bytes,
+) -> Result<Bytes, Error> {
+    let len = first.len() + second.len();
+    if let Some(mut sink) = backend.and_then(|backend| backend.sink(key, len)) {
+        first.pieces(|piece| sink.write(piece))?;
+        second.pieces(|piece| sink.write(piece))?;
+        return Ok(Bytes::external(sink.finish()?));
+    }
+    let Some(chunking) = chunking.filter(|chunking| chunking.applies(len)) else {
+        let mut joined = Vec::with_capacity(len);
+        for bytes in [first, second] {
+            bytes.pieces(|piece| {
+                joined.extend_from_slice(piece);
+                Ok(())
+            })?;
+        }
+        return Ok(joined.into());
+    };
+    let mut chunked = match first.as_chunked() {
+        Some(chunked) => chunked.clone(),
+        None => {
+            let mut chunked = Chunked::new(chunking.size);
+            first.pieces(|piece| {
+                chunked.write(piece);
+                Ok(())
+            })?;
+            chunked
+        }
+    };
+    second.pieces(|piece| {
+        chunked.write(piece);
+        Ok(())
+    })?;
+    Ok(Bytes::chunked(chunked))
+}
+
+/// When values are kept in chunks rather than one allocation, see
+/// [`Settings::chunk_values_over`](crate::Settings::chunk_values_over).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Chunking {
+    pub(crate) over: usize,
+    pub(crate) size: usize,
+}
+
+impl Chunking {
+    pub(crate) fn applies(&self, len: usize) -> bool {
+        len > self.over
+    }
+}
+
+/// A value kept in chunks of a fixed size, all of them full but the last, so that a long
+/// value doesn't need one long allocation. Clones share the chunks.
+#[derive(Clone)]
+pub(crate) struct Chunked {
+    size: usize,
+    chunks: Vec<Arc<Vec<u8>>>,
+}
+
+impl Chunked {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self.chunks.last() {
+            Some(last) => (self.chunks.len() - 1) * self.size + last.len(),
+            None => 0,
+        }
+    }
+
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.chunks.iter().map(|chunk| &chunk[..])
+    }
+
+    /// Adds `bytes` to the end, filling up the last chunk before starting new ones.
+    pub(crate) fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self
+                .chunks
+                .last()
+                .is_none_or(|last| last.len() == self.size)
+            {
+                self.chunks.push(Arc::new(Vec::with_capacity(self.size)));
+            }
+            let last = self.chunks.len() - 1;
+            let chunk = &mut self.chunks[last];
+            if Arc::get_mut(chunk).is_none() {
+                // Shared with the value this one was cloned from, which may still be being
+                // sent, so copied into a chunk of its own.
+                let mut copy = Vec::with_capacity(self.size);
+                copy.extend_from_slice(chunk);
+                *chunk = Arc::new(copy);
+            }
+            let chunk = Arc::make_mut(chunk);
+            let n = core::cmp::min(self.size - chunk.len(), bytes.len());
+            chunk.extend_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+        }
+    }
+
+    /// [`ValueSource::read`], across as many chunks as `out` reaches into.
+    pub(crate) fn read(&self, offset: usize, out: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < out.len() {
+            let at = offset + read;
+            let Some(chunk) = self.chunks.get(at / self.size) else {
+                break;
+            };
+            match read_slice(chunk, at % self.size, &mut out[read..]) {
+                0 => break,
+                n => read += n,
+            }
+        }
+        read
+    }
+}
@@ -0,0 +1,231 @@
+//! The event stream behind the `watch` command.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+#[cfg(not(feature = "std"))]
+use spin::Mutex;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+
+use crate::write_url_encoded;
+
+/// Events buffered per watcher. Once full, new events are dropped and counted instead.
+const WATCHER_QUEUE_LEN: usize = 256;
+
+/// Set of event kinds a watcher is interested in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Topics(u8);
+
+impl Topics {
+    pub const FETCHERS: Self = Self(1);
+    pub const MUTATIONS: Self = Self(2);
+    pub const EVICTIONS: Self = Self(4);
+    pub const DELETIONS: Self = Self(8);
+
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        Some(match name {
+            b"fetchers" => Self::FETCHERS,
+            b"mutations" => Self::MUTATIONS,
+            b"evictions" => Self::EVICTIONS,
+            b"deletions" => Self::DELETIONS,
+            _ => return None,
+        })
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum EventKind {
+    Get { found: bool },
+    Store { cmd: &'static str, stored: bool },
+    Delete { found: bool },
+    Evict,
+}
+
+impl EventKind {
+    fn topic(self) -> Topics {
+        match self {
+            Self::Get { .. } => Topics::FETCHERS,
+            Self::Store { .. } => Topics::MUTATIONS,
+            Self::Delete { .. } => Topics::DELETIONS,
+            Self::Evict => Topics::EVICTIONS,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Event {
+    ts: u32,
+    gid: u64,
+    kind: EventKind,
+    key: Vec<u8>,
+}
+
+impl Event {
+    fn write_line<const N: usize>(&self, out: &mut heapless::Vec<u8, N>) {
+        write!(out, "ts={} gid={} ", self.ts, self.gid).expect("formatting event");
+        let (kind, status) = match self.kind {
+            EventKind::Get { found } => {
+                ("item_get", Some(if found { "found" } else { "not_found" }))
+            }
+            EventKind::Store { stored, .. } => (
+                "item_store",
+                Some(if stored { "stored" } else { "not_stored" }),
+            ),
+            EventKind::Delete { found } => (
+                "item_delete",
+                Some(if found { "deleted" } else { "not_found" }),
+            ),
+            EventKind::Evict => ("eviction", None),
+        };
+        write!(out, "type={} key=", kind).expect("formatting event");
+        // Percent-encoded like upstream, so keys never break the line format.
+        write_url_encoded(out, &self.key);
+        if let Some(status) = status {
+            write!(out, " status={}", status).expect("formatting event");
+        }
+        if let EventKind::Store { cmd, .. } = self.kind {
+            write!(out, " cmd={}", cmd).expect("formatting event");
+        }
+        out.extend_from_slice(b"\r\n").expect("formatting event");
+    }
+}
+
+#[derive(Debug)]
+struct Queue {
+    id: u64,
+    topics: Topics,
+    events: VecDeque<Event>,
+    /// Events dropped since the watcher last drained the queue.
+    skipped: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_gid: u64,
+    next_watcher_id: u64,
+    queues: Vec<Queue>,
+}
+
+/// Fans events out to watching connections. Clones share the same watchers.
+#[derive(Debug, Default, Clone)]
+pub struct EventBus(Arc<Mutex<Inner>>);
+
+impl EventBus {
+    #[cfg(feature = "std")]
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().expect("event bus poisoned")
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn lock(&self) -> spin::MutexGuard<'_, Inner> {
+        self.0.lock()
+    }
+
+    /// Hands the event to every interested watcher. Never blocks on slow watchers: returns how
+    /// many of them had a full queue and dropped it.
+    pub fn publish(&self, ts: u32, kind: EventKind, key: &[u8]) -> u64 {
+        let mut inner = self.lock();
+        if !inner
+            .queues
+            .iter()
+            .any(|q| q.topics.intersects(kind.topic()))
+        {
+            return 0;
+        }
+        let gid = inner.next_gid;
+        inner.next_gid += 1;
+        let mut dropped = 0;
+        for queue in inner.queues.iter_mut() {
+            if !queue.topics.intersects(kind.topic()) {
+                continue;
+            }
+            if queue.events.len() >= WATCHER_QUEUE_LEN {
+                queue.skipped += 1;
+                dropped += 1;
+                continue;
+            }
+            queue.events.push_back(Event {
+                ts,
+                gid,
+                kind,
+                key: key.to_vec(),
+            });
+        }
+        dropped
+    }
+
+    pub fn subscribe(&self, topics: Topics) -> Watcher {
+        let mut inner = self.lock();
+        let id = inner.next_watcher_id;
+        inner.next_watcher_id += 1;
+        inner.queues.push(Queue {
+            id,
+            topics,
+            events: VecDeque::new(),
+            skipped: 0,
+        });
+        Watcher {
+            bus: self.clone(),
+            id,
+        }
+    }
+}
+
+/// A subscription to an [`EventBus`], unsubscribed on drop.
+#[derive(Debug)]
+pub struct Watcher {
+    bus: EventBus,
+    id: u64,
+}
+
+impl Watcher {
+    fn with_queue<R>(&self, f: impl FnOnce(&mut Queue) -> R) -> R {
+        let mut inner = self.bus.lock();
+        let queue = inner
+            .queues
+            .iter_mut()
+            .find(|q| q.id == self.id)
+            .expect("watcher queue missing");
+        f(queue)
+    }
+
+    pub fn pending(&self) -> bool {
+        self.with_queue(|q| q.skipped > 0 || !q.events.is_empty())
+    }
+
+    /// Writes the next line of the stream. Returns `false` if there's nothing to write.
+    ///
+    /// Drops are reported as `skipped=N` before any further events.
+    pub fn write_next<const N: usize>(&self, out: &mut heapless::Vec<u8, N>) -> bool {
+        self.with_queue(|q| {
+            if q.skipped > 0 {
+                write!(out, "skipped={}\r\n", q.skipped).expect("formatting event");
+                q.skipped = 0;
+                return true;
+            }
+            match q.events.pop_front() {
+                Some(event) => {
+                    event.write_line(out);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.bus.lock().queues.retain(|q| q.id != self.id);
+    }
+}
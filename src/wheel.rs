@@ -0,0 +1,110 @@
+//! An index of items by expiry, so that sweeping only visits the ones due.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Seconds the wheel covers one slot each. Items expiring further out wait in the overflow.
+const SLOTS: u32 = 256;
+
+/// Keys by the second they expire, in a wheel of one-second slots for the next [`SLOTS`]
+/// seconds and an ordered overflow for the rest, which moves into the wheel as it turns.
+#[derive(Debug)]
+pub(crate) struct TimerWheel {
+    /// The second the wheel has turned to: everything due before it has been handed out.
+    now: u32,
+    /// Slot `s % SLOTS` holds the keys due in second `s`, for `now <= s < now + SLOTS`.
+    slots: Vec<Vec<Vec<u8>>>,
+    /// Keys in the slots.
+    near: usize,
+    /// Keys due from `now + SLOTS` on.
+    overflow: BTreeMap<u32, Vec<Vec<u8>>>,
+}
+
+impl TimerWheel {
+    pub fn new(now: u32) -> Self {
+        Self {
+            now,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            near: 0,
+            overflow: BTreeMap::new(),
+        }
+    }
+
+    /// The slot for second `at`, which is due already if it's passed.
+    fn slot(&mut self, at: u32) -> &mut Vec<Vec<u8>> {
+        &mut self.slots[(at.max(self.now) % SLOTS) as usize]
+    }
+
+    pub fn insert(&mut self, key: &[u8], at: u32) {
+        if at < self.now.saturating_add(SLOTS) {
+            self.slot(at).push(key.to_vec());
+            self.near += 1;
+        } else {
+            self.overflow.entry(at).or_default().push(key.to_vec());
+        }
+    }
+
+    /// Forgets that `key` expires at `at`, unless it was handed out already.
+    pub fn remove(&mut self, key: &[u8], at: u32) {
+        let near = at < self.now.saturating_add(SLOTS);
+        let keys = if near {
+            self.slot(at)
+        } else {
+            match self.overflow.get_mut(&at) {
+                Some(keys) => keys,
+                None => return,
+            }
+        };
+        let Some(i) = keys.iter().position(|k| k == key) else {
+            return;
+        };
+        keys.swap_remove(i);
+        if near {
+            self.near -= 1;
+        } else if keys.is_empty() {
+            self.overflow.remove(&at);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.iter_mut().for_each(Vec::clear);
+        self.near = 0;
+        self.overflow.clear();
+    }
+
+    /// Hands out up to `max` keys due by `now`, turning the wheel as far as it empties. The
+    /// items may have been changed since, so they're still to be checked.
+    pub fn due(&mut self, now: u32, max: usize) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        while self.now <= now {
+            let slot = (self.now % SLOTS) as usize;
+            while let Some(key) = self.slots[slot].pop() {
+                if due.len() == max {
+                    self.slots[slot].push(key);
+                    return due;
+                }
+                self.near -= 1;
+                due.push(key);
+            }
+            if self.now == u32::MAX {
+                break;
+            }
+            self.now = if self.near == 0 {
+                // Nothing in the wheel: skip ahead to whatever the overflow has next.
+                let next = self.overflow.keys().next().copied().unwrap_or(u32::MAX);
+                next.min(now.saturating_add(1)).max(self.now + 1)
+            } else {
+                self.now + 1
+            };
+            while let Some(entry) = self.overflow.first_entry() {
+                if *entry.key() >= self.now.saturating_add(SLOTS) {
+                    break;
+                }
+                let (at, keys) = entry.remove_entry();
+                self.near += keys.len();
+                self.slot(at).extend(keys);
+            }
+        }
+        due
+    }
+}
@@ -0,0 +1,251 @@
+//! What the tests share: sockets and a clock of their own, as any crate using the handler
+//! would bring.
+
+#![allow(dead_code)]
+
+use incr_memcached::*;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// A clock the tests move by hand.
+#[derive(Clone, Default)]
+pub struct ManualClock(pub Rc<Cell<u32>>);
+
+impl Clock for ManualClock {
+    fn now(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+/// Transmits `window` bytes at a time, 7 by default, collecting the output.
+pub struct NarrowSocket {
+    pub rbuf: VecDeque<u8>,
+    pub sent: Vec<u8>,
+    pub window: usize,
+}
+
+impl Default for NarrowSocket {
+    fn default() -> Self {
+        Self {
+            rbuf: Default::default(),
+            sent: Default::default(),
+            window: 7,
+        }
+    }
+}
+
+impl Socket for NarrowSocket {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.rbuf.make_contiguous());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = vec![0; self.window];
+        let (sent, r) = f(&mut buf);
+        self.sent.extend_from_slice(&buf[..sent]);
+        Some(r)
+    }
+}
+
+/// Alternates empty and 1-byte transmit windows, like a TCP stack whose buffer keeps filling up.
+#[derive(Default)]
+pub struct StutteringSocket {
+    pub rbuf: VecDeque<u8>,
+    pub sent: Vec<u8>,
+    full: bool,
+}
+
+impl Socket for StutteringSocket {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let (taken, r) = f(self.rbuf.make_contiguous());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        self.full = !self.full;
+        let mut buf = [0; 1];
+        let window = if self.full {
+            &mut buf[..0]
+        } else {
+            &mut buf[..]
+        };
+        let (sent, r) = f(window);
+        self.sent.extend_from_slice(&window[..sent]);
+        Some(r)
+    }
+}
+
+/// What `handler` answers `request` with, through `window` byte windows.
+pub fn serve<S: Storage, C: Clock, const KEY_LEN: usize>(
+    handler: &mut CommandHandler<S, C, KEY_LEN>,
+    request: &[u8],
+    window: usize,
+) -> Vec<u8> {
+    let mut socket = NarrowSocket {
+        window,
+        ..Default::default()
+    };
+    socket.rbuf.extend(request);
+    while handler.poll(&mut socket).progress {}
+    socket.sent
+}
+
+/// Hands out input in random chunks and takes output through random windows, empty ones
+/// included, all driven by a xorshift generator so a failing seed can be replayed.
+pub struct FuzzSocket {
+    pub rng: u64,
+    pub rbuf: VecDeque<u8>,
+}
+
+impl FuzzSocket {
+    pub fn next(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// A request: mostly a command with arguments and maybe a data block, sometimes a soup of
+    /// protocol fragments and random bytes.
+    pub fn request(&mut self) -> Vec<u8> {
+        const COMMANDS: &[&[u8]] = &[
+            b"get",
+            b"set",
+            b"incr",
+            b"decr",
+            b"mg",
+            b"ms",
+            b"md",
+            b"ma",
+            b"me",
+            b"mn",
+            b"stats",
+            b"version",
+            b"watch",
+            b"lru_crawler",
+            b"slabs",
+            b"cache_memlimit",
+            b"shutdown",
+            b"flush_all",
+            b"GET",
+            b"bogus",
+        ];
+        const TOKENS: &[&[u8]] = &[
+            b"foo",
+            b"bar",
+            b"Zm9v",
+            b"",
+            b"0",
+            b"1",
+            b"3",
+            b"-1",
+            b"4294967296",
+            b"18446744073709551616",
+            b"v",
+            b"k",
+            b"b",
+            b"q",
+            b"T1",
+            b"T-1",
+            b"O123",
+            b"MA",
+            b"ME",
+            b"N0",
+            b"D1",
+            b"J5",
+            b"I",
+            b"W",
+            b"f",
+            b"s",
+            b"t",
+            b"c",
+            b"sizes",
+            b"conns",
+            b"detail",
+            b"dump",
+            b"on",
+            b"settings",
+            b"metadump",
+            b"all",
+            b"reassign",
+            b"automove",
+            b"\r",
+        ];
+        let mut request = Vec::new();
+        if self.below(4) == 0 {
+            for _ in 0..self.below(16) {
+                match self.below(4) {
+                    0 => {
+                        let len = self.below(600);
+                        request.extend(std::iter::repeat_n(b'x', len));
+                    }
+                    1 => {
+                        // A binary header with random fields.
+                        request.push(0x80);
+                        for _ in 0..23 {
+                            let byte = self.next() as u8;
+                            request.push(byte);
+                        }
+                    }
+                    2 => {
+                        let byte = self.next() as u8;
+                        request.push(byte);
+                    }
+                    _ => {
+                        let token = TOKENS[self.below(TOKENS.len())];
+                        request.extend(token);
+                    }
+                }
+            }
+            return request;
+        }
+        request.extend(COMMANDS[self.below(COMMANDS.len())]);
+        for _ in 0..self.below(6) {
+            request.push(b' ');
+            let token = TOKENS[self.below(TOKENS.len())];
+            request.extend(token);
+        }
+        request.extend(b"\r\n");
+        if self.below(2) == 0 {
+            let len = self.below(8);
+            request.extend(std::iter::repeat_n(b'7', len));
+            request.extend(b"\r\n");
+        }
+        request
+    }
+}
+
+impl Socket for FuzzSocket {
+    fn receive<R>(&mut self, f: impl FnOnce(&[u8]) -> (usize, R)) -> Option<R> {
+        if self.rbuf.is_empty() {
+            return None;
+        }
+        let len = 1 + self.below(self.rbuf.len());
+        let chunk: Vec<u8> = self.rbuf.iter().take(len).copied().collect();
+        let (taken, r) = f(&chunk);
+        assert!(taken <= chunk.len());
+        self.rbuf.drain(..taken);
+        Some(r)
+    }
+
+    fn transmit<R>(&mut self, f: impl FnOnce(&mut [u8]) -> (usize, R)) -> Option<R> {
+        let mut buf = vec![0; self.below(32)];
+        let (sent, r) = f(&mut buf);
+        assert!(sent <= buf.len());
+        Some(r)
+    }
+}
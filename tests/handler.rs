@@ -0,0 +1,1337 @@
+//! The handler driven from outside the crate, through a socket of the test's own.
+
+mod common;
+
+use common::*;
+use incr_memcached::*;
+use std::collections::HashMap;
+
+#[test]
+fn serves_through_an_outside_socket() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    let mut socket = NarrowSocket::default();
+    socket
+        .rbuf
+        .extend(b"set foo 0 0 3\r\nbar\r\nget foo\r\nget nope\r\n");
+    while handler.poll(&mut socket).progress {}
+    assert_eq!(
+        socket.sent,
+        b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\nEND\r\n"
+    );
+    assert_eq!(handler.get(b"foo").unwrap().value(), &b"bar"[..]);
+}
+
+/// Multi-gets with hits before the last key.
+#[test]
+fn multi_get_answers_hits_before_the_last_key() {
+    // Hits before the last key answer with their value and move on too, with one END at the
+    // end of the line
+    let mut multi = CommandHandler::new(HashMap::new());
+    multi.insert(b"foo", Entry::new(b"bar".to_vec())).unwrap();
+    multi.insert(b"k", Entry::new(b"v".to_vec())).unwrap();
+    for window in [1, 7, 1460] {
+        let sent = serve(
+            &mut multi,
+            b"get foo k\r\nget foo nope\r\nget foo foo \r\n",
+            window,
+        );
+        assert_eq!(
+            String::from_utf8(sent).unwrap(),
+            "VALUE foo 0 3\r\nbar\r\nVALUE k 0 1\r\nv\r\nEND\r\n\
+             VALUE foo 0 3\r\nbar\r\nEND\r\n\
+             VALUE foo 0 3\r\nbar\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n"
+        );
+    }
+    let mut one_pending = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            max_pending_responses: 1,
+            ..Default::default()
+        },
+    );
+    for key in ["a", "b", "c"] {
+        one_pending
+            .insert(key.as_bytes(), Entry::new(key.as_bytes().to_vec()))
+            .unwrap();
+    }
+    let sent = serve(&mut one_pending, b"get a b nope c\r\nget c\r\n", 3);
+    assert_eq!(
+        String::from_utf8(sent).unwrap(),
+        "VALUE a 0 1\r\na\r\nVALUE b 0 1\r\nb\r\nVALUE c 0 1\r\nc\r\nEND\r\n\
+         VALUE c 0 1\r\nc\r\nEND\r\n"
+    );
+}
+
+/// Byte for byte against a memcached transcript, through windows that split the VALUE
+/// line's CRLF.
+#[test]
+fn responses_match_a_memcached_transcript() {
+    let mut handler = CommandHandler::new(HashMap::from([(
+        b"foo"[..].into(),
+        Entry::new(b"bar".to_vec()),
+    )]));
+    let mut socket = NarrowSocket::default();
+    for request in [&b"get foo\r\n"[..], b"get nope\r\n", b"bogus\r\n"] {
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+    }
+    assert_eq!(
+        String::from_utf8(socket.sent).unwrap(),
+        "VALUE foo 0 3\r\nbar\r\nEND\r\nEND\r\nERROR\r\n"
+    );
+}
+
+/// The split halves of `poll`, called in different orders, send what `poll` sends.
+#[test]
+fn split_polls_send_what_poll_sends() {
+    let cycle = [
+        &b"set foo 0 0 3\r\nbar\r\n"[..],
+        b"get foo\r\n",
+        b"get nope\r\n",
+    ];
+    for order in ["transmit first", "receive first", "receive twice"] {
+        let mut handler = CommandHandler::new(HashMap::new());
+        let mut socket = NarrowSocket::default();
+        for request in cycle {
+            socket.rbuf.extend(request);
+            loop {
+                let progress = match order {
+                    "transmit first" => {
+                        let sent = handler.poll_transmit(&mut socket).progress;
+                        handler.poll_receive(&mut socket).progress || sent
+                    }
+                    "receive first" => {
+                        let received = handler.poll_receive(&mut socket).progress;
+                        handler.poll_transmit(&mut socket).progress || received
+                    }
+                    _ => {
+                        let received = handler.poll_receive(&mut socket).progress;
+                        let again = handler.poll_receive(&mut socket).progress;
+                        handler.poll_transmit(&mut socket).progress || received || again
+                    }
+                };
+                if !progress {
+                    break;
+                }
+            }
+        }
+        let mut combined = CommandHandler::new(HashMap::new());
+        let expected: Vec<_> = cycle
+            .iter()
+            .flat_map(|request| serve(&mut combined, request, 7))
+            .collect();
+        assert_eq!(
+            String::from_utf8(socket.sent).unwrap(),
+            String::from_utf8(expected).unwrap(),
+            "{}",
+            order
+        );
+    }
+}
+
+/// A data block is read by its length, so one holding a command line isn't run, nor one
+/// arriving a byte at a time cut short by its CR or LF.
+#[test]
+fn values_are_read_by_their_length() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"set blob 0 0 13\r\na\r\nget foo\r\nb\r\nget blob\r\n",
+            64
+        ),
+        b"STORED\r\nVALUE blob 0 13\r\na\r\nget foo\r\nb\r\nEND\r\n"
+    );
+
+    let mut socket = NarrowSocket::default();
+    for &c in b"set trickle 0 0 6\r\na\rb\n\0c\r\nget trickle\r\n" {
+        socket.rbuf.push_back(c);
+        while handler.poll(&mut socket).progress {}
+    }
+    assert_eq!(
+        socket.sent,
+        b"STORED\r\nVALUE trickle 0 6\r\na\rb\n\0c\r\nEND\r\n"
+    );
+}
+
+/// A data block longer or shorter than announced isn't stored. The client hears of the bad
+/// chunk, and what's left of the line after it is taken for a command, as upstream does.
+#[test]
+fn bad_data_chunks_are_refused_and_the_connection_recovers() {
+    // What's left is the CRLF, an empty line, which upstream answers with ERROR
+    let mut upstream_like = CommandHandler::with_settings(
+        HashMap::new(),
+        SystemClock,
+        Settings {
+            ignore_empty_lines: false,
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut upstream_like,
+            b"set long 0 0 3\r\nabcde\r\nget long\r\n",
+            64
+        ))
+        .unwrap(),
+        "CLIENT_ERROR bad data chunk\r\nERROR\r\nEND\r\n"
+    );
+
+    // The block takes the CRLF and the start of the next line, and the rest of it is no
+    // command
+    let mut handler = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut handler,
+            b"set short 0 0 5\r\nabc\r\nget short\r\nget short\r\n",
+            64
+        ))
+        .unwrap(),
+        "CLIENT_ERROR bad data chunk\r\nERROR\r\nEND\r\n"
+    );
+    assert_eq!(
+        serve(&mut handler, b"set ok 0 0 3\r\nabc\r\nget ok\r\n", 64),
+        b"STORED\r\nVALUE ok 0 3\r\nabc\r\nEND\r\n"
+    );
+}
+
+/// Flags come back as they were set, up to `u32::MAX`. Past it the set is refused, its data
+/// block still swallowed, and the item left as it was.
+#[test]
+fn flags_round_trip() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        serve(&mut handler, b"set foo 42 0 3\r\nbar\r\nget foo\r\n", 7),
+        b"STORED\r\nVALUE foo 42 3\r\nbar\r\nEND\r\n"
+    );
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut handler,
+            b"set foo 4294967296 0 3\r\nbaz\r\nget foo\r\n",
+            7
+        ))
+        .unwrap(),
+        "CLIENT_ERROR bad command line format\r\nVALUE foo 42 3\r\nbar\r\nEND\r\n"
+    );
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"set foo 4294967295 0 3\r\nbaz\r\nget foo\r\n",
+            7
+        ),
+        b"STORED\r\nVALUE foo 4294967295 3\r\nbaz\r\nEND\r\n"
+    );
+}
+
+/// Keys with a control character, empty keys and keys past 250 bytes are refused on the text
+/// and meta paths alike, with a refused set's data block skipped.
+#[test]
+fn bad_keys_are_refused() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    let error = "CLIENT_ERROR bad command line format\r\n";
+    for request in [
+        &b"get ctl\x01key\r\n"[..],
+        b"set ctl\x01key 0 0 1\r\nx\r\n",
+        b"ms ctl\x7fkey 1\r\nx\r\n",
+        b"get  \r\n",
+    ] {
+        assert_eq!(
+            String::from_utf8(serve(&mut handler, request, 7)).unwrap(),
+            error
+        );
+    }
+    let long_key = "k".repeat(251);
+    for request in [
+        format!("get {long_key}\r\n"),
+        format!("set {long_key} 0 0 1\r\nx\r\n"),
+    ] {
+        assert_eq!(
+            String::from_utf8(serve(&mut handler, request.as_bytes(), 7)).unwrap(),
+            error
+        );
+    }
+    assert!(handler.is_empty());
+
+    let request = format!("set {} 0 0 1\r\nx\r\n", &long_key[1..]);
+    assert_eq!(serve(&mut handler, request.as_bytes(), 7), b"STORED\r\n");
+    assert_eq!(handler.len(), 1);
+}
+
+/// Runs of spaces anywhere on a line, before the command, between tokens or right before the
+/// line's end, count as one delimiter.
+#[test]
+fn runs_of_spaces_delimit_tokens() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"set  foo  0  0  3\r\nbar\r\nget  foo\r\n  get   foo \r\n",
+            7
+        ),
+        b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n"
+    );
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"set foo   7   0   3 \r\nbaz\r\nget foo  nope   \r\n",
+            7
+        ),
+        b"STORED\r\nVALUE foo 7 3\r\nbaz\r\nEND\r\n"
+    );
+}
+
+/// A handler built for 8-byte keys refuses a 9-byte one that a default handler would take, on
+/// the text and meta paths alike.
+#[test]
+fn key_length_is_set_by_the_handler() {
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let on_error = {
+        let seen = seen.clone();
+        move |error: &Error| seen.lock().unwrap().push(*error)
+    };
+    let mut handler = CommandHandler::<_, _, 8>::with_key_len(
+        HashMap::new(),
+        ManualClock::default(),
+        Settings {
+            on_error: Some(std::sync::Arc::new(on_error)),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        serve(&mut handler, b"set eightkey 0 0 1\r\nx\r\n", 7),
+        b"STORED\r\n"
+    );
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut handler,
+            b"set ninebytes 0 0 1\r\nx\r\nget ninebytes\r\nmg ninebytes v\r\nget eightkey\r\n",
+            7
+        ))
+        .unwrap(),
+        "CLIENT_ERROR bad command line format\r\n\
+         CLIENT_ERROR bad command line format\r\n\
+         CLIENT_ERROR bad command line format\r\n\
+         VALUE eightkey 0 1\r\nx\r\nEND\r\n"
+    );
+    assert_eq!(*seen.lock().unwrap(), [Error::KeyTooLong { limit: 8 }; 3]);
+
+    let mut default = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        serve(&mut default, b"set ninebytes 0 0 1\r\nx\r\n", 7),
+        b"STORED\r\n"
+    );
+}
+
+/// What the handler wants at each step of a get: input until the line is whole, then to write
+/// until the response is out, and it's only idle before and after.
+#[test]
+fn wants_follow_a_get_round_trip() {
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        ManualClock::default(),
+        Settings {
+            max_transmits_per_poll: 1,
+            ..Default::default()
+        },
+    );
+    handler.insert(b"foo", Entry::new(b"bar".to_vec())).unwrap();
+    let wants = |h: &CommandHandler<_, _>| (h.needs_read(), h.needs_write(), h.is_idle());
+    let mut socket = NarrowSocket::default();
+    assert_eq!(wants(&handler), (true, false, true));
+
+    socket.rbuf.extend(b"get fo");
+    handler.poll(&mut socket);
+    assert_eq!(wants(&handler), (true, false, false));
+
+    socket.rbuf.extend(b"o\r\n");
+    handler.poll(&mut socket);
+    while socket.sent.len() < 24 {
+        assert_eq!(wants(&handler), (true, true, false));
+        handler.poll(&mut socket);
+    }
+    assert_eq!(socket.sent, b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+    assert_eq!(wants(&handler), (true, false, true));
+}
+
+/// What `poll` says at each phase of a connection: idle, answering, done answering, and
+/// closing after `quit`.
+#[test]
+fn poll_results_follow_the_connection() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    handler.insert(b"foo", Entry::new(b"bar".to_vec())).unwrap();
+    let mut socket = NarrowSocket::default();
+    let idle = handler.poll(&mut socket);
+    assert!(!idle.progress && !idle.wants_transmit && idle.can_receive);
+    assert!(!idle.should_close && !idle.closed && !idle.more_pending);
+
+    socket.rbuf.extend(b"get foo\r\n");
+    let answering = handler.poll(&mut socket);
+    assert!(answering.progress && answering.wants_transmit && answering.can_receive);
+    let mut result = answering;
+    while result.wants_transmit {
+        result = handler.poll(&mut socket);
+        assert!(result.progress);
+    }
+    assert_eq!(handler.poll(&mut socket), idle);
+    assert_eq!(socket.sent, b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+
+    socket.rbuf.extend(b"get nope\r\nquit\r\nget foo\r\n");
+    let quitting = handler.poll(&mut socket);
+    assert!(quitting.progress && quitting.wants_transmit && !quitting.can_receive);
+    assert!(quitting.should_close && !quitting.closed);
+    let closed = handler.poll(&mut socket);
+    assert!(closed.progress && !closed.wants_transmit && closed.closed);
+    let after = handler.poll(&mut socket);
+    assert!(!after.progress && after.should_close && after.closed);
+    assert_eq!(socket.sent, b"VALUE foo 0 3\r\nbar\r\nEND\r\nEND\r\n");
+}
+
+/// An empty transmit window isn't progress, so a driver polling until there's none doesn't
+/// spin on it, and the next window resumes the response where it stopped.
+#[test]
+fn empty_windows_are_not_progress() {
+    let mut handler = CommandHandler::new(HashMap::new());
+    handler.insert(b"k", Entry::new(b"v".to_vec())).unwrap();
+    let mut socket = StutteringSocket::default();
+    socket.rbuf.extend(b"get k\r\n");
+    let mut loops = 0;
+    while handler.needs_write() || !socket.rbuf.is_empty() {
+        assert!(loops < 100, "the response never went out");
+        while handler.poll(&mut socket).progress {}
+        loops += 1;
+    }
+    assert_eq!(socket.sent, b"VALUE k 0 1\r\nv\r\nEND\r\n");
+}
+
+/// Gets reading sensor readings through on a miss, for a minute each.
+#[test]
+fn misses_read_through() {
+    let clock = ManualClock::default();
+    let asked = std::sync::Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let on_miss = {
+        let asked = asked.clone();
+        move |key: &[u8], now: u32| {
+            let key = String::from_utf8_lossy(key).into_owned();
+            asked.lock().unwrap().push(key.clone());
+            let n: u32 = key.strip_prefix("sensor:")?.parse().ok()?;
+            Some(Entry::with_expiry(
+                (n * 10).to_string().into_bytes(),
+                0,
+                now + 60,
+            ))
+        }
+    };
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            on_miss: Some(std::sync::Arc::new(on_miss)),
+            ..Default::default()
+        },
+    );
+    // Asked about each key a multi-get misses
+    assert_eq!(
+        serve(&mut handler, b"get other sensor:7\r\n", 64),
+        b"VALUE sensor:7 0 2\r\n70\r\nEND\r\n"
+    );
+    assert_eq!(*asked.lock().unwrap(), ["other", "sensor:7"]);
+    // Kept for the gets after, without asking again
+    assert_eq!(
+        serve(&mut handler, b"get sensor:7\r\nmg sensor:7 v\r\n", 64),
+        b"VALUE sensor:7 0 2\r\n70\r\nEND\r\nVA 2\r\n70\r\n"
+    );
+    assert_eq!(asked.lock().unwrap().len(), 2);
+    // Nothing to read through is still a miss
+    assert_eq!(serve(&mut handler, b"get other\r\n", 64), b"END\r\n");
+    assert!(!handler.contains_key(b"other"));
+    // Read through again once expired
+    clock.0.set(clock.0.get() + 61);
+    assert_eq!(
+        serve(&mut handler, b"mg sensor:7 v\r\n", 64),
+        b"VA 2\r\n70\r\n"
+    );
+    assert_eq!(asked.lock().unwrap().len(), 4);
+}
+
+/// Every way an item goes, told about as it goes.
+#[test]
+fn removals_are_told_about() {
+    let clock = ManualClock::default();
+    let removed = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let on_removal = {
+        let removed = removed.clone();
+        move |key: &[u8], entry: &Entry, cause: RemovalCause| {
+            let key = String::from_utf8_lossy(key).into_owned();
+            let value = String::from_utf8_lossy(&entry.value()).into_owned();
+            removed.lock().unwrap().push((key, value, cause));
+        }
+    };
+    let settings = || Settings {
+        on_removal: Some(std::sync::Arc::new(on_removal.clone())),
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(HashMap::new(), clock.clone(), settings());
+    let take = |expected: &[(&str, &str, RemovalCause)]| {
+        let mut removed = removed.lock().unwrap();
+        let got: Vec<_> = removed
+            .iter()
+            .map(|(key, value, cause)| (key.as_str(), value.as_str(), *cause))
+            .collect();
+        assert_eq!(got, expected);
+        removed.clear();
+    };
+
+    serve(
+        &mut handler,
+        b"set a 0 0 1\r\n1\r\nset a 0 0 1\r\n2\r\n",
+        64,
+    );
+    take(&[("a", "1", RemovalCause::Replaced)]);
+    serve(&mut handler, b"md a\r\nmd a\r\n", 64);
+    take(&[("a", "2", RemovalCause::Deleted)]);
+
+    // Expired items are told about when they're found, by a get or by a tick
+    serve(
+        &mut handler,
+        b"set b 0 10 1\r\nb\r\nset c 0 20 1\r\nc\r\n",
+        64,
+    );
+    clock.0.set(clock.0.get() + 11);
+    take(&[]);
+    assert_eq!(serve(&mut handler, b"get b\r\n", 64), b"END\r\n");
+    take(&[("b", "b", RemovalCause::Expired)]);
+    clock.0.set(clock.0.get() + 10);
+    assert_eq!(handler.tick(16), 1);
+    take(&[("c", "c", RemovalCause::Expired)]);
+
+    // Flushed items, as they're reclaimed
+    serve(&mut handler, b"set d 0 0 1\r\nd\r\n", 64);
+    handler.flush_all();
+    take(&[]);
+    assert_eq!(serve(&mut handler, b"get d\r\n", 64), b"END\r\n");
+    take(&[("d", "d", RemovalCause::Flushed)]);
+
+    // Evicted to make room
+    let mut full = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            max_items: 1,
+            ..settings()
+        },
+    );
+    serve(&mut full, b"set e 0 0 1\r\ne\r\nset f 0 0 1\r\nf\r\n", 64);
+    take(&[("e", "e", RemovalCause::Evicted)]);
+    drop(full.remove(b"f"));
+    take(&[("f", "f", RemovalCause::Deleted)]);
+}
+
+/// Deletes leaving tombstones: adds turned away until they expire, sets let through unless
+/// told otherwise, and the tombstones counted apart from the items and reclaimed by ticks.
+#[test]
+fn tombstones_turn_adds_away() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let settings = Settings {
+        tombstone_secs: Some(5),
+        ..Default::default()
+    };
+    let mut handler =
+        CommandHandler::with_settings(HashMap::new(), clock.clone(), settings.clone());
+    let stats = |handler: &mut CommandHandler<HashMap<Box<[u8]>, Entry>, ManualClock>| {
+        let sent = String::from_utf8(serve(handler, b"stats\r\n", 1460)).unwrap();
+        let stat = |name: &str| {
+            sent.lines()
+                .find_map(|line| line.strip_prefix(&format!("STAT {} ", name))?.parse().ok())
+                .unwrap()
+        };
+        let stats: [u64; 4] = [
+            stat("curr_items"),
+            stat("curr_tombstones"),
+            stat("total_tombstones"),
+            stat("tombstone_blocked"),
+        ];
+        stats
+    };
+
+    // A fill racing the delete is turned away, even where there was nothing to delete
+    let sent = serve(
+        &mut handler,
+        b"ms page 3\r\nold\r\nmd page\r\nmg page v\r\nms page 3 ME\r\nold\r\nmd fresh\r\nms fresh 3 ME\r\nold\r\n",
+        64,
+    );
+    assert_eq!(sent, b"HD\r\nHD\r\nEN\r\nNS\r\nNF\r\nNS\r\n");
+    assert!(!handler.contains_key(b"page") && handler.is_empty());
+    assert_eq!(stats(&mut handler), [0, 2, 2, 2]);
+
+    // Once they expire, adds go through
+    clock.0.set(1005);
+    let sent = serve(&mut handler, b"ms page 3 ME\r\nnew\r\nmg page v\r\n", 64);
+    assert_eq!(sent, b"HD\r\nVA 3\r\nnew\r\n");
+    assert_eq!(handler.tick(16), 1);
+    assert_eq!(stats(&mut handler), [1, 0, 2, 2]);
+
+    // A set takes a tombstone's place
+    let sent = serve(
+        &mut handler,
+        b"md page\r\nset page 0 0 3\r\nset\r\nget page\r\n",
+        64,
+    );
+    assert_eq!(sent, b"HD\r\nSTORED\r\nVALUE page 0 3\r\nset\r\nEND\r\n");
+    assert_eq!(stats(&mut handler), [1, 0, 3, 2]);
+
+    // Unless sets are blocked too
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            tombstones_block_set: true,
+            ..settings
+        },
+    );
+    let sent = serve(
+        &mut handler,
+        b"md page\r\nset page 0 0 3\r\nset\r\nget page\r\n",
+        64,
+    );
+    assert_eq!(sent, b"NF\r\nNOT_STORED\r\nEND\r\n");
+    assert!(matches!(
+        handler.insert(b"page", Entry::new(b"direct".to_vec())),
+        Err(Error::NotStored)
+    ));
+    assert_eq!(stats(&mut handler), [0, 1, 1, 2]);
+    clock.0.set(1010);
+    let sent = serve(&mut handler, b"set page 0 0 3\r\nset\r\nget page\r\n", 64);
+    assert_eq!(sent, b"STORED\r\nVALUE page 0 3\r\nset\r\nEND\r\n");
+    assert_eq!(stats(&mut handler), [1, 0, 1, 2]);
+}
+
+/// Threads hammering their own keys and counters they all share, through handlers over the
+/// same sharded storage. Ten times harder with `--release`.
+#[test]
+fn threads_share_sharded_storage() {
+    const THREADS: usize = 8;
+    const SLOTS: usize = 64;
+    const COUNTERS: usize = 8;
+    let rounds = if cfg!(debug_assertions) { 500 } else { 5000 };
+    let storage = Sharded::new(16, HashMap::new);
+    let settings = Settings::default();
+    let mut handler = CommandHandler::with_settings(storage.clone(), SystemClock, settings.clone());
+    for counter in 0..COUNTERS {
+        handler
+            .insert(
+                format!("shared:{}", counter).as_bytes(),
+                Entry::new(b"0".to_vec()),
+            )
+            .unwrap();
+    }
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let (storage, settings) = (storage.clone(), settings.clone());
+            std::thread::spawn(move || {
+                let mut handler = CommandHandler::with_settings(storage, SystemClock, settings);
+                for round in 0..rounds {
+                    let (slot, counter) = (round % SLOTS, round % COUNTERS);
+                    let value = format!("{}:{}", thread, round);
+                    let request = format!(
+                        "ms t{}:{} {}\r\n{}\r\nincr shared:{} 1\r\nmg t{0}:{1} v\r\nmg shared:{4} s\r\n",
+                        thread,
+                        slot,
+                        value.len(),
+                        value,
+                        counter
+                    );
+                    let sent = serve(&mut handler, request.as_bytes(), 1460);
+                    let sent = String::from_utf8(sent).unwrap();
+                    let expected = format!("VA {}\r\n{}\r\n", value.len(), value);
+                    assert!(sent.contains(&expected), "{:?}", sent);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut total = 0;
+    for counter in 0..COUNTERS {
+        let entry = handler
+            .get(format!("shared:{}", counter).as_bytes())
+            .unwrap();
+        total += String::from_utf8_lossy(&entry.value())
+            .parse::<usize>()
+            .unwrap();
+    }
+    assert_eq!(total, THREADS * rounds);
+    assert_eq!(handler.len(), THREADS * SLOTS + COUNTERS);
+    let last = rounds - SLOTS;
+    let entry = handler
+        .get(format!("t3:{}", last % SLOTS).as_bytes())
+        .unwrap();
+    assert_eq!(entry.value(), format!("3:{}", last).as_bytes());
+    let dump = serve(&mut handler, b"lru_crawler metadump all\r\n", 1460);
+    let dumped = String::from_utf8_lossy(&dump)
+        .lines()
+        .filter(|line| line.starts_with("key="))
+        .count();
+    assert_eq!(dumped, THREADS * SLOTS + COUNTERS);
+
+    // A flush by one handler is seen by all, in every shard
+    let other = CommandHandler::with_settings(storage, SystemClock, settings);
+    handler.flush_all();
+    assert!(other.is_empty() && other.get(b"shared:0").is_none());
+}
+
+#[test]
+fn fixed_storage_refuses_items_past_its_capacity() {
+    let settings = Settings {
+        evict_when_full: false,
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(Fixed::<4>::new(), SystemClock, settings);
+    let stores: Vec<u8> = (0..5)
+        .flat_map(|i| format!("set k{} 0 0 1\r\n{}\r\n", i, i).into_bytes())
+        .collect();
+    let sent = serve(&mut handler, &stores, 64);
+    assert_eq!(
+        sent,
+        [
+            &b"STORED\r\n".repeat(4)[..],
+            b"SERVER_ERROR out of memory storing object\r\n"
+        ]
+        .concat()
+    );
+    // Replacing an item takes no more room
+    let sent = serve(&mut handler, b"set k0 0 0 1\r\nx\r\nget k0 k4\r\n", 64);
+    assert_eq!(sent, b"STORED\r\nVALUE k0 0 1\r\nx\r\nEND\r\n");
+
+    // Or evicts one for it
+    let mut evicting = CommandHandler::new(Fixed::<4>::new());
+    serve(&mut evicting, &stores, 64);
+    assert_eq!(evicting.len(), 4);
+    assert!(evicting.contains_key(b"k4"));
+}
+
+/// Keys longer than `Fixed` keeps, and a shard that fills up before the rest, are refused
+/// like any item there's no room for, rather than bring the server down.
+#[test]
+fn fixed_storage_refuses_what_it_cannot_hold() {
+    let mut handler = CommandHandler::new(Fixed::<4, 8>::new());
+    let sent = serve(
+        &mut handler,
+        b"set longer_than_8 0 0 1\r\nx\r\nset short 0 0 1\r\ny\r\nget longer_than_8 short\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"SERVER_ERROR out of memory storing object\r\nSTORED\r\nVALUE short 0 1\r\ny\r\nEND\r\n"
+    );
+    assert_eq!(handler.len(), 1);
+
+    let mut sharded = CommandHandler::new(Sharded::new(2, Fixed::<2>::new));
+    let stores: Vec<u8> = (0..4)
+        .flat_map(|i| format!("set k{} 0 0 1\r\n{}\r\n", i, i).into_bytes())
+        .collect();
+    let sent = String::from_utf8(serve(&mut sharded, &stores, 64)).unwrap();
+    // Unless the keys spread evenly, a shard is full before the handler's capacity is reached
+    let stored = sent.matches("STORED").count();
+    assert_eq!(
+        stored + sent.matches("SERVER_ERROR out of memory").count(),
+        4
+    );
+    assert_eq!(sharded.len(), stored);
+}
+
+#[test]
+fn touch_and_gat_set_when_items_expire() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let settings = Settings {
+        expiry_index: true,
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(HashMap::new(), clock.clone(), settings);
+    let sent = serve(
+        &mut handler,
+        b"set a 0 0 1\r\na\r\nset b 0 0 1\r\nb\r\nset c 0 5 1\r\nc\r\n\
+          touch a 10\r\ntouch nope 10\r\ntouch b -1 noreply\r\ntouch a x\r\ntouch a\r\n\
+          gat 100 c nope\r\nget b\r\n",
+        64,
+    );
+    assert_eq!(
+        String::from_utf8(sent).unwrap(),
+        "STORED\r\nSTORED\r\nSTORED\r\n\
+         TOUCHED\r\nNOT_FOUND\r\nCLIENT_ERROR invalid exptime argument\r\nERROR\r\n\
+         VALUE c 0 1\r\nc\r\nEND\r\nEND\r\n"
+    );
+    // Expired at once, so an add goes through
+    assert_eq!(serve(&mut handler, b"ms b 1 ME\r\nB\r\n", 64), b"HD\r\n");
+
+    // a now goes after 10 seconds, and c after 100, not 5
+    clock.0.set(1009);
+    assert_eq!(
+        serve(&mut handler, b"get a c\r\n", 64),
+        b"VALUE a 0 1\r\na\r\nVALUE c 0 1\r\nc\r\nEND\r\n"
+    );
+    clock.0.set(1010);
+    assert_eq!(handler.tick(16), 1);
+    assert!(!handler.contains_key(b"a") && handler.contains_key(b"c"));
+
+    // A gat that expires the item still sends it, once
+    assert_eq!(
+        serve(&mut handler, b"gat -1 c\r\nget c\r\ngat 10\r\n", 64),
+        b"VALUE c 0 1\r\nc\r\nEND\r\nEND\r\nERROR\r\n"
+    );
+}
+
+#[test]
+fn delete_takes_noreply_as_its_last_token_only() {
+    for (pieces, sent, deleted) in [
+        (&[&b"delete key\r\n"[..]][..], &b"DELETED\r\n"[..], true),
+        (&[b"delete key noreply\r\n"], b"", true),
+        (&[b"delete key noreply \r\n"], b"", true),
+        (&[b"delete key   noreply\r\n"], b"", true),
+        (&[b"delete key 0\r\n"], b"DELETED\r\n", true),
+        (&[b"delete key 0 noreply\r\n"], b"", true),
+        (&[b"delete key nore", b"ply\r", b"\n"], b"", true),
+        (&[b"delete nope noreply\r\n"], b"", false),
+        (&[b"delete nope\r\n"], b"NOT_FOUND\r\n", false),
+        (
+            &[b"delete key noreplyx\r\n"],
+            b"CLIENT_ERROR bad command line format\r\n",
+            false,
+        ),
+        (
+            &[b"delete key NOREPLY\r\n"],
+            b"CLIENT_ERROR bad command line format\r\n",
+            false,
+        ),
+        (
+            &[b"delete key noreply junk\r\n"],
+            b"CLIENT_ERROR bad command line format\r\n",
+            false,
+        ),
+        (
+            &[b"delete key 10\r\n"],
+            b"CLIENT_ERROR bad command line format\r\n",
+            false,
+        ),
+        (
+            &[b"delete key noreply\rx\r\n"],
+            b"CLIENT_ERROR bad command line format\r\n",
+            false,
+        ),
+        (&[b"delete\r\n"], b"ERROR\r\n", false),
+    ] {
+        let mut handler = CommandHandler::new(HashMap::new());
+        handler.insert(b"key", Entry::new(b"v".to_vec())).unwrap();
+        let mut socket = NarrowSocket::default();
+        for piece in pieces {
+            socket.rbuf.extend(*piece);
+            while handler.poll(&mut socket).progress {}
+        }
+        let request = String::from_utf8_lossy(&pieces.concat()).into_owned();
+        assert_eq!(socket.sent, sent, "{:?}", request);
+        assert_eq!(handler.contains_key(b"key"), !deleted, "{:?}", request);
+    }
+}
+
+#[test]
+fn tombstones_turn_text_adds_away() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let settings = Settings {
+        tombstone_secs: Some(5),
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(HashMap::new(), clock.clone(), settings);
+    let sent = serve(
+        &mut handler,
+        b"set page 0 0 3\r\nold\r\ndelete page\r\nget page\r\nadd page 0 0 3\r\nold\r\n\
+          delete fresh noreply\r\nadd fresh 0 0 3\r\nold\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"STORED\r\nDELETED\r\nEND\r\nNOT_STORED\r\nNOT_STORED\r\n"
+    );
+
+    // Once they expire, adds go through, and an add finding an item is turned away as ever
+    clock.0.set(1005);
+    let sent = serve(
+        &mut handler,
+        b"add page 0 0 3\r\nnew\r\nadd page 0 0 3\r\nnix\r\nget page\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"STORED\r\nNOT_STORED\r\nVALUE page 0 3\r\nnew\r\nEND\r\n"
+    );
+
+    // A set takes a tombstone's place
+    let sent = serve(
+        &mut handler,
+        b"delete page\r\nset page 0 0 3\r\nset\r\nget page\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"DELETED\r\nSTORED\r\nVALUE page 0 3\r\nset\r\nEND\r\n"
+    );
+}
+
+#[test]
+fn text_commands_treat_expired_items_as_absent() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let stat = |handler: &mut CommandHandler<HashMap<Box<[u8]>, Entry>, ManualClock>,
+                name: &str| {
+        let sent = String::from_utf8(serve(handler, b"stats\r\n", 1460)).unwrap();
+        sent.lines()
+            .find_map(|line| {
+                line.strip_prefix(&format!("STAT {} ", name))?
+                    .parse::<u64>()
+                    .ok()
+            })
+            .unwrap()
+    };
+    serve(&mut handler, b"set k 0 10 1\r\nv\r\n", 64);
+    let cas = handler.get(b"k").unwrap().cas();
+
+    // A second before its expiry, it's there for every command
+    clock.0.set(1009);
+    let sent = serve(
+        &mut handler,
+        b"gets k\r\nappend k 0 0 1\r\na\r\nprepend k 0 0 1\r\np\r\nreplace k 0 0 3\r\nrep\r\n",
+        64,
+    );
+    assert_eq!(
+        String::from_utf8(sent).unwrap(),
+        format!(
+            "VALUE k 0 1 {}\r\nv\r\nEND\r\nSTORED\r\nSTORED\r\nSTORED\r\n",
+            cas
+        )
+    );
+    let sent = serve(
+        &mut handler,
+        b"set k 0 1 1\r\nv\r\nadd k 0 0 1\r\nx\r\n",
+        64,
+    );
+    assert_eq!(sent, b"STORED\r\nNOT_STORED\r\n");
+
+    // At the second it expires, it's gone for all of them
+    clock.0.set(1010);
+    let sent = serve(
+        &mut handler,
+        b"get k\r\ngets k\r\ngats 100 k\r\nappend k 0 0 1\r\na\r\nreplace k 0 0 1\r\nr\r\n",
+        64,
+    );
+    assert_eq!(sent, b"END\r\nEND\r\nEND\r\nNOT_STORED\r\nNOT_STORED\r\n");
+    assert_eq!(stat(&mut handler, "get_expired"), 1);
+    assert_eq!(stat(&mut handler, "expired_unfetched"), 1);
+
+    // And a second after, an add takes its place
+    clock.0.set(1011);
+    let sent = serve(&mut handler, b"add k 0 0 1\r\nn\r\ngats 0 k\r\n", 64);
+    let cas = handler.get(b"k").unwrap().cas();
+    assert_eq!(
+        String::from_utf8(sent).unwrap(),
+        format!("STORED\r\nVALUE k 0 1 {}\r\nn\r\nEND\r\n", cas)
+    );
+}
+
+/// `stats items` counts the items served: not those past an expiry, be it relative or an
+/// absolute time past, nor tombstones.
+#[test]
+fn stats_items_counts_the_items_served() {
+    let clock = ManualClock::default();
+    clock.0.set(3_000_000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(serve(&mut handler, b"stats items\r\n", 64), b"END\r\n");
+
+    let sent = serve(
+        &mut handler,
+        b"set relative 0 10 1\r\nr\r\nset boundary 0 2592000 1\r\nb\r\n\
+          set future 0 3000100 1\r\nf\r\nset past 0 2999999 1\r\np\r\n\
+          set gone 0 0 1\r\ng\r\ndelete gone\r\n",
+        64,
+    );
+    assert_eq!(
+        sent,
+        b"STORED\r\n"
+            .repeat(5)
+            .into_iter()
+            .chain(*b"DELETED\r\n")
+            .collect::<Vec<_>>()
+    );
+    clock.0.set(3_000_005);
+    assert_eq!(
+        serve(&mut handler, b"get boundary\r\n", 64),
+        b"VALUE boundary 0 1\r\nb\r\nEND\r\n"
+    );
+    assert_eq!(
+        String::from_utf8(serve(&mut handler, b"stats items\r\n", 64)).unwrap(),
+        "STAT items:1:number 3\r\nSTAT items:1:age 5\r\nSTAT items:1:evicted 0\r\n\
+         STAT items:1:reclaimed 0\r\nSTAT items:1:expired_unfetched 0\r\nEND\r\n"
+    );
+
+    // The relative 10 seconds are up, the absolute time is not
+    clock.0.set(3_000_010);
+    assert_eq!(
+        String::from_utf8(serve(&mut handler, b"stats items\r\n", 64)).unwrap(),
+        "STAT items:1:number 2\r\nSTAT items:1:age 10\r\nSTAT items:1:evicted 0\r\n\
+         STAT items:1:reclaimed 0\r\nSTAT items:1:expired_unfetched 0\r\nEND\r\n"
+    );
+    clock.0.set(3_000_100);
+    assert!(serve(&mut handler, b"stats items\r\n", 64).starts_with(b"STAT items:1:number 1\r\n"));
+}
+
+/// `stats reset` zeroes the counters, but not what's stored.
+#[test]
+fn stats_reset_zeroes_only_the_counters() {
+    let stats = |handler: &mut CommandHandler<HashMap<_, _>>| {
+        let sent = String::from_utf8(serve(handler, b"stats\r\n", 4096)).unwrap();
+        sent.lines()
+            .filter_map(|line| {
+                let mut tokens = line.strip_prefix("STAT ")?.split(' ');
+                Some((
+                    tokens.next()?.to_owned(),
+                    tokens.next()?.parse::<u64>().ok()?,
+                ))
+            })
+            .collect::<HashMap<_, _>>()
+    };
+    let mut handler = CommandHandler::new(HashMap::new());
+    serve(
+        &mut handler,
+        b"set foo 0 0 3\r\nbar\r\nget foo\r\nget nope\r\n",
+        64,
+    );
+    let before = stats(&mut handler);
+    assert_eq!(
+        (before["cmd_get"], before["cmd_set"], before["get_hits"]),
+        (2, 1, 1)
+    );
+
+    assert_eq!(serve(&mut handler, b"stats reset\r\n", 64), b"RESET\r\n");
+    let after = stats(&mut handler);
+    for counter in [
+        "total_items",
+        "cmd_get",
+        "cmd_set",
+        "get_hits",
+        "get_misses",
+        "evictions",
+        "expired",
+    ] {
+        assert_eq!(after[counter], 0, "{}", counter);
+    }
+    // Only the reset and the stats since are read, and RESET written
+    assert_eq!(after["bytes_read"], 20);
+    assert_eq!(after["bytes_written"], 7);
+    for gauge in ["curr_items", "bytes", "key_bytes", "value_bytes"] {
+        assert_eq!(after[gauge], before[gauge], "{}", gauge);
+    }
+    assert_eq!(after["curr_items"], 1);
+}
+
+/// With values in slabs, `me`, `lru_crawler metadump` and `stats items` tell the classes
+/// apart.
+#[test]
+fn items_report_their_slab_class() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let settings = SlabSettings {
+        page_size: 4096,
+        min_chunk_size: 64,
+        growth_factor: 2.0,
+        max_pages: 16,
+    };
+    let mut handler =
+        CommandHandler::with_clock(Slabs::new(HashMap::new(), settings), clock.clone());
+    let mut request = b"set small 0 0 10\r\n0123456789\r\nset large 0 0 200\r\n".to_vec();
+    request.extend([b'v'; 200]);
+    request.extend(b"\r\n");
+    assert_eq!(serve(&mut handler, &request, 64), b"STORED\r\nSTORED\r\n");
+
+    let me = String::from_utf8(serve(&mut handler, b"me small\r\nme large\r\n", 64)).unwrap();
+    let classes: Vec<_> = me
+        .lines()
+        .map(|line| line.split(' ').find(|f| f.starts_with("cls=")).unwrap())
+        .collect();
+    assert_eq!(classes, ["cls=1", "cls=3"]);
+
+    let dump = String::from_utf8(serve(&mut handler, b"lru_crawler metadump 3\r\n", 64)).unwrap();
+    assert!(dump.starts_with("key=large "), "{}", dump);
+    assert_eq!(dump.lines().count(), 2);
+    assert_eq!(
+        serve(&mut handler, b"lru_crawler metadump 2\r\n", 64),
+        b"END\r\n"
+    );
+
+    clock.0.set(1004);
+    assert_eq!(
+        String::from_utf8(serve(&mut handler, b"stats items\r\n", 64)).unwrap(),
+        "STAT items:1:number 1\r\nSTAT items:1:age 4\r\nSTAT items:1:evicted 0\r\n\
+         STAT items:1:reclaimed 0\r\nSTAT items:1:expired_unfetched 0\r\n\
+         STAT items:3:number 1\r\nSTAT items:3:age 4\r\nSTAT items:3:evicted 0\r\n\
+         STAT items:3:reclaimed 0\r\nSTAT items:3:expired_unfetched 0\r\nEND\r\n"
+    );
+}
+
+/// `slabs reassign` moves a page between classes, evicting the items in it, and answers as
+/// upstream does when it can't.
+#[test]
+fn slabs_reassign_moves_a_page() {
+    let settings = SlabSettings {
+        page_size: 4096,
+        min_chunk_size: 64,
+        growth_factor: 2.0,
+        max_pages: 16,
+    };
+    let mut handler = CommandHandler::new(Slabs::new(HashMap::new(), settings));
+    // 64 chunks to a page of class 1, so the last item takes a second page
+    let mut request = Vec::new();
+    for i in 0..65 {
+        request.extend(format!("set k{} 0 0 10 noreply\r\n0123456789\r\n", i).as_bytes());
+    }
+    serve(&mut handler, &request, 1460);
+    let pages = |handler: &mut CommandHandler<Slabs<HashMap<_, _>>>| {
+        let sent = String::from_utf8(serve(handler, b"stats slabs\r\n", 1460)).unwrap();
+        sent.lines()
+            .filter(|line| line.contains(":total_pages "))
+            .map(|line| line["STAT ".len()..].replace(":total_pages", ""))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(pages(&mut handler), ["1 2"]);
+
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut handler,
+            b"slabs reassign 1 1\r\nslabs reassign 1 8\r\nslabs reassign 0 2\r\n\
+              slabs reassign 3 1\r\nslabs reassign 1 3\r\nslabs reassign 1 3\r\n",
+            1460
+        ))
+        .unwrap(),
+        "SAME src and dst class are identical\r\nBADCLASS invalid src or dst class id\r\n\
+         BADCLASS invalid src or dst class id\r\nNOTFULL source class has no spare pages\r\n\
+         OK\r\nNOTFULL source class has no spare pages\r\n"
+    );
+    assert_eq!(pages(&mut handler), ["1 1", "3 1"]);
+    // The items in the page moved were evicted
+    let stats = String::from_utf8(serve(&mut handler, b"stats\r\n", 1460)).unwrap();
+    assert!(stats.contains("STAT curr_items 64\r\n"), "{}", stats);
+    assert!(stats.contains("STAT evictions 1\r\n"), "{}", stats);
+
+    // Without slabs there's no class to move a page to
+    let mut unslabbed = CommandHandler::new(HashMap::new());
+    assert_eq!(
+        serve(&mut unslabbed, b"slabs reassign -1 1\r\n", 64),
+        b"BADCLASS invalid src or dst class id\r\n"
+    );
+}
+
+/// `mg N` leaves a stub that expires after its TTL, so a winner that never recaches doesn't
+/// leave everyone else waiting, and `mg R` hands out a win once an item is about to expire.
+#[test]
+fn meta_get_wins_expire_and_come_early() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(
+        serve(&mut handler, b"mg page N30\r\nmg page N30\r\n", 64),
+        b"EN W\r\nHD Z\r\n"
+    );
+    clock.0.set(1030);
+    assert_eq!(serve(&mut handler, b"mg page N30\r\n", 64), b"EN W\r\n");
+
+    assert_eq!(
+        serve(&mut handler, b"ms page 1 T100\r\nx\r\n", 64),
+        b"HD\r\n"
+    );
+    clock.0.set(1099);
+    assert_eq!(
+        serve(&mut handler, b"mg page R30 v\r\n", 64),
+        b"VA 1\r\nx\r\n"
+    );
+    clock.0.set(1101);
+    assert_eq!(
+        serve(&mut handler, b"mg page R30 v\r\nmg page R30 v\r\n", 64),
+        b"VA 1 W\r\nx\r\nVA 1 Z\r\nx\r\n"
+    );
+    // Recaching hands the win back
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"ms page 1 T100\r\ny\r\nmg page R30 v\r\n",
+            64
+        ),
+        b"HD\r\nVA 1\r\ny\r\n"
+    );
+}
+
+/// `mg t` reports the seconds an item has left, as `me` does, and -1 for one that never
+/// expires.
+#[test]
+fn meta_get_reports_the_ttl_left() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    serve(
+        &mut handler,
+        b"ms soon 1 T100\r\nx\r\nms never 1\r\ny\r\n",
+        64,
+    );
+    clock.0.set(1040);
+    let sent = serve(&mut handler, b"mg soon t\r\nmg never t\r\nme soon\r\n", 64);
+    let sent = String::from_utf8(sent).unwrap();
+    assert!(
+        sent.starts_with("HD t60\r\nHD t-1\r\nME soon exp=60 "),
+        "{}",
+        sent
+    );
+}
+
+/// `md I T` marks an item stale with a new short TTL: the first `mg` wins the right to
+/// recache it, the others are told someone has, and the recached item is fresh again.
+#[test]
+fn invalidated_items_are_recached_once() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"ms page 2 T1000\r\nv1\r\nmd page I T30\r\n",
+            64
+        ),
+        b"HD\r\nHD\r\n"
+    );
+    assert_eq!(
+        serve(&mut handler, b"mg page v t\r\nmg page v t\r\n", 64),
+        b"VA 2 t30 W X\r\nv1\r\nVA 2 t30 X Z\r\nv1\r\n"
+    );
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"ms page 2 T1000\r\nv2\r\nmg page v t\r\n",
+            64
+        ),
+        b"HD\r\nVA 2 t1000\r\nv2\r\n"
+    );
+
+    // Left alone, the stale item is gone after its new TTL
+    serve(&mut handler, b"md page I T30\r\n", 64);
+    clock.0.set(1030);
+    assert_eq!(serve(&mut handler, b"mg page v\r\n", 64), b"EN\r\n");
+}
+
+/// `ma N` creates a missing counter with the TTL given, which then expires like any item.
+#[test]
+fn meta_arithmetic_vivifies_with_a_ttl() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"ma hits N30 J10 v\r\nma hits v\r\nmg hits t\r\n",
+            64
+        ),
+        b"VA 2\r\n10\r\nVA 2\r\n11\r\nHD t30\r\n"
+    );
+    clock.0.set(1030);
+    assert_eq!(serve(&mut handler, b"ma hits\r\n", 64), b"NF\r\n");
+}
+
+/// `flush_all` misses the items stored before it, and hits those stored after. With a delay,
+/// they go once it's up.
+#[test]
+fn flush_all_drops_the_items_stored_before_it() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"set a 0 0 1\r\na\r\nflush_all\r\nset b 0 0 1\r\nb\r\nget a b\r\n",
+            64
+        ),
+        b"STORED\r\nOK\r\nSTORED\r\nVALUE b 0 1\r\nb\r\nEND\r\n"
+    );
+    assert_eq!(handler.len(), 1);
+
+    assert_eq!(
+        serve(&mut handler, b"flush_all 10 noreply\r\nget b\r\n", 64),
+        b"VALUE b 0 1\r\nb\r\nEND\r\n"
+    );
+    clock.0.set(1010);
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"get b\r\nset c 0 0 1\r\nc\r\nget b c\r\n",
+            64
+        ),
+        b"END\r\nSTORED\r\nVALUE c 0 1\r\nc\r\nEND\r\n"
+    );
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"flush_all soon\r\nflush_all 0 x\r\nflush_all noreply\r\nget c\r\n",
+            64
+        ),
+        b"CLIENT_ERROR bad command line format\r\nCLIENT_ERROR bad command line format\r\nEND\r\n"
+    );
+}
+
+/// A delayed `flush_all` belongs to its connection: once the handler is reset for another
+/// one, it never takes effect.
+#[test]
+fn reset_drops_a_delayed_flush() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    assert_eq!(
+        serve(&mut handler, b"set a 0 0 1\r\na\r\nflush_all 10\r\n", 64),
+        b"STORED\r\nOK\r\n"
+    );
+    handler.reset();
+    clock.0.set(1010);
+    assert_eq!(
+        serve(&mut handler, b"get a\r\n", 64),
+        b"VALUE a 0 1\r\na\r\nEND\r\n"
+    );
+}
+
+/// Appending or prepending past `item_size_max` is refused and leaves the value as it was,
+/// and the bytes held are counted as for the value stored in one go.
+#[test]
+fn appends_stay_within_the_item_size() {
+    let settings = Settings {
+        item_size_max: ITEM_OVERHEAD + 1 + 10,
+        ..Default::default()
+    };
+    let mut handler = CommandHandler::with_settings(HashMap::new(), SystemClock, settings.clone());
+    assert_eq!(
+        String::from_utf8(serve(
+            &mut handler,
+            b"set a 0 0 5\r\n12345\r\nappend a 0 0 4\r\n6789\r\nappend a 0 0 2\r\nab\r\n\
+              prepend a 0 0 1\r\n0\r\nprepend a 0 0 1\r\nx\r\nget a\r\n",
+            64
+        ))
+        .unwrap(),
+        "STORED\r\nSTORED\r\nSERVER_ERROR object too large for cache\r\nSTORED\r\n\
+         SERVER_ERROR object too large for cache\r\nVALUE a 0 10\r\n0123456789\r\nEND\r\n"
+    );
+
+    let mut in_one_go = CommandHandler::with_settings(HashMap::new(), SystemClock, settings);
+    serve(&mut in_one_go, b"set a 0 0 10\r\n0123456789\r\n", 64);
+    let bytes = |handler: &mut CommandHandler<_>| {
+        let stats = String::from_utf8(serve(handler, b"stats\r\n", 1460)).unwrap();
+        let line = stats.lines().find(|line| line.starts_with("STAT bytes "));
+        line.unwrap().to_string()
+    };
+    assert_eq!(bytes(&mut handler), bytes(&mut in_one_go));
+}
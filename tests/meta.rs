@@ -0,0 +1,215 @@
+//! The meta commands over the text protocol: the flags each takes, the order they're returned
+//! in, and the quiet, base64 and opaque flags they share.
+
+mod common;
+
+use common::*;
+use incr_memcached::*;
+use std::collections::HashMap;
+
+/// A handler at time 1000 holding `foo`, with flags 5 and 100 seconds to live.
+fn handler() -> CommandHandler<HashMap<Box<[u8]>, Entry>, ManualClock> {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(HashMap::new(), clock);
+    assert_eq!(
+        serve(&mut handler, b"set foo 5 100 3\r\nbar\r\n", 64),
+        b"STORED\r\n"
+    );
+    handler
+}
+
+/// What `handler` answers `request` with, as a string.
+fn answer(
+    handler: &mut CommandHandler<HashMap<Box<[u8]>, Entry>, ManualClock>,
+    request: &[u8],
+) -> String {
+    String::from_utf8(serve(handler, request, 64)).unwrap()
+}
+
+/// `mg` returns the flags asked for in the order they were asked for, through a one byte
+/// window too. `k` and `O` come after them.
+#[test]
+fn meta_get_returns_flags_in_request_order() {
+    let mut handler = handler();
+    assert_eq!(
+        serve(&mut handler, b"mg foo s v f t\r\nmg foo t f s\r\n", 1),
+        b"VA 3 s3 f5 t100\r\nbar\r\nHD t100 f5 s3\r\n"
+    );
+    assert_eq!(
+        answer(&mut handler, b"mg nope v\r\nmg nope s\r\n"),
+        "EN\r\nEN\r\n"
+    );
+    // Quiet, a miss says nothing, but a hit still does
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"mg nope v q\r\nmg foo q k O1\r\nmg nope q O2\r\n"
+        ),
+        "HD kfoo O1\r\n"
+    );
+    assert_eq!(
+        answer(&mut handler, b"mg Zm9v b v k\r\n"),
+        "VA 3 kZm9v\r\nbar\r\n"
+    );
+}
+
+/// `ms` stores in the mode asked for, with the flags and TTL given. Quiet, only failures are
+/// answered.
+#[test]
+fn meta_set_stores_by_mode() {
+    let mut handler = handler();
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"ms new 2 T0 F3 q\r\nhi\r\nms new 2 ME O4\r\nhi\r\nms new 2 MA k\r\n!!\r\n\
+              ms miss 2 MR q O5\r\nxx\r\nmg new f v\r\n"
+        ),
+        "NS O4\r\nHD knew\r\nNS O5\r\nVA 4 f3\r\nhi!!\r\n"
+    );
+    // A key with a space in it, base64 encoded
+    assert_eq!(
+        answer(&mut handler, b"ms YSBi 2 b k O6\r\nyo\r\nmg YSBi b v\r\n"),
+        "HD kYSBi O6\r\nVA 2\r\nyo\r\n"
+    );
+    assert_eq!(handler.get(b"a b").unwrap().value(), &b"yo"[..]);
+}
+
+/// Pipelined quiet `md`s only answer for the keys that weren't there, each with its opaque
+/// token.
+#[test]
+fn meta_delete_answers_misses_when_quiet() {
+    let mut handler = handler();
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"set a 0 0 1\r\na\r\nset b 0 0 1\r\nb\r\n\
+              md a q O1\r\nmd nope q O2\r\nmd b q O3\r\nmd nope2 q O4\r\nmd a\r\n"
+        ),
+        "STORED\r\nSTORED\r\nNF O2\r\nNF O4\r\nNF\r\n"
+    );
+    assert_eq!(
+        answer(&mut handler, b"md Zm9v b k\r\nmg foo\r\n"),
+        "HD kZm9v\r\nEN\r\n"
+    );
+}
+
+/// `ma` counts up or down from what's stored, or from `J` once `N` creates it.
+#[test]
+fn meta_arithmetic_counts() {
+    let mut handler = handler();
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"ma n q O1\r\nma n N0 J10 q O2\r\nma n v O3\r\nma n MD D20 v\r\n"
+        ),
+        "NF O1\r\nVA 2 O3\r\n11\r\nVA 1\r\n0\r\n"
+    );
+    assert_eq!(
+        answer(&mut handler, b"ma foo\r\n"),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+    );
+    // `bg==` is `n`
+    assert_eq!(
+        answer(&mut handler, b"ma bg== b D5 k v\r\n"),
+        "VA 1 kbg==\r\n5\r\n"
+    );
+}
+
+/// `mn` comes after the answers to everything sent before it, quiet or not.
+#[test]
+fn meta_no_op_comes_after_the_answers_before_it() {
+    let mut handler = handler();
+    assert_eq!(
+        serve(
+            &mut handler,
+            b"mg foo v\r\nmg nope q\r\nmd nope q O1\r\nms foo 1 q\r\nx\r\nmn\r\n",
+            1
+        ),
+        b"VA 3\r\nbar\r\nNF O1\r\nMN\r\n"
+    );
+    assert_eq!(
+        answer(&mut handler, b"mg a q\r\nmg b q\r\nmn\r\n"),
+        "MN\r\n"
+    );
+}
+
+/// `me` shows an item's metadata on one line, its key base64 encoded if it came so.
+#[test]
+fn meta_debug_shows_an_item() {
+    let mut handler = handler();
+    let cas = handler.get(b"foo").unwrap().cas();
+    assert_eq!(
+        answer(&mut handler, b"me foo\r\nme Zm9v b\r\nme nope\r\n"),
+        format!(
+            "ME foo exp=100 la=0 cas={cas} fetch=no cls=1 size=46\r\n\
+             ME Zm9v exp=100 la=0 cas={cas} fetch=no cls=1 size=46\r\nEN\r\n"
+        )
+    );
+    assert_eq!(
+        answer(&mut handler, b"me foo q\r\n"),
+        "CLIENT_ERROR invalid flag\r\n"
+    );
+}
+
+/// With `b`, keys are base64 encoded, so they may hold any bytes, and decode to as long a key
+/// as any other.
+#[test]
+fn meta_keys_may_be_base64_encoded() {
+    let mut handler = handler();
+    // `AAEg` is a NUL, a 1 and a space
+    assert_eq!(
+        answer(&mut handler, b"ms AAEg 1 b k\r\nz\r\nmg AAEg b k v\r\n"),
+        "HD kAAEg\r\nVA 1 kAAEg\r\nz\r\n"
+    );
+    assert_eq!(handler.get(b"\0\x01 ").unwrap().value(), &b"z"[..]);
+
+    // 250 `k`s, then one more
+    let longest = "a2tr".repeat(83) + "aw==";
+    let request = format!("ms {longest} 1 b\r\nx\r\nmg {longest} b s v\r\n");
+    assert_eq!(
+        answer(&mut handler, request.as_bytes()),
+        "HD\r\nVA 1 s1\r\nx\r\n"
+    );
+    assert!(handler.contains_key(&[b'k'; 250]));
+    let request = format!("mg {}a2s= b v\r\n", "a2tr".repeat(83));
+    assert_eq!(
+        answer(&mut handler, request.as_bytes()),
+        "CLIENT_ERROR bad token\r\n"
+    );
+
+    assert_eq!(
+        answer(&mut handler, b"mg !!!! b v\r\nmd Zm9 b\r\nma Zm9v= b\r\n"),
+        "CLIENT_ERROR bad token\r\n".repeat(3)
+    );
+}
+
+/// `O` is echoed on every meta command, up to 32 bytes, and quiet commands only answer when
+/// it's not the nominal answer.
+#[test]
+fn meta_opaque_tokens_match_answers_up() {
+    let mut handler = handler();
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"set a 0 0 1\r\na\r\nset b 0 0 1\r\nb\r\n\
+              mg a q v O1\r\nmg nope q v O2\r\nmg b q v O3\r\nmn\r\n"
+        ),
+        "STORED\r\nSTORED\r\nVA 1 O1\r\na\r\nVA 1 O3\r\nb\r\nMN\r\n"
+    );
+    assert_eq!(
+        answer(
+            &mut handler,
+            b"ms c 1 q O4\r\nc\r\nma c q O5\r\nmd c q O6\r\nmd c q O7\r\nmn\r\n"
+        ),
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\nNF O7\r\nMN\r\n"
+    );
+    let longest = "o".repeat(32);
+    assert_eq!(
+        answer(
+            &mut handler,
+            format!("mg nope O{longest}\r\nmg nope O{longest}o\r\n").as_bytes()
+        ),
+        format!("EN O{longest}\r\nCLIENT_ERROR invalid flag\r\n")
+    );
+}
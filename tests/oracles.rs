@@ -0,0 +1,204 @@
+//! Random workloads checked against what must hold whatever they do. FUZZ_SEED replays
+//! another fuzzing run.
+
+mod common;
+
+use common::*;
+use incr_memcached::*;
+use std::collections::{HashMap, VecDeque};
+
+/// Drives a handler with an expiry index and one without through the same random stores,
+/// deletes and flushes, delayed or not, with the clock moving on, ticking the first. Ticking
+/// removes exactly what the second no longer serves, never more.
+#[test]
+fn expiry_index_agrees_with_lookups() {
+    let mut rng = FuzzSocket {
+        rng: 0x5eed,
+        rbuf: VecDeque::new(),
+    };
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut indexed = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            expiry_index: true,
+            ..Default::default()
+        },
+    );
+    let mut lazy = CommandHandler::with_clock(HashMap::new(), clock.clone());
+    let serve = |handler: &mut CommandHandler<_, _>, request: &[u8]| {
+        let mut socket = NarrowSocket {
+            window: 4096,
+            ..Default::default()
+        };
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+        socket.sent
+    };
+    for _ in 0..100 {
+        let mut requests = Vec::new();
+        for _ in 0..50 {
+            let key = rng.below(500);
+            let request = match rng.below(8) {
+                0 => format!("md k{} q\r\n", key),
+                n => {
+                    let ttl = [0, -1, 3, 30, 255, 256, 300, 5000][n];
+                    format!("ms k{} 1 T{} q\r\nx\r\n", key, ttl)
+                }
+            };
+            requests.extend(request.into_bytes());
+        }
+        if rng.below(10) == 0 {
+            let delay = [0, 5, 30][rng.below(3)];
+            requests.extend(format!("flush_all {} noreply\r\n", delay).into_bytes());
+        }
+        serve(&mut indexed, &requests);
+        serve(&mut lazy, &requests);
+        clock.0.set(clock.0.get() + rng.below(60) as u32);
+        indexed.tick(16);
+        if rng.below(4) > 0 {
+            continue;
+        }
+        while indexed.tick(usize::MAX) > 0 {}
+        // Before looking anything up, which would reclaim what's left over.
+        let stats = serve(&mut indexed, b"stats\r\n");
+        let gets: Vec<u8> = (0..500)
+            .flat_map(|k| format!("mg k{} v\r\n", k).into_bytes())
+            .collect();
+        let found = serve(&mut lazy, &gets);
+        assert!(serve(&mut indexed, &gets) == found);
+        let live = found.windows(3).filter(|w| w == b"VA ").count();
+        let items = format!("STAT curr_items {}\r\n", live);
+        assert!(String::from_utf8_lossy(&stats).contains(&items));
+    }
+}
+
+/// A random mix of stores, growth, arithmetic, removal and expiry against a handler evicting
+/// to stay under a small memory limit. Once what's expired is reclaimed, its running memory
+/// counters add up to what a metadump of every item does.
+#[test]
+fn memory_accounting_reconciles() {
+    let mut rng = FuzzSocket {
+        rng: 0xacc7,
+        rbuf: VecDeque::new(),
+    };
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_settings(
+        HashMap::new(),
+        clock.clone(),
+        Settings {
+            memory_limit: 4096,
+            evict_when_full: true,
+            expiry_index: true,
+            ..Default::default()
+        },
+    );
+    let serve = |handler: &mut CommandHandler<_, _>, request: &[u8]| {
+        let mut socket = NarrowSocket {
+            window: 4096,
+            ..Default::default()
+        };
+        socket.rbuf.extend(request);
+        while handler.poll(&mut socket).progress {}
+        socket.sent
+    };
+    for _ in 0..100 {
+        let mut requests = Vec::new();
+        for _ in 0..50 {
+            let key = rng.below(200);
+            let len = rng.below(100);
+            let value = "v".repeat(len);
+            let request = match rng.below(10) {
+                0 => format!("ms k{} {} MA q\r\n{}\r\n", key, len, value),
+                1 => format!("ms k{} {} MP q\r\n{}\r\n", key, len, value),
+                2 => format!("incr k{} {} noreply\r\n", key, rng.below(100_000)),
+                3 => format!("decr k{} {} noreply\r\n", key, rng.below(100_000)),
+                4 => format!("md k{} q\r\n", key),
+                5 => format!("get k{}\r\n", key),
+                6 => format!("set k{} 0 0 3 noreply\r\n{:03}\r\n", key, rng.below(1000)),
+                n => {
+                    let ttl = [0, 3, 30][n - 7];
+                    format!("ms k{} {} T{} q\r\n{}\r\n", key, len, ttl, value)
+                }
+            };
+            requests.extend(request.into_bytes());
+        }
+        serve(&mut handler, &requests);
+        clock.0.set(clock.0.get() + rng.below(10) as u32);
+        handler.tick(8);
+        if rng.below(50) == 0 {
+            handler.flush_all();
+        }
+        if rng.below(4) > 0 {
+            continue;
+        }
+        // The dump leaves out items expired or flushed, which the counters include until
+        // they're reclaimed.
+        while handler.tick(usize::MAX) > 0 {}
+        let dump = serve(&mut handler, b"lru_crawler metadump all\r\n");
+        let (mut items, mut key_bytes, mut value_bytes) = (0, 0, 0);
+        for line in String::from_utf8_lossy(&dump).lines() {
+            let Some(rest) = line.strip_prefix("key=") else {
+                continue;
+            };
+            let key_len = rest.split(' ').next().unwrap().len() as u64;
+            let size: u64 = line.rsplit("size=").next().unwrap().parse().unwrap();
+            items += 1;
+            key_bytes += key_len;
+            value_bytes += size - key_len - ITEM_OVERHEAD as u64;
+        }
+        let memory = handler.memory_stats();
+        assert_eq!(
+            (memory.items, memory.key_bytes, memory.value_bytes),
+            (items, key_bytes, value_bytes)
+        );
+        assert_eq!(memory.overhead_bytes, items * ITEM_OVERHEAD as u64);
+    }
+    let memory = handler.memory_stats();
+    assert!(memory.evictions > 0 && memory.reclaimed > 0 && memory.total_items > memory.items);
+}
+
+/// Random input through random windows never brings the handler down.
+#[test]
+fn fuzzing_survives() {
+    let seed: u64 = std::env::var("FUZZ_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(0x5eed);
+    let mut socket = FuzzSocket {
+        rng: seed | 1,
+        rbuf: VecDeque::new(),
+    };
+    for _ in 0..2000 {
+        let mut map = HashMap::new();
+        map.insert(b"foo"[..].into(), Entry::new(b"1".to_vec()));
+        let mut handler = CommandHandler::with_settings(
+            map,
+            SystemClock,
+            Settings {
+                protocol: Protocol::Negotiating,
+                lenient_line_endings: socket.below(2) == 0,
+                ignore_empty_lines: socket.below(2) == 0,
+                case_insensitive_commands: socket.below(2) == 0,
+                enable_shutdown: socket.below(2) == 0,
+                memory_limit: [64, 64 * 1024 * 1024][socket.below(2)],
+                item_size_max: [80, 1024 * 1024][socket.below(2)],
+                max_line_len: [16, 512][socket.below(2)],
+                max_keys_per_get: 1 + socket.below(3),
+                ..Default::default()
+            },
+        );
+        for _ in 0..socket.below(32) {
+            let request = socket.request();
+            socket.rbuf.extend(request);
+            // Bounded, since nothing promises an empty window makes no progress.
+            for _ in 0..10_000 {
+                if !handler.poll(&mut socket).progress && socket.rbuf.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+}
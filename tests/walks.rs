@@ -0,0 +1,233 @@
+//! Walks of the items that resume from the storage's cursor: `lru_crawler metadump`,
+//! `stats sizes` and the expiry sweep of `tick`.
+
+mod common;
+
+use common::*;
+use incr_memcached::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// `lru_crawler metadump` through small windows while another handler removes and stores
+/// items between them: every item that's there throughout comes exactly once.
+fn dump_while_changing<S: Storage>(items: S) {
+    let storage = Rc::new(RefCell::new(items));
+    let mut writer = CommandHandler::new(storage.clone());
+    for i in 0..1000 {
+        let key = format!("k{}", i);
+        writer
+            .insert(key.as_bytes(), Entry::new(b"x".to_vec()))
+            .unwrap();
+    }
+    let mut dumper = CommandHandler::with_settings(
+        storage,
+        SystemClock,
+        Settings {
+            max_transmits_per_poll: 1,
+            ..Default::default()
+        },
+    );
+    let mut socket = NarrowSocket {
+        window: 64,
+        ..Default::default()
+    };
+    socket.rbuf.extend(b"lru_crawler metadump all\r\n");
+    let mut changes = 0;
+    while dumper.poll(&mut socket).progress {
+        if changes < 500 {
+            writer.remove(format!("k{}", 2 * changes).as_bytes());
+            let key = format!("n{}", changes);
+            writer
+                .insert(key.as_bytes(), Entry::new(b"x".to_vec()))
+                .unwrap();
+            changes += 1;
+        }
+    }
+    assert_eq!(changes, 500);
+    let dump = String::from_utf8_lossy(&socket.sent);
+    assert!(dump.ends_with("END\r\n"));
+    let keys: Vec<&str> = dump
+        .lines()
+        .filter_map(|line| line.strip_prefix("key=")?.split(' ').next())
+        .collect();
+    let unique: std::collections::HashSet<&str> = keys.iter().copied().collect();
+    assert_eq!(unique.len(), keys.len());
+    assert!((1..1000)
+        .step_by(2)
+        .all(|i| unique.contains(format!("k{}", i).as_str())));
+}
+
+#[test]
+fn metadump_resumes_over_a_hash_map() {
+    dump_while_changing(Map::<_, _>::default());
+}
+
+#[test]
+fn metadump_resumes_over_a_btree_map() {
+    dump_while_changing(std::collections::BTreeMap::new());
+}
+
+#[test]
+fn metadump_resumes_over_shards() {
+    // Roomy enough that no shard grows during the walk, which would repeat items
+    dump_while_changing(Sharded::new(4, || {
+        Map::<_, _>::with_capacity_and_hasher(1000, Default::default())
+    }));
+}
+
+/// Ticks carry on where the last left off, whatever they removed: of 10k items, the third
+/// that expired are all reclaimed in one round.
+fn sweep<S: Storage>(items: S) {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(items, clock.clone());
+    for i in 0..10_000 {
+        let expires_at = if i % 3 == 0 { 1010 } else { 0 };
+        let entry = Entry::with_expiry(b"x".to_vec(), 0, expires_at);
+        handler.insert(format!("k{}", i).as_bytes(), entry).unwrap();
+    }
+    clock.0.set(1010);
+    let reclaimed: usize = (0..10_000usize.div_ceil(64))
+        .map(|_| handler.tick(64))
+        .sum();
+    assert_eq!(reclaimed, 3334);
+    assert_eq!(handler.len(), 6666);
+}
+
+#[test]
+fn sweep_resumes_over_a_hash_map() {
+    sweep(Map::<_, _>::default());
+}
+
+#[test]
+fn sweep_resumes_over_a_btree_map() {
+    sweep(std::collections::BTreeMap::new());
+}
+
+#[test]
+fn sweep_resumes_over_shards() {
+    sweep(Sharded::new(4, Map::<_, _>::default));
+}
+
+/// Connections dump at once, each with its own cursor, but one at a time each.
+#[test]
+fn connections_dump_at_once() {
+    let storage = Rc::new(RefCell::new(Map::<_, _>::default()));
+    let settings = Settings {
+        max_transmits_per_poll: 1,
+        ..Default::default()
+    };
+    let mut a = CommandHandler::with_settings(storage.clone(), SystemClock, settings.clone());
+    let mut b = CommandHandler::with_settings(storage, SystemClock, settings);
+    for i in 0..100 {
+        let key = format!("k{}", i);
+        a.insert(key.as_bytes(), Entry::new(b"x".to_vec())).unwrap();
+    }
+    let mut a_socket = NarrowSocket {
+        window: 64,
+        ..Default::default()
+    };
+    let mut b_socket = NarrowSocket {
+        window: 64,
+        ..Default::default()
+    };
+    a_socket
+        .rbuf
+        .extend(b"lru_crawler metadump all\r\nlru_crawler metadump all\r\n");
+    b_socket.rbuf.extend(b"lru_crawler metadump all\r\n");
+    while a.poll(&mut a_socket).progress | b.poll(&mut b_socket).progress {}
+    let (a_sent, b_sent) = (
+        String::from_utf8_lossy(&a_socket.sent),
+        String::from_utf8_lossy(&b_socket.sent),
+    );
+    assert!(a_sent.ends_with("END\r\nBUSY currently processing crawler request\r\n"));
+    assert!(b_sent.ends_with("END\r\n"));
+    assert_eq!(a_sent.matches("key=").count(), 100);
+    assert_eq!(b_sent.matches("key=").count(), 100);
+}
+
+/// The dump says when items expire, and leaves out those that already did, though they're
+/// yet to be reaped.
+#[test]
+fn metadump_shows_expiry_and_leaves_out_expired() {
+    let clock = ManualClock::default();
+    clock.0.set(1000);
+    let mut handler = CommandHandler::with_clock(Map::<_, _>::default(), clock.clone());
+    let stored = serve(
+        &mut handler,
+        b"ms forever 1 q\r\nx\r\nms later 1 T100 q\r\nx\r\nms soon 1 T10 q\r\nx\r\nmn\r\n",
+        1460,
+    );
+    assert_eq!(stored, b"MN\r\n");
+    clock.0.set(1010);
+    let dump = serve(&mut handler, b"lru_crawler metadump all\r\n", 7);
+    let dump = String::from_utf8_lossy(&dump);
+    assert!(dump.contains("key=forever exp=-1 "));
+    assert!(dump.contains("key=later exp=1100 "));
+    assert!(!dump.contains("key=soon"));
+    assert_eq!(handler.len(), 3);
+}
+
+/// A handler reset halfway through a dump serves the next connection's dump from the start,
+/// with nothing of the last one.
+#[test]
+fn reset_drops_a_dump_half_sent() {
+    let mut handler = CommandHandler::with_settings(
+        Map::<_, _>::default(),
+        SystemClock,
+        Settings {
+            max_transmits_per_poll: 1,
+            ..Default::default()
+        },
+    );
+    for i in 0..100 {
+        let key = format!("k{}", i);
+        handler
+            .insert(key.as_bytes(), Entry::new(b"x".to_vec()))
+            .unwrap();
+    }
+    let mut socket = NarrowSocket {
+        window: 64,
+        ..Default::default()
+    };
+    socket.rbuf.extend(b"lru_crawler metadump all\r\n");
+    for _ in 0..10 {
+        handler.poll(&mut socket);
+    }
+    assert!(handler.needs_write());
+
+    handler.reset();
+    assert!(handler.is_idle());
+    let idle = handler.poll(&mut NarrowSocket::default());
+    assert!(!idle.progress && !idle.wants_transmit && !idle.more_pending);
+    let dump = serve(&mut handler, b"lru_crawler metadump all\r\n", 64);
+    let dump = String::from_utf8_lossy(&dump);
+    assert_eq!(dump.matches("key=").count(), 100);
+    assert!(dump.starts_with("key=") && dump.ends_with("END\r\n"));
+}
+
+/// `stats sizes` counts a poll's worth of items at a time, then answers.
+#[test]
+fn stats_sizes_counts_across_polls() {
+    let mut handler = CommandHandler::default();
+    for i in 0..3000 {
+        let key = format!("k{:04}", i);
+        handler
+            .insert(key.as_bytes(), Entry::new(b"x".to_vec()))
+            .unwrap();
+    }
+    let mut socket = NarrowSocket::default();
+    socket.rbuf.extend(b"stats sizes\r\n");
+    let mut polls = 0;
+    while !socket.sent.ends_with(b"END\r\n") {
+        let result = handler.poll(&mut socket);
+        assert!(result.progress);
+        polls += 1;
+    }
+    assert!(polls > 3);
+    let size = (5 + 1 + std::mem::size_of::<Entry>()).div_ceil(32) * 32;
+    assert_eq!(
+        String::from_utf8_lossy(&socket.sent),
+        format!("STAT {} 3000\r\nEND\r\n", size)
+    );
+}